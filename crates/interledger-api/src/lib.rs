@@ -4,6 +4,8 @@ extern crate tower_web;
 
 use bytes::Bytes;
 use futures::Future;
+use interledger_btp::BtpMetrics;
+use interledger_ccp::RoutePolicy;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
@@ -11,8 +13,10 @@ use interledger_router::RouterStore;
 use interledger_service::{Account as AccountTrait, IncomingService, Username};
 use interledger_service_util::{BalanceStore, ExchangeRateStore};
 use interledger_settlement::{SettlementAccount, SettlementStore};
+use interledger_spsp::InvoiceStore;
+use interledger_stream::StreamReceiverStore;
 use serde::Serialize;
-use std::str;
+use std::{str, time::Duration};
 use tower_web::{net::ConnectionStream, Extract, Response, ServiceBuilder};
 
 mod routes;
@@ -39,6 +43,24 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
         account: AccountDetails,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
 
+    /// Replace the account's incoming BTP token. The token it replaces is still accepted
+    /// until the grace period ends, so the peer can switch over without downtime.
+    fn rotate_btp_incoming_token(
+        &self,
+        id: <Self::Account as AccountTrait>::AccountId,
+        token: String,
+        grace_period: Duration,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
+
+    /// Set which routes are accepted from and advertised to the account.
+    /// Passing None for a policy removes it, so all routes are allowed again.
+    fn set_routing_policies(
+        &self,
+        id: <Self::Account as AccountTrait>::AccountId,
+        import_policy: Option<RoutePolicy>,
+        export_policy: Option<RoutePolicy>,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
+
     // TODO limit the number of results and page through them
     fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send>;
 
@@ -70,7 +92,25 @@ pub struct AccountDetails {
     pub http_endpoint: Option<String>,
     pub http_incoming_token: Option<String>,
     pub http_outgoing_token: Option<String>,
+    /// Hex-encoded SHA-256 fingerprint of the TLS client certificate the account
+    /// authenticates incoming ILP-over-HTTP requests with, instead of a token
+    pub http_incoming_certificate: Option<String>,
+    /// Base64-encoded PKCS #12 archive with the TLS client certificate and key to present
+    /// when sending ILP-over-HTTP requests to the account
+    pub http_client_identity: Option<String>,
+    pub http_client_identity_password: Option<String>,
+    /// Settings for the client used to send ILP-over-HTTP requests to the account (see
+    /// `HttpClientConfig`). If any of them are set, the ones that are not use the
+    /// `HttpClientConfig` defaults rather than the node's settings
+    pub http_client_max_idle_per_host: Option<usize>,
+    pub http_client_http2_prior_knowledge: Option<bool>,
+    /// In milliseconds
+    pub http_client_max_timeout: Option<u64>,
+    pub http_client_max_retries: Option<usize>,
     pub btp_uri: Option<String>,
+    /// Sent in the BTP `auth_username` field when connecting to the `btp_uri`.
+    /// If set, the password from the `btp_uri` is used as the token by itself.
+    pub btp_outgoing_username: Option<Username>,
     pub btp_incoming_token: Option<String>,
     pub settle_threshold: Option<i64>,
     pub settle_to: Option<i64>,
@@ -85,12 +125,14 @@ pub struct AccountDetails {
     pub settlement_engine_url: Option<String>,
 }
 
-pub struct NodeApi<S, I> {
+pub struct NodeApi<S: NodeStore, I> {
     store: S,
     admin_api_token: String,
     default_spsp_account: Option<Username>,
     incoming_handler: I,
     server_secret: Bytes,
+    btp_metrics: Vec<BtpMetrics<<S::Account as AccountTrait>::AccountId>>,
+    max_invoice_expiry: Option<u64>,
 }
 
 impl<S, I, A> NodeApi<S, I>
@@ -100,7 +142,9 @@ where
         + BalanceStore<Account = A>
         + SettlementStore<Account = A>
         + RouterStore
-        + ExchangeRateStore,
+        + ExchangeRateStore
+        + InvoiceStore
+        + StreamReceiverStore,
     I: IncomingService<A> + Clone + Send + Sync + 'static,
     A: AccountTrait
         + HttpAccount
@@ -123,6 +167,8 @@ where
             default_spsp_account: None,
             incoming_handler,
             server_secret,
+            btp_metrics: Vec::new(),
+            max_invoice_expiry: None,
         }
     }
 
@@ -131,6 +177,18 @@ where
         self
     }
 
+    /// Include the metrics of a BTP server or client in the admin-only `/btp/metrics` route
+    pub fn btp_metrics(&mut self, metrics: BtpMetrics<A::AccountId>) -> &mut Self {
+        self.btp_metrics.push(metrics);
+        self
+    }
+
+    /// Set the longest `expires_in`, in seconds, that invoices can be created with (30 days by default)
+    pub fn max_invoice_expiry(&mut self, max_invoice_expiry: u64) -> &mut Self {
+        self.max_invoice_expiry = Some(max_invoice_expiry);
+        self
+    }
+
     pub fn serve<T>(&self, incoming: T) -> impl Future<Item = (), Error = ()>
     where
         T: ConnectionStream,
@@ -152,6 +210,13 @@ where
                 }
                 spsp
             })
+            .resource({
+                let mut invoices = InvoicesApi::new(self.server_secret.clone(), self.store.clone());
+                if let Some(max_invoice_expiry) = self.max_invoice_expiry {
+                    invoices.max_expiry(max_invoice_expiry);
+                }
+                invoices
+            })
             .resource(AccountsApi::new(
                 self.admin_api_token.clone(),
                 self.store.clone(),
@@ -160,6 +225,10 @@ where
                 self.admin_api_token.clone(),
                 self.store.clone(),
             ))
+            .resource(BtpApi::new(
+                self.admin_api_token.clone(),
+                self.btp_metrics.clone(),
+            ))
             .serve(incoming)
     }
 }
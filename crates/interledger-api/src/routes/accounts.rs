@@ -4,6 +4,7 @@ use futures::{
     Future,
 };
 use hyper::Response;
+use interledger_ccp::RoutePolicy;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_service::{Account, AuthToken, Username};
 use interledger_service_util::BalanceStore;
@@ -11,9 +12,9 @@ use log::{debug, error, trace};
 use reqwest::r#async::Client;
 use serde::Serialize;
 use serde_json::{json, Value};
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 use tokio_retry::{strategy::FixedInterval, Retry};
-use tower_web::{impl_web, Response};
+use tower_web::{impl_web, Extract, Response};
 use url::Url;
 
 #[derive(Serialize, Response, Debug)]
@@ -32,6 +33,22 @@ struct BalanceResponse {
     balance: String,
 }
 
+#[derive(Extract)]
+struct RotateBtpTokenRequest {
+    /// The incoming token the peer will authenticate with from now on
+    token: String,
+    /// How long the previous token is still accepted, in seconds
+    grace_period: Option<u64>,
+}
+
+#[derive(Extract)]
+struct RoutingPoliciesRequest {
+    /// Which routes to accept from the account. All routes are accepted if this is not set
+    import_policy: Option<RoutePolicy>,
+    /// Which routes to advertise to the account. All routes are advertised if this is not set
+    export_policy: Option<RoutePolicy>,
+}
+
 #[derive(Clone)]
 pub struct AccountsApi<T> {
     store: T,
@@ -39,6 +56,8 @@ pub struct AccountsApi<T> {
 }
 
 const MAX_RETRIES: usize = 10;
+/// The previous BTP token is accepted for an hour unless the request says otherwise
+const DEFAULT_BTP_TOKEN_GRACE_PERIOD: u64 = 60 * 60;
 
 // Convenience function to clean up error handling and reduce unwrap quantity
 trait ErrorStatus {
@@ -278,6 +297,63 @@ impl_web! {
 
         }
 
+        #[post("/accounts/:username/btp_token")]
+        #[content_type("application/json")]
+        fn http_rotate_btp_token(&self, username: String, body: RotateBtpTokenRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            // Authorize before looking up the account so non-admins can't tell which accounts exist
+            self.validate_admin(authorization)
+            .and_then(move |store| {
+            result(Username::from_str(&username))
+            .map_err(move |_| {
+                error!("Invalid username: {}", username);
+                Response::error(500)
+            })
+            .and_then(move |username| {
+            store.get_account_id_from_username(&username)
+            .map_err(move |_| {
+                error!("Error getting account id from username: {}", username);
+                Response::error(404)
+            })
+            .and_then(move |id| {
+                let grace_period = Duration::from_secs(body.grace_period.unwrap_or(DEFAULT_BTP_TOKEN_GRACE_PERIOD));
+                store.rotate_btp_incoming_token(id, body.token, grace_period)
+                    .map_err(move |_| Response::error(500))
+                    .and_then(move |account| {
+                        Ok(json!(account))
+                    })
+            })
+            })
+            })
+        }
+
+        #[put("/accounts/:username/routing_policies")]
+        #[content_type("application/json")]
+        fn http_set_routing_policies(&self, username: String, body: RoutingPoliciesRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let self_clone = self.clone();
+            result(Username::from_str(&username))
+            .map_err(move |_| {
+                error!("Invalid username: {}", username);
+                Response::error(500)
+            })
+            .and_then(move |username| {
+            self_clone.store.get_account_id_from_username(&username)
+            .map_err(move |_| {
+                error!("Error getting account id from username: {}", username);
+                Response::error(404)
+            })
+            .and_then(move |id| {
+                self_clone.validate_admin(authorization)
+                .and_then(move |store|
+                    store.set_routing_policies(id, body.import_policy, body.export_policy)
+                        .map_err(move |_| Response::error(500))
+                        .and_then(move |account| {
+                            Ok(json!(account))
+                        })
+                )
+            })
+            })
+        }
+
         // TODO should this be combined into the account record?
         #[get("/accounts/:username/balance")]
         #[content_type("application/json")]
@@ -0,0 +1,68 @@
+use crate::BEARER_TOKEN_START;
+use futures::{
+    future::{err, ok},
+    Future,
+};
+use hyper::Response;
+use interledger_btp::{AccountMetrics, BtpMetrics};
+use log::error;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, fmt::Display, hash::Hash, time::UNIX_EPOCH};
+
+pub struct BtpApi<I> {
+    admin_api_token: String,
+    metrics: Vec<BtpMetrics<I>>,
+}
+
+impl_web! {
+    impl<I> BtpApi<I>
+    where I: Eq + Hash + Copy + Display + Send + Sync + 'static,
+    {
+        pub fn new(admin_api_token: String, metrics: Vec<BtpMetrics<I>>) -> Self {
+            BtpApi {
+                admin_api_token,
+                metrics,
+            }
+        }
+
+        #[get("/btp/metrics")]
+        #[content_type("application/json")]
+        fn get_btp_metrics(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            if authorization[BEARER_TOKEN_START..] != self.admin_api_token {
+                error!("Admin API endpoint called with non-admin API key");
+                return err(Response::builder().status(401).body(()).unwrap());
+            }
+            // Accounts can be connected to both the BTP server and client, so add up their metrics
+            let mut accounts: HashMap<I, AccountMetrics> = HashMap::new();
+            for metrics in self.metrics.iter() {
+                for (account_id, account_metrics) in metrics.snapshot() {
+                    accounts.entry(account_id).or_default().merge(&account_metrics);
+                }
+            }
+            let accounts: Map<String, Value> = accounts
+                .iter()
+                .map(|(account_id, metrics)| (account_id.to_string(), metrics_to_json(metrics)))
+                .collect();
+            ok(Value::Object(accounts))
+        }
+    }
+}
+
+fn metrics_to_json(metrics: &AccountMetrics) -> Value {
+    json!({
+        "connections": metrics.connections,
+        "connected_since": metrics.connected_since.map(|time| {
+            time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+        }),
+        "last_pong_rtt": metrics.last_pong_rtt.map(|rtt| rtt.as_millis() as u64),
+        "pending_outgoing": metrics.pending_outgoing,
+        "prepares_sent": metrics.prepares_sent,
+        "prepares_received": metrics.prepares_received,
+        "fulfills_sent": metrics.fulfills_sent,
+        "fulfills_received": metrics.fulfills_received,
+        "rejects_sent": metrics.rejects_sent,
+        "rejects_received": metrics.rejects_received,
+        "bytes_sent": metrics.bytes_sent,
+        "bytes_received": metrics.bytes_received,
+    })
+}
@@ -0,0 +1,241 @@
+use bytes::Bytes;
+use futures::{
+    future::{err, result},
+    Future,
+};
+use hyper::{Body, Response};
+use interledger_http::{HttpAccount, HttpStore};
+use interledger_ildcp::IldcpAccount;
+use interledger_service::{Account, AccountStore, AuthToken, Username};
+use interledger_spsp::{Invoice, InvoiceStore, SpspResponder};
+use interledger_stream::StreamReceiverStore;
+use log::{debug, error};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower_web::{impl_web, Extract, Response};
+
+/// Invoices expire after a day unless the request says otherwise
+const DEFAULT_INVOICE_EXPIRY: u64 = 24 * 60 * 60;
+/// The longest `expires_in` that can be requested, unless configured otherwise (30 days)
+const DEFAULT_MAX_INVOICE_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Extract, Debug)]
+struct InvoiceRequest {
+    /// The amount to be paid, in the account's units
+    amount: u64,
+    /// How long the invoice can be paid for, in seconds
+    expires_in: Option<u64>,
+}
+
+#[derive(Response, Debug)]
+#[web(status = "200")]
+struct InvoiceResponse {
+    id: String,
+    amount: u64,
+    /// When the invoice expires, in seconds since the UNIX epoch
+    expires_at: u64,
+    /// The amount paid so far
+    received: u64,
+    paid: bool,
+    expired: bool,
+    /// The Payment Pointer senders should use to pay the invoice
+    payment_pointer: String,
+}
+
+pub struct InvoicesApi<T> {
+    store: T,
+    server_secret: Bytes,
+    max_expiry: u64,
+}
+
+impl_web! {
+    impl<T, A> InvoicesApi<T>
+    where T: InvoiceStore + StreamReceiverStore + HttpStore<Account = A> + AccountStore<Account = A> + Clone + Send + Sync + 'static,
+    A: Account + IldcpAccount + HttpAccount + 'static,
+    {
+        pub fn new(server_secret: Bytes, store: T) -> Self {
+            InvoicesApi {
+                store,
+                server_secret,
+                max_expiry: DEFAULT_MAX_INVOICE_EXPIRY,
+            }
+        }
+
+        /// Set the longest `expires_in`, in seconds, that invoices can be created with
+        pub fn max_expiry(&mut self, max_expiry: u64) -> &mut Self {
+            self.max_expiry = max_expiry;
+            self
+        }
+
+        #[post("/invoices")]
+        #[content_type("application/json")]
+        fn post_invoices(&self, body: InvoiceRequest, authorization: String, host: String) -> impl Future<Item = InvoiceResponse, Error = Response<String>> {
+            let store = self.store.clone();
+            let server_secret = self.server_secret.clone();
+            let expires_in = body.expires_in.unwrap_or(DEFAULT_INVOICE_EXPIRY);
+            let expires_at = if expires_in > self.max_expiry {
+                Err(error_response(400, &format!("expires_in cannot be more than {} seconds", self.max_expiry)))
+            } else {
+                now().checked_add(expires_in).ok_or_else(|| error_response(400, "expires_in is too large"))
+            };
+            authenticate(&self.store, &authorization)
+                .and_then(move |account| result(expires_at).map(move |expires_at| (account, expires_at)))
+                .and_then(move |(account, expires_at)| {
+                    let store_clone = store.clone();
+                    store.create_invoice(account.username().clone(), body.amount, expires_at)
+                        .map_err(|_| error_response(500, "Error creating invoice"))
+                        .and_then(move |invoice| {
+                            debug!("Created invoice: {:?}", invoice);
+                            invoice_status(store_clone, server_secret, account, invoice, host)
+                        })
+                })
+        }
+
+        #[get("/invoices/:id")]
+        #[content_type("application/json")]
+        fn get_invoice(&self, id: String, authorization: String, host: String) -> impl Future<Item = InvoiceResponse, Error = Response<String>> {
+            let store = self.store.clone();
+            let server_secret = self.server_secret.clone();
+            authenticate(&self.store, &authorization)
+                .and_then(move |account| {
+                    let store_clone = store.clone();
+                    store.get_invoice(&id)
+                        .map_err(|_| error_response(404, "Invoice not found"))
+                        .and_then(move |invoice| {
+                            if &invoice.username == account.username() {
+                                Ok((account, invoice))
+                            } else {
+                                error!("Account {} tried to look up an invoice for {}", account.username(), invoice.username);
+                                Err(error_response(404, "Invoice not found"))
+                            }
+                        })
+                        .and_then(move |(account, invoice)| invoice_status(store_clone, server_secret, account, invoice, host))
+                })
+        }
+
+        #[get("/spsp/invoices/:id")]
+        fn get_spsp_invoice(&self, id: String) -> impl Future<Item = Response<Body>, Error = Response<()>> {
+            let store = self.store.clone();
+            let server_secret = self.server_secret.clone();
+            self.store.get_invoice(&id)
+                .map_err(move |_| {
+                    error!("Invoice not found: {}", id);
+                    Response::builder().status(404).body(()).unwrap()
+                })
+                .and_then(|invoice| {
+                    if invoice.is_expired() {
+                        debug!("Got SPSP request for expired invoice: {}", invoice.id);
+                        Err(Response::builder().status(404).body(()).unwrap())
+                    } else {
+                        Ok(invoice)
+                    }
+                })
+                .and_then(move |invoice| {
+                    let store_clone = store.clone();
+                    get_account(&store, &invoice.username)
+                        .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                        .and_then(move |account| {
+                            let mut responder = SpspResponder::new(account.client_address().clone(), server_secret);
+                            responder.asset_info(account.asset_code().to_string(), account.asset_scale());
+                            let destination_account = responder.invoice_destination_account(&invoice)
+                                .map_err(|_| Response::builder().status(500).body(()).unwrap());
+                            result(destination_account)
+                                .and_then(move |destination_account| {
+                                    store_clone.get_connection_totals(destination_account.to_string())
+                                        .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                                })
+                                .and_then(move |totals| {
+                                    responder.balance(invoice.amount, totals.connection_total);
+                                    responder.generate_invoice_response(&invoice)
+                                        .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                                })
+                        })
+                })
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn error_response(status: u16, message: &str) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(message.to_string())
+        .unwrap()
+}
+
+fn authenticate<T, A>(
+    store: &T,
+    authorization: &str,
+) -> impl Future<Item = A, Error = Response<String>>
+where
+    T: HttpStore<Account = A> + Clone,
+    A: HttpAccount,
+{
+    let store = store.clone();
+    result(AuthToken::from_str(authorization))
+        .map_err(|err| {
+            let error_msg = format!("Could not convert auth token {:?}", err);
+            error!("{}", error_msg);
+            error_response(401, &error_msg)
+        })
+        .and_then(move |auth| {
+            store
+                .get_account_from_http_auth(&auth.username(), &auth.password())
+                .map_err(|_| error_response(401, "Unauthorized"))
+        })
+}
+
+fn get_account<T, A>(store: &T, username: &Username) -> impl Future<Item = A, Error = ()>
+where
+    T: AccountStore<Account = A> + Clone,
+    A: Account,
+{
+    let store_clone = store.clone();
+    store
+        .get_account_id_from_username(username)
+        .and_then(move |id| store_clone.get_accounts(vec![id]))
+        .and_then(|mut accounts| accounts.pop().ok_or(()))
+}
+
+/// Look up how much has been paid on the invoice's STREAM connection
+fn invoice_status<T, A>(
+    store: T,
+    server_secret: Bytes,
+    account: A,
+    invoice: Invoice,
+    host: String,
+) -> Box<dyn Future<Item = InvoiceResponse, Error = Response<String>> + Send>
+where
+    T: StreamReceiverStore,
+    A: IldcpAccount,
+{
+    let destination_account =
+        match SpspResponder::new(account.client_address().clone(), server_secret)
+            .invoice_destination_account(&invoice)
+        {
+            Ok(destination_account) => destination_account,
+            Err(_) => {
+                return Box::new(err(error_response(500, "Error generating invoice address")))
+            }
+        };
+    Box::new(
+        store
+            .get_connection_totals(destination_account.to_string())
+            .map_err(|_| error_response(500, "Error loading invoice status"))
+            .map(move |totals| InvoiceResponse {
+                payment_pointer: format!("${}/spsp/invoices/{}", host, invoice.id),
+                received: totals.connection_total,
+                paid: totals.connection_total >= invoice.amount,
+                expired: invoice.is_expired(),
+                expires_at: invoice.expires_at,
+                amount: invoice.amount,
+                id: invoice.id,
+            }),
+    )
+}
@@ -1,9 +1,13 @@
 mod accounts;
+mod btp;
 mod ilp;
+mod invoices;
 mod settings;
 mod spsp;
 
 pub use accounts::AccountsApi;
+pub use btp::BtpApi;
 pub use ilp::IlpApi;
+pub use invoices::InvoicesApi;
 pub use settings::SettingsApi;
 pub use spsp::SpspApi;
@@ -84,11 +84,12 @@ impl_web! {
         #[get("/routes")]
         #[content_type("application/json")]
         fn get_routes(&self) -> impl Future<Item = Routes, Error = Response<()>> {
+            // Only the preferred next hop for each prefix is shown
             ok(Routes(HashMap::from_iter(self.store.routing_table()
-                .into_iter()
-                .filter_map(|(address, account)| {
-                    if let Ok(address) = str::from_utf8(address.as_ref()) {
-                        Some((address.to_string(), account.to_string()))
+                .iter()
+                .filter_map(|(address, next_hops)| {
+                    if let (Ok(address), Some(next_hop)) = (str::from_utf8(address.as_ref()), next_hops.first()) {
+                        Some((address.to_string(), next_hop.account_id.to_string()))
                     } else {
                         None
                     }
@@ -7,7 +7,9 @@ use hyper::{Body, Response};
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_ildcp::IldcpAccount;
 use interledger_service::{AccountStore, AuthToken, IncomingService, Username};
-use interledger_spsp::{pay, SpspResponder};
+use interledger_spsp::{
+    pay, pay_with_options, SendMoneyOptions, SpspReceiverInfo, SpspResponder, StreamDelivery,
+};
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
@@ -16,13 +18,33 @@ use tower_web::{impl_web, Extract, Response};
 #[derive(Extract, Debug)]
 struct SpspPayRequest {
     receiver: String,
+    /// The maximum amount to send, in the sender's units
     source_amount: u64,
+    /// If set, only send enough to deliver this amount, in the receiver's units
+    destination_amount: Option<u64>,
+    /// If set, the payment fails if the quoted exchange rate is below this
+    min_exchange_rate: Option<f64>,
 }
 
 #[derive(Response, Debug)]
 #[web(status = "200")]
 struct SpspPayResponse {
     delivered_amount: u64,
+    sent_amount: u64,
+    /// The receiver's asset code and scale, so the delivered amount can be displayed
+    destination_asset_code: Option<String>,
+    destination_asset_scale: Option<u8>,
+}
+
+impl From<StreamDelivery> for SpspPayResponse {
+    fn from(delivery: StreamDelivery) -> Self {
+        SpspPayResponse {
+            delivered_amount: delivery.delivered_amount,
+            sent_amount: delivery.sent_amount,
+            destination_asset_code: delivery.destination_asset_code,
+            destination_asset_scale: delivery.destination_asset_scale,
+        }
+    }
 }
 
 #[derive(Response, Debug)]
@@ -61,7 +83,6 @@ impl_web! {
 
         #[post("/pay")]
         #[content_type("application/json")]
-        // TODO add a version that lets you specify the destination amount instead
         fn post_pay(&self, body: SpspPayRequest, authorization: String) -> impl Future<Item = SpspPayResponse, Error = Response<String>> {
             let service = self.incoming_handler.clone();
             let store = self.store.clone();
@@ -79,19 +100,28 @@ impl_web! {
                 store.get_account_from_http_auth(&username, &token)
                 .map_err(|_| Response::builder().status(401).body("Unauthorized".to_string()).unwrap())
                 .and_then(move |account| {
-                    pay(service, account, &body.receiver, body.source_amount)
-                        .and_then(|delivered_amount| {
-                            debug!("Sent SPSP payment and delivered: {} of the receiver's units", delivered_amount);
-                            Ok(SpspPayResponse {
-                                delivered_amount,
-                                })
-                            })
-                            .map_err(|err| {
-                                error!("Error sending SPSP payment: {:?}", err);
-                                // TODO give a different error message depending on what type of error it is
-                                Response::builder().status(500).body(format!("Error sending SPSP payment: {:?}", err)).unwrap()
-                            })
-                    })
+                    let payment = if body.destination_amount.is_none() && body.min_exchange_rate.is_none() {
+                        Either::A(pay(service, account, &body.receiver, body.source_amount))
+                    } else {
+                        let options = SendMoneyOptions {
+                            destination_amount: body.destination_amount,
+                            min_exchange_rate: body.min_exchange_rate,
+                            ..SendMoneyOptions::new(body.source_amount)
+                        };
+                        Either::B(pay_with_options(service, account, &body.receiver, options))
+                    };
+                    payment
+                        .map(SpspPayResponse::from)
+                        .and_then(|response| {
+                            debug!("Sent SPSP payment and delivered: {} of the receiver's units", response.delivered_amount);
+                            Ok(response)
+                        })
+                        .map_err(|err| {
+                            error!("Error sending SPSP payment: {:?}", err);
+                            // TODO give a different error message depending on what type of error it is
+                            Response::builder().status(500).body(format!("Error sending SPSP payment: {:?}", err)).unwrap()
+                        })
+                })
             })
         }
 
@@ -116,9 +146,16 @@ impl_web! {
                     Response::builder().status(404).body(()).unwrap()
                 }))
                 .and_then(move |accounts| {
+                    let account = &accounts[0];
                     // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                    Ok(SpspResponder::new(accounts[0].client_address().clone(), server_secret)
-                        .generate_http_response())
+                    let mut responder = SpspResponder::new(account.client_address().clone(), server_secret);
+                    responder
+                        .asset_info(account.asset_code().to_string(), account.asset_scale())
+                        .receiver_info(SpspReceiverInfo {
+                            name: Some(account.username().to_string()),
+                            image_url: None,
+                        });
+                    Ok(responder.generate_http_response())
                     })
             })
         }
@@ -1,16 +1,37 @@
 use super::packet::*;
 use super::service::BtpOutgoingService;
 use super::BtpAccount;
-use futures::{future::join_all, Future, Sink};
+use futures::{
+    future::{err, join_all, loop_fn, ok, poll_fn, result, Either, Loop},
+    Async, Future, Sink,
+};
 use interledger_packet::Address;
 use interledger_service::*;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
+use native_tls::TlsConnector;
 use rand::random;
-use std::iter::IntoIterator;
-use tokio_tungstenite::connect_async;
-use tungstenite::Message;
+use std::{
+    io::{Error as IoError, ErrorKind},
+    iter::IntoIterator,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio_executor::spawn;
+use tokio_tcp::TcpStream;
+use tokio_threadpool::blocking;
+use tokio_timer::Delay;
+use tokio_tls::TlsConnector as AsyncTlsConnector;
+use tokio_tungstenite::{
+    client_async, connect_async, stream::Stream as WsStreamType, MaybeTlsStream, WebSocketStream,
+};
+use tungstenite::{error::Error as WebSocketError, Message};
 use url::{ParseError, Url};
 
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const INITIAL_RECONNECT_DELAY: u64 = 500; // milliseconds
+const MAX_RECONNECT_DELAY: u64 = 60_000; // milliseconds
+
 pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
     let uri = if uri.starts_with("btp+") {
         uri.split_at(4).1
@@ -23,6 +44,10 @@ pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
 /// Create a BtpOutgoingService wrapping BTP connections to the accounts specified.
 /// Calling `handle_incoming` with an `IncomingService` will turn the returned
 /// BtpOutgoingService into a bidirectional handler.
+///
+/// If a connection closes, or an account cannot be reached at startup and
+/// `error_on_unavailable` is false, the client will keep trying to reconnect
+/// with exponential backoff until the service is closed.
 pub fn connect_client<A, S>(
     ilp_address: Address,
     accounts: Vec<A>,
@@ -30,80 +55,319 @@ pub fn connect_client<A, S>(
     next_outgoing: S,
 ) -> impl Future<Item = BtpOutgoingService<S, A>, Error = ()>
 where
-    S: OutgoingService<A> + Clone + 'static,
+    S: OutgoingService<A> + Clone + Send + 'static,
     A: BtpAccount + 'static,
 {
-    join_all(accounts.into_iter().map(move |account| {
-        let account_id = account.id();
-        let mut url = account
-            .get_btp_uri()
-            .expect("Accounts must have BTP URLs")
-            .clone();
-        if url.scheme().starts_with("btp+") {
-            url.set_scheme(&url.scheme().replace("btp+", "")).unwrap();
-        }
-        let token = account
-            .get_btp_token()
-            .map(|s| s.to_vec())
-            .unwrap_or_default();
-        debug!("Connecting to {}", url);
-        connect_async(url.clone())
-            .map_err(move |err| {
-                error!(
-                    "Error connecting to WebSocket server for account: {} {:?}",
-                    account_id, err
-                )
-            })
-            .and_then(move |(connection, _)| {
-                trace!(
-                    "Connected to account {} (URI: {}), sending auth packet",
-                    account_id,
-                    url
-                );
-                // Send BTP authentication
-                let auth_packet = Message::Binary(
-                    BtpPacket::Message(BtpMessage {
-                        request_id: random(),
-                        protocol_data: vec![
-                            ProtocolData {
-                                protocol_name: String::from("auth"),
-                                content_type: ContentType::ApplicationOctetStream,
-                                data: vec![],
-                            },
-                            ProtocolData {
-                                protocol_name: String::from("auth_token"),
-                                content_type: ContentType::TextPlainUtf8,
-                                data: token,
-                            },
-                        ],
-                    })
-                    .to_bytes(),
-                );
+    connect(
+        ilp_address,
+        accounts,
+        error_on_unavailable,
+        next_outgoing,
+        None,
+    )
+}
 
-                connection
-                    .send(auth_packet)
-                    .map_err(move |_| error!("Error sending auth packet on connection: {}", url))
-            })
-            .then(move |result| match result {
-                Ok(connection) => {
-                    debug!("Connected to account {}'s server", account.id());
-                    Ok(Some((account, connection)))
-                }
-                Err(_) => {
-                    if error_on_unavailable {
-                        Err(())
-                    } else {
-                        Ok(None)
-                    }
+/// Same as `connect_client` but it uses the given TLS connector for `wss://` connections.
+/// This makes it possible to connect to peers whose certificates are signed by private
+/// root certificates (see `tls_connector_with_root_certificates`).
+pub fn connect_client_with_tls<A, S>(
+    ilp_address: Address,
+    accounts: Vec<A>,
+    error_on_unavailable: bool,
+    next_outgoing: S,
+    tls_connector: TlsConnector,
+) -> impl Future<Item = BtpOutgoingService<S, A>, Error = ()>
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    connect(
+        ilp_address,
+        accounts,
+        error_on_unavailable,
+        next_outgoing,
+        Some(AsyncTlsConnector::from(tls_connector)),
+    )
+}
+
+fn connect<A, S>(
+    ilp_address: Address,
+    accounts: Vec<A>,
+    error_on_unavailable: bool,
+    next_outgoing: S,
+    tls_connector: Option<AsyncTlsConnector>,
+) -> impl Future<Item = BtpOutgoingService<S, A>, Error = ()>
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    let tls_connector_clone = tls_connector.clone();
+    join_all(accounts.into_iter().map(move |account| {
+        connect_to_account(&account, tls_connector.clone()).then(move |result| match result {
+            Ok(connection) => Ok((account, Some(connection))),
+            Err(_) => {
+                if error_on_unavailable {
+                    Err(())
+                } else {
+                    Ok((account, None))
                 }
-            })
+            }
+        })
     }))
-    .and_then(|connections| {
+    .and_then(move |connections| {
         let service = BtpOutgoingService::new(ilp_address, next_outgoing);
-        let connections = connections.into_iter().filter_map(|conn| conn);
         for (account, connection) in connections {
-            service.add_connection(account, connection);
+            if let Some(connection) = connection {
+                add_client_connection(
+                    service.clone(),
+                    account,
+                    connection,
+                    tls_connector_clone.clone(),
+                );
+            } else {
+                reconnect(service.clone(), account, 0, tls_connector_clone.clone());
+            }
         }
         Ok(service)
     })
 }
+
+/// Open a WebSocket connection to the account's BTP server and send the auth packet
+fn connect_to_account<A>(
+    account: &A,
+    tls_connector: Option<AsyncTlsConnector>,
+) -> impl Future<Item = WsStream, Error = ()>
+where
+    A: BtpAccount,
+{
+    let account_id = account.id();
+    let url = account
+        .get_btp_uri()
+        .expect("Accounts must have BTP URLs")
+        .clone();
+    let token = account
+        .get_btp_token()
+        .map(|s| s.to_vec())
+        .unwrap_or_default();
+    let username = account.get_btp_username().cloned();
+    result(parse_btp_url(url.as_str()))
+        .map_err(move |err| error!("Invalid BTP URL for account {}: {:?}", account_id, err))
+        .and_then(move |url| {
+            debug!("Connecting to {}", url);
+            let connection = if let Some(tls_connector) = tls_connector {
+                Either::A(connect_with_tls_connector(url.clone(), tls_connector))
+            } else {
+                Either::B(connect_async(url.clone()).map(|(connection, _)| connection))
+            };
+            connection
+                .map_err(move |err| {
+                    error!(
+                        "Error connecting to WebSocket server for account: {} {:?}",
+                        account_id, err
+                    )
+                })
+                .and_then(move |connection| {
+                    trace!(
+                        "Connected to account {} (URI: {}), sending auth packet",
+                        account_id,
+                        url
+                    );
+                    // Send BTP authentication
+                    let mut protocol_data = vec![
+                        ProtocolData {
+                            protocol_name: String::from("auth"),
+                            content_type: ContentType::ApplicationOctetStream,
+                            data: vec![],
+                        },
+                        ProtocolData {
+                            protocol_name: String::from("auth_token"),
+                            content_type: ContentType::TextPlainUtf8,
+                            data: token,
+                        },
+                    ];
+                    if let Some(username) = username {
+                        protocol_data.push(ProtocolData {
+                            protocol_name: String::from("auth_username"),
+                            content_type: ContentType::TextPlainUtf8,
+                            data: username.as_bytes().to_vec(),
+                        });
+                    }
+                    let auth_packet = Message::Binary(
+                        BtpPacket::Message(BtpMessage {
+                            request_id: random(),
+                            protocol_data,
+                        })
+                        .to_bytes(),
+                    );
+
+                    connection.send(auth_packet).map_err(move |_| {
+                        error!("Error sending auth packet on connection: {}", url)
+                    })
+                })
+        })
+}
+
+/// Open the TCP connection and do the TLS handshake (for `wss://` URLs) with the
+/// given connector, instead of the default one `connect_async` uses
+fn connect_with_tls_connector(
+    url: Url,
+    tls_connector: AsyncTlsConnector,
+) -> impl Future<Item = WsStream, Error = WebSocketError> {
+    let is_tls = url.scheme() == "wss";
+    let domain = url.host_str().unwrap_or_default().to_string();
+    resolve_socket_addrs(url.clone(), if is_tls { 443 } else { 80 })
+        .and_then(connect_to_any)
+        .map_err(WebSocketError::Io)
+        .and_then(move |stream| {
+            if is_tls {
+                Either::A(
+                    tls_connector
+                        .connect(&domain, stream)
+                        .map(WsStreamType::Tls)
+                        .map_err(|err| {
+                            WebSocketError::Io(IoError::new(ErrorKind::Other, err.to_string()))
+                        }),
+                )
+            } else {
+                Either::B(ok(WsStreamType::Plain(stream)))
+            }
+        })
+        .and_then(move |stream| client_async(url, stream).map(|(connection, _)| connection))
+}
+
+/// Resolve the URL's host on the blocking thread pool so that DNS lookups
+/// do not stall the executor thread
+fn resolve_socket_addrs(
+    url: Url,
+    default_port: u16,
+) -> impl Future<Item = Vec<SocketAddr>, Error = IoError> {
+    poll_fn(
+        move || match blocking(|| url.socket_addrs(|| Some(default_port))) {
+            Ok(Async::Ready(addrs)) => addrs.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Not running on the thread pool (for example, on a current_thread
+            // runtime) so there is nowhere else to do the lookup
+            Err(_) => url.socket_addrs(|| Some(default_port)).map(Async::Ready),
+        },
+    )
+}
+
+/// Try connecting to each of the resolved addresses in turn and return
+/// the first connection that succeeds
+fn connect_to_any(addrs: Vec<SocketAddr>) -> impl Future<Item = TcpStream, Error = IoError> {
+    let not_found = IoError::new(ErrorKind::NotFound, "Could not resolve BTP server address");
+    loop_fn(
+        (addrs.into_iter(), not_found),
+        |(mut addrs, last_error)| match addrs.next() {
+            Some(addr) => Either::A(TcpStream::connect(&addr).then(move |res| match res {
+                Ok(stream) => Ok(Loop::Break(stream)),
+                Err(error) => {
+                    debug!("Unable to connect to BTP server at {}: {:?}", addr, error);
+                    Ok(Loop::Continue((addrs, error)))
+                }
+            })),
+            None => Either::B(err(last_error)),
+        },
+    )
+}
+
+/// Add the connection to the service and reconnect if it closes
+fn add_client_connection<A, S>(
+    service: BtpOutgoingService<S, A>,
+    account: A,
+    connection: WsStream,
+    tls_connector: Option<AsyncTlsConnector>,
+) where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    debug!("Connected to account {}'s server", account.id());
+    let closed = service.add_connection(account.clone(), connection);
+    spawn(closed.and_then(move |_| {
+        if service.is_closed() {
+            trace!(
+                "Not reconnecting to account {} because the service was closed",
+                account.id()
+            );
+        } else {
+            warn!(
+                "BTP connection to account {} closed, reconnecting",
+                account.id()
+            );
+            reconnect(service, account, 0, tls_connector);
+        }
+        Ok(())
+    }));
+}
+
+/// Wait for the backoff delay and then try connecting to the account again
+fn reconnect<A, S>(
+    service: BtpOutgoingService<S, A>,
+    account: A,
+    attempts: u32,
+    tls_connector: Option<AsyncTlsConnector>,
+) where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    service.set_reconnecting(account.id(), attempts);
+    let delay = reconnect_delay(attempts);
+    debug!(
+        "Reconnecting to account {} in {}ms (attempt {})",
+        account.id(),
+        delay.as_millis(),
+        attempts + 1
+    );
+    spawn(
+        Delay::new(Instant::now() + delay)
+            .map_err(|err| error!("Timer error while waiting to reconnect: {:?}", err))
+            .and_then(move |_| {
+                if service.is_closed() {
+                    return Either::A(ok(()));
+                }
+                Either::B(
+                    connect_to_account(&account, tls_connector.clone()).then(move |result| {
+                        match result {
+                            Ok(connection) => {
+                                add_client_connection(service, account, connection, tls_connector)
+                            }
+                            Err(_) => reconnect(service, account, attempts + 1, tls_connector),
+                        }
+                        Ok(())
+                    }),
+                )
+            }),
+    );
+}
+
+/// Exponential backoff with jitter, so that many clients reconnecting to
+/// the same server do not all retry at the same moment
+fn reconnect_delay(attempts: u32) -> Duration {
+    let max_delay = 2u64
+        .checked_pow(attempts)
+        .and_then(|multiplier| multiplier.checked_mul(INITIAL_RECONNECT_DELAY))
+        .unwrap_or(MAX_RECONNECT_DELAY)
+        .min(MAX_RECONNECT_DELAY);
+    Duration::from_millis(max_delay / 2 + random::<u64>() % (max_delay / 2 + 1))
+}
+
+#[cfg(test)]
+mod reconnect_delay {
+    use super::*;
+
+    #[test]
+    fn increases_exponentially() {
+        for attempts in 0..5 {
+            let delay = reconnect_delay(attempts).as_millis() as u64;
+            let max_delay = INITIAL_RECONNECT_DELAY * 2u64.pow(attempts);
+            assert!(delay >= max_delay / 2);
+            assert!(delay <= max_delay);
+        }
+    }
+
+    #[test]
+    fn is_capped() {
+        assert!(reconnect_delay(20).as_millis() as u64 <= MAX_RECONNECT_DELAY);
+        assert!(reconnect_delay(u32::max_value()).as_millis() as u64 <= MAX_RECONNECT_DELAY);
+    }
+}
@@ -8,23 +8,38 @@
 
 use futures::Future;
 use interledger_service::{Account, Username};
+use std::net::IpAddr;
 use url::Url;
 
 mod client;
 mod errors;
+mod metrics;
 mod oer;
 mod packet;
 mod server;
 mod service;
+mod tls;
 
-pub use self::client::{connect_client, parse_btp_url};
-pub use self::server::{create_open_signup_server, create_server};
-pub use self::service::{BtpOutgoingService, BtpService};
+pub use self::client::{connect_client, connect_client_with_tls, parse_btp_url};
+pub use self::metrics::{AccountMetrics, BtpMetrics};
+pub use self::packet::{ContentType, ProtocolData};
+pub use self::server::{
+    create_open_signup_server, create_server, create_tls_server, OpenSignupLimits,
+};
+pub use self::service::{BtpOutgoingService, BtpService, ConnectionState, ProtocolHandler};
+pub use self::tls::{tls_acceptor_from_pem_files, tls_connector_with_root_certificates};
 use interledger_packet::Address;
+pub use native_tls::{TlsAcceptor, TlsConnector};
 
 pub trait BtpAccount: Account {
     fn get_btp_uri(&self) -> Option<&Url>;
     fn get_btp_token(&self) -> Option<&[u8]>;
+    /// The username to send in the `auth_username` field when connecting to the account.
+    /// If one is set, the token is sent by itself; otherwise the token must have
+    /// the form `username:password`.
+    fn get_btp_username(&self) -> Option<&Username> {
+        None
+    }
 }
 
 /// The interface for Store implementations that can be used with the BTP Server.
@@ -49,6 +64,17 @@ pub struct BtpOpenSignupAccount<'a> {
     pub ilp_address: &'a Address,
     pub asset_code: &'a str,
     pub asset_scale: u8,
+    /// The IP address of the WebSocket client that signed up
+    pub source_ip: IpAddr,
+    /// The lowest balance the account is allowed to reach, if it should be limited
+    pub min_balance: Option<i64>,
+    /// The largest amount the account is allowed to send in a single packet, if it should be limited
+    pub max_packet_amount: Option<u64>,
+    /// The account must not be created if there are already this many open signup accounts
+    pub max_accounts: Option<usize>,
+    /// The account must not be created if this many open signup accounts were already
+    /// created from the same `source_ip`
+    pub max_accounts_per_ip: Option<usize>,
 }
 
 /// The interface for Store implementations that allow open BTP signups.
@@ -57,28 +83,45 @@ pub struct BtpOpenSignupAccount<'a> {
 ///
 /// **WARNING:** Users and store implementors should be careful when implementing this trait because
 /// malicious users can use open signups to create very large numbers of accounts and
-/// crash the process or fill up the database.
+/// crash the process or fill up the database. The server uses the methods below to enforce the
+/// `OpenSignupLimits` it is configured with and to delete accounts that are no longer used.
 pub trait BtpOpenSignupStore {
     type Account: BtpAccount;
 
+    /// Create an account, unless that would exceed the `max_accounts` or `max_accounts_per_ip`
+    /// given in the `BtpOpenSignupAccount`. Implementations must check the limits and create
+    /// the account atomically, so that concurrent signups cannot exceed the limits.
     fn create_btp_account<'a>(
         &self,
         account: BtpOpenSignupAccount<'a>,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
+
+    /// Load all of the accounts that were created via open signup
+    fn get_open_signup_accounts(
+        &self,
+    ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send>;
+
+    /// Delete an account that was created via open signup, but only if its balance is zero.
+    /// Resolves to `true` if the account was deleted.
+    fn delete_open_signup_account_if_empty(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+    ) -> Box<dyn Future<Item = bool, Error = ()> + Send>;
 }
 
 #[cfg(test)]
 mod client_server {
     use super::*;
-    use futures::future::{err, ok, result};
+    use futures::future::{err, lazy, ok, result};
     use interledger_packet::{Address, ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
     use interledger_service::*;
     use std::str::FromStr;
     use std::{
         sync::Arc,
-        time::{Duration, SystemTime},
+        time::{Duration, Instant, SystemTime},
     };
     use tokio::runtime::Runtime;
+    use tokio_timer::Delay;
 
     use lazy_static::lazy_static;
 
@@ -92,6 +135,7 @@ mod client_server {
         pub btp_incoming_token: Option<String>,
         pub btp_outgoing_token: Option<String>,
         pub btp_uri: Option<Url>,
+        pub btp_username: Option<Username>,
     }
 
     impl Account for TestAccount {
@@ -118,6 +162,10 @@ mod client_server {
                 None
             }
         }
+
+        fn get_btp_username(&self) -> Option<&Username> {
+            self.btp_username.as_ref()
+        }
     }
 
     #[derive(Clone)]
@@ -205,6 +253,7 @@ mod client_server {
                 btp_incoming_token: Some("alice:test_auth_token".to_string()),
                 btp_outgoing_token: None,
                 btp_uri: None,
+                btp_username: None,
             }]),
         };
         let server_address = Address::from_str("example.server").unwrap();
@@ -238,6 +287,7 @@ mod client_server {
         let account = TestAccount {
             id: 0,
             btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12345").unwrap()),
+            btp_username: None,
             btp_outgoing_token: Some("alice:test_auth_token".to_string()),
             btp_incoming_token: None,
         };
@@ -291,4 +341,915 @@ mod client_server {
         });
         runtime.block_on(client).unwrap();
     }
+
+    #[test]
+    fn reconnects_when_server_becomes_available() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12346").unwrap()),
+            btp_username: None,
+            btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let prepare = || {
+            PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: b"test data",
+            }
+            .build()
+        };
+
+        // Nothing is listening yet, so the client should start out reconnecting
+        let btp_client = runtime
+            .block_on(connect_client(
+                addr.clone(),
+                vec![account.clone()],
+                false,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr_clone),
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        assert_eq!(
+            btp_client.connection_state(0),
+            ConnectionState::Reconnecting { attempts: 0 }
+        );
+        // handle_incoming spawns a task so it needs to be called on the runtime
+        let mut btp_client = runtime
+            .block_on(lazy(move || {
+                Ok::<_, ()>(btp_client.handle_incoming(incoming_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr),
+                    }
+                    .build())
+                })))
+            }))
+            .unwrap();
+        let reject = runtime
+            .block_on(btp_client.send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: prepare(),
+            }))
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server = runtime
+            .block_on(create_server(
+                server_address.clone(),
+                "127.0.0.1:12346".parse().unwrap(),
+                server_store,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        runtime
+            .block_on(lazy(move || {
+                server.handle_incoming(incoming_service_fn(|_| {
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: b"test data",
+                    }
+                    .build())
+                }));
+                Ok::<_, ()>(())
+            }))
+            .unwrap();
+
+        // Give the client time to retry
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(btp_client.connection_state(0), ConnectionState::Connected);
+        runtime
+            .block_on(btp_client.send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: prepare(),
+            }))
+            .unwrap();
+        btp_client.close();
+    }
+
+    #[test]
+    fn multiple_connections_per_account() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server_address_clone = server_address.clone();
+        let server = runtime
+            .block_on(create_server(
+                server_address.clone(),
+                "127.0.0.1:12347".parse().unwrap(),
+                server_store,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address_clone),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        let mut server = runtime
+            .block_on(lazy(move || {
+                Ok::<_, ()>(server.handle_incoming(incoming_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                })))
+            }))
+            .unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12347").unwrap()),
+            btp_username: None,
+            btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            btp_incoming_token: None,
+        };
+        // Each client fulfills with its own name so we can tell which connection was used
+        let mut connect = |name: &'static [u8]| {
+            let addr = Address::from_str("example.client").unwrap();
+            let client = connect_client(
+                addr.clone(),
+                vec![account.clone()],
+                true,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr),
+                    }
+                    .build())
+                }),
+            )
+            .map(move |client| {
+                client.handle_incoming(incoming_service_fn(move |_| {
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: name,
+                    }
+                    .build())
+                }))
+            });
+            runtime.block_on(client).unwrap()
+        };
+        let client_1 = connect(b"client 1");
+        let client_2 = connect(b"client 2");
+
+        // Wait for the server to authenticate both connections
+        let wait = |runtime: &mut Runtime, millis: u64| {
+            runtime
+                .block_on(Delay::new(Instant::now() + Duration::from_millis(millis)))
+                .unwrap()
+        };
+        wait(&mut runtime, 200);
+        assert_eq!(server.connection_count(0), 2);
+
+        let server_clone = server.clone();
+        let mut send = |runtime: &mut Runtime| {
+            let fulfill = runtime
+                .block_on(
+                    server.send_request(OutgoingRequest {
+                        from: account.clone(),
+                        to: account.clone(),
+                        original_amount: 100,
+                        prepare: PrepareBuilder {
+                            destination: Address::from_str("example.client").unwrap(),
+                            amount: 100,
+                            execution_condition: &[0; 32],
+                            expires_at: SystemTime::now() + Duration::from_secs(30),
+                            data: &[],
+                        }
+                        .build(),
+                    }),
+                )
+                .unwrap();
+            fulfill.data().to_vec()
+        };
+        let mut used = vec![send(&mut runtime), send(&mut runtime)];
+        used.sort();
+        assert_eq!(used, vec![b"client 1".to_vec(), b"client 2".to_vec()]);
+
+        let metrics = server_clone.metrics().account(0).unwrap();
+        assert_eq!(metrics.connections, 2);
+        assert!(metrics.connected_since.is_some());
+        assert_eq!(metrics.prepares_sent, 2);
+        assert_eq!(metrics.fulfills_received, 2);
+        assert_eq!(metrics.rejects_received, 0);
+        assert_eq!(metrics.pending_outgoing, 0);
+        assert!(metrics.bytes_sent > 0 && metrics.bytes_received > 0);
+        let client_metrics = client_1.metrics().account(0).unwrap();
+        assert_eq!(
+            client_metrics.prepares_received
+                + client_2.metrics().account(0).unwrap().prepares_received,
+            2
+        );
+
+        // Requests whose callers stop waiting for the response are no longer counted as pending
+        drop(
+            server_clone.clone().send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.client").unwrap(),
+                    amount: 100,
+                    execution_condition: &[0; 32],
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &[],
+                }
+                .build(),
+            }),
+        );
+        assert_eq!(
+            server_clone.metrics().account(0).unwrap().pending_outgoing,
+            0
+        );
+        wait(&mut runtime, 100);
+
+        // Closing one connection leaves the other one in use
+        client_1.close();
+        wait(&mut runtime, 200);
+        assert_eq!(server_clone.connection_count(0), 1);
+        assert_eq!(send(&mut runtime), b"client 2".to_vec());
+        assert_eq!(send(&mut runtime), b"client 2".to_vec());
+    }
+
+    #[test]
+    fn sub_protocol_messages() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server = runtime
+            .block_on(create_server(
+                server_address.clone(),
+                "127.0.0.1:12348".parse().unwrap(),
+                server_store,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12348").unwrap()),
+            btp_username: None,
+            btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.client").unwrap();
+        let client = runtime
+            .block_on(connect_client(
+                addr.clone(),
+                vec![account],
+                true,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr),
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        client.register_protocol_handler("echo", |_account, mut protocol_data| {
+            protocol_data[0].data.reverse();
+            Box::new(ok(protocol_data))
+        });
+
+        // Wait for the server to authenticate the connection
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(200)))
+            .unwrap();
+
+        let response = runtime
+            .block_on(server.send_protocol_data(
+                0,
+                vec![ProtocolData {
+                    protocol_name: "echo".to_string(),
+                    content_type: ContentType::ApplicationOctetStream,
+                    data: vec![1, 2, 3],
+                }],
+            ))
+            .unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].protocol_name, "echo");
+        assert_eq!(response[0].data, vec![3, 2, 1]);
+
+        let result = runtime.block_on(server.send_protocol_data(
+            0,
+            vec![ProtocolData {
+                protocol_name: "unknown".to_string(),
+                content_type: ContentType::TextPlainUtf8,
+                data: b"hello".to_vec(),
+            }],
+        ));
+        assert!(result.is_err());
+        client.close();
+    }
+
+    #[test]
+    fn authenticates_with_auth_username() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server = runtime
+            .block_on(create_server(
+                server_address.clone(),
+                "127.0.0.1:12352".parse().unwrap(),
+                server_store,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+
+        let connect = |runtime: &mut Runtime, token: &str| {
+            let account = TestAccount {
+                id: 0,
+                btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12352").unwrap()),
+                btp_username: Some(ALICE.clone()),
+                btp_outgoing_token: Some(token.to_string()),
+                btp_incoming_token: None,
+            };
+            let addr = Address::from_str("example.client").unwrap();
+            let client = runtime
+                .block_on(connect_client(
+                    addr.clone(),
+                    vec![account],
+                    false,
+                    outgoing_service_fn(move |_| {
+                        Err(RejectBuilder {
+                            code: ErrorCode::F02_UNREACHABLE,
+                            message: &[],
+                            data: &[],
+                            triggered_by: Some(&addr),
+                        }
+                        .build())
+                    }),
+                ))
+                .unwrap();
+            // Wait for the server to authenticate the connection
+            runtime
+                .block_on(Delay::new(Instant::now() + Duration::from_millis(200)))
+                .unwrap();
+            client
+        };
+
+        // The token is sent without the username prefix
+        let client = connect(&mut runtime, "test_auth_token");
+        assert_eq!(server.connection_count(0), 1);
+
+        let other_client = connect(&mut runtime, "wrong_token");
+        assert_eq!(server.connection_count(0), 1);
+
+        client.close();
+        other_client.close();
+    }
+
+    #[test]
+    fn tls_client_server() {
+        let mut runtime = Runtime::new().unwrap();
+        let fixture =
+            |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server_address_clone = server_address.clone();
+        let tls_acceptor =
+            tls_acceptor_from_pem_files(&fixture("server.pem"), &fixture("server.key")).unwrap();
+        let server = create_tls_server(
+            server_address,
+            "127.0.0.1:12349".parse().unwrap(),
+            server_store,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address_clone),
+                    data: &[],
+                }
+                .build())
+            }),
+            tls_acceptor,
+        )
+        .and_then(|btp_server| {
+            btp_server.handle_incoming(incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }));
+            Ok(())
+        });
+        runtime.block_on(server).unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+wss://localhost:12349").unwrap()),
+            btp_username: None,
+            btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let next_outgoing = || {
+            let addr = addr.clone();
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr),
+                }
+                .build())
+            })
+        };
+
+        // The server's certificate is not signed by one of the system's trusted roots
+        let result = runtime.block_on(connect_client(
+            addr.clone(),
+            vec![account.clone()],
+            true,
+            next_outgoing(),
+        ));
+        assert!(result.is_err());
+
+        let tls_connector = tls_connector_with_root_certificates(&fixture("ca.pem")).unwrap();
+        let mut btp_client = runtime
+            .block_on(connect_client_with_tls(
+                addr.clone(),
+                vec![account.clone()],
+                true,
+                next_outgoing(),
+                tls_connector,
+            ))
+            .unwrap();
+        let fulfill = runtime
+            .block_on(
+                btp_client.send_request(OutgoingRequest {
+                    from: account.clone(),
+                    to: account.clone(),
+                    original_amount: 100,
+                    prepare: PrepareBuilder {
+                        destination: Address::from_str("example.destination").unwrap(),
+                        amount: 100,
+                        execution_condition: &[0; 32],
+                        expires_at: SystemTime::now() + Duration::from_secs(30),
+                        data: b"test data",
+                    }
+                    .build(),
+                }),
+            )
+            .unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+        btp_client.close();
+    }
+
+    #[test]
+    fn drains_connections_gracefully() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let server_address_clone = server_address.clone();
+        let server = runtime
+            .block_on(create_server(
+                server_address.clone(),
+                "127.0.0.1:12351".parse().unwrap(),
+                server_store,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address_clone),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        // Take a while to respond so the request is still in flight when draining starts
+        let server = runtime
+            .block_on(lazy(move || {
+                Ok::<_, ()>(server.handle_incoming(incoming_service_fn(|_| {
+                    Delay::new(Instant::now() + Duration::from_millis(1000)).then(|_| {
+                        Ok(FulfillBuilder {
+                            fulfillment: &[0; 32],
+                            data: b"slow response",
+                        }
+                        .build())
+                    })
+                })))
+            }))
+            .unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12351").unwrap()),
+            btp_username: None,
+            btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.client").unwrap();
+        let mut client = runtime
+            .block_on(connect_client(
+                addr.clone(),
+                vec![account.clone()],
+                true,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr),
+                    }
+                    .build())
+                }),
+            ))
+            .unwrap();
+        let mut send = || {
+            client
+                .send_request(OutgoingRequest {
+                    from: account.clone(),
+                    to: account.clone(),
+                    original_amount: 100,
+                    prepare: PrepareBuilder {
+                        destination: Address::from_str("example.server").unwrap(),
+                        amount: 100,
+                        execution_condition: &[0; 32],
+                        expires_at: SystemTime::now() + Duration::from_secs(30),
+                        data: &[],
+                    }
+                    .build(),
+                })
+                .then(Ok::<_, ()>)
+        };
+
+        let in_flight = send();
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(300)))
+            .unwrap();
+        let drained = server.drain(Duration::from_secs(5));
+        let during_drain = send();
+        let outgoing_during_drain = server
+            .clone()
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.client").unwrap(),
+                    amount: 100,
+                    execution_condition: &[0; 32],
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &[],
+                }
+                .build(),
+            })
+            .then(Ok::<_, ()>);
+        // The server stops listening while it waits for the in-flight request
+        let connect_during_drain = Delay::new(Instant::now() + Duration::from_millis(300))
+            .then(|_| Ok::<_, ()>(std::net::TcpStream::connect("127.0.0.1:12351").is_err()));
+        let ((in_flight, during_drain, outgoing_during_drain, connect_failed), _) = runtime
+            .block_on(
+                in_flight
+                    .join4(during_drain, outgoing_during_drain, connect_during_drain)
+                    .join(drained),
+            )
+            .unwrap();
+
+        // The request that was already being handled is answered before the connection closes
+        assert_eq!(in_flight.unwrap().data(), b"slow response");
+        assert_eq!(
+            during_drain.unwrap_err().code(),
+            ErrorCode::T01_PEER_UNREACHABLE
+        );
+        assert_eq!(
+            outgoing_during_drain.unwrap_err().code(),
+            ErrorCode::T01_PEER_UNREACHABLE
+        );
+        assert!(connect_failed);
+        assert_eq!(server.connection_count(0), 0);
+        client.close();
+    }
+
+    /// A store that keeps the accounts created via open signup
+    #[derive(Clone, Default)]
+    pub struct SignupStore {
+        accounts: Arc<parking_lot::Mutex<Vec<(TestAccount, std::net::IpAddr)>>>,
+        addresses: Arc<parking_lot::Mutex<Vec<Address>>>,
+    }
+
+    impl AccountStore for SignupStore {
+        type Account = TestAccount;
+
+        // stub implementation (not used in these tests)
+        fn get_accounts(
+            &self,
+            _account_ids: Vec<u64>,
+        ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
+            Box::new(err(()))
+        }
+
+        // stub implementation (not used in these tests)
+        fn get_account_id_from_username(
+            &self,
+            _username: &Username,
+        ) -> Box<dyn Future<Item = u64, Error = ()> + Send> {
+            Box::new(err(()))
+        }
+    }
+
+    impl BtpStore for SignupStore {
+        type Account = TestAccount;
+
+        fn get_account_from_btp_auth(
+            &self,
+            username: &Username,
+            token: &str,
+        ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+            let saved_token = format!("{}:{}", username, token);
+            Box::new(result(
+                self.accounts
+                    .lock()
+                    .iter()
+                    .map(|(account, _)| account)
+                    .find(|account| account.btp_incoming_token.as_ref() == Some(&saved_token))
+                    .cloned()
+                    .ok_or(()),
+            ))
+        }
+
+        fn get_btp_outgoing_accounts(
+            &self,
+        ) -> Box<dyn Future<Item = Vec<TestAccount>, Error = ()> + Send> {
+            Box::new(ok(Vec::new()))
+        }
+    }
+
+    impl BtpOpenSignupStore for SignupStore {
+        type Account = TestAccount;
+
+        fn create_btp_account<'a>(
+            &self,
+            account: BtpOpenSignupAccount<'a>,
+        ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+            let mut accounts = self.accounts.lock();
+            if let Some(max_accounts) = account.max_accounts {
+                if accounts.len() >= max_accounts {
+                    return Box::new(err(()));
+                }
+            }
+            if let Some(max_accounts_per_ip) = account.max_accounts_per_ip {
+                let count = accounts
+                    .iter()
+                    .filter(|(_, ip)| *ip == account.source_ip)
+                    .count();
+                if count >= max_accounts_per_ip {
+                    return Box::new(err(()));
+                }
+            }
+            let test_account = TestAccount {
+                id: accounts.len() as u64 + 1,
+                btp_incoming_token: Some(account.auth_token.to_string()),
+                btp_outgoing_token: None,
+                btp_uri: None,
+                btp_username: None,
+            };
+            accounts.push((test_account.clone(), account.source_ip));
+            self.addresses.lock().push(account.ilp_address.clone());
+            Box::new(ok(test_account))
+        }
+
+        fn get_open_signup_accounts(
+            &self,
+        ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
+            Box::new(ok(self
+                .accounts
+                .lock()
+                .iter()
+                .map(|(account, _)| account.clone())
+                .collect()))
+        }
+
+        fn delete_open_signup_account_if_empty(
+            &self,
+            account_id: u64,
+        ) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
+            let mut accounts = self.accounts.lock();
+            let count = accounts.len();
+            accounts.retain(|(account, _)| account.id != account_id);
+            Box::new(ok(accounts.len() < count))
+        }
+    }
+
+    #[test]
+    fn open_signup_limits() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let store = SignupStore::default();
+        let server_address = Address::from_str("example.server").unwrap();
+        let server_address_clone = server_address.clone();
+        let ildcp_info = interledger_ildcp::IldcpResponseBuilder {
+            client_address: &server_address,
+            asset_code: "XYZ",
+            asset_scale: 9,
+        }
+        .build();
+        let server = runtime
+            .block_on(create_open_signup_server(
+                server_address.clone(),
+                "127.0.0.1:12350".parse().unwrap(),
+                ildcp_info,
+                store.clone(),
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address_clone),
+                        data: &[],
+                    }
+                    .build())
+                }),
+                OpenSignupLimits {
+                    max_accounts_per_ip: Some(1),
+                    idle_account_timeout: Some(Duration::from_millis(300)),
+                    reap_interval: Duration::from_millis(50),
+                    ..Default::default()
+                },
+            ))
+            .unwrap();
+
+        let connect = |runtime: &mut Runtime, token: &str| {
+            let account = TestAccount {
+                id: 0,
+                btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12350").unwrap()),
+                btp_username: None,
+                btp_outgoing_token: Some(token.to_string()),
+                btp_incoming_token: None,
+            };
+            let addr = Address::from_str("example.client").unwrap();
+            runtime
+                .block_on(connect_client(
+                    addr.clone(),
+                    vec![account],
+                    true,
+                    outgoing_service_fn(move |_| {
+                        Err(RejectBuilder {
+                            code: ErrorCode::F02_UNREACHABLE,
+                            message: &[],
+                            data: &[],
+                            triggered_by: Some(&addr),
+                        }
+                        .build())
+                    }),
+                ))
+                .unwrap()
+        };
+        let wait = |runtime: &mut Runtime, millis: u64| {
+            runtime
+                .block_on(Delay::new(Instant::now() + Duration::from_millis(millis)))
+                .unwrap()
+        };
+
+        let alice = connect(&mut runtime, "alice:alice_token");
+        wait(&mut runtime, 100);
+        assert_eq!(store.accounts.lock().len(), 1);
+        // The address is not derived from the username, which anyone could claim
+        assert_ne!(
+            store.addresses.lock()[0],
+            Address::from_str("example.server.alice").unwrap()
+        );
+        assert!(store.addresses.lock()[0]
+            .to_bytes()
+            .starts_with(b"example.server."));
+
+        // A second account from the same IP is not created
+        let bob = connect(&mut runtime, "bob:bob_token");
+        wait(&mut runtime, 100);
+        assert_eq!(store.accounts.lock().len(), 1);
+        bob.close();
+
+        // Connected accounts are not deleted, even after the idle timeout
+        wait(&mut runtime, 400);
+        assert_eq!(server.connection_count(1), 1);
+        assert_eq!(store.accounts.lock().len(), 1);
+
+        // Once the account has been disconnected for long enough, it is deleted
+        alice.close();
+        wait(&mut runtime, 100);
+        assert_eq!(store.accounts.lock().len(), 1);
+        wait(&mut runtime, 500);
+        assert!(store.accounts.lock().is_empty());
+
+        // Which makes room for new signups again
+        let bob = connect(&mut runtime, "bob:bob_token");
+        wait(&mut runtime, 100);
+        assert_eq!(store.accounts.lock().len(), 1);
+        bob.close();
+        server.close();
+    }
 }
@@ -0,0 +1,188 @@
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Statistics about the BTP connections to a single account and the packets sent over them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountMetrics {
+    /// The number of WebSocket connections currently open
+    pub connections: usize,
+    /// When the account's first currently-open connection was established
+    pub connected_since: Option<SystemTime>,
+    /// The round trip time of the last Ping that was answered with a Pong
+    pub last_pong_rtt: Option<Duration>,
+    /// The number of Prepare packets sent to the account that are waiting for responses
+    pub pending_outgoing: usize,
+    pub prepares_sent: u64,
+    pub prepares_received: u64,
+    pub fulfills_sent: u64,
+    pub fulfills_received: u64,
+    pub rejects_sent: u64,
+    pub rejects_received: u64,
+    /// The size of all WebSocket messages sent to the account
+    pub bytes_sent: u64,
+    /// The size of all WebSocket messages received from the account
+    pub bytes_received: u64,
+}
+
+impl AccountMetrics {
+    /// Add the metrics an account has in another BTP service (for example, when it is
+    /// connected to both the node's BTP server and its BTP client)
+    pub fn merge(&mut self, other: &AccountMetrics) {
+        self.connections += other.connections;
+        self.connected_since = match (self.connected_since, other.connected_since) {
+            (Some(ours), Some(theirs)) => Some(ours.min(theirs)),
+            (ours, theirs) => ours.or(theirs),
+        };
+        self.last_pong_rtt = self.last_pong_rtt.max(other.last_pong_rtt);
+        self.pending_outgoing += other.pending_outgoing;
+        self.prepares_sent += other.prepares_sent;
+        self.prepares_received += other.prepares_received;
+        self.fulfills_sent += other.fulfills_sent;
+        self.fulfills_received += other.fulfills_received;
+        self.rejects_sent += other.rejects_sent;
+        self.rejects_received += other.rejects_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+/// A handle to the metrics a BTP service collects for each of its accounts.
+/// Clones of the handle share the same underlying data.
+#[derive(Clone)]
+pub struct BtpMetrics<Id> {
+    accounts: Arc<Mutex<HashMap<Id, AccountMetrics>>>,
+}
+
+impl<Id> BtpMetrics<Id>
+where
+    Id: Eq + Hash + Copy,
+{
+    pub(crate) fn new() -> Self {
+        BtpMetrics {
+            accounts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The metrics of every account that is connected or has requests waiting for responses
+    pub fn snapshot(&self) -> HashMap<Id, AccountMetrics> {
+        self.accounts.lock().clone()
+    }
+
+    /// The metrics of a single account, if it is connected or has requests waiting for responses
+    pub fn account(&self, account_id: Id) -> Option<AccountMetrics> {
+        self.accounts.lock().get(&account_id).cloned()
+    }
+
+    pub(crate) fn update<F>(&self, account_id: Id, update: F)
+    where
+        F: FnOnce(&mut AccountMetrics),
+    {
+        let mut accounts = self.accounts.lock();
+        let metrics = accounts.entry(account_id).or_default();
+        update(metrics);
+        // Forget the account once it is disconnected and has no requests waiting for
+        // responses, so the metrics only take up space for the accounts that are connected
+        if metrics.connections == 0 && metrics.pending_outgoing == 0 {
+            accounts.remove(&account_id);
+        }
+    }
+
+    pub(crate) fn connection_opened(&self, account_id: Id) {
+        self.update(account_id, |metrics| {
+            metrics.connections += 1;
+            metrics.connected_since.get_or_insert_with(SystemTime::now);
+        })
+    }
+
+    pub(crate) fn connection_closed(&self, account_id: Id) {
+        self.update(account_id, |metrics| {
+            metrics.connections = metrics.connections.saturating_sub(1);
+            if metrics.connections == 0 {
+                metrics.connected_since = None;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_connected_since_across_connections() {
+        let metrics = BtpMetrics::new();
+        assert_eq!(metrics.account(1), None);
+
+        metrics.connection_opened(1);
+        let connected_since = metrics.account(1).unwrap().connected_since;
+        assert!(connected_since.is_some());
+
+        metrics.connection_opened(1);
+        metrics.connection_closed(1);
+        let account = metrics.account(1).unwrap();
+        assert_eq!(account.connections, 1);
+        assert_eq!(account.connected_since, connected_since);
+
+        metrics.connection_closed(1);
+        assert_eq!(metrics.account(1), None);
+    }
+
+    #[test]
+    fn keeps_counters_per_account() {
+        let metrics = BtpMetrics::new();
+        metrics.connection_opened(1);
+        metrics.connection_opened(2);
+        metrics.update(1, |metrics| metrics.prepares_sent += 1);
+        metrics.update(1, |metrics| metrics.prepares_sent += 1);
+        metrics.update(2, |metrics| metrics.bytes_received += 10);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[&1].prepares_sent, 2);
+        assert_eq!(snapshot[&2].bytes_received, 10);
+    }
+
+    #[test]
+    fn evicts_accounts_once_requests_finish_after_disconnecting() {
+        let metrics = BtpMetrics::new();
+        metrics.connection_opened(1);
+        metrics.update(1, |metrics| metrics.pending_outgoing += 1);
+        metrics.connection_closed(1);
+        assert_eq!(metrics.account(1).unwrap().pending_outgoing, 1);
+
+        metrics.update(1, |metrics| metrics.pending_outgoing -= 1);
+        assert_eq!(metrics.account(1), None);
+        assert!(metrics.snapshot().is_empty());
+    }
+
+    #[test]
+    fn merges_metrics_from_different_services() {
+        let earlier = SystemTime::now();
+        let mut server = AccountMetrics {
+            connections: 1,
+            connected_since: Some(earlier + Duration::from_secs(1)),
+            last_pong_rtt: Some(Duration::from_millis(10)),
+            prepares_sent: 1,
+            ..AccountMetrics::default()
+        };
+        let client = AccountMetrics {
+            connections: 2,
+            connected_since: Some(earlier),
+            last_pong_rtt: None,
+            prepares_sent: 2,
+            bytes_received: 5,
+            ..AccountMetrics::default()
+        };
+        server.merge(&client);
+        assert_eq!(server.connections, 3);
+        assert_eq!(server.connected_since, Some(earlier));
+        assert_eq!(server.last_pong_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(server.prepares_sent, 3);
+        assert_eq!(server.bytes_received, 5);
+    }
+}
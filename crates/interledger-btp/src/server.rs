@@ -1,19 +1,26 @@
 use super::{
     packet::*, BtpAccount, BtpOpenSignupAccount, BtpOpenSignupStore, BtpOutgoingService, BtpStore,
 };
-use base64;
 use futures::{
-    future::{err, result, Either},
+    future::{join_all, ok, result, Either},
     Future, Sink, Stream,
 };
 use interledger_ildcp::IldcpResponse;
 use interledger_packet::Address;
 use interledger_service::*;
 use log::{debug, error, warn};
+use native_tls::TlsAcceptor;
 use ring::digest::{digest, SHA256};
-use std::{net::SocketAddr, str, str::FromStr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 use tokio_executor::spawn;
 use tokio_tcp::{TcpListener, TcpStream};
+use tokio_timer::Interval;
+use tokio_tls::TlsAcceptor as AsyncTlsAcceptor;
 use tokio_tungstenite::{
     accept_async_with_config, stream::Stream as WsStreamType, MaybeTlsStream, WebSocketStream,
 };
@@ -25,6 +32,38 @@ use tungstenite::{
 type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 const MAX_MESSAGE_SIZE: usize = 40000;
+const DEFAULT_REAP_INTERVAL: u64 = 60; // seconds
+
+/// Safeguards for servers that create accounts for any client that connects
+#[derive(Clone, Copy, Debug)]
+pub struct OpenSignupLimits {
+    /// The most accounts that may be created via open signup in total
+    pub max_accounts: Option<usize>,
+    /// The most accounts that may be created by clients connecting from a single IP address
+    pub max_accounts_per_ip: Option<usize>,
+    /// The minimum balance new accounts are created with
+    pub min_balance: Option<i64>,
+    /// The maximum packet amount new accounts are created with
+    pub max_packet_amount: Option<u64>,
+    /// Delete accounts that have had no open connections and a zero balance for this long.
+    /// Accounts are never deleted if this is None
+    pub idle_account_timeout: Option<Duration>,
+    /// How often to check for idle accounts
+    pub reap_interval: Duration,
+}
+
+impl Default for OpenSignupLimits {
+    fn default() -> Self {
+        OpenSignupLimits {
+            max_accounts: None,
+            max_accounts_per_ip: None,
+            min_balance: None,
+            max_packet_amount: None,
+            idle_account_timeout: None,
+            reap_interval: Duration::from_secs(DEFAULT_REAP_INTERVAL),
+        }
+    }
+}
 
 /// Returns a BtpOutgoingService that wraps all BTP/WebSocket connections that come
 /// in on the given address. Calling `handle_incoming` with an `IncomingService` will
@@ -39,6 +78,44 @@ pub fn create_server<T, U, A>(
     store: U,
     next_outgoing: T,
 ) -> impl Future<Item = BtpOutgoingService<T, A>, Error = ()>
+where
+    T: OutgoingService<A> + Clone + Send + Sync + 'static,
+    U: BtpStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + 'static,
+{
+    listen(ilp_address, address, store, next_outgoing, None)
+}
+
+/// Same as `create_server` but it serves secure WebSockets (`wss://`), using the given
+/// TLS acceptor (see `tls_acceptor_from_pem_files`) to terminate TLS connections.
+pub fn create_tls_server<T, U, A>(
+    ilp_address: Address,
+    address: SocketAddr,
+    store: U,
+    next_outgoing: T,
+    tls_acceptor: TlsAcceptor,
+) -> impl Future<Item = BtpOutgoingService<T, A>, Error = ()>
+where
+    T: OutgoingService<A> + Clone + Send + Sync + 'static,
+    U: BtpStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + 'static,
+{
+    listen(
+        ilp_address,
+        address,
+        store,
+        next_outgoing,
+        Some(AsyncTlsAcceptor::from(tls_acceptor)),
+    )
+}
+
+fn listen<T, U, A>(
+    ilp_address: Address,
+    address: SocketAddr,
+    store: U,
+    next_outgoing: T,
+    tls_acceptor: Option<AsyncTlsAcceptor>,
+) -> impl Future<Item = BtpOutgoingService<T, A>, Error = ()>
 where
     T: OutgoingService<A> + Clone + Send + Sync + 'static,
     U: BtpStore<Account = A> + Clone + Send + Sync + 'static,
@@ -52,31 +129,25 @@ where
         let service = BtpOutgoingService::new(ilp_address, next_outgoing);
 
         let service_clone = service.clone();
-        let handle_incoming = socket
-            .incoming()
+        // Stop listening once the service is closed
+        let handle_incoming = service
+            .stop_on_close(socket.incoming())
             .map_err(|err| error!("Error handling incoming connection: {:?}", err))
             .for_each(move |stream| {
                 let service_clone = service_clone.clone();
                 let store = store.clone();
-                accept_async_with_config(
-                    WsStreamType::Plain(stream),
-                    Some(WebSocketConfig {
-                        max_send_queue: None,
-                        max_message_size: Some(MAX_MESSAGE_SIZE),
-                        max_frame_size: None,
-                    }),
-                )
-                .map_err(|err| error!("Error accepting incoming WebSocket connection: {:?}", err))
-                .and_then(|connection: WsStream| validate_auth(store, connection))
-                .and_then(move |(account, connection)| {
-                    debug!("Added connection for account {}", account.id());
-                    service_clone.add_connection(account, connection);
-                    Ok(())
-                })
-                .or_else(|_| {
-                    warn!("Closing Websocket connection because it encountered an error");
-                    Ok(())
-                })
+                accept_connection(stream, tls_acceptor.clone())
+                    .and_then(|connection: WsStream| validate_auth(store, connection))
+                    .and_then(move |(account, connection)| {
+                        debug!("Added connection for account {}", account.id());
+                        // The server doesn't need to know when the connection closes
+                        let _ = service_clone.add_connection(account, connection);
+                        Ok(())
+                    })
+                    .or_else(|_| {
+                        warn!("Closing Websocket connection because it encountered an error");
+                        Ok(())
+                    })
             })
             .then(move |result| {
                 debug!("Finished listening for incoming BTP/Websocket connections");
@@ -91,6 +162,9 @@ where
 /// Same as `create_server` but it returns a BTP server that will accept new connections
 /// and create account records on the fly.
 ///
+/// The `limits` cap how many accounts can be created and determine when unused accounts are
+/// deleted again.
+///
 /// **WARNING:** Users of this should be very careful to prevent malicious users from creating huge numbers of accounts.
 pub fn create_open_signup_server<T, U, A>(
     ilp_address: Address,
@@ -98,6 +172,7 @@ pub fn create_open_signup_server<T, U, A>(
     ildcp_info: IldcpResponse,
     store: U,
     next_outgoing: T,
+    limits: OpenSignupLimits,
 ) -> impl Future<Item = BtpOutgoingService<T, A>, Error = ()>
 where
     T: OutgoingService<A> + Clone + Send + Sync + 'static,
@@ -107,38 +182,49 @@ where
     result(TcpListener::bind(&address).map_err(|err| {
         error!("Error binding to address {:?} {:?}", address, err);
     }))
-    .and_then(|socket| {
+    .and_then(move |socket| {
         let service = BtpOutgoingService::new(ilp_address, next_outgoing);
 
+        if let Some(idle_account_timeout) = limits.idle_account_timeout {
+            spawn(reap_idle_accounts(
+                store.clone(),
+                service.clone(),
+                idle_account_timeout,
+                limits.reap_interval,
+            ));
+        }
+
         let service_clone = service.clone();
-        let handle_incoming = socket
-            .incoming()
+        // Stop listening once the service is closed
+        let handle_incoming = service
+            .stop_on_close(socket.incoming())
             .map_err(|err| error!("Error handling incoming connection: {:?}", err))
             .for_each(move |stream| {
                 let service_clone = service_clone.clone();
                 let store = store.clone();
                 let ildcp_info = ildcp_info.clone();
-                accept_async_with_config(
-                    WsStreamType::Plain(stream),
-                    Some(WebSocketConfig {
-                        max_send_queue: None,
-                        max_message_size: Some(MAX_MESSAGE_SIZE),
-                        max_frame_size: None,
-                    }),
-                )
-                .map_err(|err| error!("Error accepting incoming WebSocket connection: {:?}", err))
-                .and_then(move |connection: WsStream| {
-                    get_or_create_account(store, ildcp_info, connection)
-                })
-                .and_then(move |(account, connection)| {
-                    debug!("Added connection for account: {:?}", account);
-                    service_clone.add_connection(account, connection);
-                    Ok(())
-                })
-                .or_else(|_| {
-                    warn!("Closing Websocket connection because it encountered an error");
-                    Ok(())
-                })
+                let source_ip = match stream.peer_addr() {
+                    Ok(peer_addr) => peer_addr.ip(),
+                    Err(err) => {
+                        warn!("Unable to get address of incoming connection: {:?}", err);
+                        return Either::A(ok(()));
+                    }
+                };
+                let handle_connection = accept_connection(stream, None)
+                    .and_then(move |connection: WsStream| {
+                        get_or_create_account(store, ildcp_info, limits, source_ip, connection)
+                    })
+                    .and_then(move |(account, connection)| {
+                        debug!("Added connection for account: {:?}", account);
+                        // The server doesn't need to know when the connection closes
+                        let _ = service_clone.add_connection(account, connection);
+                        Ok(())
+                    })
+                    .or_else(|_| {
+                        warn!("Closing Websocket connection because it encountered an error");
+                        Ok(())
+                    });
+                Either::B(handle_connection)
             })
             .then(move |result| {
                 debug!("Finished listening for incoming BTP/Websocket connections");
@@ -150,12 +236,58 @@ where
     })
 }
 
+/// Complete the TLS handshake (if the server uses TLS) and the WebSocket handshake
+fn accept_connection(
+    stream: TcpStream,
+    tls_acceptor: Option<AsyncTlsAcceptor>,
+) -> impl Future<Item = WsStream, Error = ()> {
+    let stream = if let Some(tls_acceptor) = tls_acceptor {
+        Either::A(
+            tls_acceptor
+                .accept(stream)
+                .map(WsStreamType::Tls)
+                .map_err(|err| error!("Error accepting incoming TLS connection: {:?}", err)),
+        )
+    } else {
+        Either::B(ok(WsStreamType::Plain(stream)))
+    };
+    stream.and_then(|stream| {
+        accept_async_with_config(
+            stream,
+            Some(WebSocketConfig {
+                max_send_queue: None,
+                max_message_size: Some(MAX_MESSAGE_SIZE),
+                max_frame_size: None,
+            }),
+        )
+        .map_err(|err| error!("Error accepting incoming WebSocket connection: {:?}", err))
+    })
+}
+
 struct Auth {
     request_id: u32,
     username: Option<Username>,
     token: String,
 }
 
+impl Auth {
+    /// The username and password the client authenticated with. Clients that send an
+    /// `auth_username` send the password by itself as the `auth_token`, while others
+    /// send a single token of the form `username:password`.
+    fn credentials(&self) -> Result<(Username, String), ()> {
+        if let Some(ref username) = self.username {
+            Ok((username.clone(), self.token.clone()))
+        } else {
+            let auth_token = AuthToken::from_str(&self.token)
+                .map_err(|_| warn!("BTP auth token is not of the form username:password"))?;
+            Ok((
+                auth_token.username().clone(),
+                auth_token.password().to_string(),
+            ))
+        }
+    }
+}
+
 fn validate_auth<U, A>(
     store: U,
     connection: impl Stream<Item = Message, Error = WebSocketError>
@@ -173,9 +305,9 @@ where
     A: BtpAccount + 'static,
 {
     get_auth(connection).and_then(move |(auth, connection)| {
-        result(AuthToken::from_str(&auth.token).map_err(|_| ())).and_then(move |auth_token| {
+        result(auth.credentials()).and_then(move |(username, password)| {
             store
-                .get_account_from_btp_auth(&auth_token.username(), &auth_token.password())
+                .get_account_from_btp_auth(&username, &password)
                 .map_err(move |_| {
                     warn!("Got BTP connection that does not correspond to an account")
                 })
@@ -199,6 +331,8 @@ where
 fn get_or_create_account<A, U>(
     store: U,
     ildcp_info: IldcpResponse,
+    limits: OpenSignupLimits,
+    source_ip: IpAddr,
     connection: impl Stream<Item = Message, Error = WebSocketError>
         + Sink<SinkItem = Message, SinkError = WebSocketError>,
 ) -> impl Future<
@@ -215,40 +349,49 @@ where
 {
     get_auth(connection).and_then(move |(auth, connection)| {
         let request_id = auth.request_id;
-        result(AuthToken::from_str(&auth.token).map_err(|_| ())).and_then(move |auth_token| {
+        result(auth.credentials()).and_then(move |(username, password)| {
             store
-                .get_account_from_btp_auth(&auth_token.username(), &auth_token.password())
+                .get_account_from_btp_auth(&username, &password)
                 .or_else(move |_| {
-                    let local_part = if let Some(username) = auth.username {
-                        username
-                    } else {
-                        match Username::from_str(&base64::encode_config(
-                            digest(&SHA256, auth.token.as_str().as_bytes()).as_ref(),
-                            base64::URL_SAFE_NO_PAD,
-                        )) {
-                            Ok(username) => username,
-                            Err(_) => return Either::A(err(())),
-                        }
-                    };
-                    let ilp_address = ildcp_info.client_address();
-                    // auth.username is always a valid suffix, enforced by the
-                    // Username type, hence we can unwrap safely here.
-                    let ilp_address = ilp_address.with_suffix(local_part.as_bytes()).unwrap();
+                    // Store the token in the username:password form regardless of
+                    // which form the client sent it in
+                    let token = format!("{}:{}", username, password);
+                    // The address is derived from the hash of the whole token rather than
+                    // from the username, so that a client that fails to authenticate as an
+                    // existing account cannot create a new account at that account's address
+                    let local_part = base64::encode_config(
+                        digest(&SHA256, token.as_bytes()).as_ref(),
+                        base64::URL_SAFE_NO_PAD,
+                    );
+                    // URL-safe base64 characters are always valid in ILP addresses
+                    let ilp_address = ildcp_info
+                        .client_address()
+                        .with_suffix(local_part.as_bytes())
+                        .unwrap();
 
-                    Either::B(
-                        store
-                            .create_btp_account(BtpOpenSignupAccount {
-                                auth_token: &auth.token,
-                                ilp_address: &ilp_address,
-                                asset_code: str::from_utf8(ildcp_info.asset_code())
-                                    .expect("Asset code provided is not valid utf8"),
-                                asset_scale: ildcp_info.asset_scale(),
-                            })
-                            .and_then(|account| {
-                                debug!("Created new account: {:?}", account);
-                                Ok(account)
-                            }),
-                    )
+                    store
+                        .create_btp_account(BtpOpenSignupAccount {
+                            auth_token: &token,
+                            ilp_address: &ilp_address,
+                            asset_code: str::from_utf8(ildcp_info.asset_code())
+                                .expect("Asset code provided is not valid utf8"),
+                            asset_scale: ildcp_info.asset_scale(),
+                            source_ip,
+                            min_balance: limits.min_balance,
+                            max_packet_amount: limits.max_packet_amount,
+                            max_accounts: limits.max_accounts,
+                            max_accounts_per_ip: limits.max_accounts_per_ip,
+                        })
+                        .map_err(move |_| {
+                            warn!(
+                                "Unable to create account via open signup from {} (the signup limits may have been reached)",
+                                source_ip
+                            )
+                        })
+                        .and_then(|account| {
+                            debug!("Created new account: {:?}", account);
+                            Ok(account)
+                        })
                 })
                 .and_then(move |account| {
                     let auth_response = Message::Binary(
@@ -267,6 +410,60 @@ where
     })
 }
 
+/// Periodically delete the open signup accounts that have had no open connections
+/// for the `idle_account_timeout` and that have a zero balance.
+/// This stops once the service is closed.
+fn reap_idle_accounts<T, U, A>(
+    store: U,
+    service: BtpOutgoingService<T, A>,
+    idle_account_timeout: Duration,
+    reap_interval: Duration,
+) -> impl Future<Item = (), Error = ()>
+where
+    T: OutgoingService<A> + Clone + Send + Sync + 'static,
+    U: BtpOpenSignupStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + 'static,
+{
+    let service_clone = service.clone();
+    Interval::new_interval(reap_interval)
+        .map_err(|err| error!("Timer error in idle account reaper: {:?}", err))
+        .take_while(move |_| Ok(!service_clone.is_closed()))
+        .for_each(move |_| {
+            let store_clone = store.clone();
+            let service = service.clone();
+            store
+                .get_open_signup_accounts()
+                .and_then(move |accounts| {
+                    let now = Instant::now();
+                    let idle_accounts: Vec<A::AccountId> = accounts
+                        .iter()
+                        .map(|account| account.id())
+                        .filter(|account_id| {
+                            service
+                                .idle_since(*account_id)
+                                .map(|idle_since| {
+                                    now.duration_since(idle_since) >= idle_account_timeout
+                                })
+                                .unwrap_or(false)
+                        })
+                        .collect();
+                    join_all(idle_accounts.into_iter().map(move |account_id| {
+                        let service = service.clone();
+                        store_clone
+                            .delete_open_signup_account_if_empty(account_id)
+                            .map(move |deleted| {
+                                if deleted {
+                                    debug!("Deleted idle open signup account {}", account_id);
+                                    service.forget_account(account_id);
+                                }
+                            })
+                    }))
+                })
+                // Keep checking even if this round failed
+                .then(|_| Ok(()))
+        })
+}
+
 fn get_auth(
     connection: impl Stream<Item = Message, Error = WebSocketError>
         + Sink<SinkItem = Message, SinkError = WebSocketError>,
@@ -1,5 +1,6 @@
-use super::packet::*;
+use super::{metrics::BtpMetrics, packet::*};
 use bytes::BytesMut;
+use chrono::Utc;
 use futures::{
     future::err,
     sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
@@ -14,35 +15,121 @@ use rand::random;
 use std::collections::HashMap;
 use std::{
     convert::TryFrom,
+    hash::Hash,
     io::{Error as IoError, ErrorKind},
     iter::IntoIterator,
     marker::PhantomData,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use stream_cancel::{Trigger, Valve};
+use stream_cancel::{Trigger, Valve, Valved};
 use tokio_executor::spawn;
 use tokio_timer::Interval;
 use tungstenite::{error::Error as WebSocketError, Message};
 
 const PING_INTERVAL: u64 = 30; // seconds
+const DRAIN_POLL_INTERVAL: u64 = 10; // milliseconds
 
 type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
-type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
+type ProtocolDataChannel = oneshot::Sender<Result<Vec<ProtocolData>, BtpError>>;
+type PendingMessages = Arc<Mutex<HashMap<u32, (usize, ProtocolDataChannel)>>>;
+type ProtocolHandlers<A> = Arc<RwLock<HashMap<String, ProtocolHandler<A>>>>;
+
+/// A handler for one of the BTP sub-protocols, which is called with the account that
+/// sent the message and the message's `protocol_data`. The protocol data it returns
+/// is sent back to the peer in the BTP response.
+pub type ProtocolHandler<A> = Arc<
+    dyn Fn(A, Vec<ProtocolData>) -> Box<dyn Future<Item = Vec<ProtocolData>, Error = ()> + Send>
+        + Send
+        + Sync,
+>;
+type BufferedRequest<A> = (A, u32, Prepare, UnboundedSender<Message>);
+type IncomingRequestBuffer<A> = UnboundedReceiver<BufferedRequest<A>>;
+
+/// One of the (possibly several) WebSocket connections open for an account
+#[derive(Clone)]
+struct Connection {
+    id: usize,
+    sender: UnboundedSender<Message>,
+    /// The number of outgoing requests on this connection that are waiting for responses
+    pending: Arc<AtomicUsize>,
+}
+
+/// Counts an outgoing request as pending on its connection and in the account's metrics
+/// for as long as it exists, so the counts go back down even if the caller drops the
+/// Future waiting for the response
+struct PendingRequest<I: Eq + Hash + Copy> {
+    pending: Arc<AtomicUsize>,
+    metrics: BtpMetrics<I>,
+    account_id: I,
+}
+
+impl<I: Eq + Hash + Copy> PendingRequest<I> {
+    fn new(pending: Arc<AtomicUsize>, metrics: BtpMetrics<I>, account_id: I) -> Self {
+        pending.fetch_add(1, Ordering::SeqCst);
+        metrics.update(account_id, |metrics| {
+            metrics.prepares_sent += 1;
+            metrics.pending_outgoing += 1;
+        });
+        PendingRequest {
+            pending,
+            metrics,
+            account_id,
+        }
+    }
+}
+
+impl<I: Eq + Hash + Copy> Drop for PendingRequest<I> {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.update(self.account_id, |metrics| {
+            metrics.pending_outgoing = metrics.pending_outgoing.saturating_sub(1)
+        });
+    }
+}
+
+/// The state of the BTP connection to an account
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The connection is open and packets can be sent over it
+    Connected,
+    /// The connection closed (or could not be opened) and the client is trying to reconnect.
+    /// `attempts` is the number of reconnection attempts made so far
+    Reconnecting { attempts: u32 },
+    /// There is no connection to the account
+    Disconnected,
+}
 
 /// A container for BTP/WebSocket connections that implements OutgoingService
 /// for sending outgoing ILP Prepare packets over one of the connected BTP connections.
 #[derive(Clone)]
 pub struct BtpOutgoingService<O, A: Account> {
-    // TODO support multiple connections per account
     ilp_address: Address,
-    connections: Arc<RwLock<HashMap<A::AccountId, UnboundedSender<Message>>>>,
-    pending_outgoing: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
+    connections: Arc<RwLock<HashMap<A::AccountId, Vec<Connection>>>>,
+    next_connection_id: Arc<AtomicUsize>,
+    round_robin: Arc<AtomicUsize>,
+    reconnecting: Arc<RwLock<HashMap<A::AccountId, u32>>>,
+    /// When the last connection for each disconnected account closed
+    disconnected_at: Arc<RwLock<HashMap<A::AccountId, Instant>>>,
+    /// Maps request IDs to the ID of the connection the request was sent on
+    /// and the channel to send the response back on
+    pending_outgoing: Arc<Mutex<HashMap<u32, (usize, IlpResultChannel)>>>,
+    pending_messages: PendingMessages,
+    protocol_handlers: ProtocolHandlers<A>,
     pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
-    incoming_sender: UnboundedSender<(A, u32, Prepare)>,
+    incoming_sender: UnboundedSender<BufferedRequest<A>>,
+    /// The number of incoming requests that have not been responded to yet
+    incoming_in_flight: Arc<AtomicUsize>,
+    draining: Arc<AtomicBool>,
+    metrics: BtpMetrics<A::AccountId>,
     next: O,
     close_all_connections: Arc<Mutex<Option<Trigger>>>,
     stream_valve: Arc<Valve>,
+    stop_listening: Arc<Mutex<Option<Trigger>>>,
+    listener_valve: Arc<Valve>,
 }
 
 impl<O, A> BtpOutgoingService<O, A>
@@ -53,15 +140,27 @@ where
     pub fn new(ilp_address: Address, next: O) -> Self {
         let (incoming_sender, incoming_receiver) = unbounded();
         let (close_all_connections, stream_valve) = Valve::new();
+        let (stop_listening, listener_valve) = Valve::new();
         BtpOutgoingService {
             ilp_address,
             connections: Arc::new(RwLock::new(HashMap::new())),
+            next_connection_id: Arc::new(AtomicUsize::new(0)),
+            round_robin: Arc::new(AtomicUsize::new(0)),
+            reconnecting: Arc::new(RwLock::new(HashMap::new())),
+            disconnected_at: Arc::new(RwLock::new(HashMap::new())),
             pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
+            pending_messages: Arc::new(Mutex::new(HashMap::new())),
+            protocol_handlers: Arc::new(RwLock::new(HashMap::new())),
             pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
             incoming_sender,
+            incoming_in_flight: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            metrics: BtpMetrics::new(),
             next,
             close_all_connections: Arc::new(Mutex::new(Some(close_all_connections))),
             stream_valve: Arc::new(stream_valve),
+            stop_listening: Arc::new(Mutex::new(Some(stop_listening))),
+            listener_valve: Arc::new(listener_valve),
         }
     }
 
@@ -71,12 +170,141 @@ where
     pub fn close(&self) {
         debug!("Closing all WebSocket connections");
         self.close_all_connections.lock().take();
+        self.reconnecting.write().clear();
+    }
+
+    /// Gracefully shut down all of the WebSocket connections.
+    ///
+    /// New connections are no longer accepted and new incoming and outgoing Prepare packets
+    /// are rejected from now on. Once the responses to all of the
+    /// requests that are in flight have been received and sent, a WebSocket close frame is sent
+    /// on each connection. The returned Future resolves when the connections are closed. If that
+    /// takes longer than the `timeout`, the remaining connections are closed immediately instead.
+    pub fn drain(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> {
+        debug!("Draining all WebSocket connections");
+        self.draining.store(true, Ordering::SeqCst);
+        self.stop_listening.lock().take();
+        self.reconnecting.write().clear();
+        let deadline = Instant::now() + timeout;
+
+        let pending_outgoing = self.pending_outgoing.clone();
+        let incoming_in_flight = self.incoming_in_flight.clone();
+        let connections = self.connections.clone();
+        let connections_clone = self.connections.clone();
+        let service = self.clone();
+        wait_until(deadline, move || {
+            pending_outgoing.lock().is_empty() && incoming_in_flight.load(Ordering::SeqCst) == 0
+        })
+        .and_then(move |_| {
+            for connection in connections.read().values().flatten() {
+                let _ = connection.sender.unbounded_send(Message::Close(None));
+            }
+            wait_until(deadline, move || connections_clone.read().is_empty())
+        })
+        .then(move |result| {
+            if !service.connections.read().is_empty() {
+                warn!("Timed out waiting for WebSocket connections to close gracefully");
+            }
+            service.close();
+            result
+        })
+    }
+
+    /// Wrap the stream of incoming connections so that it ends when the service is closed or starts draining
+    pub(crate) fn stop_on_close<S: Stream>(&self, stream: S) -> Valved<Valved<S>> {
+        self.listener_valve.wrap(self.stream_valve.wrap(stream))
+    }
+
+    /// Returns true once `close` or `drain` has been called
+    pub(crate) fn is_closed(&self) -> bool {
+        self.close_all_connections.lock().is_none() || self.draining.load(Ordering::SeqCst)
+    }
+
+    /// The state of the connection to the given account
+    pub fn connection_state(&self, account_id: A::AccountId) -> ConnectionState {
+        if self.connections.read().contains_key(&account_id) {
+            ConnectionState::Connected
+        } else if let Some(attempts) = self.reconnecting.read().get(&account_id) {
+            ConnectionState::Reconnecting {
+                attempts: *attempts,
+            }
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    /// The number of WebSocket connections open for the given account
+    pub fn connection_count(&self, account_id: A::AccountId) -> usize {
+        self.connections
+            .read()
+            .get(&account_id)
+            .map(|connections| connections.len())
+            .unwrap_or(0)
+    }
+
+    /// A handle to the connection and packet metrics collected for each account
+    pub fn metrics(&self) -> BtpMetrics<A::AccountId> {
+        self.metrics.clone()
+    }
+
+    /// The states of all of the connections that are open or being reconnected
+    pub fn connection_states(&self) -> HashMap<A::AccountId, ConnectionState> {
+        let mut states: HashMap<A::AccountId, ConnectionState> = self
+            .reconnecting
+            .read()
+            .iter()
+            .map(|(account_id, attempts)| {
+                (
+                    *account_id,
+                    ConnectionState::Reconnecting {
+                        attempts: *attempts,
+                    },
+                )
+            })
+            .collect();
+        for account_id in self.connections.read().keys() {
+            states.insert(*account_id, ConnectionState::Connected);
+        }
+        states
+    }
+
+    /// Mark the account as being reconnected to so that packets sent to it in the meantime
+    /// are rejected instead of being passed to the next service
+    pub(crate) fn set_reconnecting(&self, account_id: A::AccountId, attempts: u32) {
+        self.reconnecting.write().insert(account_id, attempts);
+    }
+
+    /// The time since which the account has had no open connections, or None if it is connected.
+    /// Accounts this service has never seen are considered idle from the first time this is called.
+    pub(crate) fn idle_since(&self, account_id: A::AccountId) -> Option<Instant> {
+        if self.connections.read().contains_key(&account_id) {
+            None
+        } else {
+            Some(
+                *self
+                    .disconnected_at
+                    .write()
+                    .entry(account_id)
+                    .or_insert_with(Instant::now),
+            )
+        }
+    }
+
+    /// Stop tracking the idle time of an account that has been deleted
+    pub(crate) fn forget_account(&self, account_id: A::AccountId) {
+        self.disconnected_at.write().remove(&account_id);
     }
 
     /// Set up a WebSocket connection so that outgoing Prepare packets can be sent to it,
     /// incoming Prepare packets are buffered in a channel (until an IncomingService is added
     /// via the handle_incoming method), and ILP Fulfill and Reject packets will be
     /// sent back to the Future that sent the outgoing request originally.
+    ///
+    /// Accounts can have multiple connections open at the same time. Outgoing requests
+    /// are spread across them and responses to incoming requests are sent back on the
+    /// connection the request came in on.
+    ///
+    /// The returned Future resolves when the connection closes.
     pub(crate) fn add_connection(
         &self,
         account: A,
@@ -84,8 +312,10 @@ where
             + Sink<SinkItem = Message, SinkError = WebSocketError>
             + Send
             + 'static,
-    ) {
+    ) -> impl Future<Item = (), Error = ()> {
         let account_id = account.id();
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        let (connection_closed, closed) = oneshot::channel();
 
         // Set up a channel to forward outgoing packets to the WebSocket connection
         let (tx, rx) = unbounded();
@@ -93,11 +323,15 @@ where
         let (close_connection, valve) = Valve::new();
         let stream = valve.wrap(stream);
         let stream = self.stream_valve.wrap(stream);
+        let metrics = self.metrics.clone();
         let forward_to_connection = sink
             .send_all(
-                rx.map_err(|_err| {
-                    WebSocketError::from(IoError::from(ErrorKind::ConnectionAborted))
-                }),
+                rx.inspect(move |message: &Message| {
+                    metrics.update(account_id, |metrics| {
+                        metrics.bytes_sent += message.len() as u64
+                    })
+                })
+                .map_err(|_err| WebSocketError::from(IoError::from(ErrorKind::ConnectionAborted))),
             )
             .then(move |_| {
                 debug!(
@@ -109,6 +343,9 @@ where
             });
 
         // Send pings every PING_INTERVAL until the connection closes or the Service is dropped
+        // and remember when the last one was sent so we can measure the round trip time
+        let ping_sent_at: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+        let ping_sent_at_clone = ping_sent_at.clone();
         let tx_clone = tx.clone();
         let send_pings = valve
             .wrap(
@@ -124,6 +361,8 @@ where
                         "Error sending Ping on connection to account {}: {:?}",
                         account_id, err
                     );
+                } else {
+                    *ping_sent_at_clone.lock() = Some(Instant::now());
                 }
                 Ok(())
             });
@@ -132,20 +371,47 @@ where
         // Set up a listener to handle incoming packets from the WebSocket connection
         // TODO do we need all this cloning?
         let pending_requests = self.pending_outgoing.clone();
+        let pending_messages = self.pending_messages.clone();
+        let protocol_handlers = self.protocol_handlers.clone();
         let incoming_sender = self.incoming_sender.clone();
+        let incoming_in_flight = self.incoming_in_flight.clone();
+        let draining = self.draining.clone();
+        let ilp_address = self.ilp_address.clone();
+        let metrics = self.metrics.clone();
         let tx_clone = tx.clone();
         let handle_incoming = stream.map_err(move |err| error!("Error reading from WebSocket stream for account {}: {:?}", account_id, err)).for_each(move |message| {
+          metrics.update(account_id, |metrics| metrics.bytes_received += message.len() as u64);
           // Handle the packets based on whether they are an incoming request or a response to something we sent
           if message.is_binary() {
               match parse_ilp_packet(message) {
                 Ok((request_id, Packet::Prepare(prepare))) => {
                     trace!("Got incoming Prepare packet on request ID: {} {:?}", request_id, prepare);
-                    incoming_sender.clone().unbounded_send((account.clone(), request_id, prepare))
-                        .map_err(|err| error!("Unable to buffer incoming request: {:?}", err))
+                    metrics.update(account_id, |metrics| metrics.prepares_received += 1);
+                    if draining.load(Ordering::SeqCst) {
+                        debug!("Rejecting incoming request {} because the connection is shutting down", request_id);
+                        let reject = RejectBuilder {
+                            code: ErrorCode::T01_PEER_UNREACHABLE,
+                            message: b"BTP connection is shutting down",
+                            triggered_by: Some(&ilp_address),
+                            data: &[],
+                        }.build();
+                        metrics.update(account_id, |metrics| metrics.rejects_sent += 1);
+                        return tx_clone.unbounded_send(ilp_packet_to_ws_message(request_id, Packet::Reject(reject)))
+                            .map_err(|err| error!("Error sending Reject message back: {:?}", err));
+                    }
+                    // Count the request before buffering it so that it cannot be handled (and
+                    // uncounted) first, and stop counting it if it could not be buffered
+                    incoming_in_flight.fetch_add(1, Ordering::SeqCst);
+                    incoming_sender.clone().unbounded_send((account.clone(), request_id, prepare, tx_clone.clone()))
+                        .map_err(|err| {
+                            incoming_in_flight.fetch_sub(1, Ordering::SeqCst);
+                            error!("Unable to buffer incoming request: {:?}", err)
+                        })
                 },
                 Ok((request_id, Packet::Fulfill(fulfill))) => {
                   trace!("Got fulfill response to request id {}", request_id);
-                  if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
+                  if let Some((_, channel)) = (*pending_requests.lock()).remove(&request_id) {
+                    metrics.update(account_id, |metrics| metrics.fulfills_received += 1);
                     channel.send(Ok(fulfill)).map_err(|fulfill| error!("Error forwarding Fulfill packet back to the Future that sent the Prepare: {:?}", fulfill))
                   } else {
                     warn!("Got Fulfill packet that does not match an outgoing Prepare we sent: {:?}", fulfill);
@@ -154,22 +420,32 @@ where
                 }
                 Ok((request_id, Packet::Reject(reject))) => {
                   trace!("Got reject response to request id {}", request_id);
-                  if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
+                  if let Some((_, channel)) = (*pending_requests.lock()).remove(&request_id) {
+                    metrics.update(account_id, |metrics| metrics.rejects_received += 1);
                     channel.send(Err(reject)).map_err(|reject| error!("Error forwarding Reject packet back to the Future that sent the Prepare: {:?}", reject))
                   } else {
                     warn!("Got Reject packet that does not match an outgoing Prepare we sent: {:?}", reject);
                     Ok(())
                   }
                 },
-                Err(_) => {
-                  debug!("Unable to parse ILP packet from BTP packet (if this is the first time this appears, the packet was probably the auth response)");
-                  // TODO Send error back
+                Err(Some(packet)) => {
+                  handle_sub_protocol_packet(packet, &account, &protocol_handlers, &pending_messages, &tx_clone)
+                }
+                Err(None) => {
+                  debug!("Unable to parse ILP packet from BTP packet");
                   Ok(())
                 }
               }
           } else if message.is_ping() {
               trace!("Responding to Ping message from account {}", account.id());
               tx_clone.unbounded_send(Message::Pong(Vec::new())).map_err(|err| error!("Error sending Pong message back: {:?}", err))
+          } else if message.is_pong() {
+              if let Some(sent_at) = ping_sent_at.lock().take() {
+                  let rtt = sent_at.elapsed();
+                  trace!("Got Pong from account {} after {}ms", account_id, rtt.as_millis());
+                  metrics.update(account_id, |metrics| metrics.last_pong_rtt = Some(rtt));
+              }
+              Ok(())
           } else {
               Ok(())
           }
@@ -178,25 +454,154 @@ where
             result
         });
 
+        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket.
+        // This must happen before the connection is handled, so that the close handler can remove it
+        // even if the connection closes right away
+        self.reconnecting.write().remove(&account_id);
+        self.disconnected_at.write().remove(&account_id);
+        self.metrics.connection_opened(account_id);
+        self.connections
+            .write()
+            .entry(account_id)
+            .or_default()
+            .push(Connection {
+                id: connection_id,
+                sender: tx,
+                pending: Arc::new(AtomicUsize::new(0)),
+            });
+
         let connections = self.connections.clone();
+        let disconnected_at = self.disconnected_at.clone();
+        let pending_requests = self.pending_outgoing.clone();
+        let pending_messages = self.pending_messages.clone();
+        let metrics = self.metrics.clone();
         let keep_connections_open = self.close_all_connections.clone();
         let handle_connection = handle_incoming
             .select(forward_to_connection)
             .then(move |_| {
                 let _ = keep_connections_open;
                 let mut connections = connections.write();
-                connections.remove(&account_id);
+                let remaining = if let Some(account_connections) = connections.get_mut(&account_id)
+                {
+                    account_connections.retain(|connection| connection.id != connection_id);
+                    account_connections.len()
+                } else {
+                    0
+                };
+                if remaining == 0 {
+                    connections.remove(&account_id);
+                    disconnected_at.write().insert(account_id, Instant::now());
+                }
+                metrics.connection_closed(account_id);
                 debug!(
-                    "WebSocket connection closed for account {} ({} connections still open)",
+                    "WebSocket connection closed for account {} ({} still open for the account, {} accounts connected)",
                     account_id,
+                    remaining,
                     connections.len()
                 );
+                // Dropping the channels rejects the requests that were waiting for responses
+                // on this connection, rather than leaving them to hang until they expire
+                pending_requests
+                    .lock()
+                    .retain(|_, (pending_connection_id, _)| *pending_connection_id != connection_id);
+                pending_messages
+                    .lock()
+                    .retain(|_, (pending_connection_id, _)| *pending_connection_id != connection_id);
+                let _ = connection_closed.send(());
                 Ok(())
             });
         spawn(handle_connection);
 
-        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
-        self.connections.write().insert(account_id, tx);
+        closed.then(|_| Ok(()))
+    }
+
+    /// Register a handler for messages of the given BTP sub-protocol (for example, payment
+    /// channel claims). Messages are dispatched based on the name of their first
+    /// `protocol_data` entry. ILP packets are always handled by the service itself.
+    pub fn register_protocol_handler<F>(&self, protocol_name: &str, handler: F)
+    where
+        F: Fn(A, Vec<ProtocolData>) -> Box<dyn Future<Item = Vec<ProtocolData>, Error = ()> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.protocol_handlers
+            .write()
+            .insert(protocol_name.to_string(), Arc::new(handler));
+    }
+
+    /// Send a BTP message with the given sub-protocol data to the account and
+    /// return the protocol data from the peer's response
+    pub fn send_protocol_data(
+        &self,
+        account_id: A::AccountId,
+        protocol_data: Vec<ProtocolData>,
+    ) -> Box<dyn Future<Item = Vec<ProtocolData>, Error = ()> + Send> {
+        let connection = self
+            .connections
+            .read()
+            .get(&account_id)
+            .and_then(|connections| self.select_connection(connections));
+        let connection = if let Some(connection) = connection {
+            connection
+        } else {
+            error!(
+                "Cannot send sub-protocol message to account {} because it is not connected",
+                account_id
+            );
+            return Box::new(err(()));
+        };
+
+        let request_id = random::<u32>();
+        let (sender, receiver) = oneshot::channel();
+        self.pending_messages
+            .lock()
+            .insert(request_id, (connection.id, sender));
+        let message = BtpPacket::Message(BtpMessage {
+            request_id,
+            protocol_data,
+        });
+        if let Err(send_error) = connection
+            .sender
+            .unbounded_send(Message::binary(message.to_bytes()))
+        {
+            self.pending_messages.lock().remove(&request_id);
+            error!(
+                "Error sending sub-protocol message to account {}: {:?}",
+                account_id, send_error
+            );
+            return Box::new(err(()));
+        }
+
+        let keep_connections_open = self.close_all_connections.clone();
+        Box::new(
+            receiver
+                .map_err(move |_| {
+                    error!(
+                        "BTP connection to account {} closed before a response was received",
+                        account_id
+                    )
+                })
+                .and_then(move |result| {
+                    let _ = keep_connections_open;
+                    result.map_err(|error| {
+                        error!(
+                            "Account {} responded to sub-protocol message with error: {:?}",
+                            account_id, error
+                        )
+                    })
+                }),
+        )
+    }
+
+    /// Pick the connection with the fewest requests waiting for responses.
+    /// Ties are broken round-robin so that idle connections are used evenly
+    fn select_connection(&self, connections: &[Connection]) -> Option<Connection> {
+        let start = self.round_robin.fetch_add(1, Ordering::SeqCst);
+        (0..connections.len())
+            .map(|i| &connections[(start + i) % connections.len()])
+            .min_by_key(|connection| connection.pending.load(Ordering::SeqCst))
+            .cloned()
     }
 
     /// Convert this BtpOutgoingService into a bidirectional BtpService by adding a handler for incoming requests.
@@ -210,15 +615,17 @@ where
         // Now that we're adding an incoming handler, this will spawn a task to read
         // all Prepare packets from the buffer, handle them, and send the responses back
         let mut incoming_handler_clone = incoming_handler.clone();
-        let connections_clone = self.connections.clone();
+        let incoming_in_flight = self.incoming_in_flight.clone();
+        let metrics = self.metrics.clone();
         let handle_pending_incoming = self
             .pending_incoming
             .lock()
             .take()
             .expect("handle_incoming can only be called once")
-            .for_each(move |(account, request_id, prepare)| {
+            .for_each(move |(account, request_id, prepare, connection)| {
                 let account_id = account.id();
-                let connections_clone = connections_clone.clone();
+                let incoming_in_flight = incoming_in_flight.clone();
+                let metrics = metrics.clone();
                 let request = IncomingRequest {
                     from: account,
                     prepare,
@@ -232,26 +639,24 @@ where
                     .handle_request(request)
                     .then(move |result| {
                         let packet = match result {
-                            Ok(fulfill) => Packet::Fulfill(fulfill),
-                            Err(reject) => Packet::Reject(reject),
-                        };
-                        if let Some(connection) = connections_clone
-                            .read()
-                            .get(&account_id) {
-                            let message = ilp_packet_to_ws_message(request_id, packet);
-                            connection
-                                .clone()
-                                .unbounded_send(message)
-                                .map_err(move |err| {
-                                    error!(
-                                        "Error sending response to account: {} {:?}",
-                                        account_id, err
-                                    )
-                                })
-                            } else {
-                                error!("Error sending response to account: {}, connection was closed. {:?}", account_id, packet);
-                                Err(())
+                            Ok(fulfill) => {
+                                metrics.update(account_id, |metrics| metrics.fulfills_sent += 1);
+                                Packet::Fulfill(fulfill)
+                            }
+                            Err(reject) => {
+                                metrics.update(account_id, |metrics| metrics.rejects_sent += 1);
+                                Packet::Reject(reject)
                             }
+                        };
+                        // Send the response back on the same connection the request came in on
+                        let message = ilp_packet_to_ws_message(request_id, packet);
+                        incoming_in_flight.fetch_sub(1, Ordering::SeqCst);
+                        connection.unbounded_send(message).map_err(move |err| {
+                            error!(
+                                "Error sending response to account: {}, connection was closed. {:?}",
+                                account_id, err
+                            )
+                        })
                     })
             })
             .then(move |_| {
@@ -280,7 +685,25 @@ where
     /// request will be passed through to the `next` handler.
     fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
         let account_id = request.to.id();
-        if let Some(connection) = (*self.connections.read()).get(&account_id) {
+        let connection = self
+            .connections
+            .read()
+            .get(&account_id)
+            .and_then(|connections| self.select_connection(connections));
+        if let Some(connection) = connection {
+            if self.draining.load(Ordering::SeqCst) {
+                debug!(
+                    "Rejecting outgoing request to account {} because the BTP connections are shutting down",
+                    account_id
+                );
+                return Box::new(err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: b"BTP connection is shutting down",
+                    triggered_by: Some(&self.ilp_address),
+                    data: &[],
+                }
+                .build()));
+            }
             let request_id = random::<u32>();
             let ilp_address = self.ilp_address.clone();
 
@@ -294,13 +717,15 @@ where
                 account_id
             );
 
-            match connection.unbounded_send(ilp_packet_to_ws_message(
+            let (sender, receiver) = oneshot::channel();
+            (*self.pending_outgoing.lock()).insert(request_id, (connection.id, sender));
+            match connection.sender.unbounded_send(ilp_packet_to_ws_message(
                 request_id,
                 Packet::Prepare(request.prepare),
             )) {
                 Ok(_) => {
-                    let (sender, receiver) = oneshot::channel();
-                    (*self.pending_outgoing.lock()).insert(request_id, sender);
+                    let pending_request =
+                        PendingRequest::new(connection.pending, self.metrics.clone(), account_id);
                     Box::new(
                         receiver
                             .then(move |result| {
@@ -308,6 +733,7 @@ where
                                 // and don't need to keep the connections open if this was the
                                 // last thing we were waiting for
                                 let _ = keep_connections_open;
+                                drop(pending_request);
                                 result
                             })
                             .map_err(move |err| {
@@ -317,7 +743,8 @@ where
                                 );
                                 RejectBuilder {
                                     code: ErrorCode::T00_INTERNAL_ERROR,
-                                    message: &[],
+                                    message:
+                                        b"BTP connection closed before a response was received",
                                     triggered_by: Some(&ilp_address),
                                     data: &[],
                                 }
@@ -330,6 +757,7 @@ where
                     )
                 }
                 Err(send_error) => {
+                    self.pending_outgoing.lock().remove(&request_id);
                     error!(
                         "Error sending websocket message for request {} to account {}: {:?}",
                         request_id, account_id, send_error
@@ -344,6 +772,18 @@ where
                     Box::new(err(reject))
                 }
             }
+        } else if self.reconnecting.read().contains_key(&account_id) {
+            debug!(
+                "Rejecting request to account {} because its BTP connection is down",
+                account_id
+            );
+            Box::new(err(RejectBuilder {
+                code: ErrorCode::T01_PEER_UNREACHABLE,
+                message: b"BTP connection to peer is down, reconnecting",
+                triggered_by: Some(&self.ilp_address),
+                data: &[],
+            }
+            .build()))
         } else {
             trace!(
                 "No open connection for account: {}, forwarding request to the next service",
@@ -370,6 +810,53 @@ where
     pub fn close(&self) {
         self.outgoing.close();
     }
+
+    /// A handle to the connection and packet metrics collected for each account
+    pub fn metrics(&self) -> BtpMetrics<A::AccountId> {
+        self.outgoing.metrics()
+    }
+
+    /// Gracefully shut down all of the WebSocket connections (see `BtpOutgoingService::drain`)
+    pub fn drain(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> {
+        self.outgoing.drain(timeout)
+    }
+
+    /// The state of the connection to the given account
+    pub fn connection_state(&self, account_id: A::AccountId) -> ConnectionState {
+        self.outgoing.connection_state(account_id)
+    }
+
+    /// The number of WebSocket connections open for the given account
+    pub fn connection_count(&self, account_id: A::AccountId) -> usize {
+        self.outgoing.connection_count(account_id)
+    }
+
+    /// Register a handler for messages of the given BTP sub-protocol
+    pub fn register_protocol_handler<F>(&self, protocol_name: &str, handler: F)
+    where
+        F: Fn(A, Vec<ProtocolData>) -> Box<dyn Future<Item = Vec<ProtocolData>, Error = ()> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.outgoing
+            .register_protocol_handler(protocol_name, handler)
+    }
+
+    /// Send a BTP message with the given sub-protocol data to the account and
+    /// return the protocol data from the peer's response
+    pub fn send_protocol_data(
+        &self,
+        account_id: A::AccountId,
+        protocol_data: Vec<ProtocolData>,
+    ) -> Box<dyn Future<Item = Vec<ProtocolData>, Error = ()> + Send> {
+        self.outgoing.send_protocol_data(account_id, protocol_data)
+    }
+
+    /// The states of all of the connections that are open or being reconnected
+    pub fn connection_states(&self) -> HashMap<A::AccountId, ConnectionState> {
+        self.outgoing.connection_states()
+    }
 }
 
 impl<I, O, A> OutgoingService<A> for BtpService<I, O, A>
@@ -388,47 +875,162 @@ where
     }
 }
 
-fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
+/// Resolves once `done` returns true or the `deadline` passes, whichever comes first
+fn wait_until<F>(deadline: Instant, done: F) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn() -> bool,
+{
+    Interval::new_interval(Duration::from_millis(DRAIN_POLL_INTERVAL))
+        .map_err(|err| error!("Timer error while draining connections: {:?}", err))
+        .take_while(move |_| Ok(!done() && Instant::now() < deadline))
+        .for_each(|_| Ok(()))
+}
+
+/// Parse the ILP packet out of a BTP message or response.
+///
+/// BTP packets that are valid but do not carry ILP data (sub-protocol messages,
+/// their responses, and errors) are returned as `Err(Some(packet))`.
+fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), Option<BtpPacket>> {
     if let Message::Binary(data) = message {
         let (request_id, ilp_data) = match BtpPacket::from_bytes(&data) {
-            Ok(BtpPacket::Message(message)) => {
-                let ilp_data = message
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (message.request_id, ilp_data)
+            Ok(BtpPacket::Message(mut message)) => {
+                match take_ilp_data(&mut message.protocol_data) {
+                    Some(ilp_data) => (message.request_id, ilp_data),
+                    None => return Err(Some(BtpPacket::Message(message))),
+                }
             }
-            Ok(BtpPacket::Response(response)) => {
-                let ilp_data = response
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (response.request_id, ilp_data)
+            Ok(BtpPacket::Response(mut response)) => {
+                match take_ilp_data(&mut response.protocol_data) {
+                    Some(ilp_data) => (response.request_id, ilp_data),
+                    None => return Err(Some(BtpPacket::Response(response))),
+                }
             }
             Ok(BtpPacket::Error(error)) => {
-                error!("Got BTP error: {:?}", error);
-                return Err(());
+                return Err(Some(BtpPacket::Error(error)));
             }
             Err(err) => {
                 error!("Error parsing BTP packet: {:?}", err);
-                return Err(());
+                return Err(None);
             }
         };
         if let Ok(packet) = Packet::try_from(BytesMut::from(ilp_data)) {
             Ok((request_id, packet))
         } else {
-            Err(())
+            Err(None)
         }
     } else {
         error!("Got a non-binary WebSocket message");
-        Err(())
+        Err(None)
+    }
+}
+
+fn take_ilp_data(protocol_data: &mut Vec<ProtocolData>) -> Option<Vec<u8>> {
+    let index = protocol_data
+        .iter()
+        .position(|proto| proto.protocol_name == "ilp")?;
+    Some(protocol_data.swap_remove(index).data)
+}
+
+/// Handle a BTP packet that did not carry an ILP packet. Messages are passed to the
+/// handler registered for their (first) sub-protocol and the handler's result is sent
+/// back to the peer. Responses and errors are matched up with the sub-protocol
+/// messages we sent.
+fn handle_sub_protocol_packet<A: Account + 'static>(
+    packet: BtpPacket,
+    account: &A,
+    protocol_handlers: &ProtocolHandlers<A>,
+    pending_messages: &PendingMessages,
+    connection: &UnboundedSender<Message>,
+) -> Result<(), ()> {
+    match packet {
+        BtpPacket::Message(message) => {
+            let request_id = message.request_id;
+            let protocol_name = message
+                .protocol_data
+                .first()
+                .map(|proto| proto.protocol_name.clone())
+                .unwrap_or_default();
+            let handler = protocol_handlers.read().get(&protocol_name).cloned();
+            let connection = connection.clone();
+            let account_id = account.id();
+            if let Some(handler) = handler {
+                trace!(
+                    "Handling {} sub-protocol message {} from account {}",
+                    protocol_name,
+                    request_id,
+                    account_id
+                );
+                spawn(
+                    (handler)(account.clone(), message.protocol_data).then(move |result| {
+                        let packet = match result {
+                            Ok(protocol_data) => BtpPacket::Response(BtpResponse {
+                                request_id,
+                                protocol_data,
+                            }),
+                            Err(_) => btp_error(
+                                request_id,
+                                &format!("Error handling {} sub-protocol message", protocol_name),
+                            ),
+                        };
+                        connection
+                            .unbounded_send(Message::binary(packet.to_bytes()))
+                            .map_err(move |err| {
+                                error!(
+                                    "Error sending sub-protocol response to account: {} {:?}",
+                                    account_id, err
+                                )
+                            })
+                    }),
+                );
+                Ok(())
+            } else {
+                debug!(
+                    "Got message for unknown sub-protocol {:?} from account {}",
+                    protocol_name, account_id
+                );
+                let packet = btp_error(
+                    request_id,
+                    &format!("Unknown sub-protocol: {}", protocol_name),
+                );
+                connection
+                    .unbounded_send(Message::binary(packet.to_bytes()))
+                    .map_err(|err| error!("Error sending BTP error: {:?}", err))
+            }
+        }
+        BtpPacket::Response(response) => {
+            if let Some((_, channel)) = pending_messages.lock().remove(&response.request_id) {
+                channel.send(Ok(response.protocol_data)).map_err(|_| {
+                    error!("Error forwarding sub-protocol response back to the Future that sent the message")
+                })
+            } else {
+                debug!("Got BTP response that does not match a message we sent (if this is the first time this appears, the packet was probably the auth response)");
+                Ok(())
+            }
+        }
+        BtpPacket::Error(error) => {
+            if let Some((_, channel)) = pending_messages.lock().remove(&error.request_id) {
+                channel.send(Err(error)).map_err(|_| {
+                    error!("Error forwarding BTP error back to the Future that sent the message")
+                })
+            } else {
+                error!("Got BTP error: {:?}", error);
+                Ok(())
+            }
+        }
     }
 }
 
+fn btp_error(request_id: u32, message: &str) -> BtpPacket {
+    BtpPacket::Error(BtpError {
+        request_id,
+        code: String::from("F00"),
+        name: String::from("NotAcceptedError"),
+        triggered_at: Utc::now(),
+        data: message.to_string(),
+        protocol_data: Vec::new(),
+    })
+}
+
 fn ilp_packet_to_ws_message(request_id: u32, packet: Packet) -> Message {
     match packet {
         Packet::Prepare(prepare) => {
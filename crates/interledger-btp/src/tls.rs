@@ -0,0 +1,76 @@
+use log::error;
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use std::fs;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Load the TLS certificate (chain) and PKCS#8 private key the BTP server should use
+/// from PEM files. The result can be passed to `create_tls_server`.
+pub fn tls_acceptor_from_pem_files(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, ()> {
+    let cert = fs::read(cert_path).map_err(|err| {
+        error!(
+            "Error reading TLS certificate file {}: {:?}",
+            cert_path, err
+        )
+    })?;
+    let key = fs::read(key_path)
+        .map_err(|err| error!("Error reading TLS key file {}: {:?}", key_path, err))?;
+    let identity = Identity::from_pkcs8(&cert, &key)
+        .map_err(|err| error!("Error loading TLS certificate and key: {:?}", err))?;
+    TlsAcceptor::new(identity).map_err(|err| error!("Error creating TLS acceptor: {:?}", err))
+}
+
+/// Create a TLS connector that trusts the root certificates in the given PEM file,
+/// in addition to the system's trusted roots. This is used to connect to peers that
+/// use self-signed or privately issued certificates. The file may contain multiple
+/// certificates. The result can be passed to `connect_client_with_tls`.
+pub fn tls_connector_with_root_certificates(ca_path: &str) -> Result<TlsConnector, ()> {
+    let pem = fs::read_to_string(ca_path)
+        .map_err(|err| error!("Error reading root certificate file {}: {:?}", ca_path, err))?;
+    let certificates = split_pem_certificates(&pem);
+    if certificates.is_empty() {
+        error!("No certificates found in root certificate file {}", ca_path);
+        return Err(());
+    }
+
+    let mut builder = TlsConnector::builder();
+    for certificate in certificates {
+        let certificate = Certificate::from_pem(certificate.as_bytes())
+            .map_err(|err| error!("Error parsing root certificate: {:?}", err))?;
+        builder.add_root_certificate(certificate);
+    }
+    builder
+        .build()
+        .map_err(|err| error!("Error creating TLS connector: {:?}", err))
+}
+
+/// Split a PEM bundle into the individual certificates, because
+/// `Certificate::from_pem` only reads the first one
+fn split_pem_certificates(pem: &str) -> Vec<String> {
+    pem.split(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| format!("{}{}\n", block.trim_start(), PEM_CERTIFICATE_END))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pem_bundles() {
+        let pem = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\n-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            split_pem_certificates(pem),
+            vec![
+                "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n".to_string(),
+                "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_text_without_certificates() {
+        assert!(split_pem_certificates("not a certificate").is_empty());
+    }
+}
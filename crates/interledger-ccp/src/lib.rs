@@ -19,12 +19,14 @@ use std::{str::FromStr, string::ToString};
 #[cfg(test)]
 mod fixtures;
 mod packet;
+mod policy;
 mod routing_table;
 mod server;
 #[cfg(test)]
 mod test_helpers;
 
-pub use server::{CcpRouteManager, CcpRouteManagerBuilder};
+pub use policy::RoutePolicy;
+pub use server::{trusted_route_auth, CcpRouteManager, CcpRouteManagerBuilder};
 
 use serde::{Deserialize, Serialize};
 
@@ -73,6 +75,18 @@ pub trait CcpRoutingAccount: Account + IldcpAccount {
     fn should_receive_routes(&self) -> bool {
         false
     }
+
+    /// Which routes to accept from this account. If this returns None,
+    /// all routes that pass the Route Manager's own checks are accepted
+    fn routing_import_policy(&self) -> Option<&RoutePolicy> {
+        None
+    }
+
+    /// Which routes to advertise to this account. If this returns None,
+    /// all of the routes in our Forwarding Routing Table are advertised
+    fn routing_export_policy(&self) -> Option<&RoutePolicy> {
+        None
+    }
 }
 
 // key = Bytes, key should be Address -- TODO
@@ -99,4 +113,21 @@ pub trait RouteManagerStore: Clone {
         &mut self,
         routes: impl IntoIterator<Item = (Bytes, Self::Account)>,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Save all of the next hops for each prefix, in order of preference, along with the
+    /// weight the Router should give each one when splitting packets between them.
+    ///
+    /// Stores that do not support multipath routing do not need to implement this.
+    /// By default, only the first next hop for each prefix is passed to `set_routes`.
+    fn set_multipath_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<(Self::Account, u32)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        self.set_routes(routes.into_iter().filter_map(|(prefix, next_hops)| {
+            next_hops
+                .into_iter()
+                .next()
+                .map(|(account, _weight)| (prefix, account))
+        }))
+    }
 }
@@ -0,0 +1,117 @@
+use crate::{packet::Route, RoutingRelation};
+use serde::{Deserialize, Serialize};
+
+/// Rules for which routes we accept from (import) or advertise to (export) an account.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutePolicy {
+    /// Only routes for prefixes that start with one of these are allowed.
+    /// If this is empty, routes for all prefixes are allowed.
+    pub allow_prefixes: Vec<String>,
+    /// Routes for prefixes that start with one of these are not allowed,
+    /// even if they are in `allow_prefixes`
+    pub deny_prefixes: Vec<String>,
+    /// Routes with longer paths are not allowed
+    pub max_path_length: Option<usize>,
+    /// How many additional times to add the account's address (on import) or our
+    /// address (on export) to the path of each route. Longer paths are less preferred,
+    /// so this can be used to make routes through this account a last resort.
+    pub prepend_path: usize,
+    /// Only used for exports: if this is set and the account is our parent or peer,
+    /// we only advertise routes we learned from our children or configured ourselves,
+    /// so that we do not carry traffic between our parents and peers
+    pub valley_free: bool,
+}
+
+impl RoutePolicy {
+    /// Check the route's prefix and path length against the policy
+    pub(crate) fn allows(&self, route: &Route) -> bool {
+        let prefix: &[u8] = route.prefix.as_ref();
+        if !self.allow_prefixes.is_empty()
+            && !self
+                .allow_prefixes
+                .iter()
+                .any(|allowed| prefix.starts_with(allowed.as_bytes()))
+        {
+            return false;
+        }
+        if self
+            .deny_prefixes
+            .iter()
+            .any(|denied| prefix.starts_with(denied.as_bytes()))
+        {
+            return false;
+        }
+        if let Some(max_path_length) = self.max_path_length {
+            if route.path.len() > max_path_length {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check whether a route we learned from an account with the given relation
+    /// may be advertised to an account with the other relation
+    pub(crate) fn allows_export(&self, learned_from: RoutingRelation, to: RoutingRelation) -> bool {
+        !self.valley_free || to == RoutingRelation::Child || learned_from == RoutingRelation::Child
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn route(prefix: &'static str, path_length: usize) -> Route {
+        Route {
+            prefix: Bytes::from(prefix),
+            path: vec![Bytes::from("example.hop"); path_length],
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let policy = RoutePolicy::default();
+        assert!(policy.allows(&route("example.a", 0)));
+        assert!(policy.allows(&route("example.b", 100)));
+        assert!(policy.allows_export(RoutingRelation::Peer, RoutingRelation::Parent));
+    }
+
+    #[test]
+    fn filters_prefixes() {
+        let policy = RoutePolicy {
+            allow_prefixes: vec!["example.a".to_string(), "example.b".to_string()],
+            deny_prefixes: vec!["example.b.private".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.allows(&route("example.a", 1)));
+        assert!(policy.allows(&route("example.b.public", 1)));
+        assert!(!policy.allows(&route("example.b.private", 1)));
+        assert!(!policy.allows(&route("example.c", 1)));
+    }
+
+    #[test]
+    fn filters_long_paths() {
+        let policy = RoutePolicy {
+            max_path_length: Some(2),
+            ..Default::default()
+        };
+        assert!(policy.allows(&route("example.a", 2)));
+        assert!(!policy.allows(&route("example.a", 3)));
+    }
+
+    #[test]
+    fn valley_free_exports() {
+        let policy = RoutePolicy {
+            valley_free: true,
+            ..Default::default()
+        };
+        assert!(policy.allows_export(RoutingRelation::Child, RoutingRelation::Peer));
+        assert!(policy.allows_export(RoutingRelation::Parent, RoutingRelation::Child));
+        assert!(!policy.allows_export(RoutingRelation::Parent, RoutingRelation::Peer));
+        assert!(!policy.allows_export(RoutingRelation::Peer, RoutingRelation::Peer));
+        assert!(!policy.allows_export(RoutingRelation::Peer, RoutingRelation::Parent));
+    }
+}
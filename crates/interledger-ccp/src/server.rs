@@ -6,7 +6,7 @@ use crate::{
         CCP_RESPONSE, CCP_UPDATE_DESTINATION,
     },
     routing_table::RoutingTable,
-    CcpRoutingAccount, RouteManagerStore,
+    CcpRoutingAccount, RouteManagerStore, RoutingRelation,
 };
 use bytes::Bytes;
 use futures::{
@@ -15,7 +15,7 @@ use futures::{
 };
 #[cfg(test)]
 use interledger_packet::PrepareBuilder;
-use interledger_packet::{Address, ErrorCode, Fulfill, Reject, RejectBuilder};
+use interledger_packet::{Address, ErrorCode, Fulfill, Prepare, Reject, RejectBuilder};
 use interledger_service::{
     Account, BoxedIlpFuture, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
 };
@@ -23,10 +23,14 @@ use interledger_service::{
 use lazy_static::lazy_static;
 use log::{debug, error, trace, warn};
 use parking_lot::{Mutex, RwLock};
-use ring::digest::{digest, SHA256};
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
 use std::collections::HashMap;
 use std::{
-    cmp::min,
+    cmp::{max, min},
     convert::TryFrom,
     str,
     sync::Arc,
@@ -40,6 +44,8 @@ use tokio_executor::spawn;
 const DEFAULT_ROUTE_EXPIRY_TIME: u32 = 45000;
 const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
 const DUMMY_ROUTING_TABLE_ID: [u8; 16] = [0; 16];
+/// The auth value of routes whose origin did not authenticate them
+const UNAUTHENTICATED_ROUTE_AUTH: [u8; 32] = [0; 32];
 
 fn hash(preimage: &[u8; 32]) -> [u8; 32] {
     let mut out = [0; 32];
@@ -47,7 +53,74 @@ fn hash(preimage: &[u8; 32]) -> [u8; 32] {
     out
 }
 
+/// The auth value for a route we are the origin of. Each node the route is forwarded
+/// through hashes it once, so the auth of a route with a path of N hops is
+/// the N-th hash of this value.
+fn generate_route_auth(routing_secret: &[u8; 32], prefix: &[u8]) -> [u8; 32] {
+    let mut auth = [0; 32];
+    let key = hmac::SigningKey::new(&SHA256, routing_secret);
+    auth.copy_from_slice(hmac::sign(&key, prefix).as_ref());
+    auth
+}
+
+/// Add the hop to the start of the route's path the given number of times,
+/// hashing the auth for each one like a node forwarding the route would
+fn prepend_path(route: &mut Route, hop: &Bytes, times: usize) {
+    for _ in 0..times {
+        route.path.insert(0, hop.clone());
+        if route.auth != UNAUTHENTICATED_ROUTE_AUTH {
+            route.auth = hash(&route.auth);
+        }
+    }
+}
+
+/// The auth value the origin's direct peers receive for a route the origin created with
+/// the given routing secret. Other nodes can be configured to trust this value
+/// (see `CcpRouteManagerBuilder::trusted_route_auth`) to verify the origin's routes.
+///
+/// Anyone who knows this value can create routes for the prefix that look like they come
+/// from the origin, so it should only be shared with nodes that are trusted with it.
+pub fn trusted_route_auth(routing_secret: &[u8; 32], prefix: &[u8]) -> [u8; 32] {
+    hash(&generate_route_auth(routing_secret, prefix))
+}
+
+/// Check whether the route's auth is the one the origin's trusted auth value turns into
+/// after being hashed by every additional hop in the route's path.
+/// Nodes that only know the auth of a route further from the origin cannot forge this
+/// or make the route's path look shorter than it is.
+fn is_verified_by(route: &Route, trusted_auth: &[u8; 32]) -> bool {
+    if route.path.is_empty() {
+        return false;
+    }
+    let mut auth = *trusted_auth;
+    for _ in 1..route.path.len() {
+        auth = hash(&auth);
+    }
+    auth == route.auth
+}
+
 type NewAndWithdrawnRoutes = (Vec<Route>, Vec<Bytes>);
+/// The accounts we can forward packets for each prefix to and their weights
+type NextHopTable<A> = HashMap<Bytes, Vec<(A, u32)>>;
+
+/// What a peer we send routes to has asked for and received
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PeerState {
+    /// The mode from the peer's last Route Control Request.
+    /// Peers in Idle mode do not get any route updates until they switch back to Sync.
+    mode: Mode,
+    /// The epoch of our Forwarding Routing Table the peer has all of the updates up to
+    last_acknowledged_epoch: u32,
+}
+
+impl PeerState {
+    fn new(last_acknowledged_epoch: u32) -> Self {
+        PeerState {
+            mode: Mode::Sync,
+            last_acknowledged_epoch,
+        }
+    }
+}
 
 pub struct CcpRouteManagerBuilder<I, O, S> {
     /// The next request handler that will be used both to pass on requests that are not CCP messages.
@@ -62,6 +135,9 @@ pub struct CcpRouteManagerBuilder<I, O, S> {
     ilp_address: Address,
     global_prefix: Bytes,
     broadcast_interval: u64,
+    routing_secret: [u8; 32],
+    trusted_route_auths: HashMap<Bytes, [u8; 32]>,
+    accept_unverified_routes_from: Vec<RoutingRelation>,
 }
 
 impl<I, O, S, A> CcpRouteManagerBuilder<I, O, S>
@@ -72,6 +148,10 @@ where
     A: CcpRoutingAccount + Send + Sync + 'static,
 {
     pub fn new(ilp_address: Address, store: S, outgoing: O, next_incoming: I) -> Self {
+        let mut routing_secret = [0; 32];
+        SystemRandom::new()
+            .fill(&mut routing_secret)
+            .expect("Unable to get randomness");
         CcpRouteManagerBuilder {
             ilp_address,
             global_prefix: Bytes::from_static(b"g."),
@@ -79,6 +159,9 @@ where
             outgoing,
             store,
             broadcast_interval: DEFAULT_BROADCAST_INTERVAL,
+            routing_secret,
+            trusted_route_auths: HashMap::new(),
+            accept_unverified_routes_from: vec![RoutingRelation::Parent, RoutingRelation::Child],
         }
     }
 
@@ -99,11 +182,47 @@ where
         self
     }
 
+    /// Set the secret the auth values of the routes we are the origin of are generated from.
+    /// This should stay the same when the node restarts, otherwise peers will see
+    /// our routes as coming from a different origin. Defaults to a random secret.
+    pub fn routing_secret(&mut self, secret: [u8; 32]) -> &mut Self {
+        self.routing_secret = secret;
+        self
+    }
+
+    /// Only accept routes for the prefix whose auth shows they come from the origin that
+    /// the given auth value was obtained from out of band (see `trusted_route_auth`).
+    /// Routes for the prefix that do not match it are dropped regardless of who sent them.
+    pub fn trusted_route_auth(&mut self, prefix: Bytes, auth: [u8; 32]) -> &mut Self {
+        self.trusted_route_auths.insert(prefix, auth);
+        self
+    }
+
+    /// Set whether to accept routes that cannot be verified from accounts with the given relation.
+    /// Routes are unverified if no trusted auth is configured for their prefix, including all
+    /// routes that were not authenticated by their origin.
+    /// By default they are accepted from parents and children but not from peers.
+    pub fn accept_unverified_routes(
+        &mut self,
+        relation: RoutingRelation,
+        accept: bool,
+    ) -> &mut Self {
+        self.accept_unverified_routes_from
+            .retain(|other| other != &relation);
+        if accept {
+            self.accept_unverified_routes_from.push(relation);
+        }
+        self
+    }
+
     pub fn to_service(&self) -> CcpRouteManager<I, O, S, A> {
         #[allow(clippy::let_and_return)]
         let service = CcpRouteManager {
             ilp_address: self.ilp_address.clone(),
             global_prefix: self.global_prefix.clone(),
+            routing_secret: self.routing_secret,
+            trusted_route_auths: self.trusted_route_auths.clone(),
+            accept_unverified_routes_from: self.accept_unverified_routes_from.clone(),
             next_incoming: self.next_incoming.clone(),
             outgoing: self.outgoing.clone(),
             store: self.store.clone(),
@@ -112,6 +231,8 @@ where
             last_epoch_updates_sent_for: Arc::new(Mutex::new(0)),
             local_table: Arc::new(RwLock::new(RoutingTable::default())),
             incoming_tables: Arc::new(RwLock::new(HashMap::new())),
+            peer_states: Arc::new(RwLock::new(HashMap::new())),
+            next_hops: Arc::new(RwLock::new(HashMap::new())),
         };
 
         #[cfg(not(test))]
@@ -134,6 +255,12 @@ where
 pub struct CcpRouteManager<I, O, S, A: Account> {
     ilp_address: Address,
     global_prefix: Bytes,
+    /// The secret the auth values of the routes we are the origin of are generated from
+    routing_secret: [u8; 32],
+    /// The auth values of the origins of these prefixes' routes, obtained out of band
+    trusted_route_auths: HashMap<Bytes, [u8; 32]>,
+    /// Routes that cannot be verified are dropped unless they come from accounts with these relations
+    accept_unverified_routes_from: Vec<RoutingRelation>,
     /// The next request handler that will be used both to pass on requests that are not CCP messages.
     next_incoming: I,
     /// The outgoing request handler that will be used to send outgoing CCP messages.
@@ -155,6 +282,12 @@ pub struct CcpRouteManager<I, O, S, A: Account> {
     /// Updates from peers are applied to our local_table if they are better than the
     /// existing best route and if they do not attempt to overwrite configured routes.
     incoming_tables: Arc<RwLock<HashMap<A::AccountId, RoutingTable<A>>>>,
+    /// The mode and last acknowledged epoch of each peer we have sent routes to
+    /// or that has sent us a Route Control Request.
+    peer_states: Arc<RwLock<HashMap<A::AccountId, PeerState>>>,
+    /// All of the next hops for each prefix in the local_table, in order of preference,
+    /// with the weight the Router should give each one. The first is always the local_table's route.
+    next_hops: Arc<RwLock<NextHopTable<A>>>,
     store: S,
 }
 
@@ -236,7 +369,13 @@ where
             control
         );
 
-        // TODO stop sending updates if they are in Idle mode
+        self.peer_states
+            .write()
+            .entry(request.from.id())
+            .or_insert_with(|| PeerState::new(0))
+            .mode = control.mode;
+
+        // Peers in Idle mode don't get any updates until they ask for them again
         if control.mode == Mode::Sync {
             let (from_epoch_index, to_epoch_index) = {
                 let forwarding_table = self.forwarding_table.read();
@@ -318,6 +457,73 @@ where
         update
     }
 
+    /// Remove the routes we cannot be sure come from the route's origin.
+    /// Routes for prefixes we have a trusted auth for are removed if their auth does not match it.
+    /// Routes for other prefixes are removed unless we accept unverified routes from the account.
+    fn verify_route_auth(&self, account: &A, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+        let accept_unverified = self
+            .accept_unverified_routes_from
+            .contains(&account.routing_relation());
+        update.new_routes.retain(|route| {
+            if let Some(trusted_auth) = self.trusted_route_auths.get(&route.prefix) {
+                if is_verified_by(route, trusted_auth) {
+                    true
+                } else {
+                    warn!(
+                        "Ignoring route from account {} because its auth does not match the trusted auth for the prefix: {:?}",
+                        account.id(),
+                        route
+                    );
+                    false
+                }
+            } else {
+                if !accept_unverified {
+                    warn!(
+                        "Ignoring unverified route from account {} (no trusted auth is configured for the prefix): {:?}",
+                        account.id(),
+                        route
+                    );
+                }
+                accept_unverified
+            }
+        });
+        update
+    }
+
+    /// Remove the routes the account's import policy does not allow and prepend the
+    /// account's address to the paths of the others if the policy says so.
+    /// The removed routes are withdrawn, in case we accepted them before the policy changed.
+    fn apply_import_policy(
+        &self,
+        account: &A,
+        mut update: RouteUpdateRequest,
+    ) -> RouteUpdateRequest {
+        let policy = match account.routing_import_policy() {
+            Some(policy) => policy,
+            None => return update,
+        };
+        let mut denied = Vec::new();
+        update.new_routes.retain(|route| {
+            if policy.allows(route) {
+                true
+            } else {
+                trace!(
+                    "Import policy of account {} does not allow route: {:?}",
+                    account.id(),
+                    route
+                );
+                denied.push(route.prefix.clone());
+                false
+            }
+        });
+        update.withdrawn_routes.extend(denied);
+        let hop = account.client_address().to_bytes();
+        for route in update.new_routes.iter_mut() {
+            prepend_path(route, &hop, policy.prepend_path);
+        }
+        update
+    }
+
     /// Check if this Route Update Request is valid and, if so, apply any updates it contains.
     /// If updates are applied to the Incoming Routing Table for this peer, we will
     /// then check whether those routes are better than the current best ones we have in the
@@ -353,6 +559,8 @@ where
 
         // Filter out routes that don't make sense or that we won't accept
         let update = self.filter_routes(update);
+        let update = self.verify_route_auth(&request.from, update);
+        let update = self.apply_import_policy(&request.from, update);
 
         let mut incoming_tables = self.incoming_tables.write();
         if !&incoming_tables.contains_key(&request.from.id()) {
@@ -489,16 +697,19 @@ where
         let forwarding_table = self.forwarding_table.clone();
         let forwarding_table_updates = self.forwarding_table_updates.clone();
         let incoming_tables = self.incoming_tables.clone();
+        let next_hops = self.next_hops.clone();
         let ilp_address = self.ilp_address.clone();
         let global_prefix = self.global_prefix.clone();
+        let routing_secret = self.routing_secret;
         let mut store = self.store.clone();
 
         self.store.get_local_and_configured_routes().and_then(
             move |(ref local_routes, ref configured_routes)| {
-                let (better_routes, withdrawn_routes) = {
+                let (better_routes, withdrawn_routes, changed_next_hops) = {
                     // Note we only use a read lock here and later get a write lock if we need to update the table
                     let local_table = local_table.read();
                     let incoming_tables = incoming_tables.read();
+                    let next_hops = next_hops.read();
 
                     // Either check the given prefixes or check all of our local and configured routes
                     let prefixes_to_check: Box<dyn Iterator<Item = Bytes>> = if let Some(prefixes) = prefixes {
@@ -512,31 +723,61 @@ where
                     // and which ones we don't have routes for anymore
                     let mut better_routes: Vec<(Bytes, A, Route)> = Vec::with_capacity(prefixes_to_check.size_hint().0);
                     let mut withdrawn_routes: Vec<Bytes> = Vec::new();
+                    let mut changed_next_hops: Vec<(Bytes, Vec<(A, u32)>)> = Vec::new();
                     for prefix in prefixes_to_check {
-                        // See which prefixes there is now a better route for
-                        if let Some((best_next_account, best_route)) = get_best_route_for_prefix(
+                        let routes = get_routes_for_prefix(
                             local_routes,
                             configured_routes,
                             &incoming_tables,
                             prefix.as_ref(),
-                        ) {
-                            if let Some((ref next_account, ref route)) = local_table.get_route(&prefix) {
+                        );
+
+                        // The alternatives can change even if the best route stays the same
+                        let prefix_next_hops = get_next_hops(&routes);
+                        let changed = match next_hops.get(&prefix) {
+                            Some(current) => !same_next_hops(current, &prefix_next_hops),
+                            None => !prefix_next_hops.is_empty(),
+                        };
+                        if changed {
+                            changed_next_hops.push((prefix.clone(), prefix_next_hops));
+                        }
+
+                        // See which prefixes there is now a better route for
+                        if let Some((best_next_account, best_route)) = routes.into_iter().next() {
+                            if let Some((ref next_account, _)) = local_table.get_route(&prefix) {
                                 if next_account.id() == best_next_account.id() {
                                     continue
-                                } else {
-                                    better_routes.push((prefix.clone(), next_account.clone(), route.clone()));
                                 }
-                            } else {
-                                better_routes.push((prefix.clone(), best_next_account, best_route));
                             }
+                            better_routes.push((prefix.clone(), best_next_account, best_route));
                         } else {
                             // No longer have a route to this prefix
                             withdrawn_routes.push(prefix);
                         }
                     }
-                    (better_routes, withdrawn_routes)
+                    (better_routes, withdrawn_routes, changed_next_hops)
                 };
 
+                let routing_table_changed = !better_routes.is_empty()
+                    || !withdrawn_routes.is_empty()
+                    || !changed_next_hops.is_empty();
+
+                // Update the alternative next hops the Router can use
+                if !changed_next_hops.is_empty() || !withdrawn_routes.is_empty() {
+                    let mut next_hops = next_hops.write();
+                    for (prefix, prefix_next_hops) in changed_next_hops {
+                        trace!(
+                            "Next hops for prefix {} are now: {:?}",
+                            str::from_utf8(prefix.as_ref()).unwrap_or("<not utf8>"),
+                            prefix_next_hops.iter().map(|(account, weight)| (account.id(), *weight)).collect::<Vec<_>>(),
+                        );
+                        next_hops.insert(prefix, prefix_next_hops);
+                    }
+                    for prefix in withdrawn_routes.iter() {
+                        next_hops.remove(prefix);
+                    }
+                }
+
                 // Update the local and forwarding tables
                 if !better_routes.is_empty() || !withdrawn_routes.is_empty() {
                     let mut local_table = local_table.write();
@@ -566,9 +807,16 @@ where
 
                                 let old_route = forwarding_table.get_route(&prefix);
                                 if old_route.is_none() || old_route.unwrap().0.id() != account.id() {
+                                    if route.path.is_empty() {
+                                        // We are the origin of this route
+                                        route.auth = generate_route_auth(&routing_secret, &route.prefix);
+                                    }
+                                    // Each hop hashes the auth before forwarding,
+                                    // but unauthenticated routes stay that way
+                                    if route.auth != UNAUTHENTICATED_ROUTE_AUTH {
+                                        route.auth = hash(&route.auth);
+                                    }
                                     route.path.insert(0, ilp_address.to_bytes());
-                                    // Each hop hashes the auth before forwarding
-                                    route.auth = hash(&route.auth);
                                     forwarding_table.set_route(prefix.clone(), account.clone(), route.clone());
                                     new_routes.push(route);
                                 }
@@ -584,8 +832,24 @@ where
                     let epoch = forwarding_table.increment_epoch();
                     forwarding_table_updates.push((new_routes, withdrawn_routes));
                     debug_assert_eq!(epoch as usize + 1, forwarding_table_updates.len());
+                }
 
-                    Either::A(store.set_routes(local_table.get_simplified_table()))
+                if routing_table_changed {
+                    let local_table = local_table.read();
+                    let next_hops = next_hops.read();
+                    // The first next hop is always the best route from the local table
+                    let routes: Vec<(Bytes, Vec<(A, u32)>)> = local_table
+                        .get_simplified_table()
+                        .into_iter()
+                        .map(|(prefix, account)| {
+                            let prefix_next_hops = next_hops
+                                .get(&prefix)
+                                .cloned()
+                                .unwrap_or_else(|| vec![(account, 1)]);
+                            (prefix, prefix_next_hops)
+                        })
+                        .collect();
+                    Either::A(store.set_multipath_routes(routes))
                 } else {
                     // The routing table hasn't changed
                     Either::B(ok(()))
@@ -594,9 +858,14 @@ where
         )
     }
 
-    /// Send RouteUpdateRequests to all peers that we send routing messages to
+    /// Send RouteUpdateRequests to all peers that we send routing messages to,
+    /// except for the ones that asked us not to by switching to Idle mode.
+    ///
+    /// Most peers get the updates since the last broadcast. Peers that have not
+    /// acknowledged some earlier updates (for example because they were unreachable)
+    /// get all of the updates since the last epoch they acknowledged.
     fn send_route_updates(&self) -> impl Future<Item = (), Error = ()> {
-        let mut outgoing = self.outgoing.clone();
+        let clone = self.clone();
         let to_epoch_index = self.forwarding_table.read().epoch();
 
         let from_epoch_index: u32 = {
@@ -606,47 +875,109 @@ where
             epoch
         };
 
-        let route_update_request = self.create_route_update(from_epoch_index, to_epoch_index);
         debug!(
-            "Sending route udpates for epochs {} - {}: {:?}",
-            from_epoch_index, to_epoch_index, route_update_request,
+            "Sending route udpates for epochs {} - {}",
+            from_epoch_index, to_epoch_index,
         );
 
-        let prepare = route_update_request.to_prepare();
         self.store
             .get_accounts_to_send_routes_to()
             .and_then(move |mut accounts| {
                 accounts.sort_unstable_by_key(|a| a.id().to_string());
                 accounts.dedup_by_key(|a| a.id());
 
-                let broadcasting = !accounts.is_empty();
+                let updates: Vec<(A, u32, Prepare)> = {
+                    let peer_states = clone.peer_states.read();
+                    let mut route_updates: HashMap<u32, RouteUpdateRequest> = HashMap::new();
+                    let mut prepares: HashMap<u32, Prepare> = HashMap::new();
+                    accounts
+                        .into_iter()
+                        .filter_map(|account| match peer_states.get(&account.id()) {
+                            Some(state) if state.mode == Mode::Idle => {
+                                trace!(
+                                    "Not sending route update to account {} because it is in Idle mode",
+                                    account.id()
+                                );
+                                None
+                            }
+                            Some(state) => Some((
+                                account,
+                                min(state.last_acknowledged_epoch, from_epoch_index),
+                            )),
+                            None => Some((account, from_epoch_index)),
+                        })
+                        .map(|(account, from_epoch_index)| {
+                            let route_update =
+                                route_updates.entry(from_epoch_index).or_insert_with(|| {
+                                    clone.create_route_update(from_epoch_index, to_epoch_index)
+                                });
+                            // Accounts without an export policy can share the same packet
+                            let prepare = if account.routing_export_policy().is_some() {
+                                clone
+                                    .apply_export_policy(&account, route_update.clone())
+                                    .to_prepare()
+                            } else {
+                                prepares
+                                    .entry(from_epoch_index)
+                                    .or_insert_with(|| route_update.to_prepare())
+                                    .clone()
+                            };
+                            (account, from_epoch_index, prepare)
+                        })
+                        .collect()
+                };
+
+                let broadcasting = !updates.is_empty();
                 if broadcasting {
                     debug!("Sending route updates to accounts: {}", {
-                        let account_list: Vec<String> = accounts
+                        let account_list: Vec<String> = updates
                             .iter()
-                            .map(|a| format!("{} ({})", a.id(), a.client_address()))
+                            .map(|(a, from_epoch_index, _)| {
+                                format!(
+                                    "{} ({}, from epoch {})",
+                                    a.id(),
+                                    a.client_address(),
+                                    from_epoch_index
+                                )
+                            })
                             .collect();
                         account_list.join(", ")
                     });
+                    let mut outgoing = clone.outgoing.clone();
+                    let peer_states = clone.peer_states.clone();
                     Either::A(
-                        join_all(accounts.into_iter().map(move |account| {
-                            let account_id = account.id();
-                            outgoing
-                                .send_request(OutgoingRequest {
-                                    from: account.clone(),
-                                    to: account,
-                                    original_amount: prepare.amount(),
-                                    prepare: prepare.clone(),
-                                })
-                                .map_err(move |err| {
-                                    warn!(
-                                        "Error sending route update to account {}: {:?}",
-                                        account_id, err
-                                    )
-                                })
-                                .then(|_| Ok(()))
-                        }))
-                        .and_then(|_| {
+                        join_all(updates.into_iter().map(
+                            move |(account, from_epoch_index, prepare)| {
+                                let account_id = account.id();
+                                let peer_states = peer_states.clone();
+                                outgoing
+                                    .send_request(OutgoingRequest {
+                                        from: account.clone(),
+                                        to: account,
+                                        original_amount: prepare.amount(),
+                                        prepare,
+                                    })
+                                    .then(move |result| {
+                                        let mut peer_states = peer_states.write();
+                                        let state = peer_states
+                                            .entry(account_id)
+                                            .or_insert_with(|| PeerState::new(from_epoch_index));
+                                        if let Err(err) = result {
+                                            warn!(
+                                                "Error sending route update to account {}: {:?}",
+                                                account_id, err
+                                            )
+                                        } else {
+                                            state.last_acknowledged_epoch = max(
+                                                state.last_acknowledged_epoch,
+                                                to_epoch_index,
+                                            );
+                                        }
+                                        Ok(())
+                                    })
+                            },
+                        ))
+                        .and_then(|_: Vec<()>| {
                             trace!("Finished sending route updates");
                             Ok(())
                         }),
@@ -720,6 +1051,51 @@ where
         }
     }
 
+    /// Remove the routes the account's export policy does not allow and prepend our
+    /// address to the paths of the others if the policy says so.
+    /// The removed routes are withdrawn, in case we advertised them before the policy changed.
+    fn apply_export_policy(
+        &self,
+        account: &A,
+        mut update: RouteUpdateRequest,
+    ) -> RouteUpdateRequest {
+        let policy = match account.routing_export_policy() {
+            Some(policy) => policy,
+            None => return update,
+        };
+        let mut denied = Vec::new();
+        {
+            let forwarding_table = self.forwarding_table.read();
+            update.new_routes.retain(|route| {
+                // The account we learned the route from is the one we forward packets for it to
+                let learned_from = forwarding_table
+                    .get_route(&route.prefix)
+                    .map(|(next_hop, _route)| next_hop.routing_relation());
+                let allowed = policy.allows(route)
+                    && learned_from
+                        .map(|learned_from| {
+                            policy.allows_export(learned_from, account.routing_relation())
+                        })
+                        .unwrap_or(true);
+                if !allowed {
+                    trace!(
+                        "Export policy of account {} does not allow route: {:?}",
+                        account.id(),
+                        route
+                    );
+                    denied.push(route.prefix.clone());
+                }
+                allowed
+            });
+        }
+        update.withdrawn_routes.extend(denied);
+        let hop = self.ilp_address.to_bytes();
+        for route in update.new_routes.iter_mut() {
+            prepend_path(route, &hop, policy.prepend_path);
+        }
+        update
+    }
+
     /// Send a Route Update Request to a specific account for the given epoch range.
     /// This is used when the peer has fallen behind and has requested a specific range of updates.
     fn send_route_update(
@@ -728,10 +1104,12 @@ where
         from_epoch_index: u32,
         to_epoch_index: u32,
     ) -> impl Future<Item = (), Error = ()> {
+        let route_update = self.create_route_update(from_epoch_index, to_epoch_index);
         let prepare = self
-            .create_route_update(from_epoch_index, to_epoch_index)
+            .apply_export_policy(&account, route_update)
             .to_prepare();
         let account_id = account.id();
+        let peer_states = self.peer_states.clone();
         debug!(
             "Sending individual route update to account: {} for epochs from: {} to: {}",
             account_id, from_epoch_index, to_epoch_index
@@ -751,18 +1129,26 @@ where
                         "Error sending route update to account {}: {:?}",
                         account_id, err
                     )
+                } else {
+                    let mut peer_states = peer_states.write();
+                    let state = peer_states
+                        .entry(account_id)
+                        .or_insert_with(|| PeerState::new(to_epoch_index));
+                    state.last_acknowledged_epoch =
+                        max(state.last_acknowledged_epoch, to_epoch_index);
                 }
                 Ok(())
             })
     }
 }
 
-fn get_best_route_for_prefix<A: CcpRoutingAccount>(
+/// Get all of the routes we have for the given prefix, starting with the best one
+fn get_routes_for_prefix<A: CcpRoutingAccount>(
     local_routes: &HashMap<Bytes, A>,
     configured_routes: &HashMap<Bytes, A>,
     incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
     prefix: &[u8],
-) -> Option<(A, Route)> {
+) -> Vec<(A, Route)> {
     // Check if we have a configured route for that specific prefix
     // or any shorter prefix ("example.a.b.c" will match "example.a.b" and "example.a")
     // Note that this logic is duplicated from the Address type. We are not using
@@ -772,7 +1158,7 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     for i in 0..segments.len() {
         let prefix = &segments[0..segments.len() - i].join(&b'.');
         if let Some(account) = configured_routes.get(prefix.as_ref() as &[u8]) {
-            return Some((
+            return vec![(
                 account.clone(),
                 Route {
                     prefix: account.client_address().to_bytes(),
@@ -780,12 +1166,12 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
                     path: Vec::new(),
                     props: Vec::new(),
                 },
-            ));
+            )];
         }
     }
 
     if let Some(account) = local_routes.get(prefix) {
-        return Some((
+        return vec![(
             account.clone(),
             Route {
                 prefix: account.client_address().to_bytes(),
@@ -793,44 +1179,57 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
                 path: Vec::new(),
                 props: Vec::new(),
             },
-        ));
+        )];
     }
 
-    let mut candidate_routes = incoming_tables
+    let mut candidate_routes: Vec<&(A, Route)> = incoming_tables
         .values()
-        .filter_map(|incoming_table| incoming_table.get_route(prefix));
-    if let Some((account, route)) = candidate_routes.next() {
-        let (best_account, best_route) = candidate_routes.fold(
-            (account, route),
-            |(best_account, best_route), (account, route)| {
-                // Prioritize child > peer > parent
-                if best_account.routing_relation() > account.routing_relation() {
-                    return (best_account, best_route);
-                } else if best_account.routing_relation() < account.routing_relation() {
-                    return (account, route);
-                }
-
-                // Prioritize shortest path
-                if best_route.path.len() < route.path.len() {
-                    return (best_account, best_route);
-                } else if best_route.path.len() > route.path.len() {
-                    return (account, route);
-                }
+        .filter_map(|incoming_table| incoming_table.get_route(prefix))
+        .collect();
+    candidate_routes.sort_by(|(account_a, route_a), (account_b, route_b)| {
+        // Prioritize child > peer > parent
+        (account_b.routing_relation() as u8)
+            .cmp(&(account_a.routing_relation() as u8))
+            // Prioritize shortest path
+            .then_with(|| route_a.path.len().cmp(&route_b.path.len()))
+            // Finally base it on account ID
+            .then_with(|| account_a.id().to_string().cmp(&account_b.id().to_string()))
+    });
+    candidate_routes.into_iter().cloned().collect()
+}
 
-                // Finally base it on account ID
-                if best_account.id().to_string() < account.id().to_string() {
-                    (best_account, best_route)
+/// Weight the routes for a prefix so that the Router splits packets evenly between the
+/// best route and the ones that are just as good (same relation and path length).
+/// The other routes get a weight of 0 so they are only used if those fail.
+fn get_next_hops<A: CcpRoutingAccount>(routes: &[(A, Route)]) -> Vec<(A, u32)> {
+    if let Some((best_account, best_route)) = routes.first() {
+        routes
+            .iter()
+            .map(|(account, route)| {
+                let weight = if account.routing_relation() == best_account.routing_relation()
+                    && route.path.len() == best_route.path.len()
+                {
+                    1
                 } else {
-                    (account, route)
-                }
-            },
-        );
-        Some((best_account.clone(), best_route.clone()))
+                    0
+                };
+                (account.clone(), weight)
+            })
+            .collect()
     } else {
-        None
+        Vec::new()
     }
 }
 
+fn same_next_hops<A: CcpRoutingAccount>(a: &[(A, u32)], b: &[(A, u32)]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|((account_a, weight_a), (account_b, weight_b))| {
+                account_a.id() == account_b.id() && weight_a == weight_b
+            })
+}
+
 impl<I, O, S, A> IncomingService<A> for CcpRouteManager<I, O, S, A>
 where
     I: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -946,39 +1345,80 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_configured_routes() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.a");
-        assert_eq!(best_route.unwrap().0.id(), 4);
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.a");
+        assert_eq!(routes[0].0.id(), 4);
     }
 
     #[test]
     fn prioritizes_shorter_configured_routes() {
-        let best_route =
-            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.a.sub-prefix");
-        assert_eq!(best_route.unwrap().0.id(), 4);
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.a.sub-prefix");
+        assert_eq!(routes[0].0.id(), 4);
     }
 
     #[test]
     fn prioritizes_local_routes_over_broadcasted_ones() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.c");
-        assert_eq!(best_route.unwrap().0.id(), 3);
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.c");
+        assert_eq!(routes[0].0.id(), 3);
     }
 
     #[test]
     fn prioritizes_children_over_peers() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.d");
-        assert_eq!(best_route.unwrap().0.id(), 6);
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.d");
+        assert_eq!(routes[0].0.id(), 6);
     }
 
     #[test]
     fn prioritizes_shorter_paths() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.e");
-        assert_eq!(best_route.unwrap().0.id(), 7);
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.e");
+        assert_eq!(routes[0].0.id(), 7);
+    }
+
+    #[test]
+    fn returns_alternative_routes_in_order() {
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.e");
+        assert_eq!(
+            routes
+                .iter()
+                .map(|(account, _)| account.id())
+                .collect::<Vec<u64>>(),
+            vec![7, 8]
+        );
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.d");
+        assert_eq!(
+            routes
+                .iter()
+                .map(|(account, _)| account.id())
+                .collect::<Vec<u64>>(),
+            vec![6, 7]
+        );
+    }
+
+    #[test]
+    fn weights_equally_good_routes() {
+        let route = |path_length: usize| Route {
+            prefix: Bytes::from("example.e"),
+            path: vec![Bytes::from("example.one"); path_length],
+            auth: [0; 32],
+            props: Vec::new(),
+        };
+        let routes = vec![
+            (TestAccount::new(7, "example.peer1"), route(1)),
+            (TestAccount::new(8, "example.peer2"), route(1)),
+            (TestAccount::new(9, "example.peer3"), route(2)),
+        ];
+        assert_eq!(
+            get_next_hops(&routes)
+                .iter()
+                .map(|(account, weight)| (account.id(), *weight))
+                .collect::<Vec<(u64, u32)>>(),
+            vec![(7, 1), (8, 1), (9, 0)]
+        );
     }
 
     #[test]
     fn returns_none_for_no_route() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.z");
-        assert!(best_route.is_none());
+        let routes = get_routes_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.z");
+        assert!(routes.is_empty());
     }
 }
 
@@ -1100,8 +1540,10 @@ mod handle_route_update_request {
     use super::*;
     use crate::fixtures::*;
     use crate::test_helpers::*;
+    use crate::RoutePolicy;
     use std::{
         iter::FromIterator,
+        str::FromStr,
         time::{Duration, SystemTime},
     };
 
@@ -1329,6 +1771,64 @@ mod handle_route_update_request {
         );
     }
 
+    #[test]
+    fn writes_alternative_next_hops_to_store() {
+        let mut service = test_service();
+        let mut child = TestAccount::new(3, "example.child");
+        child.relation = RoutingRelation::Child;
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        for account in &[
+            ROUTING_ACCOUNT.clone(),
+            TestAccount::new(2, "example.peer2"),
+        ] {
+            service
+                .handle_request(IncomingRequest {
+                    from: account.clone(),
+                    prepare: request.to_prepare(),
+                })
+                .wait()
+                .unwrap();
+        }
+        let next_hops = service.store.next_hops.clone();
+        let next_hop_ids = |prefix: &[u8]| -> Vec<(u64, u32)> {
+            next_hops.lock()[prefix]
+                .iter()
+                .map(|(account, weight)| (account.id(), *weight))
+                .collect()
+        };
+        assert_eq!(next_hop_ids(b"example.prefix1"), vec![(1, 1), (2, 1)]);
+        assert_eq!(next_hop_ids(b"example.prefix2"), vec![(1, 1), (2, 1)]);
+
+        // A better route becomes the preferred next hop and the others are kept as backups
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.new_routes.truncate(1);
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: child,
+                prepare: request.to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(
+            next_hop_ids(b"example.prefix1"),
+            vec![(3, 1), (1, 0), (2, 0)]
+        );
+        assert_eq!(next_hop_ids(b"example.prefix2"), vec![(1, 1), (2, 1)]);
+        assert_eq!(service.store.routes.lock()[&b"example.prefix1"[..]].id(), 3);
+        assert_eq!(
+            (*service.local_table.read())
+                .get_route(b"example.prefix1")
+                .unwrap()
+                .0
+                .id(),
+            3
+        );
+    }
+
     #[test]
     fn doesnt_overwrite_configured_or_local_routes() {
         let mut service = test_service();
@@ -1487,6 +1987,205 @@ mod handle_route_update_request {
         let control = RouteControlRequest::try_from(&request.prepare).unwrap();
         assert_eq!(control.last_known_epoch, 1);
     }
+
+    fn remote_route(path: &[&str], auth: [u8; 32]) -> Route {
+        Route {
+            prefix: Bytes::from("example.remote"),
+            path: path.iter().map(|hop| Bytes::from(*hop)).collect(),
+            auth,
+            props: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn filters_unverified_routes_from_peers_by_default() {
+        let mut service = test_service();
+        service.accept_unverified_routes_from = CcpRouteManagerBuilder::new(
+            EXAMPLE_CONNECTOR.clone(),
+            TestStore::new(),
+            service.outgoing.clone(),
+            service.next_incoming.clone(),
+        )
+        .accept_unverified_routes_from;
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request
+            .new_routes
+            .push(remote_route(&["example.peer"], [0; 32]));
+        request
+            .new_routes
+            .push(remote_route(&["example.peer"], [1; 32]));
+
+        let peer = TestAccount::new(10, "example.peer");
+        assert!(service
+            .verify_route_auth(&peer, request.clone())
+            .new_routes
+            .is_empty());
+
+        let mut child = TestAccount::new(11, "example.connector.child");
+        child.relation = RoutingRelation::Child;
+        assert_eq!(
+            service
+                .verify_route_auth(&child, request.clone())
+                .new_routes
+                .len(),
+            2
+        );
+
+        service.accept_unverified_routes_from = vec![RoutingRelation::Peer];
+        assert_eq!(
+            service.verify_route_auth(&peer, request).new_routes.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn filters_routes_that_do_not_match_trusted_auth() {
+        let mut service = test_service();
+        let origin_secret = [9; 32];
+        service.trusted_route_auths.insert(
+            Bytes::from("example.remote"),
+            trusted_route_auth(&origin_secret, b"example.remote"),
+        );
+        let origin_auth = generate_route_auth(&origin_secret, b"example.remote");
+
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        // Directly from the origin
+        request
+            .new_routes
+            .push(remote_route(&["example.remote"], hash(&origin_auth)));
+        // From the origin via another node
+        request.new_routes.push(remote_route(
+            &["example.peer", "example.remote"],
+            hash(&hash(&origin_auth)),
+        ));
+        // Pretending to have a shorter path
+        request
+            .new_routes
+            .push(remote_route(&["example.remote"], hash(&hash(&origin_auth))));
+        // From a different origin
+        request.new_routes.push(remote_route(
+            &["example.peer", "example.remote"],
+            hash(&hash(&[2; 32])),
+        ));
+        // Unauthenticated
+        request
+            .new_routes
+            .push(remote_route(&["example.remote"], [0; 32]));
+
+        // Routes for prefixes with trusted auths are checked even for accounts
+        // we accept unverified routes from
+        let mut child = TestAccount::new(11, "example.connector.child");
+        child.relation = RoutingRelation::Child;
+        for account in &[TestAccount::new(10, "example.peer"), child] {
+            let request = service.verify_route_auth(account, request.clone());
+            assert_eq!(request.new_routes.len(), 2);
+            assert_eq!(request.new_routes[0].path.len(), 1);
+            assert_eq!(request.new_routes[1].path.len(), 2);
+        }
+
+        // Routes other peers announced first do not stop the origin's routes from being accepted
+        let mut table = RoutingTable::new([0; 16]);
+        table.add_route(
+            TestAccount::new(12, "example.attacker"),
+            remote_route(&["example.attacker"], [3; 32]),
+        );
+        service.incoming_tables.write().insert(12, table);
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request
+            .new_routes
+            .push(remote_route(&["example.remote"], hash(&origin_auth)));
+        assert_eq!(
+            service
+                .verify_route_auth(&TestAccount::new(10, "example.peer"), request)
+                .new_routes
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn authenticates_own_routes() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: TestAccount::new(10, "example.peer"),
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.remote").unwrap(),
+                    new_routes: vec![remote_route(&["example.peer"], [0; 32])],
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+
+        let forwarding_table = service.forwarding_table.read();
+        let (_account, route) = forwarding_table.get_route(b"example.local.1").unwrap();
+        assert_eq!(
+            route.auth,
+            hash(&generate_route_auth(
+                &service.routing_secret,
+                b"example.local.1"
+            ))
+        );
+        // Routes from peers that were not authenticated are not made to look like they were
+        let (_account, route) = forwarding_table.get_route(b"example.remote").unwrap();
+        assert_eq!(route.auth, [0; 32]);
+    }
+
+    #[test]
+    fn applies_import_policy() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        let mut peer = TestAccount::new(10, "example.peer");
+        peer.import_policy = Some(RoutePolicy {
+            deny_prefixes: vec!["example.remote.private".to_string()],
+            prepend_path: 1,
+            ..Default::default()
+        });
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: peer,
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.remote").unwrap(),
+                    new_routes: vec![
+                        remote_route(&["example.remote"], [1; 32]),
+                        Route {
+                            prefix: Bytes::from("example.remote.private"),
+                            path: vec![Bytes::from("example.remote")],
+                            auth: [0; 32],
+                            props: Vec::new(),
+                        },
+                    ],
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+
+        let incoming_tables = service.incoming_tables.read();
+        let (_account, route) = incoming_tables[&10].get_route(b"example.remote").unwrap();
+        assert_eq!(
+            route.path,
+            vec![Bytes::from("example.peer"), Bytes::from("example.remote")]
+        );
+        assert_eq!(route.auth, hash(&[1; 32]));
+        let (_account, route) = incoming_tables[&10]
+            .get_route(b"example.remote.private")
+            .unwrap();
+        assert_eq!(route.prefix, Bytes::from("example.remote"));
+    }
 }
 
 #[cfg(test)]
@@ -1569,6 +2268,7 @@ mod create_route_update {
 mod send_route_updates {
     use super::*;
     use crate::test_helpers::*;
+    use crate::RoutePolicy;
     use std::str::FromStr;
 
     #[test]
@@ -1710,4 +2410,173 @@ mod send_route_updates {
             "example.remote"
         );
     }
+
+    fn control_request(mode: Mode, last_known_routing_table_id: [u8; 16]) -> Prepare {
+        RouteControlRequest {
+            mode,
+            last_known_routing_table_id,
+            last_known_epoch: 0,
+            features: Vec::new(),
+        }
+        .to_prepare()
+    }
+
+    fn update_recipients(outgoing_requests: &OutgoingRequests) -> Vec<(u64, u32)> {
+        let mut recipients: Vec<(u64, u32)> = outgoing_requests
+            .lock()
+            .iter()
+            .map(|request| {
+                let update = RouteUpdateRequest::try_from(&request.prepare).unwrap();
+                (request.to.id(), update.from_epoch_index)
+            })
+            .collect();
+        recipients.sort_unstable();
+        recipients
+    }
+
+    #[test]
+    fn does_not_broadcast_to_idle_peers() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_request(IncomingRequest {
+                from: TestAccount::new(1, "example.local.1"),
+                prepare: control_request(Mode::Idle, [0; 16]),
+            })
+            .wait()
+            .unwrap();
+        assert!(outgoing_requests.lock().is_empty());
+
+        service.send_route_updates().wait().unwrap();
+        assert_eq!(update_recipients(&outgoing_requests), vec![(2, 0)]);
+    }
+
+    #[test]
+    fn resumes_with_delta_when_peer_switches_back_to_sync() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let routing_table_id = service.forwarding_table.read().id();
+        service
+            .handle_request(IncomingRequest {
+                from: TestAccount::new(1, "example.local.1"),
+                prepare: control_request(Mode::Idle, routing_table_id),
+            })
+            .wait()
+            .unwrap();
+        service.send_route_updates().wait().unwrap();
+        outgoing_requests.lock().clear();
+
+        service
+            .handle_request(IncomingRequest {
+                from: TestAccount::new(1, "example.local.1"),
+                prepare: control_request(Mode::Sync, routing_table_id),
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(update_recipients(&outgoing_requests), vec![(1, 0)]);
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        assert_eq!(update.to_epoch_index, 1);
+        assert_eq!(update.new_routes.len(), 2);
+        outgoing_requests.lock().clear();
+
+        // It gets the regular broadcasts again
+        service.send_route_updates().wait().unwrap();
+        assert_eq!(update_recipients(&outgoing_requests), vec![(1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn resends_epochs_a_peer_has_not_acknowledged() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service.send_route_updates().wait().unwrap();
+        outgoing_requests.lock().clear();
+
+        // As if the last broadcast to this peer had failed
+        service.peer_states.write().insert(1, PeerState::new(0));
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: TestAccount::new(10, "example.peer"),
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.remote").unwrap(),
+                    new_routes: vec![Route {
+                        prefix: Bytes::from("example.remote"),
+                        path: vec![Bytes::from("example.peer")],
+                        auth: [0; 32],
+                        props: Vec::new(),
+                    }],
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        service.send_route_updates().wait().unwrap();
+        assert_eq!(
+            update_recipients(&outgoing_requests),
+            vec![(1, 0), (2, 1), (10, 1)]
+        );
+        assert_eq!(service.peer_states.read()[&1].last_acknowledged_epoch, 2);
+    }
+
+    #[test]
+    fn applies_export_policy() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let route_update = service.create_route_update(0, 1);
+        assert_eq!(route_update.new_routes.len(), 2);
+
+        let mut account = TestAccount::new(10, "example.peer");
+        account.export_policy = Some(RoutePolicy {
+            deny_prefixes: vec!["example.configured".to_string()],
+            prepend_path: 2,
+            ..Default::default()
+        });
+        let filtered = service.apply_export_policy(&account, route_update.clone());
+        assert_eq!(filtered.new_routes.len(), 1);
+        assert_eq!(
+            filtered.new_routes[0].prefix,
+            Bytes::from("example.local.1")
+        );
+        assert_eq!(filtered.new_routes[0].path.len(), 3);
+        let original = route_update
+            .new_routes
+            .iter()
+            .find(|route| route.prefix == filtered.new_routes[0].prefix)
+            .unwrap();
+        assert_eq!(filtered.new_routes[0].auth, hash(&hash(&original.auth)));
+        assert_eq!(
+            filtered.withdrawn_routes,
+            vec![Bytes::from("example.configured.1")]
+        );
+    }
+
+    #[test]
+    fn valley_free_export_policy() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let route_update = service.create_route_update(0, 1);
+        let policy = RoutePolicy {
+            valley_free: true,
+            ..Default::default()
+        };
+
+        // Both routes were learned from peers, so they are only advertised to children
+        let mut peer = TestAccount::new(10, "example.peer");
+        peer.export_policy = Some(policy.clone());
+        let filtered = service.apply_export_policy(&peer, route_update.clone());
+        assert!(filtered.new_routes.is_empty());
+        assert_eq!(filtered.withdrawn_routes.len(), 2);
+
+        let mut child = TestAccount::new(11, "example.connector.child");
+        child.relation = RoutingRelation::Child;
+        child.export_policy = Some(policy);
+        let filtered = service.apply_export_policy(&child, route_update);
+        assert_eq!(filtered.new_routes.len(), 2);
+        assert!(filtered.withdrawn_routes.is_empty());
+    }
 }
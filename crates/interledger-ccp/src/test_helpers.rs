@@ -25,6 +25,8 @@ lazy_static! {
         send_routes: true,
         receive_routes: true,
         relation: RoutingRelation::Peer,
+        import_policy: None,
+        export_policy: None,
     };
     pub static ref NON_ROUTING_ACCOUNT: TestAccount = TestAccount {
         id: 2,
@@ -32,6 +34,8 @@ lazy_static! {
         send_routes: false,
         receive_routes: false,
         relation: RoutingRelation::Child,
+        import_policy: None,
+        export_policy: None,
     };
     pub static ref EXAMPLE_CONNECTOR: Address = Address::from_str("example.connector").unwrap();
     pub static ref ALICE: Username = Username::from_str("alice").unwrap();
@@ -44,6 +48,8 @@ pub struct TestAccount {
     pub receive_routes: bool,
     pub send_routes: bool,
     pub relation: RoutingRelation,
+    pub import_policy: Option<RoutePolicy>,
+    pub export_policy: Option<RoutePolicy>,
 }
 
 impl TestAccount {
@@ -54,6 +60,8 @@ impl TestAccount {
             receive_routes: true,
             send_routes: true,
             relation: RoutingRelation::Peer,
+            import_policy: None,
+            export_policy: None,
         }
     }
 }
@@ -96,6 +104,14 @@ impl CcpRoutingAccount for TestAccount {
     fn should_send_routes(&self) -> bool {
         self.send_routes
     }
+
+    fn routing_import_policy(&self) -> Option<&RoutePolicy> {
+        self.import_policy.as_ref()
+    }
+
+    fn routing_export_policy(&self) -> Option<&RoutePolicy> {
+        self.export_policy.as_ref()
+    }
 }
 
 #[derive(Clone)]
@@ -103,6 +119,7 @@ pub struct TestStore {
     pub local: HashMap<Bytes, TestAccount>,
     pub configured: HashMap<Bytes, TestAccount>,
     pub routes: Arc<Mutex<HashMap<Bytes, TestAccount>>>,
+    pub next_hops: Arc<Mutex<NextHopTable>>,
 }
 
 impl TestStore {
@@ -111,6 +128,7 @@ impl TestStore {
             local: HashMap::new(),
             configured: HashMap::new(),
             routes: Arc::new(Mutex::new(HashMap::new())),
+            next_hops: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -122,11 +140,13 @@ impl TestStore {
             local,
             configured,
             routes: Arc::new(Mutex::new(HashMap::new())),
+            next_hops: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
 
 type RoutingTable<A> = HashMap<Bytes, A>;
+type NextHopTable = HashMap<Bytes, Vec<(TestAccount, u32)>>;
 
 impl RouteManagerStore for TestStore {
     type Account = TestAccount;
@@ -177,6 +197,19 @@ impl RouteManagerStore for TestStore {
         *self.routes.lock() = HashMap::from_iter(routes.into_iter());
         Box::new(ok(()))
     }
+
+    fn set_multipath_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<(TestAccount, u32)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let next_hops = NextHopTable::from_iter(routes);
+        *self.routes.lock() = next_hops
+            .iter()
+            .map(|(prefix, next_hops)| (prefix.clone(), next_hops[0].0.clone()))
+            .collect();
+        *self.next_hops.lock() = next_hops;
+        Box::new(ok(()))
+    }
 }
 
 pub fn test_service() -> CcpRouteManager<
@@ -209,10 +242,12 @@ pub fn test_service() -> CcpRouteManager<
         }),
     )
     .ilp_address(addr)
+    // Most tests get routes from peers without configuring trusted auths for them
+    .accept_unverified_routes(RoutingRelation::Peer, true)
     .to_service()
 }
 
-type OutgoingRequests = Arc<Mutex<Vec<OutgoingRequest<TestAccount>>>>;
+pub type OutgoingRequests = Arc<Mutex<Vec<OutgoingRequest<TestAccount>>>>;
 
 pub fn test_service_with_routes() -> (
     CcpRouteManager<
@@ -236,6 +271,8 @@ pub fn test_service_with_routes() -> (
                 send_routes: false,
                 receive_routes: false,
                 relation: RoutingRelation::Child,
+                import_policy: None,
+                export_policy: None,
             },
         ),
     ]);
@@ -267,6 +304,8 @@ pub fn test_service_with_routes() -> (
         }),
     )
     .ilp_address(addr)
+    // Most tests get routes from peers without configuring trusted auths for them
+    .accept_unverified_routes(RoutingRelation::Peer, true)
     .to_service();
     (service, outgoing_requests)
 }
@@ -1,23 +1,67 @@
-use super::{HttpAccount, HttpStore};
-use bytes::BytesMut;
+use super::{ClientIdentity, HttpAccount, HttpStore};
+use bytes::{Bytes, BytesMut};
 use futures::{
-    future::{err, result},
+    future::{err, result, Either},
     Future, Stream,
 };
 use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Reject, RejectBuilder};
 use interledger_service::*;
-use log::{error, trace};
+use log::{debug, error, trace};
+use parking_lot::RwLock;
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
     r#async::{Chunk, Client, ClientBuilder, Response as HttpResponse},
+    Certificate, Error as ReqwestError, StatusCode,
 };
 use std::str::FromStr;
-use std::{convert::TryFrom, marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio_timer::Timeout;
+use url::Url;
+
+const DEFAULT_MAX_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_RETRIES: usize = 2;
+
+/// Settings for the HTTP client used to send packets to an account.
+/// Accounts with the same settings share a client and its pool of connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HttpClientConfig {
+    /// The maximum number of idle connections to keep open to each host
+    pub max_idle_per_host: usize,
+    /// Speak HTTP/2 without negotiating it first. Only enable this if the peer supports HTTP/2
+    pub http2_prior_knowledge: bool,
+    /// The longest to wait for a response, even if the Prepare expires later
+    pub max_timeout: Duration,
+    /// How many times to resend a request if the connection to the peer could not be established
+    pub max_retries: usize,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            max_idle_per_host: usize::max_value(),
+            http2_prior_knowledge: false,
+            max_timeout: DEFAULT_MAX_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+}
+
+/// Accounts share a client (and its connections) if they have the same settings
+/// and present the same client certificate
+type ClientKey = (HttpClientConfig, Option<ClientIdentity>);
 
 #[derive(Clone)]
 pub struct HttpClientService<S, O, A> {
     ilp_address: Address,
-    client: Client,
+    default_config: HttpClientConfig,
+    root_certificates: Vec<Certificate>,
+    clients: Arc<RwLock<HashMap<ClientKey, Client>>>,
     store: Arc<S>,
     next: O,
     account_type: PhantomData<A>,
@@ -30,25 +74,33 @@ where
     A: HttpAccount,
 {
     pub fn new(ilp_address: Address, store: S, next: O) -> Self {
-        let mut headers = HeaderMap::with_capacity(2);
-        headers.insert(
-            HeaderName::from_static("content-type"),
-            HeaderValue::from_static("application/octet-stream"),
-        );
-        let client = ClientBuilder::new()
-            .default_headers(headers)
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
+        Self::with_config(ilp_address, store, next, HttpClientConfig::default())
+    }
 
+    /// Like `new`, but the given settings are used for accounts that do not have their own
+    pub fn with_config(
+        ilp_address: Address,
+        store: S,
+        next: O,
+        default_config: HttpClientConfig,
+    ) -> Self {
         HttpClientService {
             ilp_address,
-            client,
+            default_config,
+            root_certificates: Vec::new(),
+            clients: Arc::new(RwLock::new(HashMap::new())),
             store: Arc::new(store),
             next,
             account_type: PhantomData,
         }
     }
+
+    /// Trust the given root certificate when connecting to accounts over HTTPS, in addition
+    /// to the system's trusted roots. This must be called before any packets are sent.
+    pub fn add_root_certificate(&mut self, certificate: Certificate) -> &mut Self {
+        self.root_certificates.push(certificate);
+        self
+    }
 }
 
 impl<S, O, A> OutgoingService<A> for HttpClientService<S, O, A>
@@ -69,13 +121,14 @@ where
                 request.to.id(),
                 url.as_str()
             );
-            let token = request.to.get_http_auth_token().unwrap_or("");
-            let auth = match AuthToken::from_str(token) {
+            // Accounts that authenticate with a client certificate may not have a token
+            let token = request.to.get_http_auth_token();
+            let auth = match token.map(AuthToken::from_str).transpose() {
                 Ok(auth) => auth,
                 Err(_) => {
                     return Box::new(err(RejectBuilder {
                         code: ErrorCode::T00_INTERNAL_ERROR,
-                        message: format!("Cannot parse authorization token {}", token)
+                        message: format!("Cannot parse authorization token {}", token.unwrap())
                             .as_str()
                             .as_ref(),
                         triggered_by: None,
@@ -84,23 +137,75 @@ where
                     .build()))
                 }
             };
+            let config = request
+                .to
+                .get_http_client_config()
+                .unwrap_or(self.default_config);
+            let timeout = match request_timeout(request.prepare.expires_at(), config.max_timeout) {
+                Some(timeout) => timeout,
+                None => {
+                    return Box::new(err(RejectBuilder {
+                        code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                        message: b"Prepare expired before it could be sent",
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build()))
+                }
+            };
+            let client = match get_or_build_client(
+                &self.clients,
+                &self.root_certificates,
+                config,
+                request.to.get_http_client_identity(),
+            ) {
+                Ok(client) => client,
+                Err(_) => {
+                    return Box::new(err(RejectBuilder {
+                        code: ErrorCode::T00_INTERNAL_ERROR,
+                        message: &[],
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build()))
+                }
+            };
+            let body = BytesMut::from(request.prepare).freeze();
             Box::new(
-                self.client
-                    .post(url.as_ref())
-                    .header("authorization", auth.to_bearer())
-                    .body(BytesMut::from(request.prepare).freeze())
-                    .send()
-                    .map_err(move |err| {
+                Timeout::new(
+                    send_with_retries(
+                        client,
+                        url.clone(),
+                        auth.map(|auth| auth.to_bearer()),
+                        body,
+                        config.max_retries,
+                    ),
+                    timeout,
+                )
+                .map_err(move |err| {
+                    let code = if err.is_elapsed() {
+                        error!("ILP over HTTP request timed out after {:?}", timeout);
+                        ErrorCode::R00_TRANSFER_TIMED_OUT
+                    } else if let Some(err) = err.into_inner() {
                         error!("Error sending HTTP request: {:?}", err);
-                        RejectBuilder {
-                            code: ErrorCode::T01_PEER_UNREACHABLE,
-                            message: &[],
-                            triggered_by: Some(&ilp_address),
-                            data: &[],
+                        if err.is_timeout() {
+                            ErrorCode::R00_TRANSFER_TIMED_OUT
+                        } else {
+                            ErrorCode::T01_PEER_UNREACHABLE
                         }
-                        .build()
-                    })
-                    .and_then(move |resp| parse_packet_from_response(resp, ilp_address_clone)),
+                    } else {
+                        error!("Timer error while sending HTTP request");
+                        ErrorCode::T00_INTERNAL_ERROR
+                    };
+                    RejectBuilder {
+                        code,
+                        message: &[],
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build()
+                })
+                .and_then(move |resp| parse_packet_from_response(resp, ilp_address_clone)),
             )
         } else {
             Box::new(self.next.send_request(request))
@@ -108,6 +213,101 @@ where
     }
 }
 
+/// Reuse the client created for the same settings before, so that its connections are pooled
+fn get_or_build_client(
+    clients: &RwLock<HashMap<ClientKey, Client>>,
+    root_certificates: &[Certificate],
+    config: HttpClientConfig,
+    identity: Option<&ClientIdentity>,
+) -> Result<Client, ()> {
+    let key = (config, identity.cloned());
+    if let Some(client) = clients.read().get(&key) {
+        return Ok(client.clone());
+    }
+
+    let mut headers = HeaderMap::with_capacity(2);
+    headers.insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    let mut builder = ClientBuilder::new()
+        .default_headers(headers)
+        .max_idle_per_host(config.max_idle_per_host)
+        .timeout(config.max_timeout);
+    if config.http2_prior_knowledge {
+        builder = builder.h2_prior_knowledge();
+    }
+    for certificate in root_certificates {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    if let Some(identity) = identity {
+        builder = builder.identity(identity.to_reqwest_identity()?);
+    }
+    let client = builder
+        .build()
+        .map_err(|err| error!("Error creating HTTP client: {:?}", err))?;
+    Ok(clients.write().entry(key).or_insert(client).clone())
+}
+
+fn send_with_retries(
+    client: Client,
+    url: Url,
+    authorization: Option<String>,
+    body: Bytes,
+    retries: usize,
+) -> Box<dyn Future<Item = HttpResponse, Error = ReqwestError> + Send> {
+    let mut request = client.post(url.as_ref()).body(body.clone());
+    if let Some(ref authorization) = authorization {
+        request = request.header("authorization", authorization.as_str());
+    }
+    Box::new(request.send().or_else(move |error| {
+        if retries > 0 && is_connect_error(&error) {
+            debug!(
+                "Error connecting to {}, retrying ({} retries left): {:?}",
+                url, retries, error
+            );
+            Either::A(send_with_retries(
+                client,
+                url,
+                authorization,
+                body,
+                retries - 1,
+            ))
+        } else {
+            Either::B(err(error))
+        }
+    }))
+}
+
+/// Whether the connection to the peer could not be established. The peer never
+/// saw those requests, so they are safe to send again
+fn is_connect_error(error: &ReqwestError) -> bool {
+    error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<hyper::Error>())
+        .map(|error| error.is_connect())
+        .unwrap_or(false)
+}
+
+/// How long to wait for a response: until the Prepare expires, but no longer than `max_timeout`.
+/// Returns None if the Prepare has already expired
+fn request_timeout(expires_at: SystemTime, max_timeout: Duration) -> Option<Duration> {
+    expires_at
+        .duration_since(SystemTime::now())
+        .ok()
+        .map(|remaining| remaining.min(max_timeout))
+}
+
+fn error_code_for_status(status: StatusCode) -> ErrorCode {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => ErrorCode::T05_RATE_LIMITED,
+        StatusCode::SERVICE_UNAVAILABLE => ErrorCode::T00_INTERNAL_ERROR,
+        StatusCode::UNAUTHORIZED => ErrorCode::F00_BAD_REQUEST,
+        status if status.is_client_error() => ErrorCode::F02_UNREACHABLE,
+        _ => ErrorCode::T01_PEER_UNREACHABLE,
+    }
+}
+
 fn parse_packet_from_response(
     response: HttpResponse,
     ilp_address: Address,
@@ -116,12 +316,7 @@ fn parse_packet_from_response(
     result(response.error_for_status().map_err(|err| {
         error!("HTTP error sending ILP over HTTP packet: {:?}", err);
         let code = if let Some(status) = err.status() {
-            if status.is_client_error() {
-                ErrorCode::F02_UNREACHABLE
-            } else {
-                // TODO more specific errors for rate limiting, etc?
-                ErrorCode::T01_PEER_UNREACHABLE
-            }
+            error_code_for_status(status)
         } else {
             ErrorCode::T00_INTERNAL_ERROR
         };
@@ -163,3 +358,67 @@ fn parse_packet_from_response(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_http_errors_to_ilp_error_codes() {
+        assert_eq!(
+            error_code_for_status(StatusCode::TOO_MANY_REQUESTS),
+            ErrorCode::T05_RATE_LIMITED
+        );
+        assert_eq!(
+            error_code_for_status(StatusCode::SERVICE_UNAVAILABLE),
+            ErrorCode::T00_INTERNAL_ERROR
+        );
+        assert_eq!(
+            error_code_for_status(StatusCode::UNAUTHORIZED),
+            ErrorCode::F00_BAD_REQUEST
+        );
+        assert_eq!(
+            error_code_for_status(StatusCode::NOT_FOUND),
+            ErrorCode::F02_UNREACHABLE
+        );
+        assert_eq!(
+            error_code_for_status(StatusCode::BAD_GATEWAY),
+            ErrorCode::T01_PEER_UNREACHABLE
+        );
+    }
+
+    #[test]
+    fn timeout_is_limited_by_prepare_expiry() {
+        let max_timeout = Duration::from_secs(30);
+        let timeout =
+            request_timeout(SystemTime::now() + Duration::from_secs(5), max_timeout).unwrap();
+        assert!(timeout <= Duration::from_secs(5) && timeout > Duration::from_secs(4));
+        assert_eq!(
+            request_timeout(SystemTime::now() + Duration::from_secs(60), max_timeout),
+            Some(max_timeout)
+        );
+        assert_eq!(
+            request_timeout(SystemTime::now() - Duration::from_secs(1), max_timeout),
+            None
+        );
+    }
+
+    #[test]
+    fn reuses_clients_with_the_same_config() {
+        let clients = RwLock::new(HashMap::new());
+        get_or_build_client(&clients, &[], HttpClientConfig::default(), None).unwrap();
+        get_or_build_client(&clients, &[], HttpClientConfig::default(), None).unwrap();
+        assert_eq!(clients.read().len(), 1);
+        get_or_build_client(
+            &clients,
+            &[],
+            HttpClientConfig {
+                http2_prior_knowledge: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(clients.read().len(), 2);
+    }
+}
@@ -2,22 +2,36 @@
 //!
 //! Client and server implementations of the [ILP-Over-HTTP](https://github.com/interledger/rfcs/blob/master/0035-ilp-over-http/0035-ilp-over-http.md) bilateral communication protocol.
 //! This protocol is intended primarily for server-to-server communication between peers on the Interledger network.
-use futures::Future;
+use futures::{future::err, Future};
 use interledger_service::{Account, Username};
 use url::Url;
 
 mod client;
 mod server;
+mod tls;
 
 /// Originally from [interledger-relay](https://github.com/coilhq/interledger-relay/blob/master/crates/interledger-relay/src/combinators/limit_stream.rs).
 mod limit_stream;
 
-pub use self::client::HttpClientService;
+pub use self::client::{HttpClientConfig, HttpClientService};
 pub use self::server::HttpServerService;
+pub use self::tls::{
+    mtls_acceptor_from_pem_files, serve_with_client_certificates, ClientCertificate, ClientIdentity,
+};
+pub use openssl::ssl::SslAcceptor;
 
 pub trait HttpAccount: Account {
     fn get_http_url(&self) -> Option<&Url>;
     fn get_http_auth_token(&self) -> Option<&str>;
+    /// Settings for the client used to send packets to this account. If this returns
+    /// None, the `HttpClientService`'s default settings are used.
+    fn get_http_client_config(&self) -> Option<HttpClientConfig> {
+        None
+    }
+    /// The TLS client certificate to present to this account's server, if it requires one
+    fn get_http_client_identity(&self) -> Option<&ClientIdentity> {
+        None
+    }
 }
 
 /// The interface for Stores that can be used with the HttpServerService.
@@ -32,4 +46,14 @@ pub trait HttpStore: Clone + Send + Sync + 'static {
         username: &Username,
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
+
+    /// Load account details based on the TLS certificate the client presented when
+    /// connecting to a server that requires client certificates.
+    /// Stores that do not support certificate-based authentication reject every certificate.
+    fn get_account_from_client_certificate(
+        &self,
+        _certificate: &ClientCertificate,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        Box::new(err(()))
+    }
 }
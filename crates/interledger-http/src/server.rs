@@ -1,5 +1,5 @@
 use super::limit_stream::LimitStream;
-use super::HttpStore;
+use super::{ClientCertificate, HttpStore};
 use bytes::BytesMut;
 use futures::{
     future::{err, Either},
@@ -34,7 +34,6 @@ where
         HttpServerService { next, store }
     }
 
-    // TODO support certificate-based authentication
     fn check_authorization(
         &self,
         request: &Request<Body>,
@@ -68,9 +67,37 @@ where
     pub fn handle_http_request(
         &mut self,
         request: Request<Body>,
+    ) -> impl Future<Item = Response<Body>, Error = Error> {
+        let from_account = self.check_authorization(&request);
+        self.handle_authorized_request(from_account, request)
+    }
+
+    /// Handle a request that arrived on a connection on which the client authenticated
+    /// with the given TLS certificate (see `serve_with_client_certificates`), instead of
+    /// with an Authorization header
+    pub fn handle_http_request_with_certificate(
+        &mut self,
+        request: Request<Body>,
+        certificate: &ClientCertificate,
+    ) -> impl Future<Item = Response<Body>, Error = Error> {
+        let certificate = certificate.clone();
+        let from_account = self
+            .store
+            .get_account_from_client_certificate(&certificate)
+            .map_err(move |_err| {
+                error!("No account found for client certificate: {:?}", certificate);
+                Response::builder().status(401).body(Body::empty()).unwrap()
+            });
+        self.handle_authorized_request(from_account, request)
+    }
+
+    fn handle_authorized_request(
+        &mut self,
+        from_account: impl Future<Item = T::Account, Error = Response<Body>>,
+        request: Request<Body>,
     ) -> impl Future<Item = Response<Body>, Error = Error> {
         let mut next = self.next.clone();
-        self.check_authorization(&request)
+        from_account
             .and_then(|from_account| {
                 parse_prepare_from_request(request, Some(MAX_MESSAGE_SIZE)).and_then(
                     move |prepare| {
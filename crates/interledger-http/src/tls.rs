@@ -0,0 +1,184 @@
+use super::{HttpServerService, HttpStore};
+use bytes::Bytes;
+use futures::{future::result, Future, Stream};
+use hyper::{rt::spawn, server::conn::Http, service::service_fn};
+use interledger_service::IncomingService;
+use log::{error, trace, warn};
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    ssl::{HandshakeError, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    x509::{X509Name, X509Ref, X509},
+};
+use reqwest::Identity;
+use std::fmt::{self, Debug};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslAcceptorExt;
+
+/// The certificate a client authenticated with when connecting to a server
+/// that requires client certificates.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientCertificate {
+    /// SHA-256 hash of the DER-encoded certificate
+    pub fingerprint: [u8; 32],
+    /// The common name (CN) of the certificate's subject, if it has one
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    /// Load the details of a PEM-encoded certificate, for example to look up the
+    /// account that authenticates with it
+    pub fn from_pem(pem: &[u8]) -> Result<Self, ()> {
+        let certificate =
+            X509::from_pem(pem).map_err(|err| error!("Error parsing certificate: {:?}", err))?;
+        Self::from_x509(&certificate)
+    }
+
+    fn from_x509(certificate: &X509Ref) -> Result<Self, ()> {
+        let digest = certificate
+            .digest(MessageDigest::sha256())
+            .map_err(|err| error!("Error hashing certificate: {:?}", err))?;
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(&digest);
+        let common_name = certificate
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|name| name.to_string());
+        Ok(ClientCertificate {
+            fingerprint,
+            common_name,
+        })
+    }
+}
+
+/// A certificate and private key the `HttpClientService` presents to an account's
+/// server when it requires client certificates.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ClientIdentity {
+    pkcs12: Bytes,
+    password: String,
+}
+
+impl ClientIdentity {
+    /// Load the identity from a DER-encoded PKCS #12 archive
+    pub fn from_pkcs12_der(der: &[u8], password: &str) -> Result<Self, ()> {
+        let identity = ClientIdentity {
+            pkcs12: Bytes::from(der),
+            password: password.to_string(),
+        };
+        identity.to_reqwest_identity()?;
+        Ok(identity)
+    }
+
+    pub(crate) fn to_reqwest_identity(&self) -> Result<Identity, ()> {
+        Identity::from_pkcs12_der(&self.pkcs12, &self.password)
+            .map_err(|err| error!("Error loading PKCS #12 client identity: {:?}", err))
+    }
+}
+
+// The archive contains the private key, so don't print it or its password
+impl Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("pkcs12", &format_args!("<{} bytes>", self.pkcs12.len()))
+            .finish()
+    }
+}
+
+/// Create a TLS acceptor for `serve_with_client_certificates` from PEM files with the
+/// server's certificate (chain) and private key, and the root certificates that client
+/// certificates must be issued by.
+pub fn mtls_acceptor_from_pem_files(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: &str,
+) -> Result<SslAcceptor, ()> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|err| error!("Error creating TLS acceptor: {:?}", err))?;
+    builder
+        .set_certificate_chain_file(cert_path)
+        .map_err(|err| error!("Error loading TLS certificate {}: {:?}", cert_path, err))?;
+    builder
+        .set_private_key_file(key_path, SslFiletype::PEM)
+        .map_err(|err| error!("Error loading TLS key {}: {:?}", key_path, err))?;
+    builder
+        .check_private_key()
+        .map_err(|err| error!("TLS key does not match the certificate: {:?}", err))?;
+    builder.set_ca_file(client_ca_path).map_err(|err| {
+        error!(
+            "Error loading client root certificates {}: {:?}",
+            client_ca_path, err
+        )
+    })?;
+    let client_ca_names = X509Name::load_client_ca_file(client_ca_path).map_err(|err| {
+        error!(
+            "Error loading client root certificates {}: {:?}",
+            client_ca_path, err
+        )
+    })?;
+    builder.set_client_ca_list(client_ca_names);
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    Ok(builder.build())
+}
+
+/// Serve ILP-over-HTTP on the incoming connections, terminating TLS with the given acceptor
+/// (see `mtls_acceptor_from_pem_files`). Clients must present a certificate issued by one of
+/// the acceptor's trusted roots, and are authenticated by that certificate instead of
+/// an Authorization header.
+pub fn serve_with_client_certificates<S, T, I, IO>(
+    server: HttpServerService<S, T>,
+    acceptor: SslAcceptor,
+    incoming: I,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: IncomingService<T::Account> + Clone + Send + 'static,
+    T: HttpStore + 'static,
+    I: Stream<Item = IO>,
+    I::Error: Debug,
+    IO: AsyncRead + AsyncWrite + Send + 'static,
+{
+    incoming
+        .map_err(|err| error!("Error accepting incoming connection: {:?}", err))
+        .for_each(move |stream| {
+            let mut server = server.clone();
+            let connection = acceptor
+                .accept_async(stream)
+                .map_err(|err| match err {
+                    HandshakeError::SetupFailure(err) => {
+                        error!("Error setting up TLS handshake: {}", err)
+                    }
+                    HandshakeError::Failure(handshake) => {
+                        warn!("TLS handshake with client failed: {}", handshake.error())
+                    }
+                    // tokio-openssl keeps polling the handshake until it is no longer blocked
+                    HandshakeError::WouldBlock(_) => error!("TLS handshake was interrupted"),
+                })
+                .and_then(|stream| {
+                    let certificate = stream
+                        .get_ref()
+                        .ssl()
+                        .peer_certificate()
+                        .ok_or_else(|| warn!("TLS client did not present a certificate"))
+                        .and_then(|certificate| ClientCertificate::from_x509(&certificate));
+                    result(certificate).map(|certificate| (certificate, stream))
+                })
+                .and_then(move |(certificate, stream)| {
+                    trace!(
+                        "Accepted TLS connection with client certificate: {:?}",
+                        certificate
+                    );
+                    Http::new()
+                        .serve_connection(
+                            stream,
+                            service_fn(move |request| {
+                                server.handle_http_request_with_certificate(request, &certificate)
+                            }),
+                        )
+                        .map_err(|err| error!("Error serving ILP-over-HTTP connection: {:?}", err))
+                });
+            spawn(connection);
+            Ok(())
+        })
+}
@@ -0,0 +1,192 @@
+use futures::{
+    future::{err, ok},
+    Future,
+};
+use interledger_http::*;
+use interledger_packet::{Address, ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+use interledger_service::*;
+use reqwest::Certificate;
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+use url::Url;
+
+const CA: &[u8] = include_bytes!("fixtures/ca.pem");
+const CLIENT_CERTIFICATE: &[u8] = include_bytes!("fixtures/client.pem");
+const CLIENT_PKCS12: &[u8] = include_bytes!("fixtures/client.p12");
+
+#[derive(Clone, Debug)]
+struct TestAccount {
+    id: u64,
+    username: Username,
+    url: Url,
+    identity: Option<ClientIdentity>,
+}
+
+impl Account for TestAccount {
+    type AccountId = u64;
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn username(&self) -> &Username {
+        &self.username
+    }
+}
+
+impl HttpAccount for TestAccount {
+    fn get_http_url(&self) -> Option<&Url> {
+        Some(&self.url)
+    }
+
+    fn get_http_auth_token(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_http_client_identity(&self) -> Option<&ClientIdentity> {
+        self.identity.as_ref()
+    }
+}
+
+#[derive(Clone)]
+struct TestStore {
+    account: TestAccount,
+    fingerprint: [u8; 32],
+}
+
+impl HttpStore for TestStore {
+    type Account = TestAccount;
+
+    fn get_account_from_http_auth(
+        &self,
+        _username: &Username,
+        _token: &str,
+    ) -> Box<dyn Future<Item = TestAccount, Error = ()> + Send> {
+        Box::new(err(()))
+    }
+
+    fn get_account_from_client_certificate(
+        &self,
+        certificate: &ClientCertificate,
+    ) -> Box<dyn Future<Item = TestAccount, Error = ()> + Send> {
+        if certificate.fingerprint == self.fingerprint {
+            Box::new(ok(self.account.clone()))
+        } else {
+            Box::new(err(()))
+        }
+    }
+}
+
+fn test_account(port: u16, identity: Option<ClientIdentity>) -> TestAccount {
+    TestAccount {
+        id: 0,
+        username: Username::from_str("alice").unwrap(),
+        url: Url::parse(&format!("https://localhost:{}/", port)).unwrap(),
+        identity,
+    }
+}
+
+/// Start a server that only accepts the fixture client certificate and fulfills every packet
+fn start_server(runtime: &mut Runtime) -> u16 {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let store = TestStore {
+        account: test_account(port, None),
+        fingerprint: ClientCertificate::from_pem(CLIENT_CERTIFICATE)
+            .unwrap()
+            .fingerprint,
+    };
+    let server = HttpServerService::new(
+        incoming_service_fn(|_request| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"hello",
+            }
+            .build())
+        }),
+        store,
+    );
+    let acceptor = mtls_acceptor_from_pem_files(
+        "tests/fixtures/server.pem",
+        "tests/fixtures/server.key",
+        "tests/fixtures/ca.pem",
+    )
+    .unwrap();
+    runtime.spawn(serve_with_client_certificates(
+        server,
+        acceptor,
+        listener.incoming(),
+    ));
+    port
+}
+
+fn send_packet(
+    runtime: &mut Runtime,
+    to: TestAccount,
+) -> Result<interledger_packet::Fulfill, interledger_packet::Reject> {
+    let store = TestStore {
+        account: to.clone(),
+        fingerprint: [0; 32],
+    };
+    let mut client = HttpClientService::new(
+        Address::from_str("example.sender").unwrap(),
+        store,
+        outgoing_service_fn(|_request| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"No other outgoing handler",
+                triggered_by: None,
+                data: &[],
+            }
+            .build())
+        }),
+    );
+    client.add_root_certificate(Certificate::from_pem(CA).unwrap());
+    runtime.block_on(
+        client.send_request(OutgoingRequest {
+            from: to.clone(),
+            to,
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.receiver").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[],
+            }
+            .build(),
+        }),
+    )
+}
+
+#[test]
+fn authenticates_with_client_certificate() {
+    let mut runtime = Runtime::new().unwrap();
+    let port = start_server(&mut runtime);
+    let identity = ClientIdentity::from_pkcs12_der(CLIENT_PKCS12, "test").unwrap();
+    let fulfill = send_packet(&mut runtime, test_account(port, Some(identity))).unwrap();
+    assert_eq!(fulfill.data(), b"hello");
+}
+
+#[test]
+fn rejects_clients_without_certificate() {
+    let mut runtime = Runtime::new().unwrap();
+    let port = start_server(&mut runtime);
+    let reject = send_packet(&mut runtime, test_account(port, None)).unwrap_err();
+    assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+}
+
+#[test]
+fn parses_client_certificate() {
+    let certificate = ClientCertificate::from_pem(CLIENT_CERTIFICATE).unwrap();
+    assert_eq!(certificate.common_name, Some("alice".to_string()));
+    assert_eq!(
+        certificate.fingerprint[..4],
+        [0xEB, 0x13, 0xA4, 0xE2],
+        "fingerprint should be the SHA-256 hash of the DER-encoded certificate"
+    );
+}
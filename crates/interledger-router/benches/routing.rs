@@ -0,0 +1,188 @@
+//! Benchmark the per-packet cost of routing with a large routing table.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::{future::ok, Future};
+use interledger_ildcp::IldcpAccount;
+use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+use interledger_router::{NextHop, Router, RouterStore, RoutingTable};
+use interledger_service::*;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    iter::FromIterator,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+const NUM_ROUTES: u64 = 100_000;
+
+lazy_static! {
+    static ref ALICE: Username = Username::from_str("alice").unwrap();
+    static ref DESTINATION: Address =
+        Address::from_str("g.connector242.account74242.receiver").unwrap();
+    static ref NEXT_HOP: Address = Address::from_str("g.next_hop").unwrap();
+}
+
+#[derive(Debug, Clone)]
+struct TestAccount(u64);
+
+impl Account for TestAccount {
+    type AccountId = u64;
+
+    fn id(&self) -> u64 {
+        self.0
+    }
+
+    fn username(&self) -> &Username {
+        &ALICE
+    }
+}
+
+impl IldcpAccount for TestAccount {
+    fn client_address(&self) -> &Address {
+        &NEXT_HOP
+    }
+
+    fn asset_scale(&self) -> u8 {
+        9
+    }
+
+    fn asset_code(&self) -> &str {
+        "XYZ"
+    }
+}
+
+#[derive(Clone)]
+struct TestStore {
+    routes: Arc<RoutingTable<u64>>,
+}
+
+impl AccountStore for TestStore {
+    type Account = TestAccount;
+
+    fn get_accounts(
+        &self,
+        account_ids: Vec<u64>,
+    ) -> Box<dyn Future<Item = Vec<TestAccount>, Error = ()> + Send> {
+        Box::new(ok(account_ids.into_iter().map(TestAccount).collect()))
+    }
+
+    fn get_account_id_from_username(
+        &self,
+        _username: &Username,
+    ) -> Box<dyn Future<Item = u64, Error = ()> + Send> {
+        Box::new(ok(1))
+    }
+}
+
+impl RouterStore for TestStore {
+    fn routing_table(&self) -> Arc<RoutingTable<u64>> {
+        self.routes.clone()
+    }
+}
+
+/// Routes that look like the ones a large connector would have:
+/// accounts grouped under a smaller number of connectors, plus a default route
+fn routes() -> Vec<(Bytes, u64)> {
+    (0..NUM_ROUTES)
+        .map(|i| {
+            let prefix = format!("g.connector{}.account{}", i % 1000, i);
+            (Bytes::from(prefix), i)
+        })
+        .chain(std::iter::once((Bytes::from(""), NUM_ROUTES)))
+        .collect()
+}
+
+fn benchmark_lookup(c: &mut Criterion) {
+    let table = RoutingTable::from_iter(routes());
+    c.bench_function("Routing table lookup (100k routes)", move |b| {
+        b.iter(|| {
+            let (prefix, next_hops) = table.resolve(DESTINATION.as_ref()).unwrap();
+            assert_eq!(prefix, &b"g.connector242.account74242"[..]);
+            assert_eq!(next_hops[0].account_id, 74242);
+        });
+    });
+
+    // For comparison, this is how the Router used to find routes
+    let map: HashMap<Bytes, u64> = HashMap::from_iter(routes());
+    c.bench_function("HashMap scan (100k routes)", move |b| {
+        b.iter(|| {
+            let dest: &[u8] = DESTINATION.as_ref();
+            let best = map
+                .iter()
+                .filter(|(prefix, _)| dest.starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, account_id)| *account_id);
+            assert_eq!(best, Some(74242));
+        });
+    });
+}
+
+fn benchmark_update(c: &mut Criterion) {
+    // Stores add routes to a copy of the table the Router is using
+    let snapshot = Arc::new(RoutingTable::from_iter(routes()));
+    let mut next_account_id = NUM_ROUTES + 1;
+    c.bench_function(
+        "Routing table insert into shared copy (100k routes)",
+        move |b| {
+            b.iter(|| {
+                let mut table = snapshot.clone();
+                let prefix = format!("g.connector242.account{}", next_account_id);
+                Arc::make_mut(&mut table)
+                    .insert(prefix.as_bytes(), vec![NextHop::new(next_account_id, 1)]);
+                next_account_id += 1;
+            });
+        },
+    );
+}
+
+fn benchmark_router(c: &mut Criterion) {
+    let store = TestStore {
+        routes: Arc::new(RoutingTable::from_iter(routes())),
+    };
+    let mut router = Router::new(
+        Address::from_str("g.us-east.connector").unwrap(),
+        store,
+        outgoing_service_fn(|request: OutgoingRequest<TestAccount>| {
+            assert_eq!(request.to.0, 74242);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        }),
+    );
+    let prepare = PrepareBuilder {
+        destination: DESTINATION.clone(),
+        amount: 100,
+        execution_condition: &[0; 32],
+        expires_at: SystemTime::now() + Duration::from_secs(30),
+        data: &[],
+    }
+    .build();
+    c.bench_function("Router (100k routes)", move |b| {
+        b.iter(|| {
+            router
+                .handle_request(IncomingRequest {
+                    from: TestAccount(0),
+                    prepare: prepare.clone(),
+                })
+                .wait()
+                .unwrap();
+        });
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(100);
+    targets =
+        benchmark_lookup,
+        benchmark_update,
+        benchmark_router,
+}
+
+criterion_main!(benches);
@@ -11,21 +11,44 @@
 //! only using the information provided by the store. The routing table in the
 //! store can either be configured or populated using the `CcpRouteManager`
 //! (see the `interledger-ccp` crate for more details).
+//!
+//! The routing table may contain multiple weighted next hops for a prefix.
+//! The Router spreads packets between them according to their weights and
+//! tries the alternatives if a next hop rejects a packet with a temporary error.
 
-use bytes::Bytes;
 use interledger_service::{Account, AccountStore};
-use std::collections::HashMap;
+use std::sync::Arc;
 
 mod router;
+mod routing_table;
 
 pub use self::router::Router;
+pub use self::routing_table::RoutingTable;
+
+/// One of the accounts that packets for a given prefix can be forwarded to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NextHop<T> {
+    pub account_id: T,
+    /// The share of the packets for the prefix this next hop should get, relative to the
+    /// weights of the other next hops. Next hops with a weight of 0 are only used as backups,
+    /// when the ones before them reject a packet with a temporary (T-class) error.
+    pub weight: u32,
+}
+
+impl<T> NextHop<T> {
+    pub fn new(account_id: T, weight: u32) -> Self {
+        NextHop { account_id, weight }
+    }
+}
 
 /// A trait for Store implmentations that have ILP routing tables.
 pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
-    /// **Synchronously** return a copy of the routing table.
+    /// **Synchronously** return the current routing table.
     /// Note that this is synchronous because it assumes that Stores should
     /// keep the routing table in memory and use PubSub or polling to keep it updated.
     /// This ensures that individual packets can be routed without hitting the underlying store.
-    // TODO avoid using HashMap because it means it'll be cloned a lot
-    fn routing_table(&self) -> HashMap<Bytes, <Self::Account as Account>::AccountId>;
+    ///
+    /// The table is never modified once it is returned. Stores should build a new one
+    /// when their routes change and swap it in, so this only needs to clone the `Arc`.
+    fn routing_table(&self) -> Arc<RoutingTable<<Self::Account as Account>::AccountId>>;
 }
@@ -1,10 +1,16 @@
-use super::RouterStore;
-use bytes::Bytes;
+use super::{NextHop, RouterStore};
 use futures::{future::err, Future};
-use interledger_packet::{Address, ErrorCode, RejectBuilder};
+use interledger_ildcp::IldcpAccount;
+use interledger_packet::{Address, ErrorClass, ErrorCode, RejectBuilder};
 use interledger_service::*;
-use log::{error, trace};
-use std::str;
+use log::{debug, error, trace};
+use std::{
+    str,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 /// # Interledger Router
 ///
@@ -16,6 +22,14 @@ use std::str;
 /// The router implements the IncomingService trait and uses the routing table
 /// to determine the `to` (or "next hop") Account for the given request.
 ///
+/// If the routing table has multiple next hops for the matching prefix, the router
+/// picks one in proportion to their weights and falls back to the others, in order,
+/// if that next hop's account cannot be loaded or the packet is rejected with a temporary
+/// (T-class) error triggered by this node or the next hop itself. This also covers next hops
+/// that are disconnected, because the outgoing services reject packets for those with
+/// `T01: Peer Unreachable`. Temporary errors from nodes further along the path are passed
+/// back, since a different next hop may well end up going through the same node.
+///
 /// Note that the router does **not**:
 ///   - apply exchange rates or fees to the Prepare packet
 ///   - adjust account balances
@@ -28,6 +42,8 @@ pub struct Router<S, O> {
     ilp_address: Address,
     store: S,
     next: O,
+    /// Counts the packets routed so far, used to spread them between weighted next hops
+    packet_counter: Arc<AtomicUsize>,
 }
 
 impl<S, O> Router<S, O>
@@ -40,6 +56,7 @@ where
             ilp_address,
             store,
             next,
+            packet_counter: Arc::new(AtomicUsize::new(0)),
         }
     }
 }
@@ -47,97 +64,169 @@ where
 impl<S, O> IncomingService<S::Account> for Router<S, O>
 where
     S: RouterStore,
+    S::Account: IldcpAccount,
     O: OutgoingService<S::Account> + Clone + Send + 'static,
 {
     type Future = BoxedIlpFuture;
 
     /// Figures out the next node to pass the received Prepare packet to.
     ///
-    /// Firstly, it checks if there is a direct path for that account and use that.
-    /// If not it scans through the routing table and checks if the route prefix matches
-    /// the prepare packet's destination or if it's a catch-all address (i.e. empty prefix)
+    /// It looks up the longest prefix in the routing table that matches the prepare packet's
+    /// destination, where the empty prefix is a catch-all route that matches every address.
     fn handle_request(&mut self, request: IncomingRequest<S::Account>) -> Self::Future {
-        let destination = request.prepare.destination();
-        let mut next_hop = None;
         let routing_table = self.store.routing_table();
         let ilp_address = self.ilp_address.clone();
 
-        // Check if we have a direct path for that account or if we need to scan
-        // through the routing table
-        let dest: &[u8] = destination.as_ref();
-        if let Some(account_id) = routing_table.get(dest) {
-            trace!(
-                "Found direct route for address: \"{}\". Account: {}",
-                destination,
-                account_id
-            );
-            next_hop = Some(*account_id);
-        } else if !routing_table.is_empty() {
-            let mut matching_prefix = Bytes::new();
-            for route in self.store.routing_table() {
+        let account_ids = {
+            let destination = request.prepare.destination();
+            let dest: &[u8] = destination.as_ref();
+            if routing_table.is_empty() {
+                error!("Unable to route request because routing table is empty");
+                None
+            } else if let Some((prefix, next_hops)) = routing_table.resolve(dest) {
                 trace!(
-                    "Checking route: \"{}\" -> {}",
-                    str::from_utf8(&route.0[..]).unwrap_or("<not utf8>"),
-                    route.1
+                    "Found matching route for address: \"{}\". Prefix: \"{}\", next hops: {:?}",
+                    destination,
+                    str::from_utf8(prefix).unwrap_or("<not utf8>"),
+                    next_hops,
                 );
-                // Check if the route prefix matches or is empty (meaning it's a catch-all address)
-                if (route.0.is_empty() || dest.starts_with(&route.0[..]))
-                    && route.0.len() >= matching_prefix.len()
-                {
-                    next_hop.replace(route.1);
-                    matching_prefix = route.0;
+                if next_hops.is_empty() {
+                    None
+                } else {
+                    let counter = self.packet_counter.fetch_add(1, Ordering::Relaxed);
+                    Some(order_next_hops(next_hops, counter))
                 }
+            } else {
+                None
             }
-            if let Some(account_id) = next_hop {
-                trace!(
-                    "Found matching route for address: \"{}\". Prefix: \"{}\", account: {}",
-                    destination,
-                    str::from_utf8(&matching_prefix[..]).unwrap_or("<not utf8>"),
-                    account_id,
-                );
+        };
+
+        match account_ids {
+            Some(account_ids) => send_to_next_hops(
+                self.store.clone(),
+                self.next.clone(),
+                ilp_address,
+                request,
+                account_ids,
+            ),
+            None => {
+                error!("No route found for request: {:?}", request);
+                Box::new(err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: Some(&ilp_address),
+                    data: &[],
+                }
+                .build()))
+            }
+        }
+    }
+}
+
+/// Put the next hop that should get this packet first, followed by the others
+/// in the order they should be tried if it rejects the packet.
+///
+/// The first next hop is picked so that, over many packets, each next hop gets a share
+/// proportional to its weight. If all of the weights are 0, the given order is kept.
+fn order_next_hops<T: Copy>(next_hops: &[NextHop<T>], counter: usize) -> Vec<T> {
+    let total_weight: u64 = next_hops.iter().map(|hop| u64::from(hop.weight)).sum();
+    let mut account_ids: Vec<T> = next_hops.iter().map(|hop| hop.account_id).collect();
+    if total_weight > 0 {
+        let mut position = counter as u64 % total_weight;
+        for (index, hop) in next_hops.iter().enumerate() {
+            if position < u64::from(hop.weight) {
+                let first = account_ids.remove(index);
+                account_ids.insert(0, first);
+                break;
             }
-        } else {
-            error!("Unable to route request because routing table is empty");
+            position -= u64::from(hop.weight);
         }
+    }
+    account_ids
+}
 
-        if let Some(account_id) = next_hop {
-            let mut next = self.next.clone();
-            Box::new(
-                self.store
-                    .get_accounts(vec![account_id])
-                    .map_err(move |_| {
+/// Load the first next hop and send the request to it. If its account can't be loaded,
+/// or the packet is rejected with a temporary error triggered by this node or that
+/// next hop, try the next one until there are no more left.
+///
+/// Only one account is loaded at a time so that a missing backup account
+/// does not stop packets from being routed over the others.
+fn send_to_next_hops<S, O>(
+    store: S,
+    next: O,
+    ilp_address: Address,
+    request: IncomingRequest<S::Account>,
+    mut account_ids: Vec<<S::Account as Account>::AccountId>,
+) -> BoxedIlpFuture
+where
+    S: RouterStore,
+    S::Account: IldcpAccount,
+    O: OutgoingService<S::Account> + Clone + Send + 'static,
+{
+    let account_id = account_ids.remove(0);
+    Box::new(
+        store
+            .get_accounts(vec![account_id])
+            .then(move |result| -> BoxedIlpFuture {
+                let mut next = next;
+                let to = match result.ok().and_then(|mut accounts| accounts.pop()) {
+                    Some(to) => to,
+                    None if account_ids.is_empty() => {
                         error!("No record found for account: {}", account_id);
-                        RejectBuilder {
+                        return Box::new(err(RejectBuilder {
                             code: ErrorCode::F02_UNREACHABLE,
                             message: &[],
                             triggered_by: Some(&ilp_address),
                             data: &[],
                         }
-                        .build()
-                    })
-                    .and_then(move |mut accounts| {
-                        let request = request.into_outgoing(accounts.remove(0));
-                        next.send_request(request)
-                    }),
-            )
-        } else {
-            error!("No route found for request: {:?}", request);
-            Box::new(err(RejectBuilder {
-                code: ErrorCode::F02_UNREACHABLE,
-                message: &[],
-                triggered_by: Some(&ilp_address),
-                data: &[],
-            }
-            .build()))
-        }
-    }
+                        .build()));
+                    }
+                    None => {
+                        error!(
+                            "No record found for account: {}, trying next hop {}",
+                            account_id, account_ids[0]
+                        );
+                        return send_to_next_hops(store, next, ilp_address, request, account_ids);
+                    }
+                };
+                if account_ids.is_empty() {
+                    return Box::new(next.send_request(request.into_outgoing(to)));
+                }
+
+                let next_hop_address = to.client_address().clone();
+                let retry_request = request.clone();
+                Box::new(next.send_request(request.into_outgoing(to)).or_else(
+                    move |reject| -> BoxedIlpFuture {
+                        let triggered_nearby = reject
+                            .triggered_by()
+                            .map(|triggered_by| {
+                                triggered_by == ilp_address || triggered_by == next_hop_address
+                            })
+                            .unwrap_or(false);
+                        if reject.code().class() == ErrorClass::Temporary && triggered_nearby {
+                            debug!(
+                                "Next hop {} rejected packet with code: {}, trying next hop {}",
+                                account_id,
+                                reject.code(),
+                                account_ids[0]
+                            );
+                            send_to_next_hops(store, next, ilp_address, retry_request, account_ids)
+                        } else {
+                            Box::new(err(reject))
+                        }
+                    },
+                ))
+            }),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::RoutingTable;
+    use bytes::Bytes;
     use futures::future::ok;
-    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use interledger_packet::{Address, Fulfill, FulfillBuilder, PrepareBuilder, Reject};
     use interledger_service::outgoing_service_fn;
     use lazy_static::lazy_static;
     use parking_lot::Mutex;
@@ -152,8 +241,15 @@ mod tests {
 
     lazy_static! {
         pub static ref ALICE: Username = Username::from_str("alice").unwrap();
+        static ref CONNECTOR: Address = Address::from_str("example.connector").unwrap();
+        static ref ACCOUNT_ADDRESSES: Vec<Address> = (0..5)
+            .map(|id| Address::from_str(&format!("example.account{}", id)).unwrap())
+            .collect();
     }
 
+    /// The test stores have no account with this ID
+    const MISSING_ACCOUNT: u64 = 99;
+
     impl Account for TestAccount {
         type AccountId = u64;
         fn id(&self) -> u64 {
@@ -165,6 +261,20 @@ mod tests {
         }
     }
 
+    impl IldcpAccount for TestAccount {
+        fn client_address(&self) -> &Address {
+            &ACCOUNT_ADDRESSES[self.0 as usize]
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+    }
+
     #[derive(Clone)]
     struct TestStore {
         routes: HashMap<Bytes, u64>,
@@ -190,11 +300,108 @@ mod tests {
     }
 
     impl RouterStore for TestStore {
-        fn routing_table(&self) -> HashMap<Bytes, u64> {
+        fn routing_table(&self) -> Arc<RoutingTable<u64>> {
+            Arc::new(RoutingTable::from_iter(self.routes.clone()))
+        }
+    }
+
+    #[derive(Clone)]
+    struct MultipathTestStore {
+        routes: Arc<RoutingTable<u64>>,
+    }
+
+    impl AccountStore for MultipathTestStore {
+        type Account = TestAccount;
+
+        fn get_accounts(
+            &self,
+            account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
+        ) -> Box<dyn Future<Item = Vec<TestAccount>, Error = ()> + Send> {
+            if account_ids.contains(&MISSING_ACCOUNT) {
+                // Like the Redis store, fail if any of the accounts is missing
+                return Box::new(err(()));
+            }
+            Box::new(ok(account_ids.into_iter().map(TestAccount).collect()))
+        }
+
+        // stub implementation (not used in these tests)
+        fn get_account_id_from_username(
+            &self,
+            _username: &Username,
+        ) -> Box<dyn Future<Item = u64, Error = ()> + Send> {
+            Box::new(ok(1))
+        }
+    }
+
+    impl RouterStore for MultipathTestStore {
+        fn routing_table(&self) -> Arc<RoutingTable<u64>> {
             self.routes.clone()
         }
     }
 
+    fn multipath_router(
+        next_hops: Vec<NextHop<u64>>,
+        rejecting_accounts: Vec<(u64, ErrorCode, Address)>,
+    ) -> (
+        Router<MultipathTestStore, impl OutgoingService<TestAccount> + Clone>,
+        Arc<Mutex<Vec<u64>>>,
+    ) {
+        let sent_to: Arc<Mutex<Vec<u64>>> = Arc::new(Mutex::new(Vec::new()));
+        let sent_to_clone = sent_to.clone();
+        let router = Router::new(
+            Address::from_str("example.connector").unwrap(),
+            MultipathTestStore {
+                routes: Arc::new(RoutingTable::from_iter(vec![(
+                    Bytes::from("example."),
+                    next_hops,
+                )])),
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                sent_to_clone.lock().push(request.to.0);
+                if let Some((_, code, triggered_by)) = rejecting_accounts
+                    .iter()
+                    .find(|(account, _, _)| *account == request.to.0)
+                {
+                    Err(RejectBuilder {
+                        code: *code,
+                        message: &[],
+                        triggered_by: Some(triggered_by),
+                        data: &[],
+                    }
+                    .build())
+                } else {
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: &[],
+                    }
+                    .build())
+                }
+            }),
+        );
+        (router, sent_to)
+    }
+
+    fn send_to_destination(
+        router: &mut Router<
+            MultipathTestStore,
+            impl OutgoingService<TestAccount> + Clone + Send + 'static,
+        >,
+    ) -> Result<Fulfill, Reject> {
+        router
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.destination").unwrap(),
+                    amount: 100,
+                    execution_condition: &[1; 32],
+                    expires_at: UNIX_EPOCH,
+                    data: &[],
+                }
+                .build(),
+            })
+            .wait()
+    }
+
     #[test]
     fn empty_routing_table() {
         let mut router = Router::new(
@@ -400,4 +607,112 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(to.lock().take().unwrap().0, 2);
     }
+
+    #[test]
+    fn orders_next_hops_by_weight() {
+        let next_hops = vec![NextHop::new(1, 1), NextHop::new(2, 2), NextHop::new(3, 0)];
+        assert_eq!(order_next_hops(&next_hops, 0), vec![1, 2, 3]);
+        assert_eq!(order_next_hops(&next_hops, 1), vec![2, 1, 3]);
+        assert_eq!(order_next_hops(&next_hops, 2), vec![2, 1, 3]);
+        assert_eq!(order_next_hops(&next_hops, 3), vec![1, 2, 3]);
+
+        let backups_only = vec![NextHop::new(3, 0), NextHop::new(1, 0)];
+        assert_eq!(order_next_hops(&backups_only, 5), vec![3, 1]);
+    }
+
+    #[test]
+    fn balances_between_weighted_next_hops() {
+        let (mut router, sent_to) =
+            multipath_router(vec![NextHop::new(1, 3), NextHop::new(2, 1)], Vec::new());
+        for _ in 0..8 {
+            assert!(send_to_destination(&mut router).is_ok());
+        }
+        let sent_to = sent_to.lock();
+        assert_eq!(sent_to.iter().filter(|id| **id == 1).count(), 6);
+        assert_eq!(sent_to.iter().filter(|id| **id == 2).count(), 2);
+    }
+
+    #[test]
+    fn fails_over_on_temporary_errors() {
+        let (mut router, sent_to) = multipath_router(
+            vec![NextHop::new(1, 1), NextHop::new(2, 0), NextHop::new(3, 0)],
+            vec![(1, ErrorCode::T01_PEER_UNREACHABLE, CONNECTOR.clone())],
+        );
+        assert!(send_to_destination(&mut router).is_ok());
+        assert_eq!(*sent_to.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn fails_over_on_temporary_errors_from_the_next_hop() {
+        let (mut router, sent_to) = multipath_router(
+            vec![NextHop::new(1, 1), NextHop::new(2, 0)],
+            vec![(
+                1,
+                ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                ACCOUNT_ADDRESSES[1].clone(),
+            )],
+        );
+        assert!(send_to_destination(&mut router).is_ok());
+        assert_eq!(*sent_to.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn does_not_fail_over_on_temporary_errors_from_further_away() {
+        let (mut router, sent_to) = multipath_router(
+            vec![NextHop::new(1, 1), NextHop::new(2, 0)],
+            vec![(
+                1,
+                ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                Address::from_str("example.far_away").unwrap(),
+            )],
+        );
+        let reject = send_to_destination(&mut router).unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T04_INSUFFICIENT_LIQUIDITY);
+        assert_eq!(*sent_to.lock(), vec![1]);
+    }
+
+    #[test]
+    fn skips_next_hops_that_are_missing_from_the_store() {
+        let (mut router, sent_to) = multipath_router(
+            vec![
+                NextHop::new(MISSING_ACCOUNT, 1),
+                NextHop::new(1, 0),
+                NextHop::new(2, 0),
+            ],
+            Vec::new(),
+        );
+        assert!(send_to_destination(&mut router).is_ok());
+        assert_eq!(*sent_to.lock(), vec![1]);
+
+        let (mut router, sent_to) =
+            multipath_router(vec![NextHop::new(MISSING_ACCOUNT, 1)], Vec::new());
+        let reject = send_to_destination(&mut router).unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert!(sent_to.lock().is_empty());
+    }
+
+    #[test]
+    fn returns_last_reject_if_all_next_hops_fail() {
+        let (mut router, sent_to) = multipath_router(
+            vec![NextHop::new(1, 1), NextHop::new(2, 0)],
+            vec![
+                (1, ErrorCode::T01_PEER_UNREACHABLE, CONNECTOR.clone()),
+                (2, ErrorCode::T04_INSUFFICIENT_LIQUIDITY, CONNECTOR.clone()),
+            ],
+        );
+        let reject = send_to_destination(&mut router).unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T04_INSUFFICIENT_LIQUIDITY);
+        assert_eq!(*sent_to.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn does_not_fail_over_on_final_errors() {
+        let (mut router, sent_to) = multipath_router(
+            vec![NextHop::new(1, 1), NextHop::new(2, 0)],
+            vec![(1, ErrorCode::F99_APPLICATION_ERROR, CONNECTOR.clone())],
+        );
+        let reject = send_to_destination(&mut router).unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(*sent_to.lock(), vec![1]);
+    }
 }
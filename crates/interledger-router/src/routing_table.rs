@@ -0,0 +1,354 @@
+use super::NextHop;
+use bytes::Bytes;
+use std::{iter::FromIterator, sync::Arc};
+
+/// A routing table that maps ILP address prefixes to the next hops packets
+/// for those prefixes should be forwarded to.
+///
+/// The prefixes are stored in a radix tree so that the longest matching prefix
+/// for an address can be found in time proportional to the address' length,
+/// regardless of how many routes there are.
+///
+/// Stores are expected to build a new table when their routes change and share it
+/// behind an `Arc`, so that the Router never clones or locks the table per packet.
+/// Clones share their nodes with the original table and inserting or removing a
+/// prefix only copies the nodes on the path to it, so a store can cheaply update
+/// a copy of the table the Router is using and swap it in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingTable<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Node<T> {
+    /// The bytes this node adds to its parent's prefix
+    segment: Bytes,
+    next_hops: Option<Vec<NextHop<T>>>,
+    /// Sorted by the first byte of their segments, which are all different
+    children: Vec<Arc<Node<T>>>,
+}
+
+impl<T: Clone> Node<T> {
+    fn new(segment: Bytes, next_hops: Option<Vec<NextHop<T>>>) -> Self {
+        Node {
+            segment,
+            next_hops,
+            children: Vec::new(),
+        }
+    }
+
+    fn find_child(&self, first_byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&first_byte, |child| child.segment[0])
+    }
+
+    fn insert(&mut self, key: &[u8], next_hops: Vec<NextHop<T>>) -> Option<Vec<NextHop<T>>> {
+        if key.is_empty() {
+            return self.next_hops.replace(next_hops);
+        }
+
+        let index = match self.find_child(key[0]) {
+            Ok(index) => index,
+            Err(index) => {
+                self.children.insert(
+                    index,
+                    Arc::new(Node::new(Bytes::from(key), Some(next_hops))),
+                );
+                return None;
+            }
+        };
+
+        let child = Arc::make_mut(&mut self.children[index]);
+        let common = common_prefix_length(&child.segment, key);
+        if common < child.segment.len() {
+            // Split the child so that the shared part of the prefix gets its own node
+            let mut rest = Node::new(child.segment.slice_from(common), None);
+            std::mem::swap(&mut rest.next_hops, &mut child.next_hops);
+            std::mem::swap(&mut rest.children, &mut child.children);
+            child.segment = child.segment.slice_to(common);
+            child.children.push(Arc::new(rest));
+        }
+        child.insert(&key[common..], next_hops)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<NextHop<T>>> {
+        if key.is_empty() {
+            return self.next_hops.take();
+        }
+
+        let index = self.find_child(key[0]).ok()?;
+        if !key.starts_with(&self.children[index].segment) {
+            return None;
+        }
+        let child = Arc::make_mut(&mut self.children[index]);
+        let removed = child.remove(&key[child.segment.len()..]);
+
+        // Keep the tree compressed
+        if removed.is_some() && child.next_hops.is_none() {
+            if child.children.is_empty() {
+                self.children.remove(index);
+            } else if child.children.len() == 1 {
+                let mut grandchild = Arc::try_unwrap(child.children.remove(0))
+                    .unwrap_or_else(|grandchild| (*grandchild).clone());
+                let mut segment = child.segment.to_vec();
+                segment.extend_from_slice(&grandchild.segment);
+                grandchild.segment = Bytes::from(segment);
+                *child = grandchild;
+            }
+        }
+        removed
+    }
+
+    fn collect<'a>(&'a self, prefix: &mut Vec<u8>, entries: &mut Vec<(Bytes, &'a [NextHop<T>])>) {
+        prefix.extend_from_slice(&self.segment);
+        if let Some(ref next_hops) = self.next_hops {
+            entries.push((Bytes::from(&prefix[..]), &next_hops[..]));
+        }
+        for child in self.children.iter() {
+            child.collect(prefix, entries);
+        }
+        prefix.truncate(prefix.len() - self.segment.len());
+    }
+}
+
+impl<T: Clone> RoutingTable<T> {
+    pub fn new() -> Self {
+        RoutingTable {
+            root: Node::new(Bytes::new(), None),
+            len: 0,
+        }
+    }
+
+    /// The number of prefixes in the table
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Set the next hops for the given prefix, returning the ones that were there before
+    pub fn insert(&mut self, prefix: &[u8], next_hops: Vec<NextHop<T>>) -> Option<Vec<NextHop<T>>> {
+        let previous = self.root.insert(prefix, next_hops);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// Remove the given prefix from the table, returning its next hops if it was there
+    pub fn remove(&mut self, prefix: &[u8]) -> Option<Vec<NextHop<T>>> {
+        let removed = self.root.remove(prefix);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Get the next hops for exactly this prefix
+    pub fn get(&self, prefix: &[u8]) -> Option<&[NextHop<T>]> {
+        let mut node = &self.root;
+        let mut rest = prefix;
+        while !rest.is_empty() {
+            node = &node.children[node.find_child(rest[0]).ok()?];
+            if !rest.starts_with(&node.segment) {
+                return None;
+            }
+            rest = &rest[node.segment.len()..];
+        }
+        node.next_hops.as_ref().map(|next_hops| &next_hops[..])
+    }
+
+    /// Find the longest prefix in the table that the given address starts with.
+    /// Returns the prefix and its next hops. The empty prefix matches every address.
+    pub fn resolve<'a>(&'a self, address: &'a [u8]) -> Option<(&'a [u8], &'a [NextHop<T>])> {
+        let mut node = &self.root;
+        let mut matched = 0;
+        let mut best = node.next_hops.as_ref().map(|next_hops| (0, next_hops));
+        while matched < address.len() {
+            let rest = &address[matched..];
+            node = match node.find_child(rest[0]) {
+                Ok(index) if rest.starts_with(&node.children[index].segment) => {
+                    &node.children[index]
+                }
+                _ => break,
+            };
+            matched += node.segment.len();
+            if let Some(ref next_hops) = node.next_hops {
+                best = Some((matched, next_hops));
+            }
+        }
+        best.map(|(length, next_hops)| (&address[..length], &next_hops[..]))
+    }
+
+    /// All of the prefixes and their next hops, sorted by prefix
+    pub fn iter(&self) -> impl Iterator<Item = (Bytes, &[NextHop<T>])> {
+        let mut entries = Vec::with_capacity(self.len);
+        self.root.collect(&mut Vec::new(), &mut entries);
+        entries.into_iter()
+    }
+}
+
+impl<T: Clone> Default for RoutingTable<T> {
+    fn default() -> Self {
+        RoutingTable::new()
+    }
+}
+
+/// Build a table with a single next hop for each prefix
+impl<T: Clone> FromIterator<(Bytes, T)> for RoutingTable<T> {
+    fn from_iter<I: IntoIterator<Item = (Bytes, T)>>(routes: I) -> Self {
+        let mut table = RoutingTable::new();
+        for (prefix, account_id) in routes {
+            table.insert(&prefix, vec![NextHop::new(account_id, 1)]);
+        }
+        table
+    }
+}
+
+impl<T: Clone> FromIterator<(Bytes, Vec<NextHop<T>>)> for RoutingTable<T> {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Vec<NextHop<T>>)>>(routes: I) -> Self {
+        let mut table = RoutingTable::new();
+        for (prefix, next_hops) in routes {
+            table.insert(&prefix, next_hops);
+        }
+        table
+    }
+}
+
+fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(prefixes: &[&str]) -> RoutingTable<u64> {
+        prefixes
+            .iter()
+            .enumerate()
+            .map(|(index, prefix)| (Bytes::from(*prefix), index as u64))
+            .collect()
+    }
+
+    fn resolve(table: &RoutingTable<u64>, address: &str) -> Option<(String, u64)> {
+        table
+            .resolve(address.as_bytes())
+            .map(|(prefix, next_hops)| {
+                (
+                    String::from_utf8(prefix.to_vec()).unwrap(),
+                    next_hops[0].account_id,
+                )
+            })
+    }
+
+    #[test]
+    fn finds_longest_matching_prefix() {
+        let table = table(&["example.", "example.a", "example.ab", "example.b.c", "test"]);
+        assert_eq!(
+            resolve(&table, "example.abc"),
+            Some(("example.ab".to_string(), 2))
+        );
+        assert_eq!(
+            resolve(&table, "example.a.b"),
+            Some(("example.a".to_string(), 1))
+        );
+        assert_eq!(
+            resolve(&table, "example.b.d"),
+            Some(("example.".to_string(), 0))
+        );
+        assert_eq!(resolve(&table, "test.x"), Some(("test".to_string(), 4)));
+        assert_eq!(resolve(&table, "private.x"), None);
+        assert_eq!(resolve(&table, "exam"), None);
+    }
+
+    #[test]
+    fn empty_prefix_matches_everything() {
+        let table = table(&["", "example.a"]);
+        assert_eq!(resolve(&table, "private.x"), Some((String::new(), 0)));
+        assert_eq!(
+            resolve(&table, "example.a.b"),
+            Some(("example.a".to_string(), 1))
+        );
+    }
+
+    #[test]
+    fn replaces_and_removes_prefixes() {
+        let mut table = table(&["example.a", "example.ab", "example.b"]);
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table.insert(b"example.a", vec![NextHop::new(7, 1)]),
+            Some(vec![NextHop::new(0, 1)])
+        );
+        assert_eq!(table.len(), 3);
+
+        assert_eq!(table.remove(b"example."), None);
+        assert_eq!(table.remove(b"example.a"), Some(vec![NextHop::new(7, 1)]));
+        assert_eq!(table.remove(b"example.a"), None);
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            resolve(&table, "example.abc"),
+            Some(("example.ab".to_string(), 1))
+        );
+        assert_eq!(resolve(&table, "example.a"), None);
+
+        // Removing and re-adding the routes should result in the same tree
+        table.remove(b"example.ab");
+        table.remove(b"example.b");
+        assert_eq!(table, RoutingTable::new());
+    }
+
+    #[test]
+    fn does_not_modify_clones() {
+        let original = table(&["example.a", "example.b", "test"]);
+        let mut copy = original.clone();
+        copy.insert(b"example.ab", vec![NextHop::new(3, 1)]);
+        copy.remove(b"test");
+        assert_eq!(original, table(&["example.a", "example.b", "test"]));
+        assert_eq!(
+            resolve(&copy, "example.abc"),
+            Some(("example.ab".to_string(), 3))
+        );
+        assert_eq!(resolve(&copy, "test.x"), None);
+
+        // Only the nodes on the path to the new prefix were copied
+        let original_example = &original.root.children[0];
+        let copy_example = &copy.root.children[0];
+        assert!(!Arc::ptr_eq(original_example, copy_example));
+        assert!(!Arc::ptr_eq(
+            &original_example.children[0],
+            &copy_example.children[0]
+        ));
+        assert!(Arc::ptr_eq(
+            &original_example.children[1],
+            &copy_example.children[1]
+        ));
+    }
+
+    #[test]
+    fn gets_exact_prefixes() {
+        let table = table(&["example.a", "example.abc"]);
+        assert_eq!(table.get(b"example.a"), Some(&[NextHop::new(0, 1)][..]));
+        assert_eq!(table.get(b"example.ab"), None);
+        assert_eq!(table.get(b"example.abcd"), None);
+        assert_eq!(table.get(b""), None);
+    }
+
+    #[test]
+    fn iterates_over_all_prefixes() {
+        let table = table(&["example.b", "", "example.a", "example.ab"]);
+        let prefixes: Vec<Bytes> = table.iter().map(|(prefix, _)| prefix).collect();
+        assert_eq!(
+            prefixes,
+            vec![
+                Bytes::from(""),
+                Bytes::from("example.a"),
+                Bytes::from("example.ab"),
+                Bytes::from("example.b")
+            ]
+        );
+    }
+}
@@ -8,6 +8,7 @@ use interledger::{
 };
 use interledger_packet::Address;
 use serde_json::json;
+use std::collections::HashMap;
 use std::str::FromStr;
 use tokio::runtime::Builder as RuntimeBuilder;
 
@@ -108,6 +109,19 @@ fn eth_xrp_interoperable() {
         settlement_address: ([127, 0, 0, 1], node1_settlement).into(),
         secret_seed: cli::random_secret(),
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     let node1_clone = node1.clone();
     runtime.spawn(
@@ -120,10 +134,18 @@ fn eth_xrp_interoperable() {
                     asset_code: "ETH".to_string(),
                     asset_scale: eth_decimals,
                     btp_incoming_token: None,
+                    btp_outgoing_username: None,
                     btp_uri: None,
                     http_endpoint: Some(format!("http://localhost:{}/ilp", node1_http)),
                     http_incoming_token: Some("in_alice".to_string()),
                     http_outgoing_token: None,
+                    http_incoming_certificate: None,
+                    http_client_identity: None,
+                    http_client_identity_password: None,
+                    http_client_max_idle_per_host: None,
+                    http_client_http2_prior_knowledge: None,
+                    http_client_max_timeout: None,
+                    http_client_max_retries: None,
                     max_packet_amount: u64::max_value(),
                     min_balance: None,
                     settle_threshold: None,
@@ -145,10 +167,18 @@ fn eth_xrp_interoperable() {
                     asset_code: "ETH".to_string(),
                     asset_scale: eth_decimals,
                     btp_incoming_token: None,
+                    btp_outgoing_username: None,
                     btp_uri: None,
                     http_endpoint: Some(format!("http://localhost:{}/ilp", node2_http)),
                     http_incoming_token: Some("alice".to_string()),
                     http_outgoing_token: Some("alice:bob".to_string()),
+                    http_incoming_certificate: None,
+                    http_client_identity: None,
+                    http_client_identity_password: None,
+                    http_client_max_idle_per_host: None,
+                    http_client_http2_prior_knowledge: None,
+                    http_client_max_timeout: None,
+                    http_client_max_retries: None,
                     max_packet_amount: u64::max_value(),
                     min_balance: Some(-100_000),
                     settle_threshold: Some(70000),
@@ -175,6 +205,19 @@ fn eth_xrp_interoperable() {
         settlement_address: ([127, 0, 0, 1], node2_settlement).into(),
         secret_seed: cli::random_secret(),
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     let node2_clone = node2.clone();
     runtime.spawn(
@@ -187,10 +230,18 @@ fn eth_xrp_interoperable() {
                         asset_code: "ETH".to_string(),
                         asset_scale: eth_decimals,
                         btp_incoming_token: None,
+                        btp_outgoing_username: None,
                         btp_uri: None,
                         http_endpoint: Some(format!("http://localhost:{}/ilp", node1_http)),
                         http_incoming_token: Some("bob".to_string()),
                         http_outgoing_token: Some("bob:alice".to_string()),
+                        http_incoming_certificate: None,
+                        http_client_identity: None,
+                        http_client_identity_password: None,
+                        http_client_max_idle_per_host: None,
+                        http_client_http2_prior_knowledge: None,
+                        http_client_max_timeout: None,
+                        http_client_max_retries: None,
                         max_packet_amount: u64::max_value(),
                         min_balance: Some(-100_000),
                         settle_threshold: None,
@@ -210,10 +261,18 @@ fn eth_xrp_interoperable() {
                             asset_code: "XRP".to_string(),
                             asset_scale: xrp_decimals,
                             btp_incoming_token: None,
+                            btp_outgoing_username: None,
                             btp_uri: None,
                             http_endpoint: Some(format!("http://localhost:{}/ilp", node3_http)),
                             http_incoming_token: Some("bob".to_string()),
                             http_outgoing_token: Some("bob:charlie".to_string()),
+                            http_incoming_certificate: None,
+                            http_client_identity: None,
+                            http_client_identity_password: None,
+                            http_client_max_idle_per_host: None,
+                            http_client_http2_prior_knowledge: None,
+                            http_client_max_timeout: None,
+                            http_client_max_retries: None,
                             max_packet_amount: u64::max_value(),
                             min_balance: Some(-100),
                             settle_threshold: Some(70000),
@@ -259,6 +318,19 @@ fn eth_xrp_interoperable() {
         settlement_address: ([127, 0, 0, 1], node3_settlement).into(),
         secret_seed: cli::random_secret(),
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     let node3_clone = node3.clone();
     runtime.spawn(
@@ -273,10 +345,18 @@ fn eth_xrp_interoperable() {
                         asset_code: "XRP".to_string(),
                         asset_scale: xrp_decimals,
                         btp_incoming_token: None,
+                        btp_outgoing_username: None,
                         btp_uri: None,
                         http_endpoint: Some(format!("http://localhost:{}/ilp", node3_http)),
                         http_incoming_token: Some("in_charlie".to_string()),
                         http_outgoing_token: None,
+                        http_incoming_certificate: None,
+                        http_client_identity: None,
+                        http_client_identity_password: None,
+                        http_client_max_idle_per_host: None,
+                        http_client_http2_prior_knowledge: None,
+                        http_client_max_timeout: None,
+                        http_client_max_retries: None,
                         max_packet_amount: u64::max_value(),
                         min_balance: None,
                         settle_threshold: None,
@@ -296,10 +376,18 @@ fn eth_xrp_interoperable() {
                             asset_code: "XRP".to_string(),
                             asset_scale: xrp_decimals,
                             btp_incoming_token: None,
+                            btp_outgoing_username: None,
                             btp_uri: None,
                             http_endpoint: Some(format!("http://localhost:{}/ilp", node2_http)),
                             http_incoming_token: Some("charlie".to_string()),
                             http_outgoing_token: Some("charlie:bob".to_string()),
+                            http_incoming_certificate: None,
+                            http_client_identity: None,
+                            http_client_identity_password: None,
+                            http_client_max_idle_per_host: None,
+                            http_client_http2_prior_knowledge: None,
+                            http_client_max_timeout: None,
+                            http_client_max_retries: None,
                             max_packet_amount: u64::max_value(),
                             min_balance: Some(-100_000),
                             settle_threshold: None,
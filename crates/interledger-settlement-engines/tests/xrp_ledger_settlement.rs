@@ -9,6 +9,7 @@ use interledger::{
 };
 use interledger_packet::Address;
 use interledger_service::Username;
+use std::collections::HashMap;
 use std::str::FromStr;
 use tokio::runtime::Builder as RuntimeBuilder;
 
@@ -87,6 +88,19 @@ fn xrp_ledger_settlement() {
         settlement_address: ([127, 0, 0, 1], node1_settlement).into(),
         secret_seed: node1_secret,
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     let node1_clone = node1.clone();
     runtime.spawn(
@@ -98,10 +112,18 @@ fn xrp_ledger_settlement() {
                 asset_code: "XRP".to_string(),
                 asset_scale: xrp_decimals,
                 btp_incoming_token: None,
+                btp_outgoing_username: None,
                 btp_uri: None,
                 http_endpoint: None,
                 http_incoming_token: Some("in_alice".to_string()),
                 http_outgoing_token: None,
+                http_incoming_certificate: None,
+                http_client_identity: None,
+                http_client_identity_password: None,
+                http_client_max_idle_per_host: None,
+                http_client_http2_prior_knowledge: None,
+                http_client_max_timeout: None,
+                http_client_max_retries: None,
                 max_packet_amount: 10,
                 min_balance: None,
                 settle_threshold: None,
@@ -121,10 +143,18 @@ fn xrp_ledger_settlement() {
                     asset_code: "XRP".to_string(),
                     asset_scale: xrp_decimals,
                     btp_incoming_token: None,
+                    btp_outgoing_username: None,
                     btp_uri: None,
                     http_endpoint: Some(format!("http://localhost:{}/ilp", node2_http)),
                     http_incoming_token: Some("alice".to_string()),
                     http_outgoing_token: Some("alice:bob".to_string()),
+                    http_incoming_certificate: None,
+                    http_client_identity: None,
+                    http_client_identity_password: None,
+                    http_client_max_idle_per_host: None,
+                    http_client_http2_prior_knowledge: None,
+                    http_client_max_timeout: None,
+                    http_client_max_retries: None,
                     max_packet_amount: 10,
                     min_balance: Some(-100),
                     settle_threshold: Some(70),
@@ -152,6 +182,19 @@ fn xrp_ledger_settlement() {
         settlement_address: ([127, 0, 0, 1], node2_settlement).into(),
         secret_seed: node2_secret,
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     runtime.spawn(
         node2
@@ -161,10 +204,18 @@ fn xrp_ledger_settlement() {
                 asset_code: "XRP".to_string(),
                 asset_scale: xrp_decimals,
                 btp_incoming_token: None,
+                btp_outgoing_username: None,
                 btp_uri: None,
                 http_endpoint: None,
                 http_incoming_token: Some("in_bob".to_string()),
                 http_outgoing_token: None,
+                http_incoming_certificate: None,
+                http_client_identity: None,
+                http_client_identity_password: None,
+                http_client_max_idle_per_host: None,
+                http_client_http2_prior_knowledge: None,
+                http_client_max_timeout: None,
+                http_client_max_retries: None,
                 max_packet_amount: 10,
                 min_balance: None,
                 settle_threshold: None,
@@ -185,10 +236,18 @@ fn xrp_ledger_settlement() {
                         asset_code: "XRP".to_string(),
                         asset_scale: xrp_decimals,
                         btp_incoming_token: None,
+                        btp_outgoing_username: None,
                         btp_uri: None,
                         http_endpoint: Some(format!("http://localhost:{}/ilp", node1_http)),
                         http_incoming_token: Some("bob".to_string()),
                         http_outgoing_token: Some("bob:alice".to_string()),
+                        http_incoming_certificate: None,
+                        http_client_identity: None,
+                        http_client_identity_password: None,
+                        http_client_max_idle_per_host: None,
+                        http_client_http2_prior_knowledge: None,
+                        http_client_max_timeout: None,
+                        http_client_max_retries: None,
                         max_packet_amount: 10,
                         min_balance: Some(-100),
                         settle_threshold: Some(70),
@@ -1,8 +1,12 @@
 use super::{Error, SpspResponse};
+use bytes::Bytes;
 use futures::{future::result, Future};
 use interledger_packet::Address;
 use interledger_service::{Account, IncomingService};
-use interledger_stream::send_money;
+use interledger_stream::{
+    resume_money, send_money, send_money_with_quote, CheckpointSink, CongestionAlgorithm,
+    PaymentCheckpoint, SendMoneyOptions, StreamDelivery,
+};
 use log::{debug, error, trace};
 use reqwest::r#async::Client;
 use std::convert::TryFrom;
@@ -25,13 +29,14 @@ pub fn query(server: &str) -> impl Future<Item = SpspResponse, Error = Error> {
 
 /// Query the details of the given Payment Pointer and send a payment using the STREAM protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units,
+/// along with the receiver's asset details if it sent them.
 pub fn pay<S, A>(
     service: S,
     from_account: A,
     receiver: &str,
     source_amount: u64,
-) -> impl Future<Item = u64, Error = Error>
+) -> impl Future<Item = StreamDelivery, Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
@@ -46,17 +51,116 @@ where
         .and_then(move |addr| {
             debug!("Sending SPSP payment to address: {}", addr);
 
-            send_money(service, &from_account, addr, &shared_secret, source_amount)
-                .map(move |(amount_delivered, _plugin)| {
+            send_money(
+                service,
+                &from_account,
+                addr,
+                &shared_secret,
+                source_amount,
+                CongestionAlgorithm::default(),
+            )
+            .map(move |(delivery, _plugin)| {
+                debug!(
+                    "Sent SPSP payment of {} and delivered {} of the receiver's units",
+                    source_amount, delivery.delivered_amount
+                );
+                delivery
+            })
+            .map_err(move |err| {
+                error!("Error sending payment of {}: {:?}", source_amount, err);
+                Error::StreamError(err)
+            })
+        })
+    })
+}
+
+/// Query the details of the given Payment Pointer and send a payment using the STREAM protocol,
+/// saving checkpoints of the payment's progress to the given sink.
+///
+/// The first checkpoint is saved before any money is sent and includes the connection
+/// details from the SPSP response, so the payment can always be continued with
+/// `interledger_stream::resume_money` without querying the receiver again.
+/// The checkpoint also records the receiver so callers can check that a resumed payment
+/// is going to the same place.
+pub fn pay_with_checkpoints<S, A, C>(
+    service: S,
+    from_account: A,
+    receiver: &str,
+    source_amount: u64,
+    sink: C,
+) -> impl Future<Item = StreamDelivery, Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+    C: CheckpointSink + 'static,
+{
+    let receiver = receiver.to_string();
+    query(&receiver).and_then(move |spsp| {
+        let shared_secret = spsp.shared_secret;
+        let dest = spsp.destination_account;
+        result(Address::try_from(dest).map_err(move |err| {
+            error!("Error parsing address");
+            Error::InvalidResponseError(err.to_string())
+        }))
+        .and_then(move |addr| {
+            debug!("Sending SPSP payment to address: {}", addr);
+
+            let mut checkpoint =
+                PaymentCheckpoint::new(addr, Bytes::from(shared_secret), source_amount);
+            checkpoint.receiver = Some(receiver);
+            resume_money(service, &from_account, checkpoint, sink)
+                .map(move |(delivery, _plugin)| {
+                    debug!(
+                        "Sent SPSP payment of {} and delivered {} of the receiver's units",
+                        source_amount, delivery.delivered_amount
+                    );
+                    delivery
+                })
+                .map_err(move |err| {
+                    error!("Error sending payment of {}: {:?}", source_amount, err);
+                    Error::StreamError(err)
+                })
+        })
+    })
+}
+
+/// Query the details of the given Payment Pointer and send a payment using the STREAM protocol,
+/// quoting the exchange rate first.
+///
+/// Depending on the options, this can deliver a fixed amount to the receiver (sending no more than
+/// `source_amount`) and refuse to send anything if the exchange rate is below the given minimum.
+pub fn pay_with_options<S, A>(
+    service: S,
+    from_account: A,
+    receiver: &str,
+    options: SendMoneyOptions,
+) -> impl Future<Item = StreamDelivery, Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    query(receiver).and_then(move |spsp| {
+        let shared_secret = spsp.shared_secret;
+        let dest = spsp.destination_account;
+        result(Address::try_from(dest).map_err(move |err| {
+            error!("Error parsing address");
+            Error::InvalidResponseError(err.to_string())
+        }))
+        .and_then(move |addr| {
+            debug!("Sending SPSP payment to address: {}", addr);
+            let source_amount = options.source_amount;
+
+            send_money_with_quote(service, &from_account, addr, &shared_secret, options)
+                .map(move |(delivery, _plugin)| {
                     debug!(
                         "Sent SPSP payment of {} and delivered {} of the receiver's units",
-                        source_amount, amount_delivered
+                        delivery.sent_amount, delivery.delivered_amount
                     );
-                    amount_delivered
+                    delivery
                 })
                 .map_err(move |err| {
-                    error!("Error sending payment: {:?}", err);
-                    Error::SendMoneyError(source_amount)
+                    error!("Error sending payment of {}: {:?}", source_amount, err);
+                    Error::StreamError(err)
                 })
         })
     })
@@ -0,0 +1,127 @@
+use futures::Future;
+use interledger_service::Username;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the connection tags used for invoices
+const INVOICE_TAG_PREFIX: &str = "inv";
+const INVOICE_TAG_SEPARATOR: char = '_';
+
+/// A request for a specific amount to be paid to an account before a given time.
+///
+/// Every SPSP query for an invoice returns the same STREAM connection, with the invoice's
+/// details encoded in the connection tag. The STREAM receiver uses `invoice_receive_max`
+/// to stop accepting money on that connection once the invoice is paid or expires.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invoice {
+    /// Identifier assigned by the store. It may only contain the characters `[a-zA-Z0-9-]`
+    pub id: String,
+    /// The account the invoice is paid to
+    pub username: Username,
+    /// The amount to be paid, in the receiving account's units
+    pub amount: u64,
+    /// When the invoice expires, in seconds since the UNIX epoch
+    pub expires_at: u64,
+}
+
+impl Invoice {
+    /// The connection tag for the invoice's STREAM connection
+    pub fn connection_tag(&self) -> String {
+        format!(
+            "{prefix}{sep}{id}{sep}{amount}{sep}{expires_at}",
+            prefix = INVOICE_TAG_PREFIX,
+            sep = INVOICE_TAG_SEPARATOR,
+            id = self.id,
+            amount = self.amount,
+            expires_at = self.expires_at
+        )
+    }
+
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.expires_at)
+    }
+}
+
+/// The `receive_max` for a STREAM connection with the given tag, if it belongs to an invoice.
+///
+/// This is meant to be passed to `StreamReceiverService::receive_max_for_tag`. Invoices can
+/// receive up to their amount until they expire, and nothing after that.
+pub fn invoice_receive_max(connection_tag: &str) -> Option<u64> {
+    let mut parts = connection_tag.split(INVOICE_TAG_SEPARATOR);
+    if parts.next() != Some(INVOICE_TAG_PREFIX) {
+        return None;
+    }
+    let _id = parts.next()?;
+    let amount = u64::from_str(parts.next()?).ok()?;
+    let expires_at = u64::from_str(parts.next()?).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    if is_expired(expires_at) {
+        Some(0)
+    } else {
+        Some(amount)
+    }
+}
+
+fn is_expired(expires_at: u64) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    now >= expires_at
+}
+
+/// A store for invoices created through the node's API.
+pub trait InvoiceStore {
+    /// Save a new invoice for the given account and return it with its newly assigned ID
+    fn create_invoice(
+        &self,
+        username: Username,
+        amount: u64,
+        expires_at: u64,
+    ) -> Box<dyn Future<Item = Invoice, Error = ()> + Send>;
+
+    fn get_invoice(&self, invoice_id: &str) -> Box<dyn Future<Item = Invoice, Error = ()> + Send>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice(expires_at: u64) -> Invoice {
+        Invoice {
+            id: "5e0b7c4a-0f0e-4d1a-9c1e-7a6b2d1f3e4c".to_string(),
+            username: Username::from_str("alice").unwrap(),
+            amount: 1000,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn encodes_invoice_in_connection_tag() {
+        let invoice = invoice(u64::max_value());
+        assert_eq!(
+            invoice.connection_tag(),
+            "inv_5e0b7c4a-0f0e-4d1a-9c1e-7a6b2d1f3e4c_1000_18446744073709551615"
+        );
+        assert_eq!(invoice_receive_max(&invoice.connection_tag()), Some(1000));
+    }
+
+    #[test]
+    fn expired_invoices_cannot_receive_money() {
+        let invoice = invoice(1);
+        assert!(invoice.is_expired());
+        assert_eq!(invoice_receive_max(&invoice.connection_tag()), Some(0));
+    }
+
+    #[test]
+    fn ignores_other_connection_tags() {
+        assert_eq!(invoice_receive_max("some_tag"), None);
+        assert_eq!(invoice_receive_max("inv_abc_1000"), None);
+        assert_eq!(invoice_receive_max("inv_abc_1000_10_extra"), None);
+        assert_eq!(invoice_receive_max("inv_abc_lots_10"), None);
+    }
+}
@@ -11,9 +11,12 @@ use interledger_stream::Error as StreamError;
 use serde::{Deserialize, Serialize};
 
 mod client;
+mod invoice;
 mod server;
 
-pub use client::{pay, query};
+pub use client::{pay, pay_with_checkpoints, pay_with_options, query};
+pub use interledger_stream::{SendMoneyOptions, StreamDelivery};
+pub use invoice::{invoice_receive_max, Invoice, InvoiceStore};
 pub use server::SpspResponder;
 
 #[derive(Fail, Debug)]
@@ -32,11 +35,48 @@ pub enum Error {
     InvalidPaymentPointerError(String),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The response to an SPSP query.
+///
+/// Only `destination_account` and `shared_secret` are required. Receivers may also
+/// describe their asset, how much they can still receive, and who they are, so that
+/// senders can show the details (and a quote) before paying.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct SpspResponse {
-    destination_account: Address,
+    pub destination_account: Address,
     #[serde(with = "serde_base64")]
-    shared_secret: Vec<u8>,
+    pub shared_secret: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<SpspBalance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset_info: Option<SpspAssetInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiver_info: Option<SpspReceiverInfo>,
+}
+
+/// How much the receiver expects to receive (`maximum`) and has received so far (`current`),
+/// in the receiver's units. These are sent as strings so they do not lose precision in JSON.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpspBalance {
+    #[serde(with = "serde_u64_string")]
+    pub maximum: u64,
+    #[serde(with = "serde_u64_string")]
+    pub current: u64,
+}
+
+/// The asset the receiver is paid in
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpspAssetInfo {
+    pub code: String,
+    pub scale: u8,
+}
+
+/// Details about the receiver that senders can display
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SpspReceiverInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
 }
 
 // From https://github.com/serde-rs/json/issues/360#issuecomment-330095360
@@ -60,3 +100,89 @@ mod serde_base64 {
         base64::decode(s).map_err(de::Error::custom)
     }
 }
+
+mod serde_u64_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S>(amount: &u64, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&amount.to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = <&str>::deserialize(deserializer)?;
+        u64::from_str(s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod spsp_response {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parses_minimal_response() {
+        let response: SpspResponse = serde_json::from_str(
+            r#"{"destination_account":"example.receiver","shared_secret":"AAAA"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.destination_account,
+            Address::from_str("example.receiver").unwrap()
+        );
+        assert_eq!(response.shared_secret, vec![0, 0, 0]);
+        assert_eq!(response.balance, None);
+        assert_eq!(response.asset_info, None);
+        assert_eq!(response.receiver_info, None);
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"destination_account":"example.receiver","shared_secret":"AAAA"}"#
+        );
+    }
+
+    #[test]
+    fn parses_full_response() {
+        let response: SpspResponse = serde_json::from_str(
+            r#"{
+                "destination_account": "example.receiver",
+                "shared_secret": "AAAA",
+                "balance": { "maximum": "100000", "current": "5360" },
+                "asset_info": { "code": "USD", "scale": 2 },
+                "receiver_info": { "name": "Bob Dylan", "image_url": "https://red.ilpdemo.org/api/spsp/bob/profile_pic.jpg" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.balance,
+            Some(SpspBalance {
+                maximum: 100_000,
+                current: 5360,
+            })
+        );
+        assert_eq!(
+            response.asset_info,
+            Some(SpspAssetInfo {
+                code: "USD".to_string(),
+                scale: 2,
+            })
+        );
+        assert_eq!(
+            response.receiver_info,
+            Some(SpspReceiverInfo {
+                name: Some("Bob Dylan".to_string()),
+                image_url: Some("https://red.ilpdemo.org/api/spsp/bob/profile_pic.jpg".to_string()),
+            })
+        );
+        assert_eq!(
+            serde_json::from_str::<SpspResponse>(&serde_json::to_string(&response).unwrap())
+                .unwrap(),
+            response
+        );
+    }
+}
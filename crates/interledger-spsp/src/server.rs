@@ -1,4 +1,4 @@
-use super::SpspResponse;
+use super::{Invoice, SpspAssetInfo, SpspBalance, SpspReceiverInfo, SpspResponse};
 use bytes::Bytes;
 use futures::future::{ok, FutureResult, IntoFuture};
 use hyper::{service::Service as HttpService, Body, Error, Request, Response};
@@ -14,6 +14,9 @@ use std::{fmt, str};
 pub struct SpspResponder {
     ilp_address: Address,
     connection_generator: ConnectionGenerator,
+    asset_info: Option<SpspAssetInfo>,
+    receiver_info: Option<SpspReceiverInfo>,
+    balance: Option<SpspBalance>,
 }
 
 impl SpspResponder {
@@ -22,9 +25,30 @@ impl SpspResponder {
         SpspResponder {
             ilp_address,
             connection_generator,
+            asset_info: None,
+            receiver_info: None,
+            balance: None,
         }
     }
 
+    /// Include the receiver's asset in responses so senders can quote the payment up front
+    pub fn asset_info(&mut self, code: String, scale: u8) -> &mut Self {
+        self.asset_info = Some(SpspAssetInfo { code, scale });
+        self
+    }
+
+    /// Include details about the receiver that senders can display
+    pub fn receiver_info(&mut self, receiver_info: SpspReceiverInfo) -> &mut Self {
+        self.receiver_info = Some(receiver_info);
+        self
+    }
+
+    /// Tell senders how much the receiver expects (`maximum`) and has already received (`current`)
+    pub fn balance(&mut self, maximum: u64, current: u64) -> &mut Self {
+        self.balance = Some(SpspBalance { maximum, current });
+        self
+    }
+
     pub fn generate_http_response(&self) -> Response<Body> {
         let (destination_account, shared_secret) = self
             .connection_generator
@@ -33,9 +57,43 @@ impl SpspResponder {
             "Generated address and secret for: {:?}",
             destination_account
         );
+        self.spsp_response(destination_account, shared_secret)
+    }
+
+    /// Respond with the details of the STREAM connection for the given invoice.
+    ///
+    /// The same connection is returned every time the invoice is queried, so everything
+    /// paid towards it is tracked (and limited to the invoice amount) together.
+    pub fn generate_invoice_response(&self, invoice: &Invoice) -> Result<Response<Body>, ()> {
+        let (destination_account, shared_secret) = self
+            .connection_generator
+            .generate_fixed_address_and_secret(&self.ilp_address, &invoice.connection_tag())?;
+        debug!(
+            "Generated address and secret for invoice {}: {:?}",
+            invoice.id, destination_account
+        );
+        Ok(self.spsp_response(destination_account, shared_secret))
+    }
+
+    /// The `destination_account` of the given invoice's STREAM connection, which is
+    /// also the ID the `StreamReceiverStore` tracks the connection's totals under
+    pub fn invoice_destination_account(&self, invoice: &Invoice) -> Result<Address, ()> {
+        self.connection_generator
+            .generate_fixed_address_and_secret(&self.ilp_address, &invoice.connection_tag())
+            .map(|(destination_account, _shared_secret)| destination_account)
+    }
+
+    fn spsp_response(
+        &self,
+        destination_account: Address,
+        shared_secret: [u8; 32],
+    ) -> Response<Body> {
         let response = SpspResponse {
             destination_account,
             shared_secret: shared_secret.to_vec(),
+            balance: self.balance.clone(),
+            asset_info: self.asset_info.clone(),
+            receiver_info: self.receiver_info.clone(),
         };
 
         Response::builder()
@@ -87,7 +145,8 @@ impl StdError for Never {
 #[cfg(test)]
 mod spsp_server_test {
     use super::*;
-    use futures::Future;
+    use futures::{Future, Stream};
+    use interledger_service::Username;
     use std::str::FromStr;
 
     #[test]
@@ -114,4 +173,75 @@ mod spsp_server_test {
             "max-age=60"
         );
     }
+    #[test]
+    fn returns_the_same_connection_for_an_invoice() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let responder = SpspResponder::new(addr, Bytes::from(&[0; 32][..]));
+        let invoice = Invoice {
+            id: "abc".to_string(),
+            username: Username::from_str("alice").unwrap(),
+            amount: 1000,
+            expires_at: u64::max_value(),
+        };
+        let body = |response: Response<Body>| {
+            response
+                .into_body()
+                .concat2()
+                .map(|body| body.to_vec())
+                .wait()
+                .unwrap()
+        };
+
+        let first = body(responder.generate_invoice_response(&invoice).unwrap());
+        let second = body(responder.generate_invoice_response(&invoice).unwrap());
+        assert_eq!(first, second);
+
+        let response: SpspResponse = serde_json::from_slice(&first).unwrap();
+        assert_eq!(
+            response.destination_account,
+            responder.invoice_destination_account(&invoice).unwrap()
+        );
+        assert!(response
+            .destination_account
+            .to_string()
+            .ends_with(&invoice.connection_tag()));
+    }
+
+    #[test]
+    fn includes_receiver_details() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let mut responder = SpspResponder::new(addr, Bytes::from(&[0; 32][..]));
+        responder
+            .asset_info("USD".to_string(), 2)
+            .receiver_info(SpspReceiverInfo {
+                name: Some("alice".to_string()),
+                image_url: None,
+            })
+            .balance(1000, 250);
+        let body = responder
+            .generate_http_response()
+            .into_body()
+            .concat2()
+            .wait()
+            .unwrap();
+        let response: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            response.asset_info,
+            Some(SpspAssetInfo {
+                code: "USD".to_string(),
+                scale: 2,
+            })
+        );
+        assert_eq!(
+            response.receiver_info.unwrap().name,
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            response.balance,
+            Some(SpspBalance {
+                maximum: 1000,
+                current: 250,
+            })
+        );
+    }
 }
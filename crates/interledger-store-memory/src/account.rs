@@ -1,6 +1,6 @@
 use bytes::Bytes;
 use interledger_btp::BtpAccount;
-use interledger_http::HttpAccount;
+use interledger_http::{ClientIdentity, HttpAccount, HttpClientConfig};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
 use interledger_service::{Account as AccountTrait, Username};
@@ -26,9 +26,13 @@ impl AccountBuilder {
             http_incoming_token: None,
             http_outgoing_token: None,
             http_endpoint: None,
+            http_client_config: None,
+            http_client_identity: None,
+            http_incoming_certificate: None,
             btp_uri: None,
             btp_incoming_token: None,
             btp_outgoing_token: None,
+            btp_outgoing_username: None,
         };
         AccountBuilder { details }
     }
@@ -82,6 +86,24 @@ impl AccountBuilder {
         self
     }
 
+    pub fn http_client_config(mut self, config: HttpClientConfig) -> Self {
+        self.details.http_client_config = Some(config);
+        self
+    }
+
+    /// The client certificate to present when sending packets to this account over HTTPS
+    pub fn http_client_identity(mut self, identity: ClientIdentity) -> Self {
+        self.details.http_client_identity = Some(identity);
+        self
+    }
+
+    /// The SHA-256 fingerprint of the client certificate this account authenticates
+    /// incoming HTTP requests with
+    pub fn http_incoming_certificate(mut self, fingerprint: [u8; 32]) -> Self {
+        self.details.http_incoming_certificate = Some(fingerprint);
+        self
+    }
+
     pub fn btp_uri(mut self, uri: Url) -> Self {
         self.details.btp_uri = Some(uri);
         self
@@ -92,6 +114,13 @@ impl AccountBuilder {
         self
     }
 
+    /// Send this username in the `auth_username` field when connecting to the account,
+    /// instead of including it in the outgoing token
+    pub fn btp_outgoing_username(mut self, username: Username) -> Self {
+        self.details.btp_outgoing_username = Some(username);
+        self
+    }
+
     pub fn btp_incoming_token(mut self, auth_token: String) -> Self {
         self.details.btp_incoming_token = Some(auth_token);
         self
@@ -114,8 +143,12 @@ pub(crate) struct AccountDetails {
     pub(crate) http_endpoint: Option<Url>,
     pub(crate) http_incoming_token: Option<String>,
     pub(crate) http_outgoing_token: Option<String>,
+    pub(crate) http_client_config: Option<HttpClientConfig>,
+    pub(crate) http_client_identity: Option<ClientIdentity>,
+    pub(crate) http_incoming_certificate: Option<[u8; 32]>,
     pub(crate) btp_uri: Option<Url>,
     pub(crate) btp_outgoing_token: Option<String>,
+    pub(crate) btp_outgoing_username: Option<Username>,
     pub(crate) btp_incoming_token: Option<String>,
     pub(crate) max_packet_amount: u64,
 }
@@ -185,6 +218,14 @@ impl HttpAccount for Account {
     fn get_http_auth_token(&self) -> Option<&str> {
         self.inner.http_outgoing_token.as_ref().map(|s| s.as_str())
     }
+
+    fn get_http_client_config(&self) -> Option<HttpClientConfig> {
+        self.inner.http_client_config
+    }
+
+    fn get_http_client_identity(&self) -> Option<&ClientIdentity> {
+        self.inner.http_client_identity.as_ref()
+    }
 }
 
 impl BtpAccount for Account {
@@ -199,6 +240,10 @@ impl BtpAccount for Account {
             None
         }
     }
+
+    fn get_btp_username(&self) -> Option<&Username> {
+        self.inner.btp_outgoing_username.as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -218,6 +263,7 @@ mod tests {
         assert_eq!(account.asset_code(), "");
         assert_eq!(account.asset_scale(), 0);
         assert_eq!(account.get_btp_uri(), None);
+        assert_eq!(account.get_btp_username(), None);
         assert_eq!(account.get_http_auth_token(), None);
         assert_eq!(account.max_packet_amount(), u64::max_value());
         assert_eq!(
@@ -239,6 +285,7 @@ mod tests {
         .asset_scale(9)
         .btp_uri(Url::parse("btp+wss://example.com").unwrap())
         .btp_outgoing_token("token".to_string())
+        .btp_outgoing_username(Username::from_str("remote").unwrap())
         .btp_incoming_token("auth".to_string())
         .http_endpoint(Url::parse("http://example.com").unwrap())
         .http_incoming_token("sldkfjlkdsjflj".to_string())
@@ -254,6 +301,10 @@ mod tests {
             Some(&Url::parse("btp+wss://example.com").unwrap())
         );
         assert_eq!(account.get_btp_token(), Some(&b"token"[..]));
+        assert_eq!(
+            account.get_btp_username(),
+            Some(&Username::from_str("remote").unwrap())
+        );
         assert_eq!(account.get_http_auth_token(), Some("sodgiuoixfugoiudf"));
         assert_eq!(account.max_packet_amount(), 7777);
         assert_eq!(account.client_address(), &b"example.address"[..]);
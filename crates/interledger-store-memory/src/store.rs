@@ -1,19 +1,21 @@
 use super::{Account, AccountBuilder};
-use bytes::Bytes;
 use futures::{
     future::{err, ok},
     Future,
 };
 use interledger_btp::{BtpOpenSignupAccount, BtpOpenSignupStore, BtpStore};
-use interledger_http::HttpStore;
+use interledger_http::{ClientCertificate, HttpStore};
 use interledger_ildcp::IldcpAccount;
-use interledger_router::RouterStore;
+use interledger_packet::Address;
+use interledger_router::{NextHop, RouterStore, RoutingTable};
 use interledger_service::{Account as AccountTrait, AccountStore, Username};
+use interledger_stream::{ConnectionTotals, StreamReceiverStore};
 use parking_lot::{Mutex, RwLock};
 use std::collections::HashMap;
 use std::{
     cmp::max,
     iter::{empty, once, FromIterator, IntoIterator},
+    net::IpAddr,
     str,
     str::FromStr,
     sync::Arc,
@@ -25,10 +27,25 @@ use std::{
 #[derive(Clone)]
 pub struct InMemoryStore {
     accounts: Arc<RwLock<HashMap<u64, Account>>>,
-    routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
+    /// Replaced with a modified copy whenever routes change, so that the
+    /// snapshots the Router is using are never modified. The copy shares
+    /// everything but the nodes on the changed prefixes' paths with them
+    routing_table: Arc<RwLock<Arc<RoutingTable<u64>>>>,
     btp_auth: Arc<RwLock<HashMap<String, u64>>>,
     http_auth: Arc<RwLock<HashMap<String, u64>>>,
+    http_certificates: Arc<RwLock<HashMap<[u8; 32], u64>>>,
     next_account_id: Arc<Mutex<u64>>,
+    stream_connections: Arc<Mutex<HashMap<String, StreamConnection>>>,
+    /// The source IP address of each account created via open signup
+    open_signups: Arc<RwLock<HashMap<u64, IpAddr>>>,
+}
+
+/// Amounts received on an incoming STREAM connection
+#[derive(Default)]
+struct StreamConnection {
+    total: u64,
+    streams: HashMap<u64, u64>,
+    source_account: Option<Address>,
 }
 
 impl InMemoryStore {
@@ -49,8 +66,8 @@ impl InMemoryStore {
         }));
         next_account_id += 1;
 
-        let routing_table: HashMap<Bytes, u64> =
-            HashMap::from_iter(accounts.iter().flat_map(|(account_id, account)| {
+        let routing_table: RoutingTable<u64> =
+            RoutingTable::from_iter(accounts.iter().flat_map(|(account_id, account)| {
                 once((account.inner.ilp_address.to_bytes(), *account_id)).chain(
                     account
                         .inner
@@ -78,24 +95,38 @@ impl InMemoryStore {
             }
         }));
 
+        let http_certificates =
+            HashMap::from_iter(accounts.iter().filter_map(|(account_id, account)| {
+                account
+                    .inner
+                    .http_incoming_certificate
+                    .map(|fingerprint| (fingerprint, *account_id))
+            }));
+
         InMemoryStore {
             accounts: Arc::new(RwLock::new(accounts)),
-            routing_table: Arc::new(RwLock::new(routing_table)),
+            routing_table: Arc::new(RwLock::new(Arc::new(routing_table))),
             btp_auth: Arc::new(RwLock::new(btp_auth)),
             http_auth: Arc::new(RwLock::new(http_auth)),
+            http_certificates: Arc::new(RwLock::new(http_certificates)),
             next_account_id: Arc::new(Mutex::new(next_account_id)),
+            stream_connections: Arc::new(Mutex::new(HashMap::new())),
+            open_signups: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
     pub fn add_account(&self, account: Account) {
         self.accounts.write().insert(account.id(), account.clone());
-        self.routing_table
-            .write()
-            .insert(account.inner.ilp_address.to_bytes(), account.id());
-        for route in &account.inner.additional_routes {
-            self.routing_table
-                .write()
-                .insert(route.clone(), account.id());
+        {
+            let mut routing_table = self.routing_table.write();
+            let routing_table = Arc::make_mut(&mut routing_table);
+            routing_table.insert(
+                &account.inner.ilp_address.to_bytes(),
+                vec![NextHop::new(account.id(), 1)],
+            );
+            for route in &account.inner.additional_routes {
+                routing_table.insert(route, vec![NextHop::new(account.id(), 1)]);
+            }
         }
         if let Some(ref btp_auth) = account.inner.btp_incoming_token {
             let btp_auth = format!("{}:{}", account.username(), btp_auth.clone());
@@ -107,6 +138,11 @@ impl InMemoryStore {
                 .write()
                 .insert(http_auth.clone(), account.id());
         }
+        if let Some(fingerprint) = account.inner.http_incoming_certificate {
+            self.http_certificates
+                .write()
+                .insert(fingerprint, account.id());
+        }
         let mut next_account_id = self.next_account_id.lock();
         *next_account_id = max(*next_account_id, account.inner.id);
     }
@@ -154,10 +190,22 @@ impl HttpStore for InMemoryStore {
         }
         Box::new(err(()))
     }
+
+    fn get_account_from_client_certificate(
+        &self,
+        certificate: &ClientCertificate,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        if let Some(account_id) = self.http_certificates.read().get(&certificate.fingerprint) {
+            if let Some(account) = self.accounts.read().get(account_id) {
+                return Box::new(ok(account.clone()));
+            }
+        }
+        Box::new(err(()))
+    }
 }
 
 impl RouterStore for InMemoryStore {
-    fn routing_table(&self) -> HashMap<Bytes, u64> {
+    fn routing_table(&self) -> Arc<RoutingTable<u64>> {
         self.routing_table.read().clone()
     }
 }
@@ -198,38 +246,152 @@ impl BtpOpenSignupStore for InMemoryStore {
         &self,
         account: BtpOpenSignupAccount<'a>,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        // Hold the lock until the account is added so that concurrent signups cannot exceed the limits
+        let mut open_signups = self.open_signups.write();
+        let source_ip = account.source_ip;
+        if let Some(max_accounts) = account.max_accounts {
+            if open_signups.len() >= max_accounts {
+                return Box::new(err(()));
+            }
+        }
+        if let Some(max_accounts_per_ip) = account.max_accounts_per_ip {
+            if open_signups.values().filter(|ip| **ip == source_ip).count() >= max_accounts_per_ip {
+                return Box::new(err(()));
+            }
+        }
+
         let account_id = {
-            let next_id: u64 = *self.next_account_id.lock();
-            *self.next_account_id.lock() += 1;
-            next_id
+            let mut next_account_id = self.next_account_id.lock();
+            *next_account_id += 1;
+            *next_account_id - 1
         };
-        let account = AccountBuilder::new(
+        let mut builder = AccountBuilder::new(
             account.ilp_address.clone(),
             Username::from_str("username").unwrap(),
         )
         .id(account_id)
         .btp_incoming_token(account.auth_token.to_string())
         .asset_code(account.asset_code.to_string())
-        .asset_scale(account.asset_scale)
-        .build();
+        .asset_scale(account.asset_scale);
+        // This store does not track balances so the min_balance is not used
+        if let Some(max_packet_amount) = account.max_packet_amount {
+            builder = builder.max_packet_amount(max_packet_amount);
+        }
+        let account = builder.build();
 
         (*self.accounts.write()).insert(account_id, account.clone());
         let ilp_address = account.client_address().clone();
-        (*self.routing_table.write()).insert(ilp_address.to_bytes(), account_id);
+        Arc::make_mut(&mut self.routing_table.write())
+            .insert(&ilp_address.to_bytes(), vec![NextHop::new(account_id, 1)]);
         (*self.btp_auth.write()).insert(
             account.inner.btp_incoming_token.clone().unwrap(),
             account_id,
         );
+        open_signups.insert(account_id, source_ip);
 
         Box::new(ok(account))
     }
+
+    fn get_open_signup_accounts(
+        &self,
+    ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
+        let accounts = self.accounts.read();
+        Box::new(ok(self
+            .open_signups
+            .read()
+            .keys()
+            .filter_map(|account_id| accounts.get(account_id).cloned())
+            .collect()))
+    }
+
+    // This store does not track balances, so every open signup account is considered empty
+    fn delete_open_signup_account_if_empty(
+        &self,
+        account_id: u64,
+    ) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
+        if self.open_signups.write().remove(&account_id).is_none() {
+            return Box::new(ok(false));
+        }
+        if let Some(account) = self.accounts.write().remove(&account_id) {
+            Arc::make_mut(&mut self.routing_table.write())
+                .remove(&account.inner.ilp_address.to_bytes());
+            if let Some(ref token) = account.inner.btp_incoming_token {
+                self.btp_auth.write().remove(token);
+            }
+        }
+        Box::new(ok(true))
+    }
+}
+
+impl StreamReceiverStore for InMemoryStore {
+    fn credit_connection(
+        &self,
+        connection_id: String,
+        amounts: Vec<(u64, u64)>,
+        receive_max: u64,
+        source_account: Option<Address>,
+    ) -> Box<dyn Future<Item = (bool, ConnectionTotals), Error = ()> + Send> {
+        let mut connections = self.stream_connections.lock();
+        let connection = connections.entry(connection_id).or_default();
+        if source_account.is_some() {
+            connection.source_account = source_account;
+        }
+
+        let amount = amounts
+            .iter()
+            .fold(0u64, |sum, (_, amount)| sum.saturating_add(*amount));
+        let credited = connection
+            .total
+            .checked_add(amount)
+            .map(|total| total <= receive_max)
+            .unwrap_or(false);
+        if credited {
+            connection.total += amount;
+        }
+        let stream_totals = amounts
+            .iter()
+            .map(|(stream_id, amount)| {
+                let total = connection.streams.entry(*stream_id).or_insert(0);
+                if credited {
+                    *total += amount;
+                }
+                *total
+            })
+            .collect();
+
+        Box::new(ok((
+            credited,
+            ConnectionTotals {
+                connection_total: connection.total,
+                stream_totals,
+                source_account: connection.source_account.clone(),
+            },
+        )))
+    }
+
+    fn get_connection_totals(
+        &self,
+        connection_id: String,
+    ) -> Box<dyn Future<Item = ConnectionTotals, Error = ()> + Send> {
+        let totals = match self.stream_connections.lock().get(&connection_id) {
+            Some(connection) => ConnectionTotals {
+                connection_total: connection.total,
+                stream_totals: Vec::new(),
+                source_account: connection.source_account.clone(),
+            },
+            None => ConnectionTotals::default(),
+        };
+        Box::new(ok(totals))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use bytes::Bytes;
     use interledger_packet::Address;
+    use interledger_service_util::MaxPacketAmountAccount;
     use std::str::FromStr;
     #[test]
     fn get_accounts() {
@@ -279,6 +441,30 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn query_by_client_certificate() {
+        let account = AccountBuilder::new(
+            Address::from_str("example.zero").unwrap(),
+            Username::from_str("zero").unwrap(),
+        )
+        .http_incoming_certificate([1; 32])
+        .build();
+        let store = InMemoryStore::from_accounts(vec![account]);
+        let certificate = |fingerprint| ClientCertificate {
+            fingerprint,
+            common_name: None,
+        };
+        let account = store
+            .get_account_from_client_certificate(&certificate([1; 32]))
+            .wait()
+            .unwrap();
+        assert_eq!(account.username(), &Username::from_str("zero").unwrap());
+        assert!(store
+            .get_account_from_client_certificate(&certificate([2; 32]))
+            .wait()
+            .is_err());
+    }
+
     #[test]
     fn query_by_btp() {
         let account = AccountBuilder::new(
@@ -318,8 +504,8 @@ mod tests {
         ]);
 
         assert_eq!(
-            store.routing_table(),
-            HashMap::from_iter(vec![
+            *store.routing_table(),
+            RoutingTable::from_iter(vec![
                 (Bytes::from("example.one"), 1),
                 (Bytes::from("example.two"), 2),
                 (Bytes::from("example.three"), 1)
@@ -327,6 +513,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn credits_stream_connections_up_to_receive_max() {
+        let store = InMemoryStore::default();
+        let (credited, totals) = store
+            .credit_connection("conn".to_string(), vec![(1, 60), (3, 40)], 150, None)
+            .wait()
+            .unwrap();
+        assert!(credited);
+        assert_eq!(totals.connection_total, 100);
+        assert_eq!(totals.stream_totals, vec![60, 40]);
+
+        let (credited, totals) = store
+            .credit_connection("conn".to_string(), vec![(1, 60)], 150, None)
+            .wait()
+            .unwrap();
+        assert!(!credited);
+        assert_eq!(totals.connection_total, 100);
+        assert_eq!(totals.stream_totals, vec![60]);
+    }
+
+    #[test]
+    fn gets_connection_totals_without_creating_connections() {
+        let store = InMemoryStore::default();
+        let totals = store
+            .get_connection_totals("conn".to_string())
+            .wait()
+            .unwrap();
+        assert_eq!(totals, ConnectionTotals::default());
+        assert!(store.stream_connections.lock().is_empty());
+
+        store
+            .credit_connection("conn".to_string(), vec![(1, 60)], 150, None)
+            .wait()
+            .unwrap();
+        let totals = store
+            .get_connection_totals("conn".to_string())
+            .wait()
+            .unwrap();
+        assert_eq!(totals.connection_total, 60);
+    }
+
     #[test]
     fn open_btp_signup() {
         let store = InMemoryStore::default();
@@ -337,9 +564,57 @@ mod tests {
                 ilp_address: &addr,
                 asset_code: "XYZ",
                 asset_scale: 9,
+                source_ip: [127, 0, 0, 1].into(),
+                min_balance: None,
+                max_packet_amount: None,
+                max_accounts: None,
+                max_accounts_per_ip: None,
             })
             .wait()
             .unwrap();
         assert_eq!(account.id(), 1);
     }
+
+    #[test]
+    fn limits_and_deletes_open_signup_accounts() {
+        let store = InMemoryStore::default();
+        let signup = |i: usize, ip: [u8; 4]| {
+            let addr = Address::from_str(&format!("example.account{}", i)).unwrap();
+            store
+                .create_btp_account(BtpOpenSignupAccount {
+                    auth_token: &format!("user{}:token", i),
+                    ilp_address: &addr,
+                    asset_code: "XYZ",
+                    asset_scale: 9,
+                    source_ip: ip.into(),
+                    min_balance: None,
+                    max_packet_amount: Some(100),
+                    max_accounts: Some(3),
+                    max_accounts_per_ip: Some(2),
+                })
+                .wait()
+        };
+        assert!(signup(0, [127, 0, 0, 1]).is_ok());
+        assert!(signup(1, [127, 0, 0, 1]).is_ok());
+        assert!(signup(2, [127, 0, 0, 1]).is_err());
+        assert!(signup(3, [10, 0, 0, 1]).is_ok());
+        assert!(signup(4, [10, 0, 0, 2]).is_err());
+
+        let accounts = store.get_open_signup_accounts().wait().unwrap();
+        assert_eq!(accounts.len(), 3);
+        assert!(accounts
+            .iter()
+            .all(|account| account.max_packet_amount() == 100));
+
+        assert!(store.delete_open_signup_account_if_empty(1).wait().unwrap());
+        assert!(!store.delete_open_signup_account_if_empty(1).wait().unwrap());
+        // Deleting an account makes room for another one
+        assert!(signup(5, [127, 0, 0, 1]).is_ok());
+        assert!(signup(6, [127, 0, 0, 1]).is_err());
+        assert!(store
+            .get_account_from_btp_auth(&Username::from_str("user0").unwrap(), "token")
+            .wait()
+            .is_err());
+        assert_eq!(store.routing_table().len(), 3);
+    }
 }
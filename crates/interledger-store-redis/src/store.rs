@@ -4,8 +4,14 @@
 //   next_account_id        string      unique ID for each new account
 //   rates:current          hash        exchange rates
 //   routes:current         hash        dynamic routing table
+//   routes:next_hops       hash        weighted next hops for prefixes that have more than one
 //   routes:static          hash        static routing table
 //   accounts:<id>          hash        information for each account
+//   http_certificates      hash        hex-encoded client certificate fingerprint -> account id
+//   btp_previous_token:<id> string     rotated-out incoming BTP token, expires after the grace period
+//   stream_connections:<id> hash       amounts received on each incoming STREAM connection, expires when idle (except for invoices)
+//   stream_connections_opened:<id> string  marks connections that have received packets, so expired ones can be told apart from new ones
+//   invoices:<id>          hash        invoices created through the API
 //   btp_outgoing
 // For interactive exploration of the store,
 // use the redis-cli tool included with your redis install.
@@ -16,7 +22,7 @@
 //    hgetall <key>         the flattened list of every key/value entry within a hash
 
 use super::account::*;
-use super::crypto::{generate_keys, DecryptionKey, EncryptionKey};
+use super::crypto::{decrypt_token, encrypt_token, generate_keys, DecryptionKey, EncryptionKey};
 use bytes::Bytes;
 use futures::{
     future::{err, ok, result, Either},
@@ -29,12 +35,15 @@ use super::account::AccountId;
 use http::StatusCode;
 use interledger_api::{AccountDetails, NodeStore};
 use interledger_btp::BtpStore;
-use interledger_ccp::RouteManagerStore;
-use interledger_http::HttpStore;
-use interledger_router::RouterStore;
+use interledger_ccp::{RouteManagerStore, RoutePolicy};
+use interledger_http::{ClientCertificate, HttpStore};
+use interledger_packet::Address;
+use interledger_router::{NextHop, RouterStore, RoutingTable};
 use interledger_service::{Account as AccountTrait, AccountStore, Username};
 use interledger_service_util::{BalanceStore, ExchangeRateStore, RateLimitError, RateLimitStore};
 use interledger_settlement::{IdempotentData, IdempotentStore, SettlementStore};
+use interledger_spsp::{invoice_receive_max, Invoice, InvoiceStore};
+use interledger_stream::{connection_tag, ConnectionTotals, StreamReceiverStore};
 use lazy_static::lazy_static;
 use parking_lot::RwLock;
 use redis::{
@@ -49,11 +58,14 @@ use std::{
 };
 use tokio_executor::spawn;
 use tokio_timer::Interval;
+use uuid::Uuid;
 
 use secrecy::{ExposeSecret, Secret};
 use zeroize::Zeroize;
 
 const DEFAULT_POLL_INTERVAL: u64 = 30000; // 30 seconds
+/// How long the amounts received on a STREAM connection are kept after the last packet on it
+const DEFAULT_STREAM_CONNECTION_EXPIRY: u64 = 7 * 24 * 60 * 60; // 7 days
 
 // The following are Lua scripts that are used to atomically execute the given logic
 // inside Redis. This allows for more complex logic without needing multiple round
@@ -68,6 +80,41 @@ lazy_static! {
     local id_key = 'accounts:' .. acc_id
     return redis.call('HGETALL', id_key)");
 
+    // Replaces an account's incoming BTP token. The previous token is kept under a
+    // separate key that expires after the grace period, so both are accepted until then.
+    static ref ROTATE_BTP_INCOMING_TOKEN: Script = Script::new("
+    local account = 'accounts:' .. ARGV[1]
+    if redis.call('EXISTS', account) == 0 then
+        return nil
+    end
+    local previous_key = 'btp_previous_token:' .. ARGV[1]
+    local previous = redis.call('HGET', account, 'btp_incoming_token')
+    if previous and tonumber(ARGV[3]) > 0 then
+        redis.call('SET', previous_key, previous, 'EX', ARGV[3])
+    else
+        redis.call('DEL', previous_key)
+    end
+    redis.call('HSET', account, 'btp_incoming_token', ARGV[2])
+    return redis.call('HGETALL', account)");
+
+    // Empty arguments remove the policy
+    static ref SET_ROUTING_POLICIES: Script = Script::new("
+    local account = 'accounts:' .. ARGV[1]
+    if redis.call('EXISTS', account) == 0 then
+        return nil
+    end
+    if ARGV[2] == '' then
+        redis.call('HDEL', account, 'routing_import_policy')
+    else
+        redis.call('HSET', account, 'routing_import_policy', ARGV[2])
+    end
+    if ARGV[3] == '' then
+        redis.call('HDEL', account, 'routing_export_policy')
+    else
+        redis.call('HSET', account, 'routing_export_policy', ARGV[3])
+    end
+    return redis.call('HGETALL', account)");
+
     static ref PROCESS_PREPARE: Script = Script::new("
     local from_id = ARGV[1]
     local from_account = 'accounts:' .. ARGV[1]
@@ -165,9 +212,97 @@ lazy_static! {
     end
 
     return balance + prepaid_amount");
+
+    // Credits the amounts received on each stream to a STREAM connection, as long as the
+    // connection's total stays within its receive max. The arguments after the connection
+    // id, receive max, source account and expiry (in seconds, 0 to keep the totals forever)
+    // are (stream_id, amount) pairs.
+    //
+    // Once a connection's totals expire there is no way to tell how much it has received,
+    // so the connection is reported as full rather than starting again from zero.
+    //
+    // Amounts are u64s, which neither Lua's numbers (doubles) nor HINCRBY (i64) can hold,
+    // so they are stored, added and compared as decimal strings.
+    static ref CREDIT_STREAM_CONNECTION: Script = Script::new("
+    local function add(a, b)
+        local sum = ''
+        local carry = 0
+        local i, j = #a, #b
+        while i > 0 or j > 0 or carry > 0 do
+            local digit = carry
+            if i > 0 then
+                digit = digit + string.byte(a, i) - 48
+                i = i - 1
+            end
+            if j > 0 then
+                digit = digit + string.byte(b, j) - 48
+                j = j - 1
+            end
+            sum = string.char(48 + digit % 10) .. sum
+            carry = math.floor(digit / 10)
+        end
+        return sum
+    end
+    local function less_or_equal(a, b)
+        if #a ~= #b then
+            return #a < #b
+        end
+        return a <= b
+    end
+
+    local connection = 'stream_connections:' .. ARGV[1]
+    local opened = 'stream_connections_opened:' .. ARGV[1]
+    local receive_max = ARGV[2]
+    local source_account = ARGV[3]
+    local expiry = tonumber(ARGV[4])
+
+    if expiry > 0 and redis.call('EXISTS', connection) == 0 and redis.call('EXISTS', opened) == 1 then
+        local stream_totals = {}
+        for i = 5, #ARGV, 2 do
+            table.insert(stream_totals, '0')
+        end
+        return {0, receive_max, '', stream_totals}
+    end
+
+    if source_account ~= '' then
+        redis.call('HSET', connection, 'source_account', source_account)
+    end
+
+    local total = redis.call('HGET', connection, 'total') or '0'
+    local amount = '0'
+    for i = 5, #ARGV, 2 do
+        amount = add(amount, ARGV[i + 1])
+    end
+
+    local credited = 0
+    local new_total = add(total, amount)
+    if less_or_equal(new_total, receive_max) and less_or_equal(new_total, '18446744073709551615') then
+        credited = 1
+        total = new_total
+        redis.call('HSET', connection, 'total', total)
+    end
+
+    local stream_totals = {}
+    for i = 5, #ARGV, 2 do
+        local stream = 'stream:' .. ARGV[i]
+        local stream_total = redis.call('HGET', connection, stream) or '0'
+        if credited == 1 then
+            stream_total = add(stream_total, ARGV[i + 1])
+            redis.call('HSET', connection, stream, stream_total)
+        end
+        table.insert(stream_totals, stream_total)
+    end
+
+    if expiry > 0 and redis.call('EXISTS', connection) == 1 then
+        redis.call('EXPIRE', connection, expiry)
+        redis.call('SET', opened, '1')
+    end
+
+    return {credited, total, redis.call('HGET', connection, 'source_account') or '', stream_totals}");
 }
 
 static ROUTES_KEY: &str = "routes:current";
+static NEXT_HOPS_KEY: &str = "routes:next_hops";
 static RATES_KEY: &str = "rates:current";
 static STATIC_ROUTES_KEY: &str = "routes:static";
 
@@ -179,10 +314,27 @@ fn accounts_key(account_id: AccountId) -> String {
     format!("accounts:{}", account_id)
 }
 
+fn previous_btp_token_key(account_id: AccountId) -> String {
+    format!("btp_previous_token:{}", account_id)
+}
+
+fn invoice_key(invoice_id: &str) -> String {
+    format!("invoices:{}", invoice_id)
+}
+
+/// Whether the STREAM connection with the given ID (its destination address) belongs to an invoice
+fn is_invoice_connection(connection_id: &str) -> bool {
+    Address::from_str(connection_id)
+        .ok()
+        .and_then(|address| connection_tag(&address).and_then(invoice_receive_max))
+        .is_some()
+}
+
 pub struct RedisStoreBuilder {
     redis_uri: ConnectionInfo,
     secret: [u8; 32],
     poll_interval: u64,
+    stream_connection_expiry: Option<u64>,
 }
 
 impl RedisStoreBuilder {
@@ -191,6 +343,7 @@ impl RedisStoreBuilder {
             redis_uri,
             secret,
             poll_interval: DEFAULT_POLL_INTERVAL,
+            stream_connection_expiry: Some(DEFAULT_STREAM_CONNECTION_EXPIRY),
         }
     }
 
@@ -199,10 +352,26 @@ impl RedisStoreBuilder {
         self
     }
 
+    /// How long, in seconds, the amounts received on a STREAM connection are kept after the
+    /// last packet on it (7 days by default). `None` keeps them forever.
+    ///
+    /// The totals are what the connection's `receive_max` is checked against, so expiring them
+    /// would reset the limit. Instead, packets for a connection whose totals have expired are
+    /// rejected as if the connection were full. A small marker is kept for every connection
+    /// so that expired connections can be told apart from new ones.
+    ///
+    /// The totals of invoices' connections are how much the invoices have been paid, so
+    /// they never expire, just like the invoices themselves.
+    pub fn stream_connection_expiry(&mut self, expiry: Option<u64>) -> &mut Self {
+        self.stream_connection_expiry = expiry;
+        self
+    }
+
     pub fn connect(&mut self) -> impl Future<Item = RedisStore, Error = ()> {
         let (encryption_key, decryption_key) = generate_keys(&self.secret[..]);
         self.secret.zeroize(); // clear the secret after it has been used for key generation
         let poll_interval = self.poll_interval;
+        let stream_connection_expiry = self.stream_connection_expiry;
 
         result(Client::open(self.redis_uri.clone()))
             .map_err(|err| error!("Error creating Redis client: {:?}", err))
@@ -216,9 +385,10 @@ impl RedisStoreBuilder {
                 let store = RedisStore {
                     connection: Arc::new(connection),
                     exchange_rates: Arc::new(RwLock::new(HashMap::new())),
-                    routes: Arc::new(RwLock::new(HashMap::new())),
+                    routes: Arc::new(RwLock::new(Arc::new(RoutingTable::new()))),
                     encryption_key: Arc::new(encryption_key),
                     decryption_key: Arc::new(decryption_key),
+                    stream_connection_expiry,
                 };
 
                 // Start polling for rate updates
@@ -278,9 +448,10 @@ impl RedisStoreBuilder {
 pub struct RedisStore {
     connection: Arc<SharedConnection>,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
-    routes: Arc<RwLock<HashMap<Bytes, AccountId>>>,
+    routes: Arc<RwLock<Arc<RoutingTable<AccountId>>>>,
     encryption_key: Arc<Secret<EncryptionKey>>,
     decryption_key: Arc<Secret<DecryptionKey>>,
+    stream_connection_expiry: Option<u64>,
 }
 
 impl RedisStore {
@@ -309,6 +480,9 @@ impl RedisStore {
                     let mut pipe = redis::pipe();
                     pipe.exists(accounts_key(account.id));
                     pipe.hexists("usernames", account.username().as_ref());
+                    if let Some(fingerprint) = account.http_incoming_certificate {
+                        pipe.hexists("http_certificates", hex::encode(fingerprint));
+                    }
 
                     pipe.query_async(connection.as_ref().clone())
                         .map_err(|err| {
@@ -335,6 +509,11 @@ impl RedisStore {
                     // Save map for Username -> Account ID
                     pipe.hset("usernames", account.username().as_ref(), id).ignore();
 
+                    // Save map for client certificate -> Account ID
+                    if let Some(fingerprint) = account.http_incoming_certificate {
+                        pipe.hset("http_certificates", hex::encode(fingerprint), id).ignore();
+                    }
+
                     // Set account details
                     pipe.cmd("HMSET").arg(accounts_key(account.id)).arg(account.clone().encrypt_tokens(&encryption_key.expose_secret().0))
                         .ignore();
@@ -406,6 +585,13 @@ impl RedisStore {
                     // Add the account key to the list of accounts
                     pipe.sadd("accounts", id).ignore();
 
+                    // Save map for client certificate -> Account ID. Entries for certificates
+                    // the account no longer uses are ignored when looking accounts up
+                    if let Some(fingerprint) = account.http_incoming_certificate {
+                        pipe.hset("http_certificates", hex::encode(fingerprint), id)
+                            .ignore();
+                    }
+
                     // Set account details
                     pipe.cmd("HMSET")
                         .arg(accounts_key(account.id))
@@ -472,7 +658,12 @@ impl RedisStore {
                     pipe.srem("accounts", account.id).ignore();
 
                     pipe.del(accounts_key(account.id)).ignore();
+                    pipe.del(previous_btp_token_key(account.id)).ignore();
                     pipe.hdel("usernames", account.username().as_ref()).ignore();
+                    if let Some(fingerprint) = account.http_incoming_certificate {
+                        pipe.hdel("http_certificates", hex::encode(fingerprint))
+                            .ignore();
+                    }
 
                     if account.send_routes {
                         pipe.srem("send_routes_to", account.id).ignore();
@@ -718,29 +909,56 @@ impl BtpStore for RedisStore {
         // TODO cache the result so we don't hit redis for every packet (is that
         // necessary if redis is often used as a cache?)
         let decryption_key = self.decryption_key.clone();
-        let token = token.to_owned();
+        let token = Bytes::from(token);
         Box::new(
             ACCOUNT_FROM_USERNAME
                 .arg(username.as_ref())
                 .invoke_async(self.connection.as_ref().clone())
                 .map_err(|err| error!("Error getting account from BTP token: {:?}", err))
                 .and_then(
-                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
+                    move |(connection, account): (
+                        SharedConnection,
+                        Option<AccountWithEncryptedTokens>,
+                    )| {
                         if let Some(account) = account {
                             let account = account.decrypt_tokens(&decryption_key.expose_secret().0);
-                            if let Some(t) = account.btp_incoming_token.clone() {
-                                let t = t.expose_secret().clone();
-                                if t == Bytes::from(token) {
-                                    Ok(account)
-                                } else {
-                                    Err(())
+                            if let Some(ref t) = account.btp_incoming_token {
+                                if *t.expose_secret() == token {
+                                    return Either::A(ok(account));
                                 }
-                            } else {
-                                Err(())
                             }
+                            // The peer may still be using a token that was rotated out
+                            // but is within its grace period
+                            Either::B(
+                                cmd("GET")
+                                    .arg(previous_btp_token_key(account.id))
+                                    .query_async(connection)
+                                    .map_err(|err| {
+                                        error!("Error getting previous BTP token: {:?}", err)
+                                    })
+                                    .and_then(
+                                        move |(_connection, previous): (_, Option<Vec<u8>>)| {
+                                            let previous = previous.and_then(|encrypted| {
+                                                decrypt_token(
+                                                    &decryption_key.expose_secret().0,
+                                                    &encrypted,
+                                                )
+                                            });
+                                            if previous == Some(token) {
+                                                debug!(
+                                                    "Account {} authenticated with its previous BTP token",
+                                                    account.id
+                                                );
+                                                Ok(account)
+                                            } else {
+                                                Err(())
+                                            }
+                                        },
+                                    ),
+                            )
                         } else {
                             warn!("No account found with BTP token");
-                            Err(())
+                            Either::A(err(()))
                         }
                     },
                 ),
@@ -837,10 +1055,37 @@ impl HttpStore for RedisStore {
                 ),
         )
     }
+
+    fn get_account_from_client_certificate(
+        &self,
+        certificate: &ClientCertificate,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        let fingerprint = certificate.fingerprint;
+        let self_clone = self.clone();
+        Box::new(
+            cmd("HGET")
+                .arg("http_certificates")
+                .arg(hex::encode(fingerprint))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error getting account from client certificate: {:?}", err))
+                .and_then(|(_connection, id): (_, Option<AccountId>)| {
+                    id.ok_or_else(|| warn!("No account found with given client certificate"))
+                })
+                .and_then(move |id| self_clone.redis_get_accounts(vec![id]))
+                .and_then(move |accounts| {
+                    // The certificate may have been replaced since it was added to the index
+                    accounts
+                        .into_iter()
+                        .next()
+                        .filter(|account| account.http_incoming_certificate == Some(fingerprint))
+                        .ok_or_else(|| warn!("No account found with given client certificate"))
+                }),
+        )
+    }
 }
 
 impl RouterStore for RedisStore {
-    fn routing_table(&self) -> HashMap<Bytes, <Self::Account as AccountTrait>::AccountId> {
+    fn routing_table(&self) -> Arc<RoutingTable<<Self::Account as AccountTrait>::AccountId>> {
         self.routes.read().clone()
     }
 }
@@ -867,6 +1112,84 @@ impl NodeStore for RedisStore {
         self.redis_update_account(id, account)
     }
 
+    fn rotate_btp_incoming_token(
+        &self,
+        id: AccountId,
+        token: String,
+        grace_period: Duration,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        let decryption_key = self.decryption_key.clone();
+        let encrypted = encrypt_token(&self.encryption_key.expose_secret().0, token.as_bytes());
+        Box::new(
+            ROTATE_BTP_INCOMING_TOKEN
+                .arg(id)
+                .arg(&encrypted[..])
+                .arg(grace_period.as_secs())
+                .invoke_async(self.connection.as_ref().clone())
+                .map_err(move |err| error!("Error rotating BTP token of account {}: {:?}", id, err))
+                .and_then(
+                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
+                        if let Some(account) = account {
+                            debug!("Rotated incoming BTP token of account {}", id);
+                            Ok(account.decrypt_tokens(&decryption_key.expose_secret().0))
+                        } else {
+                            warn!(
+                                "No account exists with ID {}, cannot rotate its BTP token",
+                                id
+                            );
+                            Err(())
+                        }
+                    },
+                ),
+        )
+    }
+
+    fn set_routing_policies(
+        &self,
+        id: AccountId,
+        import_policy: Option<RoutePolicy>,
+        export_policy: Option<RoutePolicy>,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        let decryption_key = self.decryption_key.clone();
+        Box::new(
+            SET_ROUTING_POLICIES
+                .arg(id)
+                .arg(
+                    import_policy
+                        .as_ref()
+                        .map(policy_to_json)
+                        .unwrap_or_default(),
+                )
+                .arg(
+                    export_policy
+                        .as_ref()
+                        .map(policy_to_json)
+                        .unwrap_or_default(),
+                )
+                .invoke_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error setting routing policies of account {}: {:?}",
+                        id, err
+                    )
+                })
+                .and_then(
+                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
+                        if let Some(account) = account {
+                            debug!("Set routing policies of account {}", id);
+                            Ok(account.decrypt_tokens(&decryption_key.expose_secret().0))
+                        } else {
+                            warn!(
+                                "No account exists with ID {}, cannot set its routing policies",
+                                id
+                            );
+                            Err(())
+                        }
+                    },
+                ),
+        )
+    }
+
     // TODO limit the number of results and page through them
     fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
         let decryption_key = self.decryption_key.clone();
@@ -993,7 +1316,7 @@ impl NodeStore for RedisStore {
     }
 }
 
-type RoutingTable<A> = HashMap<Bytes, A>;
+type RouteMap<A> = HashMap<Bytes, A>;
 
 impl RouteManagerStore for RedisStore {
     type Account = Account;
@@ -1103,8 +1426,7 @@ impl RouteManagerStore for RedisStore {
 
     fn get_local_and_configured_routes(
         &self,
-    ) -> Box<dyn Future<Item = (RoutingTable<Account>, RoutingTable<Account>), Error = ()> + Send>
-    {
+    ) -> Box<dyn Future<Item = (RouteMap<Account>, RouteMap<Account>), Error = ()> + Send> {
         let get_static_routes = cmd("HGETALL")
             .arg(STATIC_ROUTES_KEY)
             .query_async(self.connection.as_ref().clone())
@@ -1160,6 +1482,8 @@ impl RouteManagerStore for RedisStore {
         pipe.atomic()
             .del(ROUTES_KEY)
             .ignore()
+            .del(NEXT_HOPS_KEY)
+            .ignore()
             .hset_multiple(ROUTES_KEY, &routes)
             .ignore();
         Box::new(
@@ -1171,6 +1495,62 @@ impl RouteManagerStore for RedisStore {
                 }),
         )
     }
+
+    fn set_multipath_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<(Account, u32)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut best_routes: Vec<(String, AccountId)> = Vec::new();
+        let mut next_hops: Vec<(String, String)> = Vec::new();
+        for (prefix, prefix_next_hops) in routes {
+            let prefix = match String::from_utf8(prefix.to_vec()) {
+                Ok(prefix) => prefix,
+                Err(_) => continue,
+            };
+            let prefix_next_hops: Vec<(AccountId, u32)> = prefix_next_hops
+                .into_iter()
+                .map(|(account, weight)| (account.id, weight))
+                .collect();
+            if let Some((account_id, _weight)) = prefix_next_hops.first() {
+                best_routes.push((prefix.clone(), *account_id));
+            }
+            // The routes:current table is enough if there are no alternatives
+            if prefix_next_hops.len() > 1 {
+                match serde_json::to_string(&prefix_next_hops) {
+                    Ok(json) => next_hops.push((prefix, json)),
+                    Err(err) => error!("Error serializing next hops: {:?}", err),
+                }
+            }
+        }
+        let num_routes = best_routes.len();
+        let num_multipath_routes = next_hops.len();
+
+        // Save routes to Redis
+        let routing_table = self.routes.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(ROUTES_KEY)
+            .ignore()
+            .del(NEXT_HOPS_KEY)
+            .ignore()
+            .hset_multiple(ROUTES_KEY, &best_routes)
+            .ignore();
+        if !next_hops.is_empty() {
+            pipe.hset_multiple(NEXT_HOPS_KEY, &next_hops).ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error setting routes: {:?}", err))
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    trace!(
+                        "Saved {} routes to Redis, {} of which have multiple next hops",
+                        num_routes,
+                        num_multipath_routes
+                    );
+                    update_routes(connection, routing_table)
+                }),
+        )
+    }
 }
 
 impl RateLimitStore for RedisStore {
@@ -1336,6 +1716,165 @@ impl IdempotentStore for RedisStore {
     }
 }
 
+impl StreamReceiverStore for RedisStore {
+    fn credit_connection(
+        &self,
+        connection_id: String,
+        amounts: Vec<(u64, u64)>,
+        receive_max: u64,
+        source_account: Option<Address>,
+    ) -> Box<dyn Future<Item = (bool, ConnectionTotals), Error = ()> + Send> {
+        let mut script = CREDIT_STREAM_CONNECTION.prepare_invoke();
+        script
+            .arg(connection_id.as_str())
+            .arg(receive_max)
+            .arg(
+                source_account
+                    .map(|address| address.to_string())
+                    .unwrap_or_default(),
+            )
+            .arg(if is_invoice_connection(&connection_id) {
+                0
+            } else {
+                self.stream_connection_expiry.unwrap_or(0)
+            });
+        for (stream_id, amount) in amounts.iter() {
+            script.arg(*stream_id).arg(*amount);
+        }
+        Box::new(
+            script
+                .invoke_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error crediting STREAM connection {}: {:?}",
+                        connection_id, err
+                    )
+                })
+                .and_then(
+                    move |(_connection, (credited, connection_total, source_account, stream_totals)): (
+                        _,
+                        (bool, u64, String, Vec<u64>),
+                    )| {
+                        trace!(
+                            "Credited STREAM connection with amounts: {:?} (credited: {}, total: {})",
+                            amounts,
+                            credited,
+                            connection_total
+                        );
+                        Ok((
+                            credited,
+                            ConnectionTotals {
+                                connection_total,
+                                stream_totals,
+                                source_account: Address::from_str(&source_account).ok(),
+                            },
+                        ))
+                    },
+                ),
+        )
+    }
+
+    fn get_connection_totals(
+        &self,
+        connection_id: String,
+    ) -> Box<dyn Future<Item = ConnectionTotals, Error = ()> + Send> {
+        Box::new(
+            cmd("HMGET")
+                .arg(format!("stream_connections:{}", connection_id))
+                .arg(&["total", "source_account"])
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting totals for STREAM connection {}: {:?}",
+                        connection_id, err
+                    )
+                })
+                .and_then(
+                    |(_connection, (connection_total, source_account)): (
+                        _,
+                        (Option<u64>, Option<String>),
+                    )| {
+                        Ok(ConnectionTotals {
+                            connection_total: connection_total.unwrap_or(0),
+                            stream_totals: Vec::new(),
+                            source_account: source_account
+                                .and_then(|address| Address::from_str(&address).ok()),
+                        })
+                    },
+                ),
+        )
+    }
+}
+
+impl InvoiceStore for RedisStore {
+    fn create_invoice(
+        &self,
+        username: Username,
+        amount: u64,
+        expires_at: u64,
+    ) -> Box<dyn Future<Item = Invoice, Error = ()> + Send> {
+        let invoice = Invoice {
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            username,
+            amount,
+            expires_at,
+        };
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HMSET")
+            .arg(invoice_key(&invoice.id))
+            .arg("username")
+            .arg(invoice.username.to_string())
+            .arg("amount")
+            .arg(invoice.amount)
+            .arg("expires_at")
+            .arg(invoice.expires_at)
+            .ignore();
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error saving invoice: {:?}", err))
+                .and_then(move |(_connection, _): (_, Value)| {
+                    debug!("Created invoice: {:?}", invoice);
+                    Ok(invoice)
+                }),
+        )
+    }
+
+    fn get_invoice(&self, invoice_id: &str) -> Box<dyn Future<Item = Invoice, Error = ()> + Send> {
+        let invoice_id = invoice_id.to_string();
+        Box::new(
+            cmd("HGETALL")
+                .arg(invoice_key(&invoice_id))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error loading invoice: {:?}", err))
+                .and_then(move |(_connection, fields): (_, HashMap<String, String>)| {
+                    let username = fields
+                        .get("username")
+                        .and_then(|username| Username::from_str(username).ok());
+                    let amount = fields
+                        .get("amount")
+                        .and_then(|amount| u64::from_str(amount).ok());
+                    let expires_at = fields
+                        .get("expires_at")
+                        .and_then(|expires_at| u64::from_str(expires_at).ok());
+                    if let (Some(username), Some(amount), Some(expires_at)) =
+                        (username, amount, expires_at)
+                    {
+                        Ok(Invoice {
+                            id: invoice_id,
+                            username,
+                            amount,
+                            expires_at,
+                        })
+                    } else {
+                        debug!("Invoice not found: {}", invoice_id);
+                        Err(())
+                    }
+                }),
+        )
+    }
+}
+
 impl SettlementStore for RedisStore {
     type Account = Account;
 
@@ -1416,29 +1955,53 @@ type RouteVec = Vec<(String, AccountId)>;
 
 fn update_routes(
     connection: SharedConnection,
-    routing_table: Arc<RwLock<HashMap<Bytes, AccountId>>>,
+    routing_table: Arc<RwLock<Arc<RoutingTable<AccountId>>>>,
 ) -> impl Future<Item = (), Error = ()> {
     let mut pipe = redis::pipe();
-    pipe.hgetall(ROUTES_KEY).hgetall(STATIC_ROUTES_KEY);
+    pipe.hgetall(ROUTES_KEY)
+        .hgetall(NEXT_HOPS_KEY)
+        .hgetall(STATIC_ROUTES_KEY);
     pipe.query_async(connection)
         .map_err(|err| error!("Error polling for routing table updates: {:?}", err))
         .and_then(
-            move |(_connection, (routes, static_routes)): (_, (RouteVec, RouteVec))| {
+            move |(_connection, (routes, next_hops, static_routes)): (
+                _,
+                (RouteVec, Vec<(String, String)>, RouteVec),
+            )| {
                 trace!(
-                    "Loaded routes from redis. Static routes: {:?}, other routes: {:?}",
+                    "Loaded routes from redis. Static routes: {:?}, other routes: {:?}, next hops: {:?}",
                     static_routes,
-                    routes
+                    routes,
+                    next_hops
                 );
-                let routes = HashMap::from_iter(
+                let mut table = RoutingTable::from_iter(
                     routes
                         .into_iter()
-                        // Having the static_routes inserted after ensures that they will overwrite
-                        // any routes with the same prefix from the first set
-                        .chain(static_routes.into_iter())
                         .map(|(prefix, account_id)| (Bytes::from(prefix), account_id)),
                 );
-                trace!("Routing table is: {:?}", routes);
-                *routing_table.write() = routes;
+                for (prefix, json) in next_hops {
+                    match serde_json::from_str::<Vec<(AccountId, u32)>>(&json) {
+                        Ok(prefix_next_hops) => {
+                            table.insert(
+                                prefix.as_bytes(),
+                                prefix_next_hops
+                                    .into_iter()
+                                    .map(|(account_id, weight)| NextHop::new(account_id, weight))
+                                    .collect(),
+                            );
+                        }
+                        Err(err) => error!("Invalid next hops for prefix {}: {:?}", prefix, err),
+                    }
+                }
+                // Having the static_routes inserted after ensures that they will overwrite
+                // any routes with the same prefix from the first set
+                for (prefix, account_id) in static_routes {
+                    table.insert(prefix.as_bytes(), vec![NextHop::new(account_id, 1)]);
+                }
+                trace!("Routing table has {} prefixes", table.len());
+                // Swap in the new table. The Router keeps using the previous
+                // one for any packets it is already routing
+                *routing_table.write() = Arc::new(table);
                 Ok(())
             },
         )
@@ -1,12 +1,14 @@
 mod common;
 
 use common::*;
+use futures::future::join_all;
+use interledger_api::NodeStore;
 use interledger_btp::{BtpAccount, BtpStore};
 use interledger_http::HttpAccount;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
-use interledger_service::Username;
-use std::str::FromStr;
+use interledger_service::{Account as AccountTrait, AccountStore, Username};
+use std::{str::FromStr, time::Duration};
 
 #[test]
 fn gets_account_from_btp_auth() {
@@ -47,6 +49,29 @@ fn decrypts_outgoing_tokens_btp() {
     .unwrap()
 }
 
+#[test]
+fn stores_btp_outgoing_username() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let mut details = ACCOUNT_DETAILS_2.clone();
+        details.btp_uri = Some("btp+ws://:btp_password@example.com/btp".to_string());
+        details.btp_outgoing_username = Some(Username::from_str("charlie_remote").unwrap());
+        let store_clone = store.clone();
+        store
+            .insert_account(details)
+            .and_then(move |account| store_clone.get_accounts(vec![account.id()]))
+            .and_then(move |accounts| {
+                assert_eq!(
+                    accounts[0].get_btp_username(),
+                    Some(&Username::from_str("charlie_remote").unwrap())
+                );
+                assert_eq!(accounts[0].get_btp_token().unwrap(), b"btp_password");
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
 #[test]
 fn errors_on_unknown_btp_token() {
     let result = block_on(test_store().and_then(|(store, context, _accs)| {
@@ -62,3 +87,49 @@ fn errors_on_unknown_btp_token() {
     }));
     assert!(result.is_err());
 }
+
+#[test]
+fn accepts_previous_btp_token_during_grace_period() {
+    block_on(test_store().and_then(|(store, context, accs)| {
+        let bob_id = accs[1].id();
+        let bob = Username::from_str("bob").unwrap();
+        let bob_clone = bob.clone();
+        let store_clone = store.clone();
+        store
+            .rotate_btp_incoming_token(bob_id, "new_btp_token".to_string(), Duration::from_secs(60))
+            .and_then(move |_| {
+                join_all(vec![
+                    store.get_account_from_btp_auth(&bob, "new_btp_token"),
+                    store.get_account_from_btp_auth(&bob, "other_btp_token"),
+                ])
+                .and_then(move |accounts| {
+                    assert_eq!(accounts[0].id(), bob_id);
+                    assert_eq!(accounts[1].id(), bob_id);
+                    // Rotating without a grace period only accepts the new token
+                    store.rotate_btp_incoming_token(
+                        bob_id,
+                        "newest_btp_token".to_string(),
+                        Duration::from_secs(0),
+                    )
+                })
+            })
+            .and_then(move |_| {
+                let accepts = |token: &str| {
+                    store_clone
+                        .get_account_from_btp_auth(&bob_clone, token)
+                        .then(|result| Ok::<bool, ()>(result.is_ok()))
+                };
+                join_all(vec![
+                    accepts("newest_btp_token"),
+                    accepts("new_btp_token"),
+                    accepts("other_btp_token"),
+                ])
+            })
+            .and_then(move |accepted: Vec<bool>| {
+                assert_eq!(accepted, vec![true, false, false]);
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
@@ -1,12 +1,13 @@
 mod common;
 
 use common::*;
+use interledger_api::NodeStore;
 use interledger_btp::BtpAccount;
-use interledger_http::{HttpAccount, HttpStore};
+use interledger_http::{ClientCertificate, HttpAccount, HttpClientConfig, HttpStore};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
-use interledger_service::Username;
-use std::str::FromStr;
+use interledger_service::{Account as AccountTrait, AccountStore, Username};
+use std::{str::FromStr, time::Duration};
 
 #[test]
 fn gets_account_from_http_bearer_token() {
@@ -73,3 +74,71 @@ fn errors_on_unknown_http_auth() {
     }));
     assert!(result.is_err());
 }
+
+#[test]
+fn gets_account_from_client_certificate() {
+    let certificate = ClientCertificate::from_pem(include_bytes!(
+        "../../interledger-http/tests/fixtures/client.pem"
+    ))
+    .unwrap();
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.http_incoming_certificate = Some(hex::encode(certificate.fingerprint));
+    details.http_client_identity = Some(base64::encode(
+        &include_bytes!("../../interledger-http/tests/fixtures/client.p12")[..],
+    ));
+    details.http_client_identity_password = Some("test".to_string());
+    block_on(test_store().and_then(move |(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .insert_account(details)
+            .and_then(move |_| store_clone.get_account_from_client_certificate(&certificate))
+            .and_then(move |account| {
+                assert_eq!(
+                    *account.client_address(),
+                    Address::from_str("example.charlie").unwrap()
+                );
+                assert!(account.get_http_client_identity().is_some());
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn errors_on_unknown_client_certificate() {
+    let result = block_on(test_store().and_then(|(store, context, _accs)| {
+        store
+            .get_account_from_client_certificate(&ClientCertificate {
+                fingerprint: [0; 32],
+                common_name: None,
+            })
+            .then(move |result| {
+                let _ = context;
+                result
+            })
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn stores_http_client_config() {
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.http_client_http2_prior_knowledge = Some(true);
+    details.http_client_max_timeout = Some(5000);
+    block_on(test_store().and_then(move |(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .insert_account(details)
+            .and_then(move |account| store_clone.get_accounts(vec![account.id()]))
+            .and_then(move |accounts| {
+                let config = accounts[0].get_http_client_config().unwrap();
+                assert!(config.http2_prior_knowledge);
+                assert_eq!(config.max_timeout, Duration::from_secs(5));
+                assert_eq!(config.max_retries, HttpClientConfig::default().max_retries);
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
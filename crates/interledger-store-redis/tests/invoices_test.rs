@@ -0,0 +1,103 @@
+mod common;
+
+use bytes::Bytes;
+use common::*;
+use interledger_packet::Address;
+use interledger_service::Username;
+use interledger_spsp::{invoice_receive_max, InvoiceStore};
+use interledger_stream::{ConnectionGenerator, StreamReceiverStore};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_timer::sleep;
+
+#[test]
+fn saves_and_loads_invoices() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .create_invoice(Username::from_str("alice").unwrap(), 1000, 1_600_000_000)
+            .and_then(move |invoice| {
+                assert_eq!(invoice.amount, 1000);
+                store_clone
+                    .get_invoice(&invoice.id)
+                    .map(move |loaded| (invoice, loaded))
+            })
+            .and_then(move |(invoice, loaded)| {
+                assert_eq!(loaded, invoice);
+                assert_eq!(loaded.username, Username::from_str("alice").unwrap());
+                assert_eq!(loaded.expires_at, 1_600_000_000);
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn errors_for_unknown_invoices() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        store.get_invoice("unknown").then(move |result| {
+            assert!(result.is_err());
+            let _ = context;
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn keeps_invoice_totals_after_other_connections_expire() {
+    let context = TestContext::new();
+    block_on(
+        RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+            .stream_connection_expiry(Some(1))
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
+                store
+                    .create_invoice(Username::from_str("alice").unwrap(), 1000, u64::max_value())
+                    .and_then(move |invoice| {
+                        let (destination_account, _shared_secret) =
+                            ConnectionGenerator::new(Bytes::from(&[0; 32][..]))
+                                .generate_fixed_address_and_secret(
+                                    &Address::from_str("example.alice").unwrap(),
+                                    &invoice.connection_tag(),
+                                )
+                                .unwrap();
+                        let connection_id = destination_account.to_string();
+                        let receive_max = invoice_receive_max(&invoice.connection_tag()).unwrap();
+                        let store_clone_2 = store_clone.clone();
+                        let store_clone_3 = store_clone.clone();
+                        let connection_id_clone = connection_id.clone();
+                        let connection_id_clone_2 = connection_id.clone();
+                        store_clone
+                            .credit_connection(connection_id, vec![(1, 1000)], receive_max, None)
+                            .and_then(|(credited, _totals)| {
+                                assert!(credited);
+                                sleep(Duration::from_millis(1500)).map_err(|_| ())
+                            })
+                            .and_then(move |_| {
+                                store_clone_2.get_connection_totals(connection_id_clone)
+                            })
+                            .and_then(move |totals| {
+                                // The invoice is still paid
+                                assert_eq!(totals.connection_total, 1000);
+                                store_clone_3.credit_connection(
+                                    connection_id_clone_2,
+                                    vec![(1, 1000)],
+                                    receive_max,
+                                    None,
+                                )
+                            })
+                            .and_then(move |(credited, totals)| {
+                                assert!(!credited);
+                                assert_eq!(totals.connection_total, 1000);
+                                assert_eq!(totals.stream_totals, vec![1000]);
+                                let _ = context;
+                                Ok(())
+                            })
+                    })
+            }),
+    )
+    .unwrap();
+}
@@ -3,11 +3,11 @@ mod common;
 use bytes::Bytes;
 use common::*;
 use interledger_api::{AccountDetails, NodeStore};
-use interledger_ccp::RouteManagerStore;
+use interledger_ccp::{CcpRoutingAccount, RouteManagerStore, RoutePolicy};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
-use interledger_router::RouterStore;
-use interledger_service::{Account as AccountTrait, Username};
+use interledger_router::{NextHop, RouterStore};
+use interledger_service::{Account as AccountTrait, AccountStore, Username};
 use interledger_store_redis::AccountId;
 use std::str::FromStr;
 use std::{collections::HashMap, time::Duration};
@@ -32,7 +32,7 @@ fn polls_for_route_updates() {
                         let routing_table = store_clone_1.routing_table();
                         assert_eq!(routing_table.len(), 1);
                         assert_eq!(
-                            *routing_table.get(&Bytes::from("example.alice")).unwrap(),
+                            routing_table.get(b"example.alice").unwrap()[0].account_id,
                             alice.id()
                         );
                         store_clone_1
@@ -46,8 +46,16 @@ fn polls_for_route_updates() {
                                 http_endpoint: None,
                                 http_incoming_token: None,
                                 http_outgoing_token: None,
+                                http_incoming_certificate: None,
+                                http_client_identity: None,
+                                http_client_identity_password: None,
+                                http_client_max_idle_per_host: None,
+                                http_client_http2_prior_knowledge: None,
+                                http_client_max_timeout: None,
+                                http_client_max_retries: None,
                                 btp_uri: None,
                                 btp_incoming_token: None,
+                                btp_outgoing_username: None,
                                 settle_threshold: None,
                                 settle_to: None,
                                 send_routes: false,
@@ -62,7 +70,7 @@ fn polls_for_route_updates() {
                                 let routing_table = store_clone_2.routing_table();
                                 assert_eq!(routing_table.len(), 2);
                                 assert_eq!(
-                                    *routing_table.get(&Bytes::from("example.bob")).unwrap(),
+                                    routing_table.get(b"example.bob").unwrap()[0].account_id,
                                     bob.id(),
                                 );
                                 let alice_id = alice.id();
@@ -89,26 +97,21 @@ fn polls_for_route_updates() {
                                         let routing_table = store_clone_2.routing_table();
                                         assert_eq!(routing_table.len(), 3);
                                         assert_eq!(
-                                            *routing_table
-                                                .get(&Bytes::from("example.alice"))
-                                                .unwrap(),
+                                            routing_table.get(b"example.alice").unwrap()[0]
+                                                .account_id,
                                             bob_id
                                         );
                                         assert_eq!(
-                                            *routing_table
-                                                .get(&Bytes::from("example.bob"))
-                                                .unwrap(),
+                                            routing_table.get(b"example.bob").unwrap()[0]
+                                                .account_id,
                                             bob.id(),
                                         );
                                         assert_eq!(
-                                            *routing_table
-                                                .get(&Bytes::from("example.charlie"))
-                                                .unwrap(),
+                                            routing_table.get(b"example.charlie").unwrap()[0]
+                                                .account_id,
                                             alice_id,
                                         );
-                                        assert!(routing_table
-                                            .get(&Bytes::from("example.other"))
-                                            .is_none());
+                                        assert!(routing_table.get(b"example.other").is_none());
                                         let _ = context;
                                         Ok(())
                                     })
@@ -137,6 +140,44 @@ fn gets_accounts_to_send_routes_to() {
     .unwrap()
 }
 
+#[test]
+fn sets_and_removes_routing_policies() {
+    block_on(test_store().and_then(|(store, context, accs)| {
+        let id = accs[1].id();
+        let import_policy = RoutePolicy {
+            deny_prefixes: vec!["example.private".to_string()],
+            max_path_length: Some(3),
+            ..Default::default()
+        };
+        let export_policy = RoutePolicy {
+            valley_free: true,
+            ..Default::default()
+        };
+        let store_clone = store.clone();
+        store
+            .set_routing_policies(id, Some(import_policy.clone()), Some(export_policy.clone()))
+            .and_then(move |account| {
+                assert_eq!(account.routing_import_policy(), Some(&import_policy));
+                assert_eq!(account.routing_export_policy(), Some(&export_policy));
+                store_clone
+                    .get_accounts_to_send_routes_to()
+                    .and_then(move |accounts| {
+                        assert_eq!(accounts[0].routing_export_policy(), Some(&export_policy));
+                        store_clone
+                            .set_routing_policies(id, None, None)
+                            .and_then(move |_| store_clone.get_accounts(vec![id]))
+                    })
+            })
+            .and_then(move |accounts| {
+                assert!(accounts[0].routing_import_policy().is_none());
+                assert!(accounts[0].routing_export_policy().is_none());
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
 #[test]
 fn gets_accounts_to_receive_routes_from() {
     block_on(test_store().and_then(|(store, context, _accs)| {
@@ -223,9 +264,9 @@ fn updates_local_routes() {
             ])
             .and_then(move |_| {
                 let routes = store.routing_table();
-                assert_eq!(routes[&b"example.a"[..]], account0_id);
-                assert_eq!(routes[&b"example.b"[..]], account0_id);
-                assert_eq!(routes[&b"example.c"[..]], account1_id);
+                assert_eq!(routes.get(b"example.a").unwrap()[0].account_id, account0_id);
+                assert_eq!(routes.get(b"example.b").unwrap()[0].account_id, account0_id);
+                assert_eq!(routes.get(b"example.c").unwrap()[0].account_id, account1_id);
                 assert_eq!(routes.len(), 3);
                 Ok(())
             })
@@ -237,6 +278,50 @@ fn updates_local_routes() {
     .unwrap()
 }
 
+#[test]
+fn saves_multipath_routes() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let get_connection = context.async_connection();
+        let account0_id = AccountId::new();
+        let account1_id = AccountId::new();
+        let account0 = Account::try_from(account0_id, ACCOUNT_DETAILS_0.clone()).unwrap();
+        let account1 = Account::try_from(account1_id, ACCOUNT_DETAILS_1.clone()).unwrap();
+        store
+            .clone()
+            .set_multipath_routes(vec![
+                (
+                    Bytes::from("example.a"),
+                    vec![(account0.clone(), 1), (account1.clone(), 0)],
+                ),
+                (Bytes::from("example.b"), vec![(account1.clone(), 1)]),
+            ])
+            .and_then(move |_| {
+                let routes = store.routing_table();
+                assert_eq!(
+                    routes.get(b"example.a").unwrap(),
+                    &[NextHop::new(account0_id, 1), NextHop::new(account1_id, 0)][..]
+                );
+                assert_eq!(
+                    routes.get(b"example.b").unwrap(),
+                    &[NextHop::new(account1_id, 1)][..]
+                );
+                get_connection.and_then(move |connection| {
+                    redis::cmd("HKEYS")
+                        .arg("routes:next_hops")
+                        .query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(_conn, prefixes): (_, Vec<String>)| {
+                            // Prefixes with a single next hop are only in routes:current
+                            assert_eq!(prefixes, vec!["example.a".to_string()]);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+            })
+    }))
+    .unwrap()
+}
+
 #[test]
 fn adds_static_routes_to_redis() {
     block_on(test_store().and_then(|(store, context, accs)| {
@@ -289,9 +374,15 @@ fn static_routes_override_others() {
                     ])
                     .and_then(move |_| {
                         let routes = store.routing_table();
-                        assert_eq!(routes[&b"example.a"[..]], accs[0].id());
-                        assert_eq!(routes[&b"example.b"[..]], accs[0].id());
-                        assert_eq!(routes[&b"example.c"[..]], account1_id);
+                        assert_eq!(
+                            routes.get(b"example.a").unwrap()[0].account_id,
+                            accs[0].id()
+                        );
+                        assert_eq!(
+                            routes.get(b"example.b").unwrap()[0].account_id,
+                            accs[0].id()
+                        );
+                        assert_eq!(routes.get(b"example.c").unwrap()[0].account_id, account1_id);
                         assert_eq!(routes.len(), 3);
                         let _ = context;
                         Ok(())
@@ -0,0 +1,201 @@
+mod common;
+
+use common::*;
+use interledger_packet::Address;
+use interledger_stream::StreamReceiverStore;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_timer::sleep;
+
+#[test]
+fn credits_stream_connection() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .credit_connection(
+                "example.receiver.abc".to_string(),
+                vec![(1, 60), (3, 40)],
+                1000,
+                Some(Address::from_str("example.sender").unwrap()),
+            )
+            .and_then(move |(credited, totals)| {
+                assert!(credited);
+                assert_eq!(totals.connection_total, 100);
+                assert_eq!(totals.stream_totals, vec![60, 40]);
+                store_clone.credit_connection(
+                    "example.receiver.abc".to_string(),
+                    vec![(1, 50)],
+                    1000,
+                    None,
+                )
+            })
+            .and_then(move |(credited, totals)| {
+                assert!(credited);
+                assert_eq!(totals.connection_total, 150);
+                assert_eq!(totals.stream_totals, vec![110]);
+                assert_eq!(
+                    totals.source_account,
+                    Some(Address::from_str("example.sender").unwrap())
+                );
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn does_not_credit_more_than_receive_max() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .credit_connection(
+                "example.receiver.abc".to_string(),
+                vec![(1, 100)],
+                150,
+                None,
+            )
+            .and_then(move |_| {
+                store_clone.credit_connection(
+                    "example.receiver.abc".to_string(),
+                    vec![(1, 100)],
+                    150,
+                    None,
+                )
+            })
+            .and_then(move |(credited, totals)| {
+                assert!(!credited);
+                assert_eq!(totals.connection_total, 100);
+                assert_eq!(totals.stream_totals, vec![100]);
+                assert_eq!(totals.source_account, None);
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn gets_connection_totals() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let store_clone = store.clone();
+        store
+            .get_connection_totals("example.receiver.abc".to_string())
+            .and_then(move |totals| {
+                assert_eq!(totals.connection_total, 0);
+                assert_eq!(totals.source_account, None);
+                store_clone
+                    .credit_connection(
+                        "example.receiver.abc".to_string(),
+                        vec![(1, 100)],
+                        150,
+                        Some(Address::from_str("example.sender").unwrap()),
+                    )
+                    .and_then(move |_| {
+                        store_clone.get_connection_totals("example.receiver.abc".to_string())
+                    })
+            })
+            .and_then(move |totals| {
+                assert_eq!(totals.connection_total, 100);
+                assert!(totals.stream_totals.is_empty());
+                assert_eq!(
+                    totals.source_account,
+                    Some(Address::from_str("example.sender").unwrap())
+                );
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn credits_amounts_that_do_not_fit_in_an_i64() {
+    block_on(test_store().and_then(|(store, context, _accs)| {
+        let store_clone = store.clone();
+        let store_clone_2 = store.clone();
+        store
+            .credit_connection(
+                "example.receiver.abc".to_string(),
+                vec![(1, u64::max_value() - 1)],
+                u64::max_value(),
+                None,
+            )
+            .and_then(move |(credited, totals)| {
+                assert!(credited);
+                assert_eq!(totals.connection_total, u64::max_value() - 1);
+                store_clone.credit_connection(
+                    "example.receiver.abc".to_string(),
+                    vec![(1, 1)],
+                    u64::max_value(),
+                    None,
+                )
+            })
+            .and_then(move |(credited, totals)| {
+                assert!(credited);
+                assert_eq!(totals.connection_total, u64::max_value());
+                assert_eq!(totals.stream_totals, vec![u64::max_value()]);
+                // Anything more would overflow
+                store_clone_2.credit_connection(
+                    "example.receiver.abc".to_string(),
+                    vec![(1, 1)],
+                    u64::max_value(),
+                    None,
+                )
+            })
+            .and_then(move |(credited, totals)| {
+                assert!(!credited);
+                assert_eq!(totals.connection_total, u64::max_value());
+                assert_eq!(totals.stream_totals, vec![u64::max_value()]);
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn does_not_credit_connections_whose_totals_expired() {
+    let context = TestContext::new();
+    block_on(
+        RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+            .stream_connection_expiry(Some(1))
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
+                let store_clone_2 = store.clone();
+                store
+                    .credit_connection(
+                        "example.receiver.abc".to_string(),
+                        vec![(1, 100)],
+                        150,
+                        None,
+                    )
+                    .and_then(|(credited, _totals)| {
+                        assert!(credited);
+                        sleep(Duration::from_millis(1500)).map_err(|_| ())
+                    })
+                    .and_then(move |_| {
+                        store_clone.get_connection_totals("example.receiver.abc".to_string())
+                    })
+                    .and_then(move |totals| {
+                        assert_eq!(totals.connection_total, 0);
+                        // Starting again from zero would let the connection receive more than 150
+                        store_clone_2.credit_connection(
+                            "example.receiver.abc".to_string(),
+                            vec![(1, 100)],
+                            150,
+                            None,
+                        )
+                    })
+                    .and_then(move |(credited, totals)| {
+                        assert!(!credited);
+                        assert_eq!(totals.connection_total, 150);
+                        assert_eq!(totals.stream_totals, vec![0]);
+                        let _ = context;
+                        Ok(())
+                    })
+            }),
+    )
+    .unwrap();
+}
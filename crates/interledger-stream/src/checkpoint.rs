@@ -0,0 +1,321 @@
+use super::congestion::CongestionAlgorithm;
+use super::error::Error;
+use base64;
+use bytes::Bytes;
+use interledger_packet::Address;
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::{self, FromStr},
+};
+
+/// The state of a STREAM payment, saved as the payment progresses so that
+/// it can be resumed with `resume_money` if the sending process dies.
+///
+/// The checkpoint contains the shared secret, so it should be stored as carefully as
+/// the secret itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentCheckpoint {
+    /// The receiver's ILP address
+    pub destination_account: Address,
+    /// The STREAM shared secret for the connection
+    pub shared_secret: Bytes,
+    /// The next STREAM packet sequence number to use
+    pub sequence: u64,
+    /// The total amount the payment should send, in the sender's units
+    pub source_amount: u64,
+    /// The amount that has been sent (fulfilled) so far, in the sender's units
+    pub sent_amount: u64,
+    /// The amount in packets that had been sent but not yet fulfilled or rejected.
+    /// If the process died, some of it may have been delivered, so `resume_money`
+    /// counts it as sent rather than risk sending it twice
+    pub in_flight_amount: u64,
+    /// The amount the receiver reported receiving so far, in the receiver's units
+    pub delivered_amount: u64,
+    /// The payment pointer or SPSP URL the payment was set up for, if it was looked up via SPSP
+    pub receiver: Option<String>,
+    /// The congestion control algorithm the payment was started with, so that
+    /// `resume_money` continues it with the same kind of controller
+    pub congestion_control: CongestionAlgorithm,
+}
+
+impl PaymentCheckpoint {
+    /// Create a checkpoint for a payment that has not sent anything yet
+    pub fn new(destination_account: Address, shared_secret: Bytes, source_amount: u64) -> Self {
+        PaymentCheckpoint {
+            destination_account,
+            shared_secret,
+            sequence: 1,
+            source_amount,
+            sent_amount: 0,
+            in_flight_amount: 0,
+            delivered_amount: 0,
+            receiver: None,
+            congestion_control: CongestionAlgorithm::default(),
+        }
+    }
+
+    /// Whether the full source amount has been sent
+    pub fn is_complete(&self) -> bool {
+        self.sent_amount >= self.source_amount
+    }
+}
+
+/// Checkpoints are stored as `key=value` lines so they are easy to inspect
+impl fmt::Display for PaymentCheckpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "destination_account={}", self.destination_account)?;
+        writeln!(f, "shared_secret={}", base64::encode(&self.shared_secret))?;
+        writeln!(f, "sequence={}", self.sequence)?;
+        writeln!(f, "source_amount={}", self.source_amount)?;
+        writeln!(f, "sent_amount={}", self.sent_amount)?;
+        writeln!(f, "in_flight_amount={}", self.in_flight_amount)?;
+        writeln!(f, "delivered_amount={}", self.delivered_amount)?;
+        if let Some(ref receiver) = self.receiver {
+            writeln!(f, "receiver={}", receiver)?;
+        }
+        let congestion_control = match self.congestion_control {
+            CongestionAlgorithm::Aimd => "aimd",
+            CongestionAlgorithm::Probing => "probing",
+        };
+        writeln!(f, "congestion_control={}", congestion_control)?;
+        Ok(())
+    }
+}
+
+impl FromStr for PaymentCheckpoint {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut destination_account = None;
+        let mut shared_secret = None;
+        let mut sequence = None;
+        let mut source_amount = None;
+        let mut sent_amount = None;
+        let mut in_flight_amount = None;
+        let mut delivered_amount = None;
+        let mut receiver = None;
+        // Checkpoints saved before the algorithm was recorded used the default one
+        let mut congestion_control = CongestionAlgorithm::default();
+
+        for line in string.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(invalid(format!("Line is not a key=value pair: {}", line))),
+            };
+            match key {
+                "destination_account" => {
+                    destination_account = Some(
+                        Address::from_str(value)
+                            .map_err(|err| invalid(format!("Invalid address: {:?}", err)))?,
+                    )
+                }
+                "shared_secret" => {
+                    shared_secret =
+                        Some(Bytes::from(base64::decode(value).map_err(|err| {
+                            invalid(format!("Invalid shared secret: {:?}", err))
+                        })?))
+                }
+                "sequence" => sequence = Some(parse_u64(key, value)?),
+                "source_amount" => source_amount = Some(parse_u64(key, value)?),
+                "sent_amount" => sent_amount = Some(parse_u64(key, value)?),
+                "in_flight_amount" => in_flight_amount = Some(parse_u64(key, value)?),
+                "delivered_amount" => delivered_amount = Some(parse_u64(key, value)?),
+                "receiver" => receiver = Some(value.to_string()),
+                "congestion_control" => {
+                    congestion_control = match value {
+                        "aimd" => CongestionAlgorithm::Aimd,
+                        "probing" => CongestionAlgorithm::Probing,
+                        _ => {
+                            return Err(invalid(format!(
+                                "Unknown congestion control algorithm: {}",
+                                value
+                            )))
+                        }
+                    }
+                }
+                _ => return Err(invalid(format!("Unknown field: {}", key))),
+            }
+        }
+
+        Ok(PaymentCheckpoint {
+            destination_account: destination_account
+                .ok_or_else(|| invalid("Missing destination_account".to_string()))?,
+            shared_secret: shared_secret
+                .ok_or_else(|| invalid("Missing shared_secret".to_string()))?,
+            sequence: sequence.ok_or_else(|| invalid("Missing sequence".to_string()))?,
+            source_amount: source_amount
+                .ok_or_else(|| invalid("Missing source_amount".to_string()))?,
+            sent_amount: sent_amount.ok_or_else(|| invalid("Missing sent_amount".to_string()))?,
+            in_flight_amount: in_flight_amount
+                .ok_or_else(|| invalid("Missing in_flight_amount".to_string()))?,
+            delivered_amount: delivered_amount
+                .ok_or_else(|| invalid("Missing delivered_amount".to_string()))?,
+            receiver,
+            congestion_control,
+        })
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::CheckpointError(format!("Invalid checkpoint: {}", message))
+}
+
+fn parse_u64(key: &str, value: &str) -> Result<u64, Error> {
+    u64::from_str(value).map_err(|_| invalid(format!("Invalid {}: {}", key, value)))
+}
+
+/// Somewhere to persist payment checkpoints.
+///
+/// `save` is called with the latest state of the payment every time packets are
+/// fulfilled. If it returns an error, the payment is stopped, because continuing
+/// would send money that could not be accounted for after a restart.
+pub trait CheckpointSink: Send {
+    fn save(&mut self, checkpoint: &PaymentCheckpoint) -> Result<(), Error>;
+}
+
+/// A `CheckpointSink` that keeps the latest checkpoint in a file.
+///
+/// Each checkpoint is written to a temporary file next to the given path and then
+/// renamed over it, so the file always contains a complete checkpoint.
+#[derive(Clone, Debug)]
+pub struct FileCheckpointSink {
+    path: PathBuf,
+}
+
+impl FileCheckpointSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileCheckpointSink { path: path.into() }
+    }
+
+    /// Load the checkpoint saved in the file, if there is one
+    pub fn load(&self) -> Result<Option<PaymentCheckpoint>, Error> {
+        match fs::read(&self.path) {
+            Ok(contents) => str::from_utf8(&contents)
+                .map_err(|_| invalid("File is not valid UTF-8".to_string()))?
+                .parse()
+                .map(Some),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::CheckpointError(format!(
+                "Unable to read checkpoint file {}: {}",
+                self.path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// Delete the checkpoint file, for example once the payment is complete
+    pub fn remove(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(Error::CheckpointError(format!(
+                "Unable to remove checkpoint file {}: {}",
+                self.path.display(),
+                err
+            ))),
+        }
+    }
+}
+
+impl CheckpointSink for FileCheckpointSink {
+    fn save(&mut self, checkpoint: &PaymentCheckpoint) -> Result<(), Error> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(checkpoint.to_string().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|err| {
+                Error::CheckpointError(format!(
+                    "Unable to write checkpoint file {}: {}",
+                    self.path.display(),
+                    err
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn checkpoint() -> PaymentCheckpoint {
+        PaymentCheckpoint {
+            destination_account: Address::from_str("example.receiver.abc").unwrap(),
+            shared_secret: Bytes::from(&[7; 32][..]),
+            sequence: 12,
+            source_amount: 1000,
+            sent_amount: 600,
+            in_flight_amount: 100,
+            delivered_amount: 300,
+            receiver: Some("$example.com/alice".to_string()),
+            congestion_control: CongestionAlgorithm::Probing,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_string() {
+        let checkpoint = checkpoint();
+        assert_eq!(
+            PaymentCheckpoint::from_str(&checkpoint.to_string()).unwrap(),
+            checkpoint
+        );
+    }
+
+    #[test]
+    fn defaults_congestion_control_for_older_checkpoints() {
+        let string = checkpoint()
+            .to_string()
+            .replace("congestion_control=probing\n", "");
+        assert_eq!(
+            PaymentCheckpoint::from_str(&string)
+                .unwrap()
+                .congestion_control,
+            CongestionAlgorithm::Aimd
+        );
+        assert!(PaymentCheckpoint::from_str(
+            &checkpoint()
+                .to_string()
+                .replace("congestion_control=probing", "congestion_control=other")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_incomplete_checkpoints() {
+        let string = checkpoint().to_string().replace("sequence=12\n", "");
+        assert_eq!(
+            PaymentCheckpoint::from_str(&string),
+            Err(Error::CheckpointError(
+                "Invalid checkpoint: Missing sequence".to_string()
+            ))
+        );
+        assert!(PaymentCheckpoint::from_str("sequence=abc").is_err());
+    }
+
+    #[test]
+    fn saves_and_loads_file() {
+        let path = env::temp_dir().join(format!("interledger-stream-checkpoint-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut sink = FileCheckpointSink::new(path.clone());
+        assert_eq!(sink.load().unwrap(), None);
+
+        sink.save(&PaymentCheckpoint::new(
+            Address::from_str("example.receiver.abc").unwrap(),
+            Bytes::from(&[7; 32][..]),
+            1000,
+        ))
+        .unwrap();
+        sink.save(&checkpoint()).unwrap();
+        assert_eq!(sink.load().unwrap(), Some(checkpoint()));
+        sink.remove().unwrap();
+        assert_eq!(sink.load().unwrap(), None);
+    }
+}
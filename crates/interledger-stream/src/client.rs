@@ -1,33 +1,46 @@
-use super::congestion::CongestionController;
+use super::checkpoint::{CheckpointSink, PaymentCheckpoint};
+use super::congestion::{CongestionAlgorithm, CongestionControl};
 use super::crypto::*;
 use super::error::Error;
 use super::packet::*;
-use bytes::Bytes;
-use futures::{Async, Future, Poll};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{loop_fn, result, Loop},
+    Async, Future, Poll,
+};
 use interledger_ildcp::get_ildcp_info;
 use interledger_packet::{
-    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
-    PrepareBuilder, Reject,
+    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, MaxPacketAmountDetails,
+    PacketType as IlpPacketType, PrepareBuilder, Reject,
 };
 use interledger_service::*;
 use log::{debug, error, warn};
 use std::{
     cell::Cell,
-    cmp::min,
+    cmp::{max, min},
     str,
     time::{Duration, SystemTime},
 };
 
+/// The default amount the exchange rate of each packet may be below the quoted rate (1%)
+pub const DEFAULT_SLIPPAGE: f64 = 0.01;
+/// The largest amount that will be sent in a test packet to quote the exchange rate
+const MAX_QUOTE_AMOUNT: u64 = 1_000_000;
+const MAX_QUOTE_ATTEMPTS: u32 = 10;
+
 /// Send a given amount of money using the STREAM transport protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// This returns the amounts sent and delivered, along with the receiver's asset details
+/// (if it sent them) so the delivered amount can be displayed in the receiver's units.
+/// The given congestion control algorithm decides how much is sent at once.
 pub fn send_money<S, A>(
     service: S,
     from_account: &A,
     destination_account: Address,
     shared_secret: &[u8],
     source_amount: u64,
-) -> impl Future<Item = (u64, S), Error = Error>
+    congestion_control: CongestionAlgorithm,
+) -> impl Future<Item = (StreamDelivery, S), Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
@@ -37,22 +50,405 @@ where
     // TODO can/should we avoid cloning the account?
     get_ildcp_info(&mut service.clone(), from_account.clone())
         .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info: {:?}".to_string()))
-        .and_then(move |account_details| SendMoneyFuture {
-            state: SendMoneyFutureState::SendMoney,
-            next: Some(service),
-            from_account,
-            source_account: account_details.client_address(),
-            destination_account,
-            shared_secret,
+        .and_then(move |account_details| {
+            SendMoneyFuture::new(
+                service,
+                from_account,
+                account_details.client_address(),
+                destination_account,
+                shared_secret,
+                source_amount,
+                congestion_control.controller(account_details.asset_scale()),
+            )
+        })
+}
+
+/// Send money like `send_money`, saving a checkpoint of the payment's progress to
+/// the given sink before each packet is sent and whenever packets are fulfilled or rejected.
+///
+/// If the process dies mid-payment, the last checkpoint can be passed to `resume_money`
+/// to send the rest of the amount over the same STREAM connection.
+pub fn send_money_with_checkpoints<S, A, C>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    source_amount: u64,
+    congestion_control: CongestionAlgorithm,
+    sink: C,
+) -> impl Future<Item = (StreamDelivery, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+    C: CheckpointSink + 'static,
+{
+    let mut checkpoint = PaymentCheckpoint::new(
+        destination_account,
+        Bytes::from(shared_secret),
+        source_amount,
+    );
+    checkpoint.congestion_control = congestion_control;
+    resume_money(service, from_account, checkpoint, sink)
+}
+
+/// Continue a payment from a checkpoint saved by `send_money_with_checkpoints`.
+///
+/// This sends whatever is left of the checkpoint's source amount and keeps saving
+/// checkpoints to the given sink. The amounts in the returned `StreamDelivery` include
+/// what was sent before the checkpoint was saved.
+///
+/// Packets that were in flight when the checkpoint was saved may or may not have been
+/// delivered, so their amount is counted as sent and is not sent again. This means the
+/// payment may deliver less than the full amount, but it never sends more.
+pub fn resume_money<S, A, C>(
+    service: S,
+    from_account: &A,
+    checkpoint: PaymentCheckpoint,
+    mut sink: C,
+) -> impl Future<Item = (StreamDelivery, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+    C: CheckpointSink + 'static,
+{
+    let from_account = from_account.clone();
+    // Make sure the sink works before sending anything
+    result(sink.save(&checkpoint))
+        .and_then(move |_| {
+            get_ildcp_info(&mut service.clone(), from_account.clone())
+                .map_err(|_err| {
+                    Error::ConnectionError("Unable to get ILDCP info: {:?}".to_string())
+                })
+                .map(move |account_details| (account_details, service, from_account))
+        })
+        .and_then(move |(account_details, service, from_account)| {
+            debug!(
+                "Resuming payment to {} at sequence {} (sent {} of {})",
+                checkpoint.destination_account,
+                checkpoint.sequence,
+                checkpoint.sent_amount,
+                checkpoint.source_amount
+            );
+            if checkpoint.in_flight_amount > 0 {
+                warn!(
+                    "Checkpoint has {} in flight that may or may not have been delivered. Counting it as sent",
+                    checkpoint.in_flight_amount
+                );
+            }
+            let sent_amount = checkpoint
+                .sent_amount
+                .saturating_add(checkpoint.in_flight_amount);
+            let mut future = SendMoneyFuture::new(
+                service,
+                from_account,
+                account_details.client_address(),
+                checkpoint.destination_account,
+                checkpoint.shared_secret,
+                checkpoint.source_amount.saturating_sub(sent_amount),
+                checkpoint
+                    .congestion_control
+                    .controller(account_details.asset_scale()),
+            );
+            future.sequence = checkpoint.sequence;
+            future.sent_amount = sent_amount;
+            future.delivered_amount = checkpoint.delivered_amount;
+            future.checkpoint_sink = Some(Box::new(sink));
+            future.checkpoint_receiver = checkpoint.receiver;
+            future.checkpoint_congestion_control = checkpoint.congestion_control;
+            future
+        })
+}
+
+/// Options for sending money with `send_money_with_quote`.
+///
+/// Exchange rates are expressed as the number of the receiver's units delivered
+/// for each of the sender's units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SendMoneyOptions {
+    /// The maximum amount to send, in the sender's units
+    pub source_amount: u64,
+    /// The amount to deliver, in the receiver's units. If this is not set,
+    /// the full `source_amount` will be sent
+    pub destination_amount: Option<u64>,
+    /// The lowest acceptable exchange rate. The payment fails without sending
+    /// any money if the quoted rate is below this
+    pub min_exchange_rate: Option<f64>,
+    /// How far below the quoted exchange rate each packet is allowed to be (0.01 is 1%)
+    pub slippage: f64,
+    /// The congestion control algorithm that decides how much to send at once
+    pub congestion_control: CongestionAlgorithm,
+}
+
+impl SendMoneyOptions {
+    pub fn new(source_amount: u64) -> Self {
+        SendMoneyOptions {
             source_amount,
-            congestion_controller: CongestionController::default(),
-            pending_requests: Cell::new(Vec::new()),
-            delivered_amount: 0,
-            should_send_source_account: true,
-            sequence: 1,
-            rejected_packets: 0,
-            error: None,
+            destination_amount: None,
+            min_exchange_rate: None,
+            slippage: DEFAULT_SLIPPAGE,
+            congestion_control: CongestionAlgorithm::default(),
+        }
+    }
+}
+
+/// The outcome of a payment sent with `send_money` or `send_money_with_quote`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamDelivery {
+    /// The amount sent, in the sender's units
+    pub sent_amount: u64,
+    /// The amount delivered, as reported by the receiver and in the receiver's units
+    pub delivered_amount: u64,
+    /// The exchange rate discovered by the quote (0 if the payment was not quoted)
+    pub quoted_exchange_rate: f64,
+    /// The receiver's asset code, if the receiver sent its asset details
+    pub destination_asset_code: Option<String>,
+    /// The receiver's asset scale, if the receiver sent its asset details
+    pub destination_asset_scale: Option<u8>,
+}
+
+/// Check the receiver's response for frames that mean no more money can be sent on the given stream
+pub(crate) fn receiver_error(packet: &StreamPacket, stream_id: u64) -> Option<Error> {
+    for frame in packet.frames() {
+        match frame {
+            Frame::ConnectionClose(frame) => {
+                return Some(Error::RemoteClosed {
+                    code: frame.code,
+                    message: frame.message.to_string(),
+                });
+            }
+            Frame::StreamClose(ref frame) if frame.stream_id == stream_id => {
+                return Some(Error::RemoteClosed {
+                    code: frame.code.clone(),
+                    message: frame.message.to_string(),
+                });
+            }
+            Frame::StreamMaxMoney(ref frame) if frame.stream_id == stream_id => {
+                // The packet is only rejected for being too big if it does not fit
+                let remaining = frame.receive_max.saturating_sub(frame.total_received);
+                if packet.ilp_packet_type() == IlpPacketType::Reject
+                    && remaining < packet.prepare_amount()
+                {
+                    return Some(Error::ReceiverFull(format!(
+                        "Receiver can only accept {} more (it has received {} of {})",
+                        remaining, frame.total_received, frame.receive_max
+                    )));
+                }
+            }
+            Frame::StreamMoneyBlocked(ref frame) => {
+                // We do not accept incoming money, so there is nothing we can do about it
+                debug!(
+                    "Receiver is blocked sending money on stream {}",
+                    frame.stream_id
+                );
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Apply the connection details the receiver included in a response:
+/// its asset details and, if it has moved, its new address.
+pub(crate) fn handle_connection_frames(
+    packet: &StreamPacket,
+    destination_asset: &mut Option<(String, u8)>,
+    destination_account: &mut Address,
+) {
+    for frame in packet.frames() {
+        match frame {
+            Frame::ConnectionAssetDetails(frame) => {
+                if destination_asset.is_none() {
+                    debug!(
+                        "Receiver's asset is {} with scale {}",
+                        frame.source_asset_code, frame.source_asset_scale
+                    );
+                }
+                *destination_asset = Some((
+                    frame.source_asset_code.to_string(),
+                    frame.source_asset_scale,
+                ));
+            }
+            Frame::ConnectionNewAddress(frame) if frame.source_account != *destination_account => {
+                debug!("Receiver's address changed to: {}", frame.source_account);
+                *destination_account = frame.source_account;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Send money using the STREAM transport protocol, enforcing a minimum exchange rate.
+///
+/// Before sending any money, this quotes the exchange rate by sending unfulfillable test packets
+/// to the receiver. Every packet then tells the receiver the minimum amount it should accept,
+/// based on the quoted rate minus the allowed slippage, so that the intermediaries cannot take
+/// more than expected. If a `destination_amount` is given, this only sends as much as is needed
+/// to deliver that amount (it may deliver slightly more if the rate improves mid-payment).
+pub fn send_money_with_quote<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    options: SendMoneyOptions,
+) -> impl Future<Item = (StreamDelivery, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    let shared_secret = Bytes::from(shared_secret);
+    let from_account = from_account.clone();
+    get_ildcp_info(&mut service.clone(), from_account.clone())
+        .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info: {:?}".to_string()))
+        .and_then(move |account_details| {
+            quote_exchange_rate(
+                service,
+                from_account.clone(),
+                destination_account.clone(),
+                shared_secret.clone(),
+                options.source_amount,
+            )
+            .and_then(move |(quoted_exchange_rate, next_sequence, service)| {
+                let min_exchange_rate = options.min_exchange_rate.unwrap_or(0.0);
+                if quoted_exchange_rate < min_exchange_rate {
+                    return Err(Error::ExchangeRateTooLow(format!(
+                        "Quoted exchange rate of {} is below the minimum of {}",
+                        quoted_exchange_rate, min_exchange_rate
+                    )));
+                }
+                if options.destination_amount.is_some() && quoted_exchange_rate <= 0.0 {
+                    return Err(Error::SendMoneyError(
+                        "Unable to deliver a fixed amount because the quoted exchange rate is zero"
+                            .to_string(),
+                    ));
+                }
+                debug!(
+                    "Quoted exchange rate: {} (minimum acceptable: {})",
+                    quoted_exchange_rate, min_exchange_rate
+                );
+
+                let mut future = SendMoneyFuture::new(
+                    service,
+                    from_account,
+                    account_details.client_address(),
+                    destination_account,
+                    shared_secret,
+                    options.source_amount,
+                    options
+                        .congestion_control
+                        .controller(account_details.asset_scale()),
+                );
+                future.sequence = next_sequence;
+                future.quoted_exchange_rate = quoted_exchange_rate;
+                future.min_exchange_rate =
+                    min_exchange_rate.max(quoted_exchange_rate * (1.0 - options.slippage.max(0.0)));
+                future.destination_amount = options.destination_amount;
+                Ok(future)
+            })
         })
+        .flatten()
+}
+
+/// Determine the exchange rate to the receiver by sending test packets with a
+/// random condition, which the receiver will reject and tell us how much arrived.
+///
+/// This returns the exchange rate along with the next STREAM sequence number to use.
+fn quote_exchange_rate<S, A>(
+    service: S,
+    from_account: A,
+    destination_account: Address,
+    shared_secret: Bytes,
+    source_amount: u64,
+) -> impl Future<Item = (f64, u64, S), Error = Error>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    let test_amount = max(min(source_amount, MAX_QUOTE_AMOUNT), 1);
+    loop_fn(
+        (service, 1, test_amount),
+        move |(mut service, sequence, amount): (S, u64, u64)| {
+            let stream_packet = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &[Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                })],
+            }
+            .build();
+            let data = stream_packet.into_encrypted(&shared_secret);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount,
+                execution_condition: &random_condition(),
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &data[..],
+            }
+            .build();
+
+            debug!(
+                "Sending test packet {} with amount {} to quote the exchange rate",
+                sequence, amount
+            );
+            let shared_secret = shared_secret.clone();
+            service
+                .handle_request(IncomingRequest {
+                    from: from_account.clone(),
+                    prepare,
+                })
+                .then(move |result| {
+                    let reject = match result {
+                        Ok(_) => {
+                            return Err(Error::SendMoneyError(
+                                "Test packet was unexpectedly fulfilled".to_string(),
+                            ))
+                        }
+                        Err(reject) => reject,
+                    };
+                    let give_up = sequence >= u64::from(MAX_QUOTE_ATTEMPTS);
+
+                    match (reject.code().class(), reject.code()) {
+                        (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
+                            if let Ok(packet) = StreamPacket::from_encrypted(
+                                &shared_secret,
+                                BytesMut::from(reject.data()),
+                            ) {
+                                if packet.ilp_packet_type() == IlpPacketType::Reject {
+                                    let rate = packet.prepare_amount() as f64 / amount as f64;
+                                    return Ok(Loop::Break((rate, sequence + 1, service)));
+                                }
+                            }
+                        }
+                        (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) if !give_up => {
+                            if let Ok(details) = MaxPacketAmountDetails::from_bytes(reject.data()) {
+                                let max_amount = u128::from(amount)
+                                    * u128::from(details.max_amount())
+                                    / u128::from(max(details.amount_received(), 1));
+                                let next_amount = max(min(max_amount as u64, amount - 1), 1);
+                                return Ok(Loop::Continue((service, sequence + 1, next_amount)));
+                            }
+                        }
+                        (ErrorClass::Temporary, _) if !give_up => {
+                            let next_amount = max(amount / 2, 1);
+                            return Ok(Loop::Continue((service, sequence + 1, next_amount)));
+                        }
+                        _ => {}
+                    }
+
+                    let message = format!(
+                        "Unable to quote exchange rate. Test packet was rejected with error: {} {}",
+                        reject.code(),
+                        str::from_utf8(reject.message()).unwrap_or_default(),
+                    );
+                    if reject.code() == IlpErrorCode::F02_UNREACHABLE {
+                        Err(Error::Unreachable(message))
+                    } else {
+                        Err(Error::SendMoneyError(message))
+                    }
+                })
+        },
+    )
 }
 
 struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
@@ -63,18 +459,27 @@ struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
     destination_account: Address,
     shared_secret: Bytes,
     source_amount: u64,
-    congestion_controller: CongestionController,
+    destination_amount: Option<u64>,
+    quoted_exchange_rate: f64,
+    min_exchange_rate: f64,
+    congestion_controller: Box<dyn CongestionControl>,
     pending_requests: Cell<Vec<PendingRequest>>,
+    sent_amount: u64,
     delivered_amount: u64,
+    destination_asset: Option<(String, u8)>,
     should_send_source_account: bool,
     sequence: u64,
     rejected_packets: u64,
     error: Option<Error>,
+    checkpoint_sink: Option<Box<dyn CheckpointSink>>,
+    checkpoint_receiver: Option<String>,
+    checkpoint_congestion_control: CongestionAlgorithm,
 }
 
 struct PendingRequest {
     sequence: u64,
     amount: u64,
+    min_destination_amount: u64,
     future: BoxedIlpFuture,
 }
 
@@ -91,19 +496,72 @@ where
     S: IncomingService<A>,
     A: Account,
 {
+    fn new(
+        service: S,
+        from_account: A,
+        source_account: Address,
+        destination_account: Address,
+        shared_secret: Bytes,
+        source_amount: u64,
+        congestion_controller: Box<dyn CongestionControl>,
+    ) -> Self {
+        SendMoneyFuture {
+            state: SendMoneyFutureState::SendMoney,
+            next: Some(service),
+            from_account,
+            source_account,
+            destination_account,
+            shared_secret,
+            source_amount,
+            destination_amount: None,
+            quoted_exchange_rate: 0.0,
+            min_exchange_rate: 0.0,
+            congestion_controller,
+            pending_requests: Cell::new(Vec::new()),
+            sent_amount: 0,
+            delivered_amount: 0,
+            destination_asset: None,
+            should_send_source_account: true,
+            sequence: 1,
+            rejected_packets: 0,
+            error: None,
+            checkpoint_sink: None,
+            checkpoint_receiver: None,
+            checkpoint_congestion_control: CongestionAlgorithm::default(),
+        }
+    }
+
     fn try_send_money(&mut self) -> Result<bool, Error> {
         // Fire off requests until the congestion controller tells us to stop or we've sent the total amount
         let mut sent_packets = false;
         loop {
             // Determine the amount to send
-            let amount = min(
+            let mut amount = min(
                 self.source_amount,
                 self.congestion_controller.get_max_amount(),
             );
+            if let Some(destination_amount) = self.destination_amount {
+                // Only send what we expect is needed to deliver the rest of the destination amount
+                let amount_in_flight: u64 = self
+                    .pending_requests
+                    .get_mut()
+                    .iter()
+                    .map(|request| request.amount)
+                    .sum();
+                let expected_delivery = self.delivered_amount as f64
+                    + amount_in_flight as f64 * self.quoted_exchange_rate;
+                let amount_left_to_deliver = destination_amount as f64 - expected_delivery;
+                if amount_left_to_deliver <= 0.0 {
+                    break;
+                }
+                let amount_needed = (amount_left_to_deliver / self.quoted_exchange_rate).ceil();
+                amount = min(amount, amount_needed as u64);
+            }
             if amount == 0 {
                 break;
             }
             self.source_amount -= amount;
+            let min_destination_amount = (amount as f64 * self.min_exchange_rate).floor() as u64;
 
             // Load up the STREAM packet
             let sequence = self.next_sequence();
@@ -118,8 +576,7 @@ where
             }
             let stream_packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
-                // TODO enforce min exchange rate
-                prepare_amount: 0,
+                prepare_amount: min_destination_amount,
                 sequence,
                 frames: &frames,
             }
@@ -127,8 +584,8 @@ where
 
             // Create the ILP Prepare packet
             debug!(
-                "Sending packet {} with amount: {} and encrypted STREAM packet: {:?}",
-                sequence, amount, stream_packet
+                "Sending packet {} with amount: {} (minimum destination amount: {}) and encrypted STREAM packet: {:?}",
+                sequence, amount, min_destination_amount, stream_packet
             );
             let data = stream_packet.into_encrypted(&self.shared_secret);
             let execution_condition = generate_condition(&self.shared_secret, &data);
@@ -142,6 +599,9 @@ where
             }
             .build();
 
+            // Record the packet as in flight before sending it so a resumed payment can't send it twice
+            self.save_checkpoint(amount)?;
+
             // Send it!
             self.congestion_controller.prepare(amount);
             if let Some(ref mut next) = self.next {
@@ -152,6 +612,7 @@ where
                 self.pending_requests.get_mut().push(PendingRequest {
                     sequence,
                     amount,
+                    min_destination_amount,
                     future: Box::new(send_request),
                 });
                 sent_packets = true;
@@ -195,6 +656,7 @@ where
             self.pending_requests.get_mut().push(PendingRequest {
                 sequence,
                 amount: 0,
+                min_destination_amount: 0,
                 future: Box::new(send_request),
             });
         } else {
@@ -205,22 +667,36 @@ where
 
     fn poll_pending_requests(&mut self) -> Poll<(), Error> {
         let pending_requests = self.pending_requests.take();
+        let mut finished_packets = false;
         let pending_requests = pending_requests
             .into_iter()
             .filter_map(|mut pending_request| match pending_request.future.poll() {
                 Ok(Async::NotReady) => Some(pending_request),
                 Ok(Async::Ready(fulfill)) => {
                     self.handle_fulfill(pending_request.sequence, pending_request.amount, fulfill);
+                    finished_packets = true;
                     None
                 }
                 Err(reject) => {
-                    self.handle_reject(pending_request.sequence, pending_request.amount, reject);
+                    self.handle_reject(
+                        pending_request.sequence,
+                        pending_request.amount,
+                        pending_request.min_destination_amount,
+                        reject,
+                    );
+                    finished_packets = true;
                     None
                 }
             })
             .collect();
         self.pending_requests.set(pending_requests);
 
+        if finished_packets {
+            if let Err(err) = self.save_checkpoint(0) {
+                self.error = Some(err);
+            }
+        }
+
         if let Some(error) = self.error.take() {
             error!("Send money stopped because of error: {:?}", error);
             Err(error)
@@ -235,11 +711,20 @@ where
         // TODO should we check the fulfillment and expiry or can we assume the plugin does that?
         self.congestion_controller.fulfill(amount);
         self.should_send_source_account = false;
+        self.sent_amount += amount;
 
         if let Ok(packet) = StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
             if packet.ilp_packet_type() == IlpPacketType::Fulfill {
                 // TODO check that the sequence matches our outgoing packet
                 self.delivered_amount += packet.prepare_amount();
+                handle_connection_frames(
+                    &packet,
+                    &mut self.destination_asset,
+                    &mut self.destination_account,
+                );
+                if let Some(error) = receiver_error(&packet, 1) {
+                    self.error = Some(error);
+                }
             }
         } else {
             warn!(
@@ -254,7 +739,13 @@ where
         );
     }
 
-    fn handle_reject(&mut self, sequence: u64, amount: u64, reject: Reject) {
+    fn handle_reject(
+        &mut self,
+        sequence: u64,
+        amount: u64,
+        min_destination_amount: u64,
+        reject: Reject,
+    ) {
         self.source_amount += amount;
         self.congestion_controller.reject(amount, &reject);
         self.rejected_packets += 1;
@@ -272,7 +763,44 @@ where
                 // Handled by the congestion controller
             }
             (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
-                // TODO handle STREAM errors
+                match StreamPacket::from_encrypted(
+                    &self.shared_secret,
+                    BytesMut::from(reject.data()),
+                ) {
+                    Ok(packet) => {
+                        handle_connection_frames(
+                            &packet,
+                            &mut self.destination_asset,
+                            &mut self.destination_account,
+                        );
+                        if let Some(error) = receiver_error(&packet, 1) {
+                            self.error = Some(error);
+                        } else if packet.prepare_amount() < min_destination_amount {
+                            // If the receiver got less than the minimum we asked for, the exchange
+                            // rate has dropped too far since we quoted it
+                            self.error = Some(Error::ExchangeRateTooLow(format!(
+                                "Exchange rate dropped below the minimum. Receiver got {} when we required {}",
+                                packet.prepare_amount(),
+                                min_destination_amount,
+                            )));
+                        }
+                    }
+                    Err(_) => {
+                        // The reject did not come from the receiver
+                        self.error = Some(Error::SendMoneyError(format!(
+                            "Packet was rejected with error: {} {}",
+                            reject.code(),
+                            str::from_utf8(reject.message()).unwrap_or_default(),
+                        )));
+                    }
+                }
+            }
+            (_, IlpErrorCode::F02_UNREACHABLE) => {
+                self.error = Some(Error::Unreachable(format!(
+                    "Packet was rejected with error: {} {}",
+                    reject.code(),
+                    str::from_utf8(reject.message()).unwrap_or_default(),
+                )));
             }
             _ => {
                 self.error = Some(Error::SendMoneyError(format!(
@@ -284,6 +812,33 @@ where
         }
     }
 
+    /// Save the payment's progress, counting `sending_amount` as in flight
+    /// along with the packets that are still pending
+    fn save_checkpoint(&mut self, sending_amount: u64) -> Result<(), Error> {
+        if let Some(ref mut sink) = self.checkpoint_sink {
+            let amount_in_flight: u64 = self
+                .pending_requests
+                .get_mut()
+                .iter()
+                .map(|request| request.amount)
+                .sum::<u64>()
+                + sending_amount;
+            sink.save(&PaymentCheckpoint {
+                destination_account: self.destination_account.clone(),
+                shared_secret: self.shared_secret.clone(),
+                sequence: self.sequence,
+                source_amount: self.sent_amount + amount_in_flight + self.source_amount,
+                sent_amount: self.sent_amount,
+                in_flight_amount: amount_in_flight,
+                delivered_amount: self.delivered_amount,
+                receiver: self.checkpoint_receiver.clone(),
+                congestion_control: self.checkpoint_congestion_control,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
     fn next_sequence(&mut self) -> u64 {
         let seq = self.sequence;
         self.sequence += 1;
@@ -296,7 +851,7 @@ where
     S: IncomingService<A>,
     A: Account,
 {
-    type Item = (u64, S);
+    type Item = (StreamDelivery, S);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -304,7 +859,13 @@ where
         loop {
             self.poll_pending_requests()?;
 
-            if self.source_amount == 0 && self.pending_requests.get_mut().is_empty() {
+            let is_delivered = self
+                .destination_amount
+                .map(|destination_amount| self.delivered_amount >= destination_amount)
+                .unwrap_or(false);
+            if (self.source_amount == 0 || is_delivered)
+                && self.pending_requests.get_mut().is_empty()
+            {
                 if self.state == SendMoneyFutureState::SendMoney {
                     self.state = SendMoneyFutureState::Closing;
                     self.try_send_connection_close()?;
@@ -313,10 +874,29 @@ where
                     debug!(
                         "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)", self.delivered_amount, self.sequence - 1, self.rejected_packets,
                     );
-                    return Ok(Async::Ready((
-                        self.delivered_amount,
-                        self.next.take().unwrap(),
-                    )));
+                    if let Some(destination_amount) = self.destination_amount {
+                        if !is_delivered {
+                            return Err(Error::SendMoneyError(format!(
+                                "Only delivered {} of the destination amount {} before running out of money to send",
+                                self.delivered_amount, destination_amount
+                            )));
+                        }
+                    }
+                    let (destination_asset_code, destination_asset_scale) = match self
+                        .destination_asset
+                        .take()
+                    {
+                        Some((asset_code, asset_scale)) => (Some(asset_code), Some(asset_scale)),
+                        None => (None, None),
+                    };
+                    let delivery = StreamDelivery {
+                        sent_amount: self.sent_amount,
+                        delivered_amount: self.delivered_amount,
+                        quoted_exchange_rate: self.quoted_exchange_rate,
+                        destination_asset_code,
+                        destination_asset_scale,
+                    };
+                    return Ok(Async::Ready((delivery, self.next.take().unwrap())));
                 }
             } else if !self.try_send_money()? {
                 return Ok(Async::NotReady);
@@ -361,9 +941,68 @@ mod send_money_tests {
             Address::from_str("example.destination").unwrap(),
             &[0; 32][..],
             100,
+            CongestionAlgorithm::default(),
         )
         .wait();
         assert!(result.is_err());
         assert_eq!(requests.lock().len(), 1);
     }
+
+    #[test]
+    fn distinguishes_unreachable_receivers() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let result = send_money(
+            IldcpService::new(incoming_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F02_UNREACHABLE,
+                    message: b"no route found",
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                    data: &[],
+                }
+                .build())
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            100,
+            CongestionAlgorithm::default(),
+        )
+        .wait();
+        match result {
+            Err(Error::Unreachable(_)) => {}
+            result => panic!("Expected Unreachable error, got: {:?}", result.map(|r| r.0)),
+        }
+    }
+
+    #[test]
+    fn applies_receiver_connection_details() {
+        let packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Fulfill,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: "EUR",
+                    source_asset_scale: 2,
+                }),
+                Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                    source_account: Address::from_str("example.receiver.moved").unwrap(),
+                }),
+            ],
+        }
+        .build();
+        let mut destination_asset = None;
+        let mut destination_account = Address::from_str("example.receiver").unwrap();
+        handle_connection_frames(&packet, &mut destination_asset, &mut destination_account);
+        assert_eq!(destination_asset, Some(("EUR".to_string(), 2)));
+        assert_eq!(
+            destination_account,
+            Address::from_str("example.receiver.moved").unwrap()
+        );
+    }
 }
@@ -10,11 +10,57 @@ use std::cmp::{max, min};
 #[cfg(feature = "metrics_csv")]
 use std::io;
 
+/// Decides how much money can be in flight at once when sending a STREAM payment.
+///
+/// The sender asks for the maximum amount it can send before each packet, and tells the
+/// controller about every packet it sends and whether it was fulfilled or rejected.
+pub trait CongestionControl: Send {
+    /// The most that can be sent in the next packet
+    fn get_max_amount(&mut self) -> u64;
+
+    /// Called when a packet with the given amount is sent
+    fn prepare(&mut self, amount: u64);
+
+    /// Called when a packet with the given amount is fulfilled
+    fn fulfill(&mut self, prepare_amount: u64);
+
+    /// Called when a packet with the given amount is rejected
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject);
+}
+
+/// The congestion control algorithms that can be used for sending money
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CongestionAlgorithm {
+    /// Additive Increase, Multiplicative Decrease with a fixed increase amount
+    /// (see `CongestionController`)
+    Aimd,
+    /// Exponential probing that scales its amounts to the asset scale and the
+    /// max packet amount (see `ProbingCongestionController`)
+    Probing,
+}
+
+impl Default for CongestionAlgorithm {
+    fn default() -> Self {
+        CongestionAlgorithm::Aimd
+    }
+}
+
+impl CongestionAlgorithm {
+    /// Create a controller for sending money in an asset with the given scale
+    pub fn controller(self, asset_scale: u8) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Aimd => Box::new(CongestionController::default()),
+            CongestionAlgorithm::Probing => Box::new(ProbingCongestionController::new(asset_scale)),
+        }
+    }
+}
+
 /// A basic congestion controller that implements an
 /// Additive Increase, Multiplicative Decrease (AIMD) algorithm.
 ///
-/// Future implementations of this will use more advanced congestion
-/// control algorithms.
+/// The window grows by a fixed amount, so it can take many round trips to
+/// send large amounts of assets with small units. `ProbingCongestionController`
+/// is better suited for those.
 pub struct CongestionController {
     state: CongestionState,
     increase_amount: u64,
@@ -59,7 +105,26 @@ impl CongestionController {
         Self::new(1000, 1000, 2.0)
     }
 
-    pub fn get_max_amount(&mut self) -> u64 {
+    #[cfg(test)]
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
+        self.max_packet_amount = Some(max_packet_amount)
+    }
+
+    #[cfg(feature = "metrics_csv")]
+    fn log_stats(&mut self, amount_sent: u64) {
+        self.csv_writer
+            .write_record(&[
+                format!("{}", Utc::now().timestamp_millis()),
+                format!("{}", self.max_in_flight),
+                format!("{}", amount_sent),
+            ])
+            .unwrap();
+        self.csv_writer.flush().unwrap();
+    }
+}
+
+impl CongestionControl for CongestionController {
+    fn get_max_amount(&mut self) -> u64 {
         let amount_left_in_window = self.max_in_flight - self.amount_in_flight;
         if let Some(max_packet_amount) = self.max_packet_amount {
             min(amount_left_in_window, max_packet_amount)
@@ -68,7 +133,7 @@ impl CongestionController {
         }
     }
 
-    pub fn prepare(&mut self, amount: u64) {
+    fn prepare(&mut self, amount: u64) {
         if amount > 0 {
             self.amount_in_flight += amount;
             debug!(
@@ -78,7 +143,7 @@ impl CongestionController {
         }
     }
 
-    pub fn fulfill(&mut self, prepare_amount: u64) {
+    fn fulfill(&mut self, prepare_amount: u64) {
         self.amount_in_flight -= prepare_amount;
 
         // Before we know how much we should be sending at a time,
@@ -113,7 +178,7 @@ impl CongestionController {
         self.log_stats(prepare_amount);
     }
 
-    pub fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
         self.amount_in_flight -= prepare_amount;
 
         match reject.code() {
@@ -148,22 +213,110 @@ impl CongestionController {
             }
         }
     }
+}
 
-    #[cfg(test)]
-    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
-        self.max_packet_amount = Some(max_packet_amount)
+/// A congestion controller that probes exponentially for the available capacity
+/// and scales its amounts to the asset being sent.
+///
+/// It starts with a hundredth of one whole unit of the asset (based on the asset scale)
+/// and doubles the window on every fulfilled packet until the path runs out of liquidity.
+/// After that, it halves the window on each T04 error and grows it by one max packet
+/// amount per fulfilled packet (or a quarter of the window if the max packet amount
+/// is unknown), so large payments need far fewer round trips than with a fixed increase.
+pub struct ProbingCongestionController {
+    state: CongestionState,
+    min_increase_amount: u64,
+    max_packet_amount: Option<u64>,
+    amount_in_flight: u64,
+    max_in_flight: u64,
+}
+
+impl ProbingCongestionController {
+    pub fn new(asset_scale: u8) -> Self {
+        let one_unit = 10u64
+            .checked_pow(u32::from(asset_scale))
+            .unwrap_or_else(u64::max_value);
+        let start_amount = max(one_unit / 100, 1);
+        ProbingCongestionController {
+            state: CongestionState::SlowStart,
+            min_increase_amount: start_amount,
+            max_packet_amount: None,
+            amount_in_flight: 0,
+            max_in_flight: start_amount,
+        }
     }
 
-    #[cfg(feature = "metrics_csv")]
-    fn log_stats(&mut self, amount_sent: u64) {
-        self.csv_writer
-            .write_record(&[
-                format!("{}", Utc::now().timestamp_millis()),
-                format!("{}", self.max_in_flight),
-                format!("{}", amount_sent),
-            ])
-            .unwrap();
-        self.csv_writer.flush().unwrap();
+    fn increase_amount(&self) -> u64 {
+        let increase = match self.max_packet_amount {
+            Some(max_packet_amount) => max_packet_amount,
+            None => self.max_in_flight / 4,
+        };
+        max(increase, self.min_increase_amount)
+    }
+}
+
+impl CongestionControl for ProbingCongestionController {
+    fn get_max_amount(&mut self) -> u64 {
+        let amount_left_in_window = self.max_in_flight.saturating_sub(self.amount_in_flight);
+        if let Some(max_packet_amount) = self.max_packet_amount {
+            min(amount_left_in_window, max_packet_amount)
+        } else {
+            amount_left_in_window
+        }
+    }
+
+    fn prepare(&mut self, amount: u64) {
+        self.amount_in_flight += amount;
+    }
+
+    fn fulfill(&mut self, prepare_amount: u64) {
+        self.amount_in_flight -= prepare_amount;
+        self.max_in_flight = if self.state == CongestionState::SlowStart {
+            self.max_in_flight.saturating_mul(2)
+        } else {
+            self.max_in_flight.saturating_add(self.increase_amount())
+        };
+        debug!(
+            "Fulfilled packet of {}, increasing max in flight to: {}",
+            prepare_amount, self.max_in_flight
+        );
+    }
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+        self.amount_in_flight -= prepare_amount;
+
+        match reject.code() {
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY => {
+                self.state = CongestionState::AvoidCongestion;
+                self.max_in_flight = max(self.max_in_flight / 2, 1);
+                debug!(
+                    "Rejected packet with T04 error, decreasing max in flight to: {}",
+                    self.max_in_flight
+                );
+            }
+            ErrorCode::F08_AMOUNT_TOO_LARGE => {
+                let new_max_packet_amount = if let Ok(details) =
+                    MaxPacketAmountDetails::from_bytes(reject.data())
+                {
+                    (u128::from(prepare_amount) * u128::from(details.max_amount())
+                        / u128::from(max(details.amount_received(), 1))) as u64
+                } else {
+                    // Without the details, all we know is that the packet was too big
+                    prepare_amount / 2
+                };
+                let new_max_packet_amount = max(new_max_packet_amount, 1);
+                self.max_packet_amount = Some(
+                    self.max_packet_amount
+                        .map(|max_packet_amount| min(max_packet_amount, new_max_packet_amount))
+                        .unwrap_or(new_max_packet_amount),
+                );
+                debug!(
+                    "Rejected packet with F08 error, max packet amount is now: {:?}",
+                    self.max_packet_amount
+                );
+            }
+            _ => {}
+        }
     }
 }
 
@@ -348,4 +501,92 @@ mod tests {
             assert_eq!(controller.get_max_amount(), 1000 - 600 - 100);
         }
     }
+
+    mod probing {
+        use super::*;
+        use interledger_packet::RejectBuilder;
+
+        lazy_static! {
+            static ref INSUFFICIENT_LIQUIDITY_ERROR: Reject = RejectBuilder {
+                code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build();
+        }
+
+        #[test]
+        fn scales_start_amount_to_asset_scale() {
+            assert_eq!(
+                ProbingCongestionController::new(9).get_max_amount(),
+                10_000_000
+            );
+            assert_eq!(ProbingCongestionController::new(2).get_max_amount(), 1);
+            assert_eq!(ProbingCongestionController::new(0).get_max_amount(), 1);
+        }
+
+        #[test]
+        fn doubles_until_t04_then_increases_by_max_packet_amount() {
+            let mut controller = ProbingCongestionController::new(4);
+            assert_eq!(controller.get_max_amount(), 100);
+            controller.prepare(100);
+            controller.fulfill(100);
+            assert_eq!(controller.get_max_amount(), 200);
+            controller.prepare(200);
+            controller.fulfill(200);
+            assert_eq!(controller.get_max_amount(), 400);
+
+            controller.prepare(400);
+            controller.reject(400, &*INSUFFICIENT_LIQUIDITY_ERROR);
+            assert_eq!(controller.get_max_amount(), 200);
+
+            // Without a known max packet amount, it grows by a quarter of the window
+            // (but never by less than the starting amount)
+            controller.prepare(200);
+            controller.fulfill(200);
+            assert_eq!(controller.get_max_amount(), 300);
+            controller.max_in_flight = 800;
+            controller.prepare(200);
+            controller.fulfill(200);
+            assert_eq!(controller.get_max_amount(), 1000);
+
+            controller.max_packet_amount = Some(100);
+            controller.prepare(100);
+            controller.fulfill(100);
+            assert_eq!(controller.max_in_flight, 1100);
+            assert_eq!(controller.get_max_amount(), 100);
+        }
+
+        #[test]
+        fn reduces_max_packet_amount_on_f08() {
+            let mut controller = ProbingCongestionController::new(9);
+            controller.prepare(10_000_000);
+            controller.reject(
+                10_000_000,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &MaxPacketAmountDetails::new(1000, 100).to_bytes(),
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_amount(), 1_000_000);
+
+            // Without details, it halves the packet amount
+            controller.prepare(1_000_000);
+            controller.reject(
+                1_000_000,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_amount(), 500_000);
+        }
+    }
 }
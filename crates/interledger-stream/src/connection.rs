@@ -0,0 +1,566 @@
+use super::client::handle_connection_frames;
+use super::congestion::{CongestionAlgorithm, CongestionControl};
+use super::crypto::*;
+use super::data::{DataStream, DataStreams, OutgoingFrames, Side, MAX_DATA_PER_PACKET};
+use super::error::Error;
+use super::money::{MoneyStream, MoneyStreams};
+use super::packet::*;
+use bytes::{Bytes, BytesMut};
+use futures::{task, Async, Future, Poll};
+use interledger_ildcp::get_ildcp_info;
+use interledger_packet::{
+    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
+    PrepareBuilder, Reject,
+};
+use interledger_service::*;
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use std::{
+    mem, str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_executor::spawn;
+use tokio_timer::Delay;
+
+/// How often to check if the receiver has data for us when we have nothing to send.
+/// (The receiver can only send data back in response to our packets)
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How many packets with money the receiver may reject in a row before we stop
+/// sending on the streams in those packets
+const MAX_RECEIVER_REJECTS: u32 = 5;
+
+/// Open a long-lived STREAM connection to the given receiver.
+///
+/// Any number of money and data streams can be opened on the connection, and money can be
+/// sent on them for as long as the connection is open. The connection is driven by a task
+/// spawned on the default executor, so this must be called from within a Tokio runtime.
+/// The connection stays open until `close` is called or all of the `StreamConnection`
+/// handles and the streams opened on it are dropped.
+/// The given congestion control algorithm decides how much is sent at once
+/// across all of the connection's money streams.
+pub fn connect<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    congestion_control: CongestionAlgorithm,
+) -> impl Future<Item = StreamConnection, Error = Error>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    let shared_secret = Bytes::from(shared_secret);
+    let from_account = from_account.clone();
+    get_ildcp_info(&mut service.clone(), from_account.clone())
+        .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info".to_string()))
+        .map(move |account_details| {
+            let data = DataStreams::new(Side::Client);
+            let money = MoneyStreams::default();
+            let closing = Arc::new(AtomicBool::new(false));
+            let destination_asset = Arc::new(Mutex::new(None));
+            let driver = ConnectionDriver {
+                service,
+                from_account,
+                source_account: account_details.client_address(),
+                destination_account,
+                destination_asset: destination_asset.clone(),
+                shared_secret,
+                data: data.clone(),
+                money: money.clone(),
+                closing: closing.clone(),
+                congestion_controller: congestion_control.controller(account_details.asset_scale()),
+                sequence: 1,
+                should_send_source_account: true,
+                pending: Vec::new(),
+                poll_delay: None,
+                retry_delay: None,
+                receiver_rejects: 0,
+                close_sent: false,
+                remote_closed: false,
+                error: None,
+            };
+            spawn(driver);
+            StreamConnection {
+                data,
+                money,
+                closing,
+                destination_asset,
+            }
+        })
+}
+
+/// A client-side STREAM connection that money and data streams can be opened on.
+#[derive(Clone)]
+pub struct StreamConnection {
+    data: DataStreams,
+    money: MoneyStreams,
+    closing: Arc<AtomicBool>,
+    destination_asset: Arc<Mutex<Option<(String, u8)>>>,
+}
+
+impl StreamConnection {
+    /// Open a new bidirectional data stream to the receiver
+    pub fn open_stream(&self) -> DataStream {
+        self.data.open_stream()
+    }
+
+    /// Open a new stream for sending money to the receiver
+    pub fn open_money_stream(&self) -> MoneyStream {
+        MoneyStream::new(
+            self.data.next_stream_id(),
+            self.money.clone(),
+            self.data.clone(),
+            self.closing.clone(),
+        )
+    }
+
+    /// The receiver's asset code, once the receiver has told us what it is
+    pub fn destination_asset_code(&self) -> Option<String> {
+        self.destination_asset
+            .lock()
+            .as_ref()
+            .map(|(asset_code, _)| asset_code.clone())
+    }
+
+    /// The receiver's asset scale, once the receiver has told us what it is
+    pub fn destination_asset_scale(&self) -> Option<u8> {
+        self.destination_asset
+            .lock()
+            .as_ref()
+            .map(|(_, asset_scale)| *asset_scale)
+    }
+
+    /// Close the connection once all of the data written to the streams
+    /// and all of the money requested has been sent
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.data.close_for_writing();
+        self.money.close_all();
+        self.data.notify_connection();
+    }
+}
+
+/// A Prepare packet that is waiting for a response
+struct PendingPacket {
+    sequence: u64,
+    amount: u64,
+    /// The amounts sent on each money stream
+    money: Vec<(u64, u64)>,
+    /// The money streams closed by this packet
+    money_closes: Vec<u64>,
+    data: OutgoingFrames,
+    future: BoxedIlpFuture,
+}
+
+/// Future that sends the connection's packets until it is closed.
+///
+/// Multiple packets carrying money can be in flight at once (as many as the congestion
+/// controller allows) but only one carries data at any time, so that the data can be
+/// put back into the queue if the packet is rejected before reaching the receiver.
+struct ConnectionDriver<S, A> {
+    service: S,
+    from_account: A,
+    source_account: Address,
+    destination_account: Address,
+    destination_asset: Arc<Mutex<Option<(String, u8)>>>,
+    shared_secret: Bytes,
+    data: DataStreams,
+    money: MoneyStreams,
+    closing: Arc<AtomicBool>,
+    congestion_controller: Box<dyn CongestionControl>,
+    sequence: u64,
+    should_send_source_account: bool,
+    pending: Vec<PendingPacket>,
+    poll_delay: Option<Delay>,
+    retry_delay: Option<Delay>,
+    receiver_rejects: u32,
+    close_sent: bool,
+    remote_closed: bool,
+    error: Option<Error>,
+}
+
+impl<S, A> ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    fn send_packet(
+        &mut self,
+        money: Vec<(u64, u64)>,
+        money_closes: Vec<u64>,
+        data: OutgoingFrames,
+        close_connection: Option<(ErrorCode, String)>,
+    ) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let amount: u64 = money.iter().map(|(_, amount)| amount).sum();
+
+        let mut frames: Vec<Frame> = money
+            .iter()
+            .map(|(stream_id, amount)| {
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: *stream_id,
+                    shares: *amount,
+                })
+            })
+            .collect();
+        frames.extend(data.frames());
+        frames.extend(money_closes.iter().map(|stream_id| {
+            Frame::StreamClose(StreamCloseFrame {
+                stream_id: *stream_id,
+                code: ErrorCode::NoError,
+                message: "",
+            })
+        }));
+        if self.should_send_source_account {
+            frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: self.source_account.clone(),
+            }));
+        }
+        if let Some((ref code, ref message)) = close_connection {
+            frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                code: code.clone(),
+                message,
+            }));
+        }
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence,
+            frames: &frames,
+        }
+        .build();
+        debug!(
+            "Sending packet {} with amount {}: {:?}",
+            sequence, amount, stream_packet
+        );
+
+        let encrypted = stream_packet.into_encrypted(&self.shared_secret);
+        let execution_condition = generate_condition(&self.shared_secret, &encrypted);
+        let prepare = PrepareBuilder {
+            destination: self.destination_account.clone(),
+            amount,
+            execution_condition: &execution_condition,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &encrypted[..],
+        }
+        .build();
+        self.congestion_controller.prepare(amount);
+        let future = self.service.handle_request(IncomingRequest {
+            from: self.from_account.clone(),
+            prepare,
+        });
+        self.pending.push(PendingPacket {
+            sequence,
+            amount,
+            money,
+            money_closes,
+            data,
+            future: Box::new(future),
+        });
+    }
+
+    /// Handle the STREAM packet the receiver sent back, if there is one.
+    ///
+    /// Returns false if the response did not come from the receiver.
+    fn handle_response(&mut self, data: BytesMut, sent: &PendingPacket) -> bool {
+        let packet = match StreamPacket::from_encrypted(&self.shared_secret, data) {
+            Ok(packet) => packet,
+            Err(_) => return false,
+        };
+        self.should_send_source_account = false;
+        handle_connection_frames(
+            &packet,
+            &mut self.destination_asset.lock(),
+            &mut self.destination_account,
+        );
+
+        let mut reported_streams = Vec::new();
+        for frame in packet.frames() {
+            match frame {
+                Frame::StreamMaxMoney(frame) => {
+                    self.money.set_total_delivered(
+                        frame.stream_id,
+                        frame.total_received,
+                        frame.receive_max,
+                    );
+                    reported_streams.push(frame.stream_id);
+                }
+                Frame::StreamClose(ref frame) => {
+                    // Data streams are closed by `handle_incoming_frames`
+                    self.money.remote_closed(
+                        frame.stream_id,
+                        &Error::RemoteClosed {
+                            code: frame.code.clone(),
+                            message: frame.message.to_string(),
+                        },
+                    );
+                }
+                Frame::StreamMoneyBlocked(ref frame) => {
+                    // We do not accept incoming money, so there is nothing we can do about it
+                    debug!(
+                        "Receiver is blocked sending money on stream {}",
+                        frame.stream_id
+                    );
+                }
+                Frame::ConnectionClose(frame) => {
+                    debug!(
+                        "Receiver closed the connection with code {:?}: {}",
+                        frame.code, frame.message
+                    );
+                    self.remote_closed = true;
+                    self.error = Some(Error::RemoteClosed {
+                        code: frame.code,
+                        message: frame.message.to_string(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // If the receiver didn't tell us the totals, split what it says
+        // arrived between the streams in proportion to what we sent on each
+        if packet.ilp_packet_type() == IlpPacketType::Fulfill && sent.amount > 0 {
+            let mut remaining = packet.prepare_amount();
+            for (index, (stream_id, amount)) in sent.money.iter().enumerate() {
+                let delivered = if index == sent.money.len() - 1 {
+                    remaining
+                } else {
+                    (u128::from(packet.prepare_amount()) * u128::from(*amount)
+                        / u128::from(sent.amount)) as u64
+                };
+                remaining -= delivered;
+                if !reported_streams.contains(stream_id) {
+                    self.money.add_delivered(*stream_id, delivered);
+                }
+            }
+        }
+
+        // We don't currently accept streams opened by the receiver,
+        // so any returned here are simply dropped (which closes them)
+        self.data.handle_incoming_frames(&packet);
+        true
+    }
+
+    fn handle_fulfill(&mut self, sent: PendingPacket, fulfill: Fulfill) {
+        self.congestion_controller.fulfill(sent.amount);
+        self.receiver_rejects = 0;
+        if !self.handle_response(fulfill.into_data(), &sent) {
+            warn!(
+                "Unable to parse STREAM packet from fulfill data for sequence {}",
+                sent.sequence
+            );
+        }
+        // Update the amount delivered before resolving the futures waiting on this money
+        self.money.fulfilled(&sent.money);
+        debug!(
+            "Packet {} with amount {} was fulfilled",
+            sent.sequence, sent.amount
+        );
+    }
+
+    fn handle_reject(&mut self, sent: PendingPacket, reject: Reject) {
+        self.congestion_controller.reject(sent.amount, &reject);
+        self.money.rejected(&sent.money);
+        debug!(
+            "Packet {} with amount {} was rejected with code: {}",
+            sent.sequence,
+            sent.amount,
+            reject.code()
+        );
+
+        if reject.code() == IlpErrorCode::F99_APPLICATION_ERROR
+            && self.handle_response(BytesMut::from(reject.data()), &sent)
+        {
+            // The receiver got the data and closes even though it rejected the money
+            if !sent.money.is_empty() {
+                let stream_ids: Vec<u64> = sent.money.iter().map(|(id, _)| *id).collect();
+                self.receiver_rejects += 1;
+                if self.receiver_rejects >= MAX_RECEIVER_REJECTS {
+                    self.receiver_rejects = 0;
+                    self.money.stop_streams(
+                        &stream_ids,
+                        &Error::SendMoneyError(
+                            "Receiver rejected the money sent on this stream".to_string(),
+                        ),
+                    );
+                } else {
+                    self.money.stop_full_streams(&stream_ids);
+                    self.retry_delay = Some(Delay::new(Instant::now() + POLL_INTERVAL));
+                }
+            }
+            return;
+        }
+
+        self.data.requeue(sent.data);
+        self.money.requeue_closed(&sent.money_closes);
+        match (reject.code().class(), reject.code()) {
+            (ErrorClass::Temporary, _) => {
+                self.retry_delay = Some(Delay::new(Instant::now() + POLL_INTERVAL));
+            }
+            (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
+                // Handled by the congestion controller
+            }
+            (_, IlpErrorCode::F02_UNREACHABLE) => {
+                self.error = Some(Error::Unreachable(format!(
+                    "Packet was rejected with error: {} {}",
+                    reject.code(),
+                    str::from_utf8(reject.message()).unwrap_or_default(),
+                )));
+            }
+            _ => {
+                self.error = Some(Error::SendMoneyError(format!(
+                    "Packet was rejected with error: {} {}",
+                    reject.code(),
+                    str::from_utf8(reject.message()).unwrap_or_default(),
+                )));
+            }
+        }
+    }
+
+    /// Poll the packets in flight and handle the responses
+    fn poll_pending(&mut self) {
+        for mut sent in mem::replace(&mut self.pending, Vec::new()) {
+            match sent.future.poll() {
+                Ok(Async::NotReady) => self.pending.push(sent),
+                Ok(Async::Ready(fulfill)) => self.handle_fulfill(sent, fulfill),
+                Err(reject) => self.handle_reject(sent, reject),
+            }
+        }
+    }
+
+    /// Send as many packets as the congestion controller allows.
+    ///
+    /// Returns true if any packets were sent.
+    fn try_send(&mut self) -> bool {
+        let mut sent_packets = false;
+        loop {
+            let data_in_flight = self.pending.iter().any(|sent| sent.data.has_data());
+            let max_amount = self.congestion_controller.get_max_amount();
+            let data = self.data.clone();
+            let money = self
+                .money
+                .take_amounts(max_amount, |id| data.is_stream_id_allowed(id));
+            let outgoing = if data_in_flight {
+                OutgoingFrames::default()
+            } else {
+                self.data.take_outgoing_frames(MAX_DATA_PER_PACKET)
+            };
+            let money_closes = self.money.take_closed();
+            if money.is_empty() && outgoing.is_empty() && money_closes.is_empty() {
+                return sent_packets;
+            }
+            self.send_packet(money, money_closes, outgoing, None);
+            sent_packets = true;
+        }
+    }
+
+    fn is_closing(&self) -> bool {
+        // The driver holds the only reference left if all of the connection handles and
+        // money streams were dropped, in which case we close once the data streams are closed too
+        self.closing.load(Ordering::SeqCst)
+            || (Arc::strong_count(&self.closing) == 1 && self.data.is_finished())
+    }
+
+    /// Close all of the streams, failing any money that has not been sent yet with the given error
+    fn close(&mut self, error: &Error) {
+        self.data.close_all();
+        self.money.stop_all(error);
+    }
+}
+
+/// Poll the delay (starting it if it isn't already running) and reset it once it has elapsed
+fn poll_timer(delay: &mut Option<Delay>) -> Poll<(), ()> {
+    let result = delay
+        .get_or_insert_with(|| Delay::new(Instant::now() + POLL_INTERVAL))
+        .poll();
+    match result {
+        Ok(Async::Ready(_)) => {
+            *delay = None;
+            Ok(Async::Ready(()))
+        }
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(err) => {
+            error!("Timer error: {:?}", err);
+            Err(())
+        }
+    }
+}
+
+impl<S, A> Future for ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            self.data.set_connection_task(task::current());
+            self.poll_pending();
+
+            if let Some(error) = self.error.take() {
+                error!("Closing connection because of error: {}", error);
+                self.close(&error);
+                if self.close_sent || self.remote_closed {
+                    return Err(());
+                }
+                // Tell the receiver why we are closing the connection
+                self.close_sent = true;
+                self.send_packet(
+                    Vec::new(),
+                    Vec::new(),
+                    OutgoingFrames::default(),
+                    Some((error.close_code(), error.to_string())),
+                );
+                continue;
+            }
+            if self.close_sent {
+                if self.pending.is_empty() {
+                    debug!("Connection closed");
+                    self.close(&Error::SendMoneyError("Connection closed".to_string()));
+                    return Ok(Async::Ready(()));
+                }
+                return Ok(Async::NotReady);
+            }
+
+            // Wait a bit before retrying after a temporary error
+            if self.retry_delay.is_some() && poll_timer(&mut self.retry_delay)?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+
+            if self.try_send() {
+                self.poll_delay = None;
+                // Poll the new packets
+                continue;
+            }
+            if !self.pending.is_empty() {
+                return Ok(Async::NotReady);
+            }
+
+            if self.is_closing() && self.data.is_finished() && self.money.is_finished() {
+                debug!("Closing connection");
+                self.close_sent = true;
+                self.send_packet(
+                    Vec::new(),
+                    Vec::new(),
+                    OutgoingFrames::default(),
+                    Some((ErrorCode::NoError, String::new())),
+                );
+            } else if self.data.is_waiting_for_remote() {
+                // Check if the receiver has anything to send us
+                if poll_timer(&mut self.poll_delay)?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                self.send_packet(Vec::new(), Vec::new(), OutgoingFrames::default(), None);
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
@@ -106,6 +106,11 @@ pub fn decrypt(shared_secret: &[u8], mut ciphertext: BytesMut) -> Result<BytesMu
     let key = aead::OpeningKey::new(&aead::AES_256_GCM, &key)
         .expect("Failed to create a new opening key for decrypting data!");
 
+    if ciphertext.len() < NONCE_LENGTH + AUTH_TAG_LENGTH {
+        error!("Ciphertext is too short to decrypt");
+        return Err(());
+    }
+
     let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
     nonce.copy_from_slice(&ciphertext.split_to(NONCE_LENGTH));
 
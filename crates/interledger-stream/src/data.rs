@@ -0,0 +1,755 @@
+use super::packet::*;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    task::{self, Task},
+    Async, Poll,
+};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use std::{
+    cmp::min,
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+    sync::Arc,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// How much data each side will buffer for each stream before the other side must wait
+/// for it to be read. This is the amount both sides assume before any `StreamMaxData` frames are sent.
+pub const DEFAULT_MAX_BUFFERED_DATA: u64 = 16384;
+/// The most data that will be put into a single STREAM packet
+pub(crate) const MAX_DATA_PER_PACKET: usize = 8192;
+/// How many streams the other side may have open at once. This determines the
+/// `ConnectionMaxStreamId` both sides assume before any are sent
+pub const DEFAULT_MAX_REMOTE_STREAMS: u64 = 10;
+
+/// Whether the local side is the STREAM client (which opens odd-numbered streams)
+/// or server (which opens even-numbered streams)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+/// A bidirectional stream of bytes sent over a STREAM connection.
+///
+/// This implements `AsyncRead` and `AsyncWrite` so it can be used with the usual Tokio
+/// combinators. Data written to the stream is buffered until the connection is able to
+/// send it, and the remote side is only allowed to send as much data as we have buffer
+/// space for (this is communicated with `StreamMaxData` frames).
+///
+/// Dropping the `DataStream` or calling `shutdown` closes the stream for writing.
+pub struct DataStream {
+    id: u64,
+    streams: DataStreams,
+}
+
+impl DataStream {
+    /// The STREAM stream ID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Close the stream for writing. Any data already written will still be sent
+    /// and the stream can still be read from until the remote side closes it.
+    pub fn close(&self) {
+        self.streams
+            .with_stream(self.id, |stream| stream.local_closed = true);
+        self.streams.notify_connection();
+    }
+}
+
+impl Read for DataStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.streams.with_stream(self.id, |stream| {
+            if stream.readable.is_empty() {
+                if stream.remote_closed {
+                    return Ok(0);
+                }
+                stream.read_task = Some(task::current());
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let amount = min(buf.len(), stream.readable.len());
+            buf[..amount].copy_from_slice(&stream.readable.split_to(amount));
+            stream.consumed_offset += amount as u64;
+            Ok(amount)
+        });
+        match read {
+            Some(Ok(amount)) => {
+                if amount > 0 {
+                    // We may be able to tell the other side to send more
+                    self.streams.notify_connection();
+                }
+                Ok(amount)
+            }
+            Some(Err(err)) => Err(err),
+            None => Ok(0),
+        }
+    }
+}
+
+impl AsyncRead for DataStream {}
+
+impl Write for DataStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.streams.with_stream(self.id, |stream| {
+            if stream.local_closed {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            let space = DEFAULT_MAX_BUFFERED_DATA as usize
+                - min(stream.outgoing.len(), DEFAULT_MAX_BUFFERED_DATA as usize);
+            if space == 0 && !buf.is_empty() {
+                stream.write_task = Some(task::current());
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let amount = min(space, buf.len());
+            stream.outgoing.extend_from_slice(&buf[..amount]);
+            Ok(amount)
+        });
+        match written {
+            Some(Ok(amount)) => {
+                self.streams.notify_connection();
+                Ok(amount)
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Wait until all of the data written so far has been sent
+    fn flush(&mut self) -> io::Result<()> {
+        let flushed = self.streams.with_stream(self.id, |stream| {
+            if stream.outgoing.is_empty() {
+                true
+            } else {
+                stream.write_task = Some(task::current());
+                false
+            }
+        });
+        match flushed {
+            Some(false) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsyncWrite for DataStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for DataStream {
+    fn drop(&mut self) {
+        self.streams.with_stream(self.id, |stream| {
+            stream.local_closed = true;
+            stream.handle_dropped = true;
+        });
+        self.streams.notify_connection();
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    /// Data that arrived out of order, keyed by offset
+    incoming: BTreeMap<u64, Bytes>,
+    /// Contiguous data that is ready to be read
+    readable: BytesMut,
+    /// The offset up to which all of the incoming data has arrived
+    received_offset: u64,
+    /// The offset up to which the application has read the incoming data
+    consumed_offset: u64,
+    /// The last max offset we told the other side about
+    advertised_max_offset: u64,
+    remote_closed: bool,
+    read_task: Option<Task>,
+    /// Data that has been written but not yet sent
+    outgoing: BytesMut,
+    /// The offset of the first byte in `outgoing`
+    sent_offset: u64,
+    /// How far the other side is willing to let us send
+    remote_max_offset: u64,
+    local_closed: bool,
+    close_sent: bool,
+    handle_dropped: bool,
+    write_task: Option<Task>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            advertised_max_offset: DEFAULT_MAX_BUFFERED_DATA,
+            remote_max_offset: DEFAULT_MAX_BUFFERED_DATA,
+            ..Default::default()
+        }
+    }
+
+    fn receive(&mut self, offset: u64, data: &[u8]) {
+        let end = offset.saturating_add(data.len() as u64);
+        if end <= self.received_offset {
+            // We already have this data
+            return;
+        }
+        let max_offset = self.consumed_offset + DEFAULT_MAX_BUFFERED_DATA;
+        if end > max_offset {
+            warn!(
+                "Remote sent data up to offset {} even though we only allowed up to {}. Ignoring the extra data",
+                end, max_offset
+            );
+        }
+        let start = offset.max(self.received_offset);
+        let end = end.min(max_offset);
+        if start >= end {
+            return;
+        }
+        let data = &data[(start - offset) as usize..(end - offset) as usize];
+        self.incoming.insert(start, Bytes::from(data));
+
+        // Move everything that is now contiguous into the readable buffer
+        while let Some(offset) = self.incoming.keys().next().cloned() {
+            if offset > self.received_offset {
+                break;
+            }
+            let chunk = self.incoming.remove(&offset).unwrap();
+            let chunk_end = offset + chunk.len() as u64;
+            if chunk_end > self.received_offset {
+                self.readable
+                    .extend_from_slice(&chunk[(self.received_offset - offset) as usize..]);
+                self.received_offset = chunk_end;
+            }
+        }
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+    }
+
+    fn set_remote_closed(&mut self) {
+        self.remote_closed = true;
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle_dropped && self.close_sent && self.remote_closed
+    }
+}
+
+/// Frames that should be sent to the other side, along with the data they carry.
+///
+/// This owns the data so that the `Frame`s, which borrow it, can be created when the
+/// STREAM packet is built.
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingFrames {
+    data: Vec<(u64, u64, Bytes)>,
+    max_data: Vec<(u64, u64)>,
+    closes: Vec<u64>,
+    max_stream_id: Option<u64>,
+    stream_id_blocked: Option<u64>,
+}
+
+impl OutgoingFrames {
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+            && self.max_data.is_empty()
+            && self.closes.is_empty()
+            && self.max_stream_id.is_none()
+            && self.stream_id_blocked.is_none()
+    }
+
+    pub fn has_data(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    pub fn frames(&self) -> Vec<Frame> {
+        let data = self.data.iter().map(|(stream_id, offset, data)| {
+            Frame::StreamData(StreamDataFrame {
+                stream_id: *stream_id,
+                offset: *offset,
+                data: &data[..],
+            })
+        });
+        let max_data = self.max_data.iter().map(|(stream_id, max_offset)| {
+            Frame::StreamMaxData(StreamMaxDataFrame {
+                stream_id: *stream_id,
+                max_offset: *max_offset,
+            })
+        });
+        let closes = self.closes.iter().map(|stream_id| {
+            Frame::StreamClose(StreamCloseFrame {
+                stream_id: *stream_id,
+                code: ErrorCode::NoError,
+                message: "",
+            })
+        });
+        let max_stream_id = self.max_stream_id.map(|max_stream_id| {
+            Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame { max_stream_id })
+        });
+        let stream_id_blocked = self.stream_id_blocked.map(|max_stream_id| {
+            Frame::ConnectionStreamIdBlocked(ConnectionStreamIdBlockedFrame { max_stream_id })
+        });
+        data.chain(max_data)
+            .chain(closes)
+            .chain(max_stream_id)
+            .chain(stream_id_blocked)
+            .collect()
+    }
+}
+
+struct DataStreamsInner {
+    side: Side,
+    streams: HashMap<u64, StreamState>,
+    next_stream_id: u64,
+    /// The highest stream ID the other side has opened
+    max_remote_stream_id: u64,
+    /// Whether new streams opened by the other side will be accepted
+    accepting_streams: bool,
+    /// How many of the streams opened by the other side have been closed
+    closed_remote_streams: u64,
+    /// The last stream ID limit we told the other side about
+    advertised_stream_id_limit: u64,
+    /// Whether the other side told us it is blocked by our stream ID limit
+    remote_stream_id_blocked: bool,
+    /// The highest stream ID the other side lets us open
+    stream_id_limit: u64,
+    /// Whether we need to tell the other side we are blocked by its stream ID limit
+    stream_id_blocked: bool,
+    /// The stream ID limit that blocked us, if we are waiting for the other side to raise it
+    blocked_at_limit: Option<u64>,
+    connection_task: Option<Task>,
+}
+
+/// The data streams of a single STREAM connection.
+///
+/// This is shared between the connection, which feeds it incoming frames and asks it what
+/// frames to send, and the `DataStream` handles used by the application.
+#[derive(Clone)]
+pub(crate) struct DataStreams {
+    inner: Arc<Mutex<DataStreamsInner>>,
+}
+
+impl DataStreams {
+    pub fn new(side: Side) -> Self {
+        DataStreams {
+            inner: Arc::new(Mutex::new(DataStreamsInner {
+                side,
+                streams: HashMap::new(),
+                next_stream_id: if side == Side::Client { 1 } else { 2 },
+                max_remote_stream_id: 0,
+                // Only the server accepts streams opened by the other side for now
+                accepting_streams: side == Side::Server,
+                closed_remote_streams: 0,
+                advertised_stream_id_limit: 2 * DEFAULT_MAX_REMOTE_STREAMS,
+                remote_stream_id_blocked: false,
+                stream_id_limit: 2 * DEFAULT_MAX_REMOTE_STREAMS,
+                stream_id_blocked: false,
+                blocked_at_limit: None,
+                connection_task: None,
+            })),
+        }
+    }
+
+    /// Reserve the next stream ID. Nothing can be sent on the stream until the ID
+    /// is within the limit set by the other side
+    pub fn next_stream_id(&self) -> u64 {
+        let mut inner = self.inner.lock();
+        let id = inner.next_stream_id;
+        inner.next_stream_id += 2;
+        id
+    }
+
+    /// Check whether the other side allows us to send on the given stream. If not,
+    /// the other side will be told we are blocked
+    pub fn is_stream_id_allowed(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock();
+        if id <= inner.stream_id_limit {
+            true
+        } else {
+            inner.set_blocked();
+            false
+        }
+    }
+
+    /// Open a new stream to the other side
+    pub fn open_stream(&self) -> DataStream {
+        let id = self.next_stream_id();
+        self.inner.lock().streams.insert(id, StreamState::new());
+        debug!("Opened data stream {}", id);
+        DataStream {
+            id,
+            streams: self.clone(),
+        }
+    }
+
+    /// Register the task that sends packets for this connection so it
+    /// is woken up when there is something new to send
+    pub fn set_connection_task(&self, task: Task) {
+        self.inner.lock().connection_task = Some(task);
+    }
+
+    /// Wake up the task sending packets for this connection
+    pub fn notify_connection(&self) {
+        if let Some(task) = self.inner.lock().connection_task.take() {
+            task.notify();
+        }
+    }
+
+    fn with_stream<T>(&self, id: u64, func: impl FnOnce(&mut StreamState) -> T) -> Option<T> {
+        self.inner.lock().streams.get_mut(&id).map(func)
+    }
+
+    /// Handle the data-related frames from an incoming STREAM packet.
+    ///
+    /// Returns the streams that were opened by the other side in this packet.
+    pub fn handle_incoming_frames(&self, packet: &StreamPacket) -> Vec<DataStream> {
+        let mut new_streams = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for frame in packet.frames() {
+                match frame {
+                    Frame::StreamData(frame) => {
+                        if let Some(stream) = inner.get_or_open_remote(frame.stream_id) {
+                            stream.receive(frame.offset, frame.data);
+                        }
+                    }
+                    Frame::StreamMaxData(frame) => {
+                        if let Some(stream) = inner.get_or_open_remote(frame.stream_id) {
+                            if frame.max_offset > stream.remote_max_offset {
+                                stream.remote_max_offset = frame.max_offset;
+                            }
+                        }
+                    }
+                    Frame::StreamClose(frame) => {
+                        if let Some(stream) = inner.streams.get_mut(&frame.stream_id) {
+                            debug!("Remote closed data stream {}", frame.stream_id);
+                            stream.set_remote_closed();
+                        } else if inner.is_remote_id(frame.stream_id) {
+                            // Streams that were only used for money have no state here
+                            // (retransmitted closes may be counted more than once, which
+                            // only makes the limit more generous)
+                            inner.closed_remote_streams += 1;
+                        }
+                    }
+                    Frame::ConnectionMaxStreamId(frame)
+                        if frame.max_stream_id > inner.stream_id_limit =>
+                    {
+                        inner.stream_id_limit = frame.max_stream_id;
+                        inner.blocked_at_limit = None;
+                    }
+                    Frame::ConnectionStreamIdBlocked(_) => {
+                        inner.remote_stream_id_blocked = true;
+                    }
+                    Frame::ConnectionClose(_) => {
+                        for stream in inner.streams.values_mut() {
+                            stream.set_remote_closed();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let max_remote_stream_id = inner.max_remote_stream_id;
+            let remote_parity = if inner.side == Side::Client { 0 } else { 1 };
+            for id in inner.streams.keys() {
+                if id % 2 == remote_parity && *id > max_remote_stream_id {
+                    new_streams.push(*id);
+                }
+            }
+            new_streams.sort();
+            if let Some(id) = new_streams.last() {
+                inner.max_remote_stream_id = *id;
+            }
+        }
+
+        self.notify_connection();
+        new_streams
+            .into_iter()
+            .map(|id| {
+                debug!("Remote opened data stream {}", id);
+                DataStream {
+                    id,
+                    streams: self.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Determine which frames should be sent next, without putting more than
+    /// `max_data` bytes of stream data into the packet.
+    ///
+    /// The data is considered sent once it is taken. If the packet does not reach the
+    /// other side, it should be passed back to `requeue`.
+    pub fn take_outgoing_frames(&self, max_data: usize) -> OutgoingFrames {
+        let mut outgoing = OutgoingFrames::default();
+        let mut max_data = max_data;
+        let mut inner = self.inner.lock();
+        let mut ids: Vec<u64> = inner.streams.keys().cloned().collect();
+        ids.sort();
+        let stream_id_limit = inner.stream_id_limit;
+        for id in ids {
+            if !inner.is_remote_id(id) && id > stream_id_limit {
+                // Wait for the other side to let us open this stream
+                inner.set_blocked();
+                continue;
+            }
+            let stream = inner.streams.get_mut(&id).unwrap();
+
+            // Let the other side send more once the application has read
+            // at least half of what we said they could send
+            let max_offset = stream.consumed_offset + DEFAULT_MAX_BUFFERED_DATA;
+            if !stream.remote_closed
+                && max_offset - stream.advertised_max_offset >= DEFAULT_MAX_BUFFERED_DATA / 2
+            {
+                stream.advertised_max_offset = max_offset;
+                outgoing.max_data.push((id, max_offset));
+            }
+
+            let window = stream.remote_max_offset.saturating_sub(stream.sent_offset);
+            let amount = min(min(stream.outgoing.len() as u64, window) as usize, max_data);
+            if amount > 0 {
+                let data = stream.outgoing.split_to(amount).freeze();
+                outgoing.data.push((id, stream.sent_offset, data));
+                stream.sent_offset += amount as u64;
+                max_data -= amount;
+                if let Some(task) = stream.write_task.take() {
+                    task.notify();
+                }
+            }
+
+            if stream.local_closed && !stream.close_sent && stream.outgoing.is_empty() {
+                stream.close_sent = true;
+                outgoing.closes.push(id);
+            }
+        }
+        let remote_parity = if inner.side == Side::Server { 1 } else { 0 };
+        let mut closed_remote_streams = 0;
+        inner.streams.retain(|id, stream| {
+            let is_finished = stream.is_finished();
+            if is_finished && id % 2 == remote_parity {
+                closed_remote_streams += 1;
+            }
+            !is_finished
+        });
+        inner.closed_remote_streams += closed_remote_streams;
+
+        // Let the other side open more streams as they close the old ones
+        let limit = inner.remote_stream_id_limit();
+        if limit > inner.advertised_stream_id_limit || inner.remote_stream_id_blocked {
+            inner.advertised_stream_id_limit = limit;
+            inner.remote_stream_id_blocked = false;
+            outgoing.max_stream_id = Some(limit);
+        }
+        if inner.stream_id_blocked {
+            inner.stream_id_blocked = false;
+            outgoing.stream_id_blocked = Some(stream_id_limit);
+        }
+        outgoing
+    }
+
+    /// Put frames that did not reach the other side back into the queue to be sent again
+    pub fn requeue(&self, frames: OutgoingFrames) {
+        let mut inner = self.inner.lock();
+        for (id, offset, data) in frames.data.into_iter().rev() {
+            if let Some(stream) = inner.streams.get_mut(&id) {
+                let mut outgoing = BytesMut::from(data);
+                outgoing.extend_from_slice(&stream.outgoing);
+                stream.outgoing = outgoing;
+                stream.sent_offset = offset;
+            }
+        }
+        for (id, _) in frames.max_data {
+            if let Some(stream) = inner.streams.get_mut(&id) {
+                // Make sure we send another StreamMaxData frame
+                stream.advertised_max_offset = 0;
+            }
+        }
+        for id in frames.closes {
+            if let Some(stream) = inner.streams.get_mut(&id) {
+                stream.close_sent = false;
+            }
+        }
+        if frames.max_stream_id.is_some() {
+            inner.remote_stream_id_blocked = true;
+        }
+        if frames.stream_id_blocked.is_some() {
+            inner.stream_id_blocked = true;
+        }
+    }
+
+    /// Close all of the streams for writing. Data that has already been written will
+    /// still be sent and no new streams will be accepted from the other side
+    pub fn close_for_writing(&self) {
+        let mut inner = self.inner.lock();
+        inner.accepting_streams = false;
+        for stream in inner.streams.values_mut() {
+            stream.local_closed = true;
+        }
+    }
+
+    /// Close all of the streams for reading and writing, discarding any unsent data
+    pub fn close_all(&self) {
+        let mut inner = self.inner.lock();
+        inner.accepting_streams = false;
+        for stream in inner.streams.values_mut() {
+            stream.local_closed = true;
+            stream.close_sent = true;
+            stream.outgoing.clear();
+            stream.set_remote_closed();
+            if let Some(task) = stream.write_task.take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// Returns true if there are streams waiting for data from the other side
+    pub fn is_waiting_for_remote(&self) -> bool {
+        let inner = self.inner.lock();
+        inner.streams.values().any(|stream| !stream.remote_closed)
+            || inner.blocked_at_limit.is_some()
+    }
+
+    /// Returns true if all of the streams are closed and there is nothing left to send
+    pub fn is_finished(&self) -> bool {
+        self.inner
+            .lock()
+            .streams
+            .values()
+            .all(|stream| stream.local_closed && stream.close_sent && stream.outgoing.is_empty())
+    }
+}
+
+impl DataStreamsInner {
+    /// Look up the stream, opening it if the other side is allowed to open a stream with this ID
+    fn get_or_open_remote(&mut self, id: u64) -> Option<&mut StreamState> {
+        if !self.streams.contains_key(&id) {
+            if !self.is_remote_id(id) || id <= self.max_remote_stream_id || !self.accepting_streams
+            {
+                // Either we never opened this stream or it was already closed
+                return None;
+            }
+            if id > self.remote_stream_id_limit() {
+                warn!(
+                    "Ignoring frame for stream {} because it is above the stream ID limit of {}",
+                    id,
+                    self.remote_stream_id_limit()
+                );
+                return None;
+            }
+            self.streams.insert(id, StreamState::new());
+        }
+        self.streams.get_mut(&id)
+    }
+
+    fn is_remote_id(&self, id: u64) -> bool {
+        (id % 2 == 1) == (self.side == Side::Server)
+    }
+
+    /// Tell the other side we are blocked, once for each limit it sets
+    fn set_blocked(&mut self) {
+        if self.blocked_at_limit != Some(self.stream_id_limit) {
+            self.blocked_at_limit = Some(self.stream_id_limit);
+            self.stream_id_blocked = true;
+        }
+    }
+
+    /// The highest stream ID the other side may open
+    fn remote_stream_id_limit(&self) -> u64 {
+        2 * (self.closed_remote_streams + DEFAULT_MAX_REMOTE_STREAMS)
+    }
+}
+
+#[cfg(test)]
+mod data_streams {
+    use super::*;
+    use futures::{future::lazy, Future};
+    use tokio_io::io::{read_to_end, write_all};
+
+    fn transfer(from: &DataStreams, to: &DataStreams) -> Vec<DataStream> {
+        let outgoing = from.take_outgoing_frames(MAX_DATA_PER_PACKET);
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: interledger_packet::PacketType::Prepare,
+            prepare_amount: 0,
+            frames: &outgoing.frames(),
+        }
+        .build();
+        to.handle_incoming_frames(&packet)
+    }
+
+    #[test]
+    fn reassembles_out_of_order_data() {
+        let mut stream = StreamState::new();
+        stream.receive(5, b"world");
+        assert!(stream.readable.is_empty());
+        stream.receive(0, b"hello");
+        stream.receive(3, b"lowo");
+        assert_eq!(&stream.readable[..], b"helloworld");
+        assert_eq!(stream.received_offset, 10);
+    }
+
+    #[test]
+    fn ignores_data_beyond_max_offset() {
+        let mut stream = StreamState::new();
+        stream.receive(DEFAULT_MAX_BUFFERED_DATA - 2, b"abcd");
+        stream.receive(0, &vec![0; DEFAULT_MAX_BUFFERED_DATA as usize - 2]);
+        assert_eq!(stream.readable.len() as u64, DEFAULT_MAX_BUFFERED_DATA);
+        assert_eq!(&stream.readable[stream.readable.len() - 2..], b"ab");
+    }
+
+    #[test]
+    fn sends_data_between_sides() {
+        lazy(|| {
+            let client = DataStreams::new(Side::Client);
+            let server = DataStreams::new(Side::Server);
+            let mut client_stream = client.open_stream();
+            let data = vec![7; 2 * DEFAULT_MAX_BUFFERED_DATA as usize];
+            assert_eq!(
+                client_stream.write(&data).unwrap() as u64,
+                DEFAULT_MAX_BUFFERED_DATA
+            );
+
+            // The data is split across packets
+            let mut server_stream = transfer(&client, &server).pop().unwrap();
+            assert_eq!(server_stream.id(), 1);
+            assert!(transfer(&client, &server).is_empty());
+            assert!(client.take_outgoing_frames(MAX_DATA_PER_PACKET).is_empty());
+
+            // Nothing more can be sent until the receiver reads some data
+            let mut buf = vec![0; data.len()];
+            assert_eq!(
+                server_stream.read(&mut buf).unwrap() as u64,
+                DEFAULT_MAX_BUFFERED_DATA
+            );
+            client_stream.write_all(b"hello").unwrap();
+            let blocked = client.take_outgoing_frames(MAX_DATA_PER_PACKET);
+            assert!(blocked.is_empty());
+            transfer(&server, &client);
+            drop(client_stream);
+            transfer(&client, &server);
+
+            let (_, received) = read_to_end(server_stream, Vec::new()).wait().unwrap();
+            assert_eq!(received, b"hello");
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn requeues_lost_data() {
+        lazy(|| {
+            let client = DataStreams::new(Side::Client);
+            let server = DataStreams::new(Side::Server);
+            let client_stream = client.open_stream();
+            let (client_stream, _) = write_all(client_stream, b"hello").wait().unwrap();
+            client.requeue(client.take_outgoing_frames(MAX_DATA_PER_PACKET));
+            drop(client_stream);
+            let incoming = transfer(&client, &server).pop().unwrap();
+            let (_, received) = read_to_end(incoming, Vec::new()).wait().unwrap();
+            assert_eq!(received, b"hello");
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
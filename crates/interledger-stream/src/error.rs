@@ -1,6 +1,7 @@
+use super::packet::ErrorCode;
 use failure::Fail;
 
-#[derive(Fail, Debug)]
+#[derive(Fail, Debug, Clone, PartialEq)]
 pub enum Error {
     #[fail(display = "Error connecting: {}", _0)]
     ConnectionError(String),
@@ -8,4 +9,37 @@ pub enum Error {
     PollError(String),
     #[fail(display = "Error polling: {}", _0)]
     SendMoneyError(String),
+    /// The receiver will not accept any more money on the connection or stream
+    #[fail(display = "Receiver is full: {}", _0)]
+    ReceiverFull(String),
+    /// The exchange rate is below the minimum the sender is willing to accept
+    #[fail(display = "Exchange rate too low: {}", _0)]
+    ExchangeRateTooLow(String),
+    /// No route to the receiver could be found
+    #[fail(display = "Receiver unreachable: {}", _0)]
+    Unreachable(String),
+    /// The other side closed the connection or stream
+    #[fail(display = "Closed by the other side with code {:?}: {}", code, message)]
+    RemoteClosed { code: ErrorCode, message: String },
+    /// A STREAM packet could not be decrypted with the shared secret
+    #[fail(display = "Unable to decrypt STREAM packet")]
+    DecryptionError,
+    /// A STREAM packet was decrypted but could not be parsed
+    #[fail(display = "Invalid STREAM packet: {}", _0)]
+    InvalidPacket(String),
+    /// A payment checkpoint could not be saved or loaded
+    #[fail(display = "Checkpoint error: {}", _0)]
+    CheckpointError(String),
+}
+
+impl Error {
+    /// The STREAM error code to send in a `ConnectionClose` frame when closing
+    /// the connection because of this error
+    pub(crate) fn close_code(&self) -> ErrorCode {
+        match self {
+            Error::DecryptionError | Error::InvalidPacket(_) => ErrorCode::ProtocolViolation,
+            Error::ConnectionError(_) | Error::PollError(_) => ErrorCode::InternalError,
+            _ => ErrorCode::ApplicationError,
+        }
+    }
 }
@@ -4,29 +4,50 @@
 //!
 //! STREAM is responsible for splitting larger payments and messages into smaller chunks of money and data, and sending them over ILP.
 
+mod checkpoint;
 mod client;
 mod congestion;
+mod connection;
 mod crypto;
+mod data;
 mod error;
+mod money;
 mod packet;
 mod server;
 
-pub use client::send_money;
+pub use checkpoint::{CheckpointSink, FileCheckpointSink, PaymentCheckpoint};
+pub use client::{
+    resume_money, send_money, send_money_with_checkpoints, send_money_with_quote, SendMoneyOptions,
+    StreamDelivery, DEFAULT_SLIPPAGE,
+};
+pub use congestion::{
+    CongestionAlgorithm, CongestionControl, CongestionController, ProbingCongestionController,
+};
+pub use connection::{connect, StreamConnection};
+pub use data::{DataStream, DEFAULT_MAX_BUFFERED_DATA, DEFAULT_MAX_REMOTE_STREAMS};
 pub use error::Error;
-pub use server::{ConnectionGenerator, StreamReceiverService};
+pub use money::MoneyStream;
+pub use packet::ErrorCode as StreamErrorCode;
+pub use server::{
+    connection_tag, ConnectionGenerator, ConnectionTotals, IncomingDataStream, PaymentNotification,
+    StreamReceiverService, StreamReceiverStore,
+};
 
 #[cfg(test)]
 pub mod test_helpers {
+    use super::{ConnectionTotals, StreamReceiverStore};
     use bytes::Bytes;
     use futures::{future::ok, Future};
     use interledger_ildcp::IldcpAccount;
     use interledger_packet::Address;
-    use interledger_router::RouterStore;
+    use interledger_router::{RouterStore, RoutingTable};
     use interledger_service::{Account, AccountStore, Username};
     use lazy_static::lazy_static;
+    use parking_lot::Mutex;
     use std::collections::HashMap;
     use std::iter::FromIterator;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     lazy_static! {
         pub static ref EXAMPLE_CONNECTOR: Address = Address::from_str("example.connector").unwrap();
@@ -93,8 +114,75 @@ pub mod test_helpers {
     }
 
     impl RouterStore for TestStore {
-        fn routing_table(&self) -> HashMap<Bytes, u64> {
-            HashMap::from_iter(vec![(self.route.0.clone(), self.route.1.id())].into_iter())
+        fn routing_table(&self) -> Arc<RoutingTable<u64>> {
+            Arc::new(RoutingTable::from_iter(vec![(
+                self.route.0.clone(),
+                self.route.1.id(),
+            )]))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct TestReceiverStore {
+        pub connections: Arc<Mutex<HashMap<String, ConnectionTotals>>>,
+        pub streams: Arc<Mutex<HashMap<(String, u64), u64>>>,
+    }
+
+    impl StreamReceiverStore for TestReceiverStore {
+        fn credit_connection(
+            &self,
+            connection_id: String,
+            amounts: Vec<(u64, u64)>,
+            receive_max: u64,
+            source_account: Option<Address>,
+        ) -> Box<dyn Future<Item = (bool, ConnectionTotals), Error = ()> + Send> {
+            let mut connections = self.connections.lock();
+            let mut streams = self.streams.lock();
+            let connection = connections.entry(connection_id.clone()).or_default();
+            if source_account.is_some() {
+                connection.source_account = source_account;
+            }
+            let amount: u64 = amounts.iter().map(|(_, amount)| amount).sum();
+            let credited = connection.connection_total + amount <= receive_max;
+            if credited {
+                connection.connection_total += amount;
+            }
+            let stream_totals = amounts
+                .iter()
+                .map(|(stream_id, amount)| {
+                    let total = streams
+                        .entry((connection_id.clone(), *stream_id))
+                        .or_insert(0);
+                    if credited {
+                        *total += amount;
+                    }
+                    *total
+                })
+                .collect();
+            Box::new(ok((
+                credited,
+                ConnectionTotals {
+                    connection_total: connection.connection_total,
+                    stream_totals,
+                    source_account: connection.source_account.clone(),
+                },
+            )))
+        }
+
+        fn get_connection_totals(
+            &self,
+            connection_id: String,
+        ) -> Box<dyn Future<Item = ConnectionTotals, Error = ()> + Send> {
+            let connection = self
+                .connections
+                .lock()
+                .get(&connection_id)
+                .cloned()
+                .unwrap_or_default();
+            Box::new(ok(ConnectionTotals {
+                stream_totals: Vec::new(),
+                ..connection
+            }))
         }
     }
 }
@@ -104,7 +192,7 @@ mod send_money_to_receiver {
     use super::test_helpers::*;
     use super::*;
     use bytes::Bytes;
-    use futures::Future;
+    use futures::{Future, Stream};
     use interledger_ildcp::IldcpService;
     use interledger_packet::Address;
     use interledger_packet::{ErrorCode, RejectBuilder};
@@ -129,6 +217,7 @@ mod send_money_to_receiver {
         let connection_generator = ConnectionGenerator::new(server_secret.clone());
         let server = StreamReceiverService::new(
             server_secret,
+            TestReceiverStore::default(),
             outgoing_service_fn(|_| {
                 Err(RejectBuilder {
                     code: ErrorCode::F02_UNREACHABLE,
@@ -139,6 +228,7 @@ mod send_money_to_receiver {
                 .build())
             }),
         );
+        let notifications = server.subscribe();
         let server = Router::new(EXAMPLE_RECEIVER.clone(), store, server);
         let server = IldcpService::new(server);
 
@@ -157,13 +247,700 @@ mod send_money_to_receiver {
             destination_account,
             &shared_secret[..],
             100,
+            CongestionAlgorithm::default(),
         )
-        .and_then(|(delivered_amount, _service)| {
-            assert_eq!(delivered_amount, 100);
+        .and_then(|(delivery, _service)| {
+            assert_eq!(delivery.delivered_amount, 100);
+            assert_eq!(delivery.sent_amount, 100);
+            assert_eq!(delivery.destination_asset_code, Some("XYZ".to_string()));
+            assert_eq!(delivery.destination_asset_scale, Some(9));
             Ok(())
         })
         .map_err(|err| panic!(err));
         let runtime = Runtime::new().unwrap();
         runtime.block_on_all(run).unwrap();
+
+        let received: u64 = notifications
+            .wait()
+            .map(|notification| notification.unwrap().amount)
+            .sum();
+        assert_eq!(received, 100);
+    }
+}
+
+#[cfg(test)]
+mod resume_money_from_checkpoint {
+    use super::test_helpers::*;
+    use super::*;
+    use bytes::Bytes;
+    use futures::Future;
+    use interledger_ildcp::IldcpService;
+    use interledger_packet::{Address, ErrorCode, RejectBuilder};
+    use interledger_router::Router;
+    use interledger_service::{
+        incoming_service_fn, outgoing_service_fn, IncomingRequest, IncomingService,
+    };
+    use parking_lot::Mutex;
+    use std::str::FromStr;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[derive(Clone, Default)]
+    struct TestSink {
+        checkpoints: Arc<Mutex<Vec<PaymentCheckpoint>>>,
+    }
+
+    impl CheckpointSink for TestSink {
+        fn save(&mut self, checkpoint: &PaymentCheckpoint) -> Result<(), Error> {
+            self.checkpoints.lock().push(checkpoint.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resumes_payment_after_failure() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: EXAMPLE_RECEIVER.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (EXAMPLE_RECEIVER.to_bytes(), account),
+        };
+        let receiver_store = TestReceiverStore::default();
+        let server = StreamReceiverService::new(
+            server_secret.clone(),
+            receiver_store.clone(),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let mut server = Router::new(EXAMPLE_RECEIVER.clone(), store, server);
+        // The "connector" goes offline after forwarding a few packets with money
+        let online = Arc::new(AtomicBool::new(true));
+        let online_clone = online.clone();
+        let packets = Arc::new(AtomicUsize::new(0));
+        let server = incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+            if request.prepare.amount() > 0 && packets.fetch_add(1, Ordering::SeqCst) == 3 {
+                online_clone.store(false, Ordering::SeqCst);
+            }
+            if online_clone.load(Ordering::SeqCst) {
+                Box::new(server.handle_request(request))
+                    as Box<dyn Future<Item = _, Error = _> + Send>
+            } else {
+                Box::new(futures::future::err(
+                    RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"Connector is offline",
+                        triggered_by: Some(&EXAMPLE_CONNECTOR),
+                        data: &[],
+                    }
+                    .build(),
+                ))
+            }
+        });
+        let server = IldcpService::new(server);
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(server_secret).generate_address_and_secret(&EXAMPLE_RECEIVER);
+        // A small asset scale makes the probing controller start with small packets
+        let sender = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 2,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+        };
+
+        let sink = TestSink::default();
+        let result = send_money_with_checkpoints(
+            server.clone(),
+            &sender,
+            destination_account.clone(),
+            &shared_secret[..],
+            20_000,
+            CongestionAlgorithm::Probing,
+            sink.clone(),
+        )
+        .wait();
+        match result {
+            Err(Error::Unreachable(_)) => {}
+            result => panic!("Expected Unreachable error, got: {:?}", result.map(|r| r.0)),
+        }
+
+        let checkpoint = sink.checkpoints.lock().last().cloned().unwrap();
+        assert_eq!(checkpoint.destination_account, destination_account);
+        assert_eq!(checkpoint.source_amount, 20_000);
+        assert!(checkpoint.sent_amount > 0);
+        assert!(!checkpoint.is_complete());
+        assert_eq!(checkpoint.delivered_amount, checkpoint.sent_amount);
+        assert_eq!(checkpoint.in_flight_amount, 0);
+        assert_eq!(checkpoint.congestion_control, CongestionAlgorithm::Probing);
+
+        online.store(true, Ordering::SeqCst);
+        let resumed_sink = TestSink::default();
+        let (delivery, _service) = resume_money(server, &sender, checkpoint, resumed_sink.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(delivery.sent_amount, 20_000);
+        assert_eq!(delivery.delivered_amount, 20_000);
+        assert!(resumed_sink
+            .checkpoints
+            .lock()
+            .last()
+            .unwrap()
+            .is_complete());
+        assert_eq!(
+            resumed_sink
+                .checkpoints
+                .lock()
+                .last()
+                .unwrap()
+                .congestion_control,
+            CongestionAlgorithm::Probing
+        );
+
+        // The receiver got exactly the full amount, across both attempts
+        let received: u64 = receiver_store
+            .connections
+            .lock()
+            .values()
+            .map(|connection| connection.connection_total)
+            .sum();
+        assert_eq!(received, 20_000);
+    }
+
+    #[test]
+    fn does_not_resend_amount_in_flight() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: EXAMPLE_RECEIVER.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (EXAMPLE_RECEIVER.to_bytes(), account),
+        };
+        let receiver_store = TestReceiverStore::default();
+        let server = StreamReceiverService::new(
+            server_secret.clone(),
+            receiver_store.clone(),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = Router::new(EXAMPLE_RECEIVER.clone(), store, server);
+        let server = IldcpService::new(server);
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(server_secret).generate_address_and_secret(&EXAMPLE_RECEIVER);
+        let sender = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+        };
+
+        let mut checkpoint =
+            PaymentCheckpoint::new(destination_account, Bytes::from(&shared_secret[..]), 20_000);
+        checkpoint.sequence = 5;
+        checkpoint.sent_amount = 4000;
+        checkpoint.in_flight_amount = 1000;
+        let sink = TestSink::default();
+        let (delivery, _service) = resume_money(server, &sender, checkpoint, sink.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(delivery.sent_amount, 20_000);
+        assert!(sink.checkpoints.lock().last().unwrap().is_complete());
+
+        let received: u64 = receiver_store
+            .connections
+            .lock()
+            .values()
+            .map(|connection| connection.connection_total)
+            .sum();
+        assert_eq!(received, 15_000);
+    }
+}
+
+#[cfg(test)]
+mod send_money_with_quote_to_receiver {
+    use super::test_helpers::*;
+    use super::*;
+    use bytes::Bytes;
+    use futures::Future;
+    use interledger_ildcp::IldcpService;
+    use interledger_packet::{Address, ErrorCode, RejectBuilder};
+    use interledger_router::Router;
+    use interledger_service::{
+        incoming_service_fn, outgoing_service_fn, IncomingRequest, IncomingService,
+    };
+    use std::str::FromStr;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Set up a receiver behind a "connector" that applies the given exchange rate to
+    /// each packet (the rate function is passed the index of the packet)
+    fn receiver_with_exchange_rate(
+        rate: impl Fn(usize) -> f64 + Clone + Send + 'static,
+    ) -> (impl IncomingService<TestAccount> + Clone, Address, [u8; 32]) {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: EXAMPLE_RECEIVER.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (EXAMPLE_RECEIVER.to_bytes(), account),
+        };
+        let server = StreamReceiverService::new(
+            server_secret.clone(),
+            TestReceiverStore::default(),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let mut server = Router::new(EXAMPLE_RECEIVER.clone(), store, server);
+        let packets = Arc::new(AtomicUsize::new(0));
+        let server = incoming_service_fn(move |mut request: IncomingRequest<TestAccount>| {
+            let index = packets.fetch_add(1, Ordering::SeqCst);
+            let amount = (request.prepare.amount() as f64 * rate(index)) as u64;
+            request.prepare.set_amount(amount);
+            server.handle_request(request)
+        });
+        let server = IldcpService::new(server);
+
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(server_secret).generate_address_and_secret(&EXAMPLE_RECEIVER);
+        (server, destination_account, shared_secret)
+    }
+
+    fn sender_account() -> TestAccount {
+        TestAccount {
+            id: 0,
+            asset_code: "ABC".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+        }
+    }
+
+    #[test]
+    fn delivers_fixed_destination_amount() {
+        let (server, destination_account, shared_secret) = receiver_with_exchange_rate(|_| 0.5);
+        let (delivery, _service) = send_money_with_quote(
+            server,
+            &sender_account(),
+            destination_account,
+            &shared_secret[..],
+            SendMoneyOptions {
+                destination_amount: Some(1000),
+                ..SendMoneyOptions::new(10_000)
+            },
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(
+            delivery,
+            StreamDelivery {
+                sent_amount: 2000,
+                delivered_amount: 1000,
+                quoted_exchange_rate: 0.5,
+                destination_asset_code: Some("XYZ".to_string()),
+                destination_asset_scale: Some(9),
+            }
+        );
+    }
+
+    #[test]
+    fn delivers_fixed_destination_amount_with_probing_congestion_control() {
+        let (server, destination_account, shared_secret) = receiver_with_exchange_rate(|_| 0.5);
+        let (delivery, _service) = send_money_with_quote(
+            server,
+            &sender_account(),
+            destination_account,
+            &shared_secret[..],
+            SendMoneyOptions {
+                destination_amount: Some(1_000_000),
+                congestion_control: CongestionAlgorithm::Probing,
+                ..SendMoneyOptions::new(10_000_000)
+            },
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(delivery.sent_amount, 2_000_000);
+        assert_eq!(delivery.delivered_amount, 1_000_000);
+    }
+
+    #[test]
+    fn fails_if_destination_amount_cannot_be_delivered() {
+        let (server, destination_account, shared_secret) = receiver_with_exchange_rate(|_| 0.5);
+        let result = send_money_with_quote(
+            server,
+            &sender_account(),
+            destination_account,
+            &shared_secret[..],
+            SendMoneyOptions {
+                destination_amount: Some(1000),
+                ..SendMoneyOptions::new(1500)
+            },
+        )
+        .wait();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn fails_if_quote_is_below_min_exchange_rate() {
+        let (server, destination_account, shared_secret) = receiver_with_exchange_rate(|_| 0.5);
+        let result = send_money_with_quote(
+            server,
+            &sender_account(),
+            destination_account,
+            &shared_secret[..],
+            SendMoneyOptions {
+                min_exchange_rate: Some(0.9),
+                ..SendMoneyOptions::new(1000)
+            },
+        )
+        .wait();
+        match result {
+            Err(Error::ExchangeRateTooLow(_)) => {}
+            result => panic!(
+                "Expected ExchangeRateTooLow error, got: {:?}",
+                result.map(|r| r.0)
+            ),
+        }
+    }
+
+    #[test]
+    fn stops_if_exchange_rate_drops_after_quote() {
+        // The first packet is the test packet used for the quote
+        let (server, destination_account, shared_secret) =
+            receiver_with_exchange_rate(|index| if index == 0 { 1.0 } else { 0.5 });
+        let result = send_money_with_quote(
+            server,
+            &sender_account(),
+            destination_account,
+            &shared_secret[..],
+            SendMoneyOptions::new(1000),
+        )
+        .wait();
+        match result {
+            Err(Error::ExchangeRateTooLow(_)) => {}
+            result => panic!(
+                "Expected ExchangeRateTooLow error, got: {:?}",
+                result.map(|r| r.0)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod stream_connection_with_receiver {
+    use super::test_helpers::*;
+    use super::*;
+    use bytes::Bytes;
+    use futures::{future::join_all, sync::mpsc::UnboundedReceiver, Future, Stream};
+    use interledger_ildcp::IldcpService;
+    use interledger_packet::{Address, ErrorCode, RejectBuilder};
+    use interledger_router::Router;
+    use interledger_service::{outgoing_service_fn, IncomingService};
+    use std::str::FromStr;
+    use tokio::runtime::Runtime;
+    use tokio_io::io::{read_exact, read_to_end, write_all};
+
+    struct TestReceiver<S> {
+        service: S,
+        store: TestReceiverStore,
+        incoming_streams: UnboundedReceiver<IncomingDataStream>,
+        destination_account: Address,
+        shared_secret: [u8; 32],
+    }
+
+    fn test_receiver(
+        receive_max: u64,
+    ) -> TestReceiver<impl IncomingService<TestAccount> + Clone + Send + 'static> {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: EXAMPLE_RECEIVER.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (EXAMPLE_RECEIVER.to_bytes(), account),
+        };
+        let receiver_store = TestReceiverStore::default();
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let mut server = StreamReceiverService::new(
+            server_secret,
+            receiver_store.clone(),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        server.receive_max(receive_max);
+        let incoming_streams = server.accept_data_streams();
+        let server = Router::new(EXAMPLE_RECEIVER.clone(), store, server);
+        let (destination_account, shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&EXAMPLE_RECEIVER, "request-1")
+            .unwrap();
+        TestReceiver {
+            service: IldcpService::new(server),
+            store: receiver_store,
+            incoming_streams,
+            destination_account,
+            shared_secret,
+        }
+    }
+
+    fn sender_account() -> TestAccount {
+        TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+        }
+    }
+
+    fn total_received(store: &TestReceiverStore) -> u64 {
+        store
+            .connections
+            .lock()
+            .values()
+            .map(|totals| totals.connection_total)
+            .sum()
+    }
+
+    #[test]
+    fn sends_data_both_ways() {
+        let receiver = test_receiver(u64::max_value());
+
+        // The receiver answers each request with a response on the same stream
+        let receiving = receiver
+            .incoming_streams
+            .into_future()
+            .map_err(|_| panic!("Error accepting data stream"))
+            .and_then(|(incoming, _)| {
+                let incoming = incoming.unwrap();
+                assert_eq!(incoming.connection_tag, Some("request-1".to_string()));
+                read_exact(incoming.stream, [0; 7])
+            })
+            .and_then(|(stream, request)| {
+                assert_eq!(&request, b"request");
+                write_all(stream, b"response")
+            })
+            .map(|_| ())
+            .map_err(|err| panic!("Receiver error: {:?}", err));
+
+        let sender = connect(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            CongestionAlgorithm::default(),
+        )
+        .map_err(|err| panic!("Error connecting: {:?}", err))
+        .and_then(|connection| {
+            let stream = connection.open_stream();
+            assert_eq!(stream.id(), 1);
+            write_all(stream, b"request")
+                .and_then(|(stream, _)| read_to_end(stream, Vec::new()))
+                .map(move |(_, response)| {
+                    connection.close();
+                    response
+                })
+                .map_err(|err| panic!("Sender error: {:?}", err))
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(receiving);
+        let response = runtime.block_on(sender).unwrap();
+        assert_eq!(response, b"response");
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_money_with_probing_congestion_control() {
+        let receiver = test_receiver(u64::max_value());
+        let store = receiver.store.clone();
+
+        let sender = connect(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            CongestionAlgorithm::Probing,
+        )
+        .and_then(|connection| {
+            let stream = connection.open_money_stream();
+            stream.send(1_000_000).map(move |delivered| {
+                connection.close();
+                delivered
+            })
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let delivered = runtime.block_on(sender).unwrap();
+        assert_eq!(delivered, 1_000_000);
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert_eq!(total_received(&store), 1_000_000);
+    }
+
+    #[test]
+    fn sends_money_on_multiple_streams() {
+        let receiver = test_receiver(u64::max_value());
+        let store = receiver.store.clone();
+
+        let sender = connect(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            CongestionAlgorithm::default(),
+        )
+        .and_then(|connection| {
+            let first = connection.open_money_stream();
+            let second = connection.open_money_stream();
+            assert_eq!(first.id(), 1);
+            assert_eq!(second.id(), 3);
+            let sends = join_all(vec![first.send(100), second.send(200), first.send(50)]);
+            sends.map(move |delivered| {
+                assert_eq!(first.total_sent(), 150);
+                assert_eq!(second.total_sent(), 200);
+                assert_eq!(connection.destination_asset_code(), Some("XYZ".to_string()));
+                assert_eq!(connection.destination_asset_scale(), Some(9));
+                connection.close();
+                delivered
+            })
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let delivered = runtime.block_on(sender).unwrap();
+        assert_eq!(delivered[1], 200);
+        assert_eq!(delivered[2], 150);
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert_eq!(total_received(&store), 350);
+        let mut stream_totals: Vec<(u64, u64)> = store
+            .streams
+            .lock()
+            .iter()
+            .map(|((_, stream_id), total)| (*stream_id, *total))
+            .collect();
+        stream_totals.sort();
+        assert_eq!(stream_totals, vec![(1, 150), (3, 200)]);
+    }
+
+    #[test]
+    fn opens_more_streams_than_the_receiver_initially_allows() {
+        let receiver = test_receiver(u64::max_value());
+        let store = receiver.store.clone();
+
+        let sender = connect(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            CongestionAlgorithm::default(),
+        )
+        .and_then(|connection| {
+            let sends: Vec<_> = (0..DEFAULT_MAX_REMOTE_STREAMS + 5)
+                .map(|_| {
+                    // Each stream is closed once its money is sent
+                    let stream = connection.open_money_stream();
+                    stream.send(10).map(move |_| stream.id())
+                })
+                .collect();
+            join_all(sends).map(move |ids| {
+                connection.close();
+                ids
+            })
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let ids = runtime.block_on(sender).unwrap();
+        assert_eq!(ids.len() as u64, DEFAULT_MAX_REMOTE_STREAMS + 5);
+        assert!(ids.iter().any(|id| *id > 2 * DEFAULT_MAX_REMOTE_STREAMS));
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert_eq!(
+            total_received(&store),
+            10 * (DEFAULT_MAX_REMOTE_STREAMS + 5)
+        );
+    }
+
+    #[test]
+    fn send_money_fails_when_receiver_is_full() {
+        let receiver = test_receiver(500);
+        let result = send_money(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            1000,
+            CongestionAlgorithm::default(),
+        )
+        .wait();
+        match result {
+            Err(Error::ReceiverFull(_)) => {}
+            result => panic!(
+                "Expected ReceiverFull error, got: {:?}",
+                result.map(|r| r.0)
+            ),
+        }
+    }
+
+    #[test]
+    fn stops_sending_when_receive_max_is_reached() {
+        let receiver = test_receiver(500);
+        let store = receiver.store.clone();
+
+        let sender = connect(
+            receiver.service,
+            &sender_account(),
+            receiver.destination_account,
+            &receiver.shared_secret[..],
+            CongestionAlgorithm::default(),
+        )
+        .and_then(|connection| {
+            let stream = connection.open_money_stream();
+            stream.send(1000).then(move |result| {
+                connection.close();
+                Ok(result)
+            })
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let result: Result<u64, Error> = runtime.block_on(sender).unwrap();
+        assert!(result.is_err());
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert!(total_received(&store) <= 500);
     }
 }
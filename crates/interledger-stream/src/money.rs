@@ -0,0 +1,353 @@
+use super::data::DataStreams;
+use super::error::Error;
+use futures::{
+    sync::oneshot::{channel, Sender},
+    Future,
+};
+use log::debug;
+use parking_lot::Mutex;
+use std::{
+    cmp::min,
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+/// A stream for sending money over a `StreamConnection`.
+///
+/// Money can be sent on the stream any number of times. The amounts sent on all of the
+/// connection's streams share the connection's congestion controller, so the packets
+/// are sized and paced the same way as they are for `send_money`.
+///
+/// Dropping the `MoneyStream` or calling `close` closes the stream once the money
+/// that has already been requested is sent.
+pub struct MoneyStream {
+    id: u64,
+    streams: MoneyStreams,
+    data: DataStreams,
+    // Keeps the connection open as long as the stream is in use
+    _connection: Arc<AtomicBool>,
+}
+
+impl MoneyStream {
+    pub(crate) fn new(
+        id: u64,
+        streams: MoneyStreams,
+        data: DataStreams,
+        connection: Arc<AtomicBool>,
+    ) -> Self {
+        streams
+            .inner
+            .lock()
+            .streams
+            .insert(id, MoneyStreamState::default());
+        debug!("Opened money stream {}", id);
+        MoneyStream {
+            id,
+            streams,
+            data,
+            _connection: connection,
+        }
+    }
+
+    /// The STREAM stream ID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Send the given amount (in the sender's units) on this stream.
+    ///
+    /// The future resolves once the amount (and everything requested before it) has been
+    /// sent, with the total amount delivered on this stream so far in the receiver's units.
+    pub fn send(&self, amount: u64) -> impl Future<Item = u64, Error = Error> {
+        let (sender, receiver) = channel();
+        {
+            let mut inner = self.streams.inner.lock();
+            match inner.streams.get_mut(&self.id) {
+                Some(ref mut stream) if !stream.closed => {
+                    stream.send_max = stream.send_max.saturating_add(amount);
+                    let target = stream.send_max;
+                    stream.waiters.push((target, sender));
+                    stream.resolve_waiters();
+                }
+                _ => {
+                    let _ = sender.send(Err(Error::SendMoneyError(
+                        "Money stream is closed".to_string(),
+                    )));
+                }
+            }
+        }
+        self.data.notify_connection();
+        receiver
+            .map_err(|_| Error::SendMoneyError("Connection closed".to_string()))
+            .and_then(|result| result)
+    }
+
+    /// The total amount sent on this stream, in the sender's units
+    pub fn total_sent(&self) -> u64 {
+        self.streams
+            .with_stream(self.id, |stream| stream.total_sent)
+            .unwrap_or_default()
+    }
+
+    /// The total amount delivered on this stream, as reported by the receiver and in the receiver's units
+    pub fn total_delivered(&self) -> u64 {
+        self.streams
+            .with_stream(self.id, |stream| stream.total_delivered)
+            .unwrap_or_default()
+    }
+
+    /// Close the stream once the money that has already been requested is sent
+    pub fn close(&self) {
+        self.streams
+            .with_stream(self.id, |stream| stream.closed = true);
+        self.data.notify_connection();
+    }
+}
+
+impl Drop for MoneyStream {
+    fn drop(&mut self) {
+        self.streams.with_stream(self.id, |stream| {
+            stream.closed = true;
+            stream.handle_dropped = true;
+        });
+        self.data.notify_connection();
+    }
+}
+
+type Waiter = Sender<Result<u64, Error>>;
+
+#[derive(Default)]
+struct MoneyStreamState {
+    /// The total the application has asked to send
+    send_max: u64,
+    total_sent: u64,
+    in_flight: u64,
+    total_delivered: u64,
+    /// The most the receiver will accept on this stream, if it told us
+    receive_max: Option<u64>,
+    /// The futures waiting for the total sent to reach the given amounts
+    waiters: Vec<(u64, Waiter)>,
+    closed: bool,
+    /// Set if the stream was stopped before all of the money could be sent
+    failed: bool,
+    close_sent: bool,
+    handle_dropped: bool,
+}
+
+impl MoneyStreamState {
+    /// How much is left to send
+    fn available(&self) -> u64 {
+        if self.failed {
+            0
+        } else {
+            self.send_max - self.total_sent - self.in_flight
+        }
+    }
+
+    fn resolve_waiters(&mut self) {
+        let total_sent = self.total_sent;
+        let total_delivered = self.total_delivered;
+        let (done, waiting) = self
+            .waiters
+            .drain(..)
+            .partition(|(target, _)| *target <= total_sent);
+        self.waiters = waiting;
+        for (_, waiter) in done {
+            let _ = waiter.send(Ok(total_delivered));
+        }
+    }
+
+    /// Stop sending on this stream and fail the waiters that have not been resolved yet
+    fn fail(&mut self, error: &Error) {
+        self.failed = true;
+        self.closed = true;
+        for (_, waiter) in self.waiters.drain(..) {
+            let _ = waiter.send(Err(error.clone()));
+        }
+    }
+}
+
+#[derive(Default)]
+struct MoneyStreamsInner {
+    streams: HashMap<u64, MoneyStreamState>,
+}
+
+/// The money streams of a single STREAM connection, shared between the
+/// connection and the `MoneyStream` handles.
+#[derive(Clone, Default)]
+pub(crate) struct MoneyStreams {
+    inner: Arc<Mutex<MoneyStreamsInner>>,
+}
+
+impl MoneyStreams {
+    fn with_stream<T>(&self, id: u64, func: impl FnOnce(&mut MoneyStreamState) -> T) -> Option<T> {
+        self.inner.lock().streams.get_mut(&id).map(func)
+    }
+
+    /// Split up to `max_amount` between the streams that have money waiting to be sent.
+    ///
+    /// The amounts are considered in flight until they are passed to `fulfilled` or `rejected`.
+    pub fn take_amounts(
+        &self,
+        max_amount: u64,
+        is_allowed: impl Fn(u64) -> bool,
+    ) -> Vec<(u64, u64)> {
+        let mut inner = self.inner.lock();
+        let mut ids: Vec<u64> = inner.streams.keys().cloned().collect();
+        ids.sort();
+        let mut remaining = max_amount;
+        let mut amounts = Vec::new();
+        for id in ids {
+            if remaining == 0 {
+                break;
+            }
+            let stream = inner.streams.get_mut(&id).unwrap();
+            let amount = min(stream.available(), remaining);
+            if amount > 0 && is_allowed(id) {
+                stream.in_flight += amount;
+                remaining -= amount;
+                amounts.push((id, amount));
+            }
+        }
+        amounts
+    }
+
+    pub fn fulfilled(&self, amounts: &[(u64, u64)]) {
+        let mut inner = self.inner.lock();
+        for (id, amount) in amounts {
+            if let Some(stream) = inner.streams.get_mut(id) {
+                stream.in_flight -= amount;
+                stream.total_sent += amount;
+                stream.resolve_waiters();
+            }
+        }
+    }
+
+    pub fn rejected(&self, amounts: &[(u64, u64)]) {
+        let mut inner = self.inner.lock();
+        for (id, amount) in amounts {
+            if let Some(stream) = inner.streams.get_mut(id) {
+                stream.in_flight -= amount;
+            }
+        }
+    }
+
+    /// Record the amount the receiver says it has received on the stream
+    pub fn set_total_delivered(&self, id: u64, total_received: u64, receive_max: u64) {
+        self.with_stream(id, |stream| {
+            stream.total_delivered = stream.total_delivered.max(total_received);
+            stream.receive_max = Some(receive_max);
+        });
+    }
+
+    /// Add to the amount delivered on a stream, for when the receiver did not tell us the total
+    pub fn add_delivered(&self, id: u64, amount: u64) {
+        self.with_stream(id, |stream| {
+            stream.total_delivered = stream.total_delivered.saturating_add(amount)
+        });
+    }
+
+    /// Stop sending on streams the receiver will not accept any more money on.
+    ///
+    /// Returns true if any of the streams were stopped.
+    pub fn stop_full_streams(&self, ids: &[u64]) -> bool {
+        let mut inner = self.inner.lock();
+        let mut stopped = false;
+        for id in ids {
+            if let Some(stream) = inner.streams.get_mut(id) {
+                if let Some(receive_max) = stream.receive_max {
+                    if stream.total_delivered >= receive_max && !stream.closed {
+                        debug!(
+                            "Receiver will not accept more money on stream {} (receive max: {})",
+                            id, receive_max
+                        );
+                        stream.fail(&Error::ReceiverFull(format!(
+                            "Receiver's maximum amount on stream {} has been reached",
+                            id
+                        )));
+                        stopped = true;
+                    }
+                }
+            }
+        }
+        stopped
+    }
+
+    /// Returns the IDs of the closed streams that have nothing left to send
+    pub fn take_closed(&self) -> Vec<u64> {
+        let mut inner = self.inner.lock();
+        let mut closed = Vec::new();
+        for (id, stream) in inner.streams.iter_mut() {
+            if stream.closed
+                && !stream.close_sent
+                && stream.available() == 0
+                && stream.in_flight == 0
+            {
+                stream.close_sent = true;
+                closed.push(*id);
+            }
+        }
+        inner
+            .streams
+            .retain(|_, stream| !stream.close_sent || !stream.handle_dropped);
+        closed.sort();
+        closed
+    }
+
+    /// Put the closes that did not reach the receiver back into the queue
+    pub fn requeue_closed(&self, ids: &[u64]) {
+        let mut inner = self.inner.lock();
+        for id in ids {
+            let stream = inner
+                .streams
+                .entry(*id)
+                .or_insert_with(|| MoneyStreamState {
+                    closed: true,
+                    handle_dropped: true,
+                    ..Default::default()
+                });
+            stream.close_sent = false;
+        }
+    }
+
+    /// Stop sending on the given streams and fail any sends that are waiting
+    pub fn stop_streams(&self, ids: &[u64], error: &Error) {
+        let mut inner = self.inner.lock();
+        for id in ids {
+            if let Some(stream) = inner.streams.get_mut(id) {
+                stream.fail(error);
+            }
+        }
+    }
+
+    /// Stop sending on a stream the receiver closed. There is no need to send
+    /// a close for it because the receiver already knows it is closed
+    pub fn remote_closed(&self, id: u64, error: &Error) {
+        self.with_stream(id, |stream| {
+            stream.fail(error);
+            stream.close_sent = true;
+        });
+    }
+
+    /// Stop sending on all of the streams, for when the connection closes
+    pub fn stop_all(&self, error: &Error) {
+        for stream in self.inner.lock().streams.values_mut() {
+            stream.fail(error);
+        }
+    }
+
+    pub fn close_all(&self) {
+        for stream in self.inner.lock().streams.values_mut() {
+            stream.closed = true;
+        }
+    }
+
+    /// Returns true if all of the streams are closed and there is nothing left to send
+    pub fn is_finished(&self) -> bool {
+        self.inner
+            .lock()
+            .streams
+            .values()
+            .all(|stream| stream.close_sent && stream.in_flight == 0)
+    }
+}
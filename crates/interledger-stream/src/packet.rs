@@ -1,4 +1,5 @@
 use super::crypto::{decrypt, encrypt};
+use super::error::Error;
 use byteorder::ReadBytesExt;
 use bytes::{BufMut, BytesMut};
 use interledger_packet::{
@@ -115,11 +116,15 @@ pub struct StreamPacket {
 }
 
 impl StreamPacket {
-    pub fn from_encrypted(shared_secret: &[u8], ciphertext: BytesMut) -> Result<Self, ParseError> {
-        // TODO handle decryption failure
-        let decrypted = decrypt(shared_secret, ciphertext)
-            .map_err(|_err| ParseError::InvalidPacket(String::from("Unable to decrypt packet")))?;
+    /// Decrypt and parse a STREAM packet.
+    ///
+    /// Packets that cannot be decrypted (which usually means they were not meant for us)
+    /// result in a `DecryptionError`, while packets that were decrypted but are malformed
+    /// result in an `InvalidPacket` error.
+    pub fn from_encrypted(shared_secret: &[u8], ciphertext: BytesMut) -> Result<Self, Error> {
+        let decrypted = decrypt(shared_secret, ciphertext).map_err(|_| Error::DecryptionError)?;
         StreamPacket::from_bytes_unencrypted(decrypted)
+            .map_err(|err| Error::InvalidPacket(err.to_string()))
     }
 
     fn from_bytes_unencrypted(buffer_unencrypted: BytesMut) -> Result<Self, ParseError> {
@@ -140,25 +145,32 @@ impl StreamPacket {
         let num_frames = reader.read_var_uint()?;
         let frames_offset = buffer_unencrypted.len() - reader.len();
 
-        // Try reading through all the frames to make sure they can be parsed correctly
-        if num_frames
-            == (FrameIterator {
-                buffer: &buffer_unencrypted[frames_offset..],
-            })
-            .count() as u64
-        {
-            Ok(StreamPacket {
-                buffer_unencrypted,
-                sequence,
-                ilp_packet_type,
-                prepare_amount,
-                frames_offset,
-            })
-        } else {
-            Err(ParseError::InvalidPacket(
-                "Incorrect number of frames or unable to parse all frames".to_string(),
-            ))
+        // Read through all the frames to make sure they can be parsed correctly,
+        // so that iterating through them later cannot fail
+        let mut iterator = FrameIterator {
+            buffer: &buffer_unencrypted[frames_offset..],
+        };
+        let mut frames_read = 0;
+        while !iterator.buffer.is_empty() {
+            iterator.try_read_next_frame().map_err(|err| {
+                ParseError::InvalidPacket(format!("Unable to parse frame {}: {}", frames_read, err))
+            })?;
+            frames_read += 1;
         }
+        if frames_read != num_frames {
+            return Err(ParseError::InvalidPacket(format!(
+                "Packet says it has {} frames but has {}",
+                num_frames, frames_read
+            )));
+        }
+
+        Ok(StreamPacket {
+            buffer_unencrypted,
+            sequence,
+            ilp_packet_type,
+            prepare_amount,
+            frames_offset,
+        })
     }
 
     pub fn into_encrypted(self, shared_secret: &[u8]) -> BytesMut {
@@ -263,15 +275,18 @@ impl<'a> Iterator for FrameIterator<'a> {
     type Item = Frame<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.buffer.is_empty() {
-            // TODO don't ignore errors if the packet is just invalid
-            match self.try_read_next_frame() {
-                Ok(frame) => return Some(frame),
-                Err(err) => warn!("Error reading STREAM frame: {:?}", err),
+        if self.buffer.is_empty() {
+            return None;
+        }
+        // The frames were all checked when the packet was parsed
+        match self.try_read_next_frame() {
+            Ok(frame) => Some(frame),
+            Err(err) => {
+                warn!("Error reading STREAM frame: {:?}", err);
+                self.buffer = &[];
+                None
             }
         }
-
-        None
     }
 }
 
@@ -844,6 +859,47 @@ mod serialization {
         assert_eq!(iter.count(), 12);
     }
 
+    #[test]
+    fn it_rejects_malformed_frames() {
+        let mut buffer = BytesMut::from(&SERIALIZED[..8]);
+        // A StreamMoney frame whose contents are cut off
+        buffer.put_u8(FrameType::StreamMoney as u8);
+        buffer.put_var_octet_string(vec![1]);
+        buffer.put_u8(FrameType::StreamMoney as u8);
+        buffer.put_var_octet_string(vec![1, 1, 1, 1]);
+        buffer[7] = 2;
+        let err = StreamPacket::from_bytes_unencrypted(buffer).unwrap_err();
+        assert!(err.to_string().contains("frame 0"));
+    }
+
+    #[test]
+    fn it_rejects_incorrect_number_of_frames() {
+        let mut buffer = SERIALIZED.clone();
+        buffer[7] = 13;
+        assert!(StreamPacket::from_bytes_unencrypted(buffer).is_err());
+    }
+
+    #[test]
+    fn it_distinguishes_decryption_errors() {
+        let encrypted = PACKET.clone().into_encrypted(&[0; 32]);
+        assert_eq!(
+            StreamPacket::from_encrypted(&[1; 32], encrypted.clone()).unwrap_err(),
+            Error::DecryptionError
+        );
+        assert_eq!(
+            StreamPacket::from_encrypted(&[0; 32], BytesMut::from(&encrypted[..10])).unwrap_err(),
+            Error::DecryptionError
+        );
+
+        let mut invalid = SERIALIZED.clone();
+        invalid[7] = 13;
+        let invalid = encrypt(&[0; 32], invalid);
+        match StreamPacket::from_encrypted(&[0; 32], invalid) {
+            Err(Error::InvalidPacket(_)) => {}
+            result => panic!("Expected InvalidPacket error, got: {:?}", result),
+        }
+    }
+
     #[test]
     fn it_saturates_max_money_frame_receive_max() {
         let mut buffer = BytesMut::new();
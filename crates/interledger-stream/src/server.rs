@@ -1,8 +1,14 @@
 use super::crypto::*;
+use super::data::{DataStream, DataStreams, OutgoingFrames, Side, MAX_DATA_PER_PACKET};
+use super::error::Error;
 use super::packet::*;
 use base64;
 use bytes::Bytes;
-use futures::future::result;
+use futures::{
+    future::{err, Either},
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Future,
+};
 use hex;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::{
@@ -10,11 +16,23 @@ use interledger_packet::{
     RejectBuilder,
 };
 use interledger_service::{Account, BoxedIlpFuture, OutgoingRequest, OutgoingService};
-use log::debug;
+use log::{debug, error};
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_secret_generator";
+const FIXED_CONNECTION_GENERATOR: &[u8] = b"ilp_stream_fixed_connection";
+/// Separates the connection tag from the rest of the `destination_account`'s last segment
+const CONNECTION_TAG_SEPARATOR: char = '~';
+/// Length of the base64url-encoded (unpadded) auth tag at the end of the connection token
+const AUTH_TAG_ENCODED_LENGTH: usize = 19;
+/// How long a connection's data streams are kept after the last packet on it.
+/// Senders that go away without closing the connection would otherwise leave them behind forever
+const DATA_CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// A STREAM connection generator that creates `destination_account` and `shared_secret` values
 /// based on a single root secret.
@@ -43,26 +61,85 @@ impl ConnectionGenerator {
     /// in any way, the server will not be able to re-derive the secret and the packet will be rejected.
     // TODO make sure this is an ILP address
     pub fn generate_address_and_secret(&self, base_address: &Address) -> (Address, [u8; 32]) {
-        let random_bytes = generate_token();
+        // An empty tag cannot make the address invalid
+        self.generate(base_address, "").unwrap()
+    }
+
+    /// Generate the STREAM parameters, like `generate_address_and_secret`, but attach the
+    /// given `connection_tag` to the `destination_account`.
+    ///
+    /// The tag is authenticated along with the rest of the address so that the receiver can
+    /// use it to associate incoming payments with something like a user or an invoice.
+    /// Tags may only contain the characters `[a-zA-Z0-9_-]`.
+    pub fn generate_tagged_address_and_secret(
+        &self,
+        base_address: &Address,
+        connection_tag: &str,
+    ) -> Result<(Address, [u8; 32]), ()> {
+        if is_valid_tag(connection_tag) {
+            self.generate(base_address, connection_tag)
+        } else {
+            error!("Invalid connection tag: {}", connection_tag);
+            Err(())
+        }
+    }
+
+    /// Generate the STREAM parameters for a connection tagged with the given `connection_tag`,
+    /// like `generate_tagged_address_and_secret`, except that the same tag always produces
+    /// the same `destination_account` and `shared_secret`.
+    ///
+    /// This is useful when everything paid with the tag should be tracked as a single
+    /// connection, such as when the tag identifies an invoice that may be queried many times.
+    pub fn generate_fixed_address_and_secret(
+        &self,
+        base_address: &Address,
+        connection_tag: &str,
+    ) -> Result<(Address, [u8; 32]), ()> {
+        if is_valid_tag(connection_tag) {
+            let mut message = FIXED_CONNECTION_GENERATOR.to_vec();
+            message.extend_from_slice(connection_tag.as_bytes());
+            let random_bytes = hmac_sha256(&self.secret_generator[..], &message[..]);
+            self.generate_from_bytes(base_address, connection_tag, &random_bytes[..18])
+        } else {
+            error!("Invalid connection tag: {}", connection_tag);
+            Err(())
+        }
+    }
+
+    fn generate(
+        &self,
+        base_address: &Address,
+        connection_tag: &str,
+    ) -> Result<(Address, [u8; 32]), ()> {
+        self.generate_from_bytes(base_address, connection_tag, &generate_token()[..])
+    }
+
+    fn generate_from_bytes(
+        &self,
+        base_address: &Address,
+        connection_tag: &str,
+        random_bytes: &[u8],
+    ) -> Result<(Address, [u8; 32]), ()> {
         // base_address + "." + 32-bytes encoded as base64url
-        let shared_secret = hmac_sha256(&self.secret_generator[..], &random_bytes[..]);
-        // Note that the unwrap here is safe because we know the base_address
-        // is valid and adding base64-url characters will always be valid
+        let shared_secret = self.derive_secret(random_bytes, connection_tag);
         let destination_account = base_address
-            .with_suffix(
-                &base64::encode_config(&random_bytes[..], base64::URL_SAFE_NO_PAD).as_ref(),
-            )
-            .unwrap();
+            .with_suffix(&base64::encode_config(random_bytes, base64::URL_SAFE_NO_PAD).as_ref())
+            .map_err(|err| error!("Unable to generate destination account: {:?}", err))?;
 
         let auth_tag = &hmac_sha256(&shared_secret[..], destination_account.as_ref())[..14];
 
         // can we avoid the copy?
         let mut dest = destination_account.to_bytes();
         dest.extend(base64::encode_config(auth_tag, base64::URL_SAFE_NO_PAD).bytes());
-        let destination_account = Address::try_from(dest).unwrap();
+        if !connection_tag.is_empty() {
+            dest.extend(CONNECTION_TAG_SEPARATOR.to_string().bytes());
+            dest.extend(connection_tag.bytes());
+        }
+        let destination_account = Address::try_from(dest)
+            .map_err(|err| error!("Unable to generate destination account: {:?}", err))?;
 
         debug!("Generated address: {}", destination_account,);
-        (destination_account, shared_secret)
+        Ok((destination_account, shared_secret))
     }
 
     /// Rederive the `shared_secret` from a `destination_account`. This will return an
@@ -70,54 +147,347 @@ impl ConnectionGenerator {
     /// with the same server secret.
     pub fn rederive_secret(&self, destination_account: &Address) -> Result<[u8; 32], ()> {
         let local_part = destination_account.segments().rev().next().unwrap();
-        let local_part =
-            base64::decode_config(local_part, base64::URL_SAFE_NO_PAD).map_err(|_| ())?;
-        if local_part.len() == 32 {
-            let (random_bytes, auth_tag) = local_part.split_at(18);
-            let shared_secret = hmac_sha256(&self.secret_generator[..], &random_bytes[..]);
+        let (token, connection_tag) = split_connection_tag(local_part);
+        let token_bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| ())?;
+        if token_bytes.len() == 32 {
+            let (random_bytes, auth_tag) = token_bytes.split_at(18);
+            let shared_secret = self.derive_secret(random_bytes, connection_tag.unwrap_or(""));
+            // The auth tag covers the address up to (but not including) the auth tag itself
             let dest: &[u8] = destination_account.as_ref();
-            let derived_auth_tag = &hmac_sha256(&shared_secret[..], &dest[..dest.len() - 19])[..14];
+            let auth_tag_start =
+                dest.len() - local_part.len() + token.len() - AUTH_TAG_ENCODED_LENGTH;
+            let derived_auth_tag = &hmac_sha256(&shared_secret[..], &dest[..auth_tag_start])[..14];
             if derived_auth_tag == auth_tag {
                 return Ok(shared_secret);
             }
         }
         Err(())
     }
+
+    // The connection tag is mixed into the shared secret so that changing the tag
+    // also invalidates the auth tag in the destination_account
+    fn derive_secret(&self, random_bytes: &[u8], connection_tag: &str) -> [u8; 32] {
+        let mut message = random_bytes.to_vec();
+        message.extend_from_slice(connection_tag.as_bytes());
+        hmac_sha256(&self.secret_generator[..], &message[..])
+    }
+}
+
+fn is_valid_tag(connection_tag: &str) -> bool {
+    !connection_tag.is_empty()
+        && connection_tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Get the connection tag attached to a `destination_account`, if there is one
+pub fn connection_tag(destination_account: &Address) -> Option<&str> {
+    destination_account
+        .segments()
+        .rev()
+        .next()
+        .and_then(|local_part| split_connection_tag(local_part).1)
+}
+
+/// Split the last segment of a `destination_account` into the connection token and
+/// the (optional) connection tag
+fn split_connection_tag(local_part: &str) -> (&str, Option<&str>) {
+    let mut parts = local_part.splitn(2, CONNECTION_TAG_SEPARATOR);
+    let token = parts.next().unwrap_or_default();
+    (token, parts.next())
+}
+
+/// The amounts received on a STREAM connection, as tracked by the `StreamReceiverStore`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionTotals {
+    /// Total received on the connection, across all of its streams
+    pub connection_total: u64,
+    /// Totals received on each of the streams passed to the store, in the same order
+    pub stream_totals: Vec<u64>,
+    /// The sender's ILP address, if they have told us what it is
+    pub source_account: Option<Address>,
+}
+
+/// A store for the amounts received on each incoming STREAM connection.
+pub trait StreamReceiverStore {
+    /// Atomically credit the `(stream_id, amount)` pairs to the given connection, unless that
+    /// would bring the total received on the connection above `receive_max`, in which case
+    /// nothing is credited. The `source_account` should be saved if one is given.
+    ///
+    /// Returns whether the amounts were credited along with the resulting totals.
+    fn credit_connection(
+        &self,
+        connection_id: String,
+        amounts: Vec<(u64, u64)>,
+        receive_max: u64,
+        source_account: Option<Address>,
+    ) -> Box<dyn Future<Item = (bool, ConnectionTotals), Error = ()> + Send>;
+
+    /// Look up the totals received on the given connection without modifying anything.
+    /// The `stream_totals` are left empty and connections that have not received anything have a total of zero.
+    fn get_connection_totals(
+        &self,
+        connection_id: String,
+    ) -> Box<dyn Future<Item = ConnectionTotals, Error = ()> + Send>;
+}
+
+/// Notification published by the `StreamReceiverService` whenever money is received
+/// on one of its streams.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentNotification {
+    /// The tag attached to the connection's `destination_account`, if there was one
+    pub connection_tag: Option<String>,
+    pub stream_id: u64,
+    pub amount: u64,
+    /// The sender's ILP address, if they have told us what it is
+    pub source_account: Option<Address>,
+}
+
+/// A data stream opened by a STREAM sender, passed to the application by the `StreamReceiverService`.
+pub struct IncomingDataStream {
+    /// The tag attached to the connection's `destination_account`, if there was one
+    pub connection_tag: Option<String>,
+    pub stream: DataStream,
+}
+
+/// The data streams of the connections handled by a `StreamReceiverService`.
+///
+/// Unlike the amounts received, the data is only kept in memory.
+/// Connections that have not sent a packet in `DATA_CONNECTION_IDLE_TIMEOUT` are dropped.
+#[derive(Clone)]
+struct DataConnections {
+    connections: Arc<Mutex<HashMap<String, DataConnection>>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<IncomingDataStream>>>>,
+    last_expired: Arc<Mutex<Instant>>,
+}
+
+struct DataConnection {
+    streams: DataStreams,
+    last_active: Instant,
+}
+
+impl Default for DataConnections {
+    fn default() -> Self {
+        DataConnections {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            last_expired: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl DataConnections {
+    /// Handle the data frames in an incoming packet and determine which data frames to send back.
+    ///
+    /// The state for a connection is only created once the sender sends data on it.
+    fn handle_packet(
+        &self,
+        connection_id: &str,
+        connection_tag: &Option<String>,
+        packet: &StreamPacket,
+    ) -> OutgoingFrames {
+        let mut has_data = false;
+        let mut is_closing = false;
+        for frame in packet.frames() {
+            match frame {
+                Frame::StreamData(_)
+                | Frame::StreamMaxData(_)
+                | Frame::StreamClose(_)
+                | Frame::ConnectionStreamIdBlocked(_) => has_data = true,
+                Frame::ConnectionClose(_) => is_closing = true,
+                _ => {}
+            }
+        }
+
+        let now = Instant::now();
+        self.expire_idle(now);
+        let streams = {
+            let mut connections = self.connections.lock();
+            if let Some(connection) = connections.get_mut(connection_id) {
+                connection.last_active = now;
+                connection.streams.clone()
+            } else if has_data && !is_closing {
+                let streams = DataStreams::new(Side::Server);
+                connections.insert(
+                    connection_id.to_string(),
+                    DataConnection {
+                        streams: streams.clone(),
+                        last_active: now,
+                    },
+                );
+                streams
+            } else {
+                return OutgoingFrames::default();
+            }
+        };
+
+        for stream in streams.handle_incoming_frames(packet) {
+            self.publish_stream(IncomingDataStream {
+                connection_tag: connection_tag.clone(),
+                stream,
+            });
+        }
+
+        if is_closing {
+            debug!("Sender closed connection, dropping its data streams");
+            streams.close_all();
+            self.connections.lock().remove(connection_id);
+            OutgoingFrames::default()
+        } else {
+            streams.take_outgoing_frames(MAX_DATA_PER_PACKET)
+        }
+    }
+
+    /// Put frames taken by `handle_packet` back into the queue if the response
+    /// they were supposed to be sent in will not carry them
+    fn requeue(&self, connection_id: &str, frames: OutgoingFrames) {
+        if frames.is_empty() {
+            return;
+        }
+        let streams = self
+            .connections
+            .lock()
+            .get(connection_id)
+            .map(|connection| connection.streams.clone());
+        if let Some(streams) = streams {
+            streams.requeue(frames);
+        }
+    }
+
+    /// Drop the connections that have been idle for longer than `DATA_CONNECTION_IDLE_TIMEOUT`.
+    /// This only checks once per timeout period, so connections may be kept for up to twice as long
+    fn expire_idle(&self, now: Instant) {
+        {
+            let mut last_expired = self.last_expired.lock();
+            if *last_expired + DATA_CONNECTION_IDLE_TIMEOUT > now {
+                return;
+            }
+            *last_expired = now;
+        }
+        self.connections.lock().retain(|connection_id, connection| {
+            if connection.last_active + DATA_CONNECTION_IDLE_TIMEOUT > now {
+                true
+            } else {
+                debug!(
+                    "Dropping data streams of connection {} because it has been idle",
+                    connection_id
+                );
+                connection.streams.close_all();
+                false
+            }
+        });
+    }
+
+    /// Hand the stream to the first subscriber that is still listening
+    fn publish_stream(&self, stream: IncomingDataStream) {
+        let mut subscribers = self.subscribers.lock();
+        let mut stream = Some(stream);
+        subscribers.retain(|subscriber| {
+            if let Some(to_send) = stream.take() {
+                match subscriber.unbounded_send(to_send) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        stream = Some(err.into_inner());
+                        false
+                    }
+                }
+            } else {
+                true
+            }
+        });
+        if stream.is_some() {
+            debug!("No one is accepting data streams, closing incoming stream");
+        }
+    }
 }
 
 /// An OutgoingService that fulfills incoming STREAM packets.
 ///
-/// Note this does **not** maintain STREAM state, but instead fulfills
-/// all incoming packets to collect the money.
+/// The amounts received on each connection and stream are tracked in the `StreamReceiverStore`,
+/// so the receiver can tell the sender how much has arrived and stop accepting money once the
+/// connection's `receive_max` is reached. Applications can `subscribe` to be notified of
+/// incoming payments.
 ///
-/// This does not currently support handling data sent via STREAM.
+/// Applications can also `accept_data_streams` to read and write data over the streams senders open.
+/// Since the receiver can only respond to the sender's packets, data written to those streams
+/// is sent back along with the Fulfill or Reject for the next packet on that connection.
 #[derive(Clone)]
-pub struct StreamReceiverService<O: OutgoingService<A>, A: Account> {
+pub struct StreamReceiverService<S, O: OutgoingService<A>, A: Account> {
     connection_generator: ConnectionGenerator,
+    store: S,
+    receive_max: u64,
+    receive_max_for_tag: Option<Arc<dyn Fn(&str) -> Option<u64> + Send + Sync>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<PaymentNotification>>>>,
+    data_connections: DataConnections,
     next: O,
     account_type: PhantomData<A>,
 }
 
-impl<O, A> StreamReceiverService<O, A>
+impl<S, O, A> StreamReceiverService<S, O, A>
 where
+    S: StreamReceiverStore,
     O: OutgoingService<A>,
     A: Account,
 {
-    pub fn new(server_secret: Bytes, next: O) -> Self {
+    pub fn new(server_secret: Bytes, store: S, next: O) -> Self {
         let connection_generator = ConnectionGenerator::new(server_secret);
         StreamReceiverService {
             connection_generator,
+            store,
+            receive_max: u64::max_value(),
+            receive_max_for_tag: None,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            data_connections: DataConnections::default(),
             next,
             account_type: PhantomData,
         }
     }
+
+    /// Set the maximum amount that will be accepted on each connection (defaults to `u64::MAX`)
+    pub fn receive_max(&mut self, receive_max: u64) -> &mut Self {
+        self.receive_max = receive_max;
+        self
+    }
+
+    /// Set a function that determines the `receive_max` for connections based on their
+    /// connection tag. If the function returns `None` for a tag (or the connection is not
+    /// tagged), the connection uses the service's overall `receive_max`.
+    ///
+    /// The tags are authenticated along with the rest of the `destination_account`, so
+    /// senders cannot modify them to get around the limit.
+    pub fn receive_max_for_tag<F>(&mut self, receive_max_for_tag: F) -> &mut Self
+    where
+        F: Fn(&str) -> Option<u64> + Send + Sync + 'static,
+    {
+        self.receive_max_for_tag = Some(Arc::new(receive_max_for_tag));
+        self
+    }
+
+    /// Returns a stream of notifications for all of the money received by this service
+    /// (and any of its clones).
+    pub fn subscribe(&self) -> UnboundedReceiver<PaymentNotification> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    /// Returns a stream of the data streams opened by senders. Each data stream is only
+    /// passed to one of the receivers returned by this method (or by clones of this service).
+    ///
+    /// Streams opened while no one is accepting them are closed immediately.
+    pub fn accept_data_streams(&self) -> UnboundedReceiver<IncomingDataStream> {
+        let (sender, receiver) = unbounded();
+        self.data_connections.subscribers.lock().push(sender);
+        receiver
+    }
 }
 
 // TODO should this be an OutgoingService instead so the balance logic is applied before this is called?
-impl<O, A> OutgoingService<A> for StreamReceiverService<O, A>
+impl<S, O, A> OutgoingService<A> for StreamReceiverService<S, O, A>
 where
+    S: StreamReceiverStore + Clone + Send + Sync + 'static,
     O: OutgoingService<A>,
-    A: Account + IldcpAccount,
+    A: Account + IldcpAccount + 'static,
 {
     type Future = BoxedIlpFuture;
 
@@ -133,109 +503,253 @@ where
         let dest: &[u8] = destination.as_ref();
         if dest.starts_with(to.as_ref()) {
             if let Ok(shared_secret) = self.connection_generator.rederive_secret(&destination) {
-                {
-                    return Box::new(result(receive_money(&shared_secret, &to, request.prepare)));
-                }
+                let subscribers = self.subscribers.clone();
+                let receive_max = match (&self.receive_max_for_tag, connection_tag(&destination)) {
+                    (Some(receive_max_for_tag), Some(tag)) => {
+                        receive_max_for_tag(tag).unwrap_or(self.receive_max)
+                    }
+                    _ => self.receive_max,
+                };
+                return Box::new(
+                    receive_money(
+                        self.store.clone(),
+                        self.data_connections.clone(),
+                        receive_max,
+                        shared_secret,
+                        &request.to,
+                        request.prepare,
+                    )
+                    .map(move |(fulfill, notifications)| {
+                        publish_notifications(&subscribers, notifications);
+                        fulfill
+                    }),
+                );
             }
         }
         Box::new(self.next.send_request(request))
     }
 }
 
-// TODO send asset code and scale back to sender also
-fn receive_money(
-    shared_secret: &[u8; 32],
-    client_address: &Address,
+fn publish_notifications(
+    subscribers: &Mutex<Vec<UnboundedSender<PaymentNotification>>>,
+    notifications: Vec<PaymentNotification>,
+) {
+    if notifications.is_empty() {
+        return;
+    }
+    // Drop the subscribers whose receivers have been dropped
+    subscribers.lock().retain(|subscriber| {
+        notifications
+            .iter()
+            .all(|notification| subscriber.unbounded_send(notification.clone()).is_ok())
+    });
+}
+
+fn receive_money<S, A>(
+    store: S,
+    data_connections: DataConnections,
+    receive_max: u64,
+    shared_secret: [u8; 32],
+    account: &A,
     prepare: Prepare,
-) -> Result<Fulfill, Reject> {
+) -> impl Future<Item = (Fulfill, Vec<PaymentNotification>), Error = Reject>
+where
+    S: StreamReceiverStore,
+    A: IldcpAccount,
+{
+    let client_address = account.client_address().clone();
+    // The receiver's asset details are sent back in every response because
+    // the sender has no way to ask for them again if a response is lost
+    let asset_code = account.asset_code().to_string();
+    let asset_scale = account.asset_scale();
+
     // Generate fulfillment
     let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
     let condition = hash_sha256(&fulfillment);
     let is_fulfillable = condition == prepare.execution_condition();
 
+    let destination = prepare.destination();
+    let connection_id = String::from_utf8_lossy(destination.as_ref()).to_string();
+    let connection_tag = connection_tag(&destination).map(|tag| tag.to_string());
+
     // Parse STREAM packet
     // TODO avoid copying data
     let prepare_amount = prepare.amount();
-    let stream_packet =
-        StreamPacket::from_encrypted(shared_secret, prepare.into_data()).map_err(|_| {
-            debug!("Unable to parse data, rejecting Prepare packet");
-            RejectBuilder {
+    let stream_packet = match StreamPacket::from_encrypted(&shared_secret, prepare.into_data()) {
+        Ok(stream_packet) => stream_packet,
+        Err(error) => {
+            debug!("Rejecting Prepare packet: {}", error);
+            let message: &[u8] = match error {
+                Error::DecryptionError => b"Could not decrypt data",
+                _ => b"Invalid STREAM packet",
+            };
+            return Either::A(err(RejectBuilder {
                 code: ErrorCode::F06_UNEXPECTED_PAYMENT,
-                message: b"Could not decrypt data",
-                triggered_by: Some(client_address),
+                message,
+                triggered_by: Some(&client_address),
                 data: &[],
             }
-            .build()
-        })?;
-
-    let mut response_frames: Vec<Frame> = Vec::new();
+            .build()));
+        }
+    };
 
     // Handle STREAM frames
-    // TODO reject if they send data?
+    let outgoing_data =
+        data_connections.handle_packet(&connection_id, &connection_tag, &stream_packet);
+    let mut money_frames: Vec<StreamMoneyFrame> = Vec::new();
+    let mut source_account = None;
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
-        if let Frame::StreamMoney(frame) = frame {
-            response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
-                stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
-            }));
-        }
-    }
-
-    // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
-        let response_packet = StreamPacketBuilder {
-            sequence: stream_packet.sequence(),
-            ilp_packet_type: IlpPacketType::Fulfill,
-            prepare_amount,
-            frames: &response_frames,
+        match frame {
+            Frame::StreamMoney(frame) => money_frames.push(frame),
+            Frame::ConnectionNewAddress(frame) => source_account = Some(frame.source_account),
+            _ => {}
         }
-        .build();
+    }
+
+    let will_fulfill = is_fulfillable && prepare_amount >= stream_packet.prepare_amount();
+    if !is_fulfillable {
+        debug!("Packet is unfulfillable");
+    } else if prepare_amount < stream_packet.prepare_amount() {
         debug!(
-            "Fulfilling prepare with fulfillment: {} and encrypted stream packet: {:?}",
-            hex::encode(&fulfillment[..]),
-            response_packet
-        );
-        let encrypted_response = response_packet.into_encrypted(shared_secret);
-        let fulfill = FulfillBuilder {
-            fulfillment: &fulfillment,
-            data: &encrypted_response[..],
-        }
-        .build();
-        Ok(fulfill)
-    } else {
-        let response_packet = StreamPacketBuilder {
-            sequence: stream_packet.sequence(),
-            ilp_packet_type: IlpPacketType::Reject,
+            "Received only: {} when we should have received at least: {}",
             prepare_amount,
-            frames: &response_frames,
-        }
-        .build();
-        if !is_fulfillable {
-            debug!("Packet is unfulfillable");
-        } else if prepare_amount < stream_packet.prepare_amount() {
-            debug!(
-                "Received only: {} when we should have received at least: {}",
-                prepare_amount,
-                stream_packet.prepare_amount()
-            );
-        }
-        debug!(
-            "Rejecting Prepare and including encrypted stream packet {:?}",
-            response_packet
+            stream_packet.prepare_amount()
         );
-        let encrypted_response = response_packet.into_encrypted(shared_secret);
-        let reject = RejectBuilder {
-            code: ErrorCode::F99_APPLICATION_ERROR,
-            message: &[],
-            triggered_by: Some(&client_address),
-            data: &encrypted_response[..],
-        }
-        .build();
-        Err(reject)
+    } else if prepare_amount > 0 && money_frames.is_empty() {
+        debug!("Packet has money but no StreamMoney frames, so we can't credit it to a stream");
     }
+
+    // Only credit the money if we're going to fulfill the packet.
+    // Otherwise, we just look up the current totals to report back to the sender
+    let amounts = split_amount(if will_fulfill { prepare_amount } else { 0 }, &money_frames);
+    let should_credit = will_fulfill && (prepare_amount == 0 || !money_frames.is_empty());
+
+    Either::B(
+        store
+            .credit_connection(
+                connection_id.clone(),
+                amounts.clone(),
+                receive_max,
+                source_account,
+            )
+            .then(move |result| {
+                let (credited, totals) = match result {
+                    Ok(result) => result,
+                    Err(_) => {
+                        error!("Error updating the amounts received on STREAM connection");
+                        // The data would be lost because this reject does not carry it
+                        data_connections.requeue(&connection_id, outgoing_data);
+                        return Err(RejectBuilder {
+                            code: ErrorCode::T00_INTERNAL_ERROR,
+                            message: &[],
+                            triggered_by: Some(&client_address),
+                            data: &[],
+                        }
+                        .build());
+                    }
+                };
+                // Tell the sender how much each stream has received and how much more it can take
+                let remaining = receive_max.saturating_sub(totals.connection_total);
+                let mut response_frames: Vec<Frame> = amounts
+                    .iter()
+                    .zip(totals.stream_totals.iter())
+                    .map(|((stream_id, _), total_received)| {
+                        Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                            stream_id: *stream_id,
+                            total_received: *total_received,
+                            receive_max: total_received.saturating_add(remaining),
+                        })
+                    })
+                    .collect();
+                response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: &asset_code,
+                    source_asset_scale: asset_scale,
+                }));
+                response_frames.extend(outgoing_data.frames());
+
+                // Return Fulfill or Reject Packet
+                if should_credit && credited {
+                    let response_packet = StreamPacketBuilder {
+                        sequence: stream_packet.sequence(),
+                        ilp_packet_type: IlpPacketType::Fulfill,
+                        prepare_amount,
+                        frames: &response_frames,
+                    }
+                    .build();
+                    debug!(
+                        "Fulfilling prepare with fulfillment: {} and encrypted stream packet: {:?}",
+                        hex::encode(&fulfillment[..]),
+                        response_packet
+                    );
+                    let encrypted_response = response_packet.into_encrypted(&shared_secret);
+                    let fulfill = FulfillBuilder {
+                        fulfillment: &fulfillment,
+                        data: &encrypted_response[..],
+                    }
+                    .build();
+                    let notifications = amounts
+                        .into_iter()
+                        .filter(|(_, amount)| *amount > 0)
+                        .map(|(stream_id, amount)| PaymentNotification {
+                            connection_tag: connection_tag.clone(),
+                            stream_id,
+                            amount,
+                            source_account: totals.source_account.clone(),
+                        })
+                        .collect();
+                    Ok((fulfill, notifications))
+                } else {
+                    if will_fulfill && !credited {
+                        debug!(
+                            "Packet would bring the total received on the connection above the receive max of {} (already received: {})",
+                            receive_max, totals.connection_total
+                        );
+                    }
+                    let response_packet = StreamPacketBuilder {
+                        sequence: stream_packet.sequence(),
+                        ilp_packet_type: IlpPacketType::Reject,
+                        prepare_amount,
+                        frames: &response_frames,
+                    }
+                    .build();
+                    debug!(
+                        "Rejecting Prepare and including encrypted stream packet {:?}",
+                        response_packet
+                    );
+                    let encrypted_response = response_packet.into_encrypted(&shared_secret);
+                    Err(RejectBuilder {
+                        code: ErrorCode::F99_APPLICATION_ERROR,
+                        message: &[],
+                        triggered_by: Some(&client_address),
+                        data: &encrypted_response[..],
+                    }
+                    .build())
+                }
+            }),
+    )
+}
+
+/// Split the amount between the StreamMoney frames in proportion to their shares.
+/// Any remainder left over from rounding down is credited to the last stream.
+fn split_amount(amount: u64, money_frames: &[StreamMoneyFrame]) -> Vec<(u64, u64)> {
+    let total_shares: u128 = money_frames
+        .iter()
+        .map(|frame| u128::from(frame.shares))
+        .sum();
+    let mut remaining = amount;
+    money_frames
+        .iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            let stream_amount = if index == money_frames.len() - 1 || total_shares == 0 {
+                remaining
+            } else {
+                (u128::from(amount) * u128::from(frame.shares) / total_shares) as u64
+            };
+            remaining -= stream_amount;
+            (frame.stream_id, stream_amount)
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -277,6 +791,85 @@ mod connection_generator {
             .rederive_secret(&destination_account)
             .is_err());
     }
+
+    #[test]
+    fn rederives_secret_for_tagged_address() {
+        let server_secret = [9; 32];
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&server_secret[..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&receiver_address, "invoice-123")
+            .unwrap();
+
+        assert!(destination_account.to_string().ends_with("~invoice-123"));
+        assert_eq!(
+            connection_generator
+                .rederive_secret(&destination_account)
+                .unwrap(),
+            shared_secret
+        );
+    }
+
+    #[test]
+    fn errors_if_connection_tag_is_modified() {
+        let server_secret = [9; 32];
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&server_secret[..]));
+        let (destination_account, _shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&receiver_address, "invoice-123")
+            .unwrap();
+
+        let modified = destination_account
+            .to_string()
+            .replace("invoice-123", "invoice-124");
+        assert!(connection_generator
+            .rederive_secret(&Address::from_str(&modified).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn generates_fixed_address_for_tag() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_fixed_address_and_secret(&receiver_address, "invoice-123")
+            .unwrap();
+
+        assert_eq!(
+            connection_generator
+                .generate_fixed_address_and_secret(&receiver_address, "invoice-123")
+                .unwrap(),
+            (destination_account.clone(), shared_secret)
+        );
+        assert_ne!(
+            connection_generator
+                .generate_fixed_address_and_secret(&receiver_address, "invoice-124")
+                .unwrap()
+                .0,
+            destination_account
+        );
+        assert_eq!(
+            connection_generator
+                .rederive_secret(&destination_account)
+                .unwrap(),
+            shared_secret
+        );
+        assert!(connection_generator
+            .generate_fixed_address_and_secret(&receiver_address, "")
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_connection_tags() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        assert!(connection_generator
+            .generate_tagged_address_and_secret(&receiver_address, "")
+            .is_err());
+        assert!(connection_generator
+            .generate_tagged_address_and_secret(&receiver_address, "a.b")
+            .is_err());
+    }
 }
 
 #[cfg(test)]
@@ -296,10 +889,23 @@ fn test_stream_packet() -> StreamPacket {
 #[cfg(test)]
 mod receiving_money {
     use super::*;
+    use crate::test_helpers::{TestAccount, TestReceiverStore};
+    use bytes::BytesMut;
     use interledger_packet::PrepareBuilder;
+    use interledger_service::outgoing_service_fn;
 
     use std::str::FromStr;
     use std::time::UNIX_EPOCH;
+
+    fn receiving_account(client_address: &Address) -> TestAccount {
+        TestAccount {
+            id: 0,
+            ilp_address: client_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        }
+    }
+
     #[test]
     fn fulfills_valid_packet() {
         let client_address = Address::from_str("example.destination").unwrap();
@@ -324,7 +930,15 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(
+            TestReceiverStore::default(),
+            DataConnections::default(),
+            u64::max_value(),
+            shared_secret,
+            &receiving_account(&client_address),
+            prepare,
+        )
+        .wait();
         assert!(result.is_ok());
     }
 
@@ -352,7 +966,15 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(
+            TestReceiverStore::default(),
+            DataConnections::default(),
+            u64::max_value(),
+            shared_secret,
+            &receiving_account(&client_address),
+            prepare,
+        )
+        .wait();
         assert!(result.is_ok());
     }
 
@@ -381,7 +1003,15 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(
+            TestReceiverStore::default(),
+            DataConnections::default(),
+            u64::max_value(),
+            shared_secret,
+            &receiving_account(&client_address),
+            prepare,
+        )
+        .wait();
         assert!(result.is_err());
     }
 
@@ -420,16 +1050,290 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(
+            TestReceiverStore::default(),
+            DataConnections::default(),
+            u64::max_value(),
+            shared_secret,
+            &receiving_account(&client_address),
+            prepare,
+        )
+        .wait();
         assert!(result.is_err());
     }
+
+    fn test_prepare(destination: Address, shared_secret: &[u8; 32], amount: u64) -> Prepare {
+        let stream_packet = test_stream_packet();
+        let data = stream_packet.into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        PrepareBuilder {
+            destination,
+            amount,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build()
+    }
+
+    fn max_money_frame(shared_secret: &[u8; 32], data: &[u8]) -> StreamMaxMoneyFrame {
+        let packet = StreamPacket::from_encrypted(shared_secret, BytesMut::from(data)).unwrap();
+        let frame = packet.frames().next().unwrap();
+        if let Frame::StreamMaxMoney(frame) = frame {
+            frame
+        } else {
+            panic!("Expected StreamMaxMoney frame, got: {:?}", frame);
+        }
+    }
+
+    #[test]
+    fn reports_total_received() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address);
+        let store = TestReceiverStore::default();
+
+        for _ in 0..2 {
+            receive_money(
+                store.clone(),
+                DataConnections::default(),
+                u64::max_value(),
+                shared_secret,
+                &receiving_account(&client_address),
+                test_prepare(destination_account.clone(), &shared_secret, 100),
+            )
+            .wait()
+            .unwrap();
+        }
+        let (fulfill, notifications) = receive_money(
+            store.clone(),
+            DataConnections::default(),
+            1000,
+            shared_secret,
+            &receiving_account(&client_address),
+            test_prepare(destination_account, &shared_secret, 100),
+        )
+        .wait()
+        .unwrap();
+
+        let frame = max_money_frame(&shared_secret, fulfill.data());
+        assert_eq!(frame.stream_id, 1);
+        assert_eq!(frame.total_received, 300);
+        assert_eq!(frame.receive_max, 1000);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].amount, 100);
+    }
+
+    #[test]
+    fn sends_asset_details() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address);
+
+        let (fulfill, _) = receive_money(
+            TestReceiverStore::default(),
+            DataConnections::default(),
+            u64::max_value(),
+            shared_secret,
+            &receiving_account(&client_address),
+            test_prepare(destination_account, &shared_secret, 100),
+        )
+        .wait()
+        .unwrap();
+
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(fulfill.data())).unwrap();
+        let asset_details = packet
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::ConnectionAssetDetails(frame) => Some((
+                    frame.source_asset_code.to_string(),
+                    frame.source_asset_scale,
+                )),
+                _ => None,
+            })
+            .next();
+        assert_eq!(asset_details, Some(("XYZ".to_string(), 9)));
+    }
+
+    #[test]
+    fn rejects_packets_over_receive_max() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address);
+        let store = TestReceiverStore::default();
+
+        receive_money(
+            store.clone(),
+            DataConnections::default(),
+            150,
+            shared_secret,
+            &receiving_account(&client_address),
+            test_prepare(destination_account.clone(), &shared_secret, 100),
+        )
+        .wait()
+        .unwrap();
+        let reject = receive_money(
+            store.clone(),
+            DataConnections::default(),
+            150,
+            shared_secret,
+            &receiving_account(&client_address),
+            test_prepare(destination_account, &shared_secret, 100),
+        )
+        .wait()
+        .unwrap_err();
+
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        let frame = max_money_frame(&shared_secret, reject.data());
+        assert_eq!(frame.total_received, 100);
+        assert_eq!(frame.receive_max, 150);
+        assert_eq!(
+            store
+                .connections
+                .lock()
+                .values()
+                .next()
+                .unwrap()
+                .connection_total,
+            100
+        );
+    }
+
+    #[test]
+    fn splits_amount_between_streams() {
+        let frames = [
+            StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            },
+            StreamMoneyFrame {
+                stream_id: 3,
+                shares: 2,
+            },
+        ];
+        assert_eq!(split_amount(100, &frames), vec![(1, 33), (3, 67)]);
+        assert_eq!(split_amount(0, &frames), vec![(1, 0), (3, 0)]);
+        assert!(split_amount(100, &[]).is_empty());
+    }
+
+    #[test]
+    fn uses_receive_max_for_tag() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            TestReceiverStore::default(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
+                panic!("shouldn't get here")
+            }),
+        );
+        service
+            .receive_max(1000)
+            .receive_max_for_tag(|tag| if tag == "small" { Some(50) } else { None });
+
+        let mut send = |destination_account: Address, shared_secret: [u8; 32]| {
+            service
+                .send_request(OutgoingRequest {
+                    from: TestAccount {
+                        id: 0,
+                        ilp_address: Address::from_str("example.sender").unwrap(),
+                        asset_code: "XYZ".to_string(),
+                        asset_scale: 9,
+                    },
+                    to: receiving_account(&client_address),
+                    original_amount: 100,
+                    prepare: test_prepare(destination_account, &shared_secret, 100),
+                })
+                .wait()
+        };
+
+        let (destination_account, shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&client_address, "small")
+            .unwrap();
+        let reject = send(destination_account, shared_secret).unwrap_err();
+        assert_eq!(
+            max_money_frame(&shared_secret, reject.data()).receive_max,
+            50
+        );
+
+        let (destination_account, shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&client_address, "other")
+            .unwrap();
+        assert!(send(destination_account, shared_secret).is_ok());
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address);
+        assert!(send(destination_account, shared_secret).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod data_connections {
+    use super::*;
+    use futures::{future::lazy, Stream};
+    use std::io::Write;
+
+    fn data_packet(data: &[u8]) -> StreamPacket {
+        StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[Frame::StreamData(StreamDataFrame {
+                stream_id: 1,
+                offset: 0,
+                data,
+            })],
+        }
+        .build()
+    }
+
+    #[test]
+    fn requeues_data_that_was_not_sent() {
+        lazy(|| {
+            let connections = DataConnections::default();
+            let (sender, receiver) = unbounded();
+            connections.subscribers.lock().push(sender);
+
+            connections.handle_packet("connection", &None, &data_packet(b"hello"));
+            let mut incoming = receiver.wait().next().unwrap().unwrap();
+            incoming.stream.write_all(b"hi there").unwrap();
+
+            let outgoing = connections.handle_packet("connection", &None, &test_stream_packet());
+            assert!(outgoing.has_data());
+            connections.requeue("connection", outgoing);
+
+            let outgoing = connections.handle_packet("connection", &None, &test_stream_packet());
+            assert!(outgoing.has_data());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn expires_idle_connections() {
+        let connections = DataConnections::default();
+        connections.handle_packet("connection", &None, &data_packet(b"hello"));
+        assert_eq!(connections.connections.lock().len(), 1);
+
+        connections.expire_idle(Instant::now());
+        assert_eq!(connections.connections.lock().len(), 1);
+
+        connections.expire_idle(Instant::now() + DATA_CONNECTION_IDLE_TIMEOUT);
+        assert!(connections.connections.lock().is_empty());
+    }
 }
 
 #[cfg(test)]
 mod stream_receiver_service {
     use super::*;
     use crate::test_helpers::*;
-    use futures::Future;
+    use futures::{Future, Stream};
     use interledger_packet::PrepareBuilder;
     use interledger_service::outgoing_service_fn;
 
@@ -458,6 +1362,7 @@ mod stream_receiver_service {
 
         let mut service = StreamReceiverService::new(
             server_secret.clone(),
+            TestReceiverStore::default(),
             outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
                 panic!("shouldn't get here")
             }),
@@ -509,6 +1414,7 @@ mod stream_receiver_service {
 
         let mut service = StreamReceiverService::new(
             server_secret.clone(),
+            TestReceiverStore::default(),
             outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
                 panic!("shouldn't get here")
             }),
@@ -560,6 +1466,7 @@ mod stream_receiver_service {
 
         let mut service = StreamReceiverService::new(
             server_secret.clone(),
+            TestReceiverStore::default(),
             outgoing_service_fn(|_| {
                 Err(RejectBuilder {
                     code: ErrorCode::F02_UNREACHABLE,
@@ -595,4 +1502,80 @@ mod stream_receiver_service {
             Address::from_str("example.other-receiver").unwrap(),
         );
     }
+
+    #[test]
+    fn publishes_payment_notifications() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) = connection_generator
+            .generate_tagged_address_and_secret(&client_address, "some_tag")
+            .unwrap();
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }),
+                Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                    source_account: Address::from_str("example.sender").unwrap(),
+                }),
+            ],
+        }
+        .build();
+        let data = stream_packet.into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            TestReceiverStore::default(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
+                panic!("shouldn't get here")
+            }),
+        );
+        let notifications = service.subscribe();
+
+        service
+            .send_request(OutgoingRequest {
+                from: TestAccount {
+                    id: 0,
+                    ilp_address: Address::from_str("example.sender").unwrap(),
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                },
+                to: TestAccount {
+                    id: 1,
+                    ilp_address: client_address.clone(),
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                },
+                original_amount: prepare.amount(),
+                prepare,
+            })
+            .wait()
+            .unwrap();
+        drop(service);
+
+        let notifications: Vec<PaymentNotification> = notifications.collect().wait().unwrap();
+        assert_eq!(
+            notifications,
+            vec![PaymentNotification {
+                connection_tag: Some("some_tag".to_string()),
+                stream_id: 1,
+                amount: 100,
+                source_account: Some(Address::from_str("example.sender").unwrap()),
+            }]
+        );
+    }
 }
@@ -1,21 +1,26 @@
 use base64;
 use bytes::Bytes;
-use futures::{future::ok, Future};
+use futures::{
+    future::{err, ok},
+    Future,
+};
 use hyper::{
     header::{HeaderValue, ACCEPT},
     service::{service_fn, Service},
     Body, Error, Method, Request, Response, Server,
 };
-use interledger_btp::{connect_client, create_open_signup_server, parse_btp_url};
+use interledger_btp::{connect_client, create_open_signup_server, parse_btp_url, OpenSignupLimits};
 use interledger_http::{HttpClientService, HttpServerService};
 use interledger_ildcp::{get_ildcp_info, IldcpAccount, IldcpResponse, IldcpService};
 use interledger_packet::{Address, ErrorCode, RejectBuilder};
 use interledger_router::Router;
-use interledger_service::{incoming_service_fn, outgoing_service_fn, OutgoingRequest, Username};
+use interledger_service::{
+    incoming_service_fn, outgoing_service_fn, IncomingService, OutgoingRequest, Username,
+};
 use interledger_service_util::ValidatorService;
-use interledger_spsp::{pay, SpspResponder};
+use interledger_spsp::{pay, pay_with_checkpoints, SpspResponder, StreamDelivery};
 use interledger_store_memory::{Account, AccountBuilder, InMemoryStore};
-use interledger_stream::StreamReceiverService;
+use interledger_stream::{resume_money, FileCheckpointSink, StreamReceiverService};
 use lazy_static::lazy_static;
 use log::debug;
 use parking_lot::RwLock;
@@ -48,6 +53,7 @@ pub fn send_spsp_payment_btp(
     btp_server: &str,
     receiver: &str,
     amount: u64,
+    checkpoint_file: Option<String>,
     quiet: bool,
 ) -> impl Future<Item = (), Error = ()> {
     let receiver = receiver.to_string();
@@ -95,20 +101,19 @@ pub fn send_spsp_payment_btp(
         let service = ValidatorService::outgoing(LOCAL_ILP_ADDRESS.clone(), service);
         let store = InMemoryStore::from_accounts(vec![account.clone()]);
         let router = Router::new(LOCAL_ILP_ADDRESS.clone(), store, service);
-        pay(router, account, &receiver, amount)
-            .map_err(|err| {
-                eprintln!("Error sending SPSP payment: {:?}", err);
-            })
-            .and_then(move |delivered| {
+        pay_with_checkpoint_file(router, account, &receiver, amount, checkpoint_file).and_then(
+            move |delivery| {
                 if !quiet {
                     println!(
-                        "Sent: {}, delivered: {} (in the receiver's units)",
-                        amount, delivered
+                        "Sent: {}, delivered: {}",
+                        delivery.sent_amount,
+                        format_delivered(&delivery)
                     );
                 }
                 btp_service.close();
                 Ok(())
-            })
+            },
+        )
     })
 }
 
@@ -117,6 +122,7 @@ pub fn send_spsp_payment_http(
     http_server: &str,
     receiver: &str,
     amount: u64,
+    checkpoint_file: Option<String>,
     quiet: bool,
 ) -> impl Future<Item = (), Error = ()> {
     let receiver = receiver.to_string();
@@ -150,19 +156,103 @@ pub fn send_spsp_payment_http(
     );
     let service = ValidatorService::outgoing(LOCAL_ILP_ADDRESS.clone(), service);
     let service = Router::new(LOCAL_ILP_ADDRESS.clone(), store, service);
-    pay(service, account, &receiver, amount)
-        .map_err(|err| {
-            eprintln!("Error sending SPSP payment: {:?}", err);
-        })
-        .and_then(move |delivered| {
+    pay_with_checkpoint_file(service, account, &receiver, amount, checkpoint_file).and_then(
+        move |delivery| {
             if !quiet {
                 println!(
-                    "Sent: {}, delivered: {} (in the receiver's units)",
-                    amount, delivered
+                    "Sent: {}, delivered: {}",
+                    delivery.sent_amount,
+                    format_delivered(&delivery)
                 );
             }
             Ok(())
-        })
+        },
+    )
+}
+
+/// Send the SPSP payment. If a checkpoint file is given, the payment's progress is saved to it
+/// and, if it already contains a checkpoint, the payment picks up where that one left off
+/// (without querying the receiver again). The receiver and amount must match the ones the
+/// checkpoint was saved for. The file is removed once the payment completes.
+fn pay_with_checkpoint_file<S>(
+    service: S,
+    account: Account,
+    receiver: &str,
+    amount: u64,
+    checkpoint_file: Option<String>,
+) -> Box<dyn Future<Item = StreamDelivery, Error = ()> + Send>
+where
+    S: IncomingService<Account> + Clone + Send + 'static,
+{
+    let sink = match checkpoint_file {
+        Some(checkpoint_file) => FileCheckpointSink::new(checkpoint_file),
+        None => {
+            return Box::new(pay(service, account, receiver, amount).map_err(|err| {
+                eprintln!("Error sending SPSP payment: {:?}", err);
+            }))
+        }
+    };
+    let payment: Box<dyn Future<Item = StreamDelivery, Error = ()> + Send> = match sink.load() {
+        Ok(Some(checkpoint)) => {
+            if checkpoint.receiver.as_ref().map(String::as_str) != Some(receiver) {
+                eprintln!(
+                    "Checkpoint is for a payment to {}, not {}",
+                    checkpoint
+                        .receiver
+                        .as_ref()
+                        .map(String::as_str)
+                        .unwrap_or("an unknown receiver"),
+                    receiver
+                );
+                return Box::new(err(()));
+            }
+            if checkpoint.source_amount != amount {
+                eprintln!(
+                    "Checkpoint is for a payment of {}, not {}",
+                    checkpoint.source_amount, amount
+                );
+                return Box::new(err(()));
+            }
+            Box::new(
+                resume_money(service, &account, checkpoint, sink.clone())
+                    .map(|(delivery, _service)| delivery)
+                    .map_err(|err| {
+                        eprintln!("Error resuming SPSP payment: {:?}", err);
+                    }),
+            )
+        }
+        Ok(None) => Box::new(
+            pay_with_checkpoints(service, account, receiver, amount, sink.clone()).map_err(|err| {
+                eprintln!("Error sending SPSP payment: {:?}", err);
+            }),
+        ),
+        Err(error) => {
+            eprintln!("Error loading checkpoint: {:?}", error);
+            return Box::new(err(()));
+        }
+    };
+    Box::new(payment.and_then(move |delivery| {
+        if let Err(error) = sink.remove() {
+            eprintln!("Error removing checkpoint: {:?}", error);
+        }
+        Ok(delivery)
+    }))
+}
+
+/// Format the delivered amount using the receiver's asset details, if it sent them
+fn format_delivered(delivery: &StreamDelivery) -> String {
+    match (
+        &delivery.destination_asset_code,
+        delivery.destination_asset_scale,
+    ) {
+        (Some(asset_code), Some(asset_scale)) => format!(
+            "{:.*} {}",
+            asset_scale as usize,
+            delivery.delivered_amount as f64 / 10f64.powi(i32::from(asset_scale)),
+            asset_code
+        ),
+        _ => format!("{} (in the receiver's units)", delivery.delivered_amount),
+    }
 }
 
 // TODO allow server secret to be specified
@@ -208,7 +298,8 @@ pub fn run_spsp_server_btp(
     .and_then(move |btp_service| {
         let outgoing_service =
             ValidatorService::outgoing(LOCAL_ILP_ADDRESS.clone(), btp_service.clone());
-        let outgoing_service = StreamReceiverService::new(server_secret.clone(), outgoing_service);
+        let outgoing_service =
+            StreamReceiverService::new(server_secret.clone(), store.clone(), outgoing_service);
         let incoming_service =
             Router::new(LOCAL_ILP_ADDRESS.clone(), store.clone(), outgoing_service);
         let mut incoming_service =
@@ -238,7 +329,11 @@ pub fn run_spsp_server_btp(
                 "SPSP server listening on {} with ILP address {}",
                 &address, client_address,
             );
-            let spsp_responder = SpspResponder::new(client_address, server_secret);
+            let mut spsp_responder = SpspResponder::new(client_address, server_secret);
+            spsp_responder.asset_info(
+                String::from_utf8(info.asset_code().to_vec()).unwrap_or_default(),
+                info.asset_scale(),
+            );
             Server::bind(&address)
                 .serve(move || spsp_responder.clone())
                 .map_err(|e| eprintln!("Server error: {:?}", e))
@@ -264,9 +359,14 @@ pub fn run_spsp_server_http(
         .build();
     let server_secret = Bytes::from(&random_secret()[..]);
     let store = InMemoryStore::from_accounts(vec![account.clone()]);
-    let spsp_responder = SpspResponder::new(ilp_address.clone(), server_secret.clone());
+    let mut spsp_responder = SpspResponder::new(ilp_address.clone(), server_secret.clone());
+    spsp_responder.asset_info(
+        String::from_utf8(ildcp_info.asset_code().to_vec()).unwrap_or_default(),
+        ildcp_info.asset_scale(),
+    );
     let outgoing_handler = StreamReceiverService::new(
         server_secret,
+        store.clone(),
         outgoing_service_fn(move |request: OutgoingRequest<Account>| {
             Err(RejectBuilder {
                 code: ErrorCode::F02_UNREACHABLE,
@@ -324,6 +424,7 @@ pub fn run_spsp_server_http(
 pub fn run_moneyd_local(
     address: SocketAddr,
     ildcp_info: IldcpResponse,
+    limits: OpenSignupLimits,
 ) -> impl Future<Item = (), Error = ()> {
     let ilp_address = ildcp_info.client_address();
     let ilp_address_clone = ilp_address.clone();
@@ -345,6 +446,7 @@ pub fn run_moneyd_local(
         ildcp_info,
         store.clone(),
         rejecter,
+        limits,
     )
     .and_then(move |btp_service| {
         let service = Router::new(LOCAL_ILP_ADDRESS.clone(), store, btp_service.clone());
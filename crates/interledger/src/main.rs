@@ -5,10 +5,11 @@ use config;
 use futures::future::Future;
 use hex;
 use interledger::{cli::*, node::*};
+use interledger_btp::OpenSignupLimits;
 use interledger_ildcp::IldcpResponseBuilder;
 use interledger_packet::Address;
 use interledger_service::Username;
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 use tokio;
 use url::Url;
 
@@ -79,6 +80,10 @@ pub fn main() {
                                 .takes_value(true)
                                 .required(true)
                                 .help("Amount to send, denominated in the connector's units"),
+                            Arg::with_name("checkpoint")
+                                .long("checkpoint")
+                                .takes_value(true)
+                                .help("File to save the payment's progress to. If the file already has a checkpoint, the payment is resumed from it"),
                             Arg::with_name("quiet")
                                 .long("quiet")
                                 .help("Suppress log output"),
@@ -102,6 +107,27 @@ pub fn main() {
                             Arg::with_name("asset_scale")
                                 .long("asset_scale")
                                 .default_value("9"),
+                            Arg::with_name("max_accounts")
+                                .long("max_accounts")
+                                .takes_value(true)
+                                .help("Maximum number of accounts that can be created via open signup"),
+                            Arg::with_name("max_accounts_per_ip")
+                                .long("max_accounts_per_ip")
+                                .takes_value(true)
+                                .help("Maximum number of accounts that can be created from a single IP address"),
+                            Arg::with_name("max_packet_amount")
+                                .long("max_packet_amount")
+                                .takes_value(true)
+                                .help("Maximum packet amount for new accounts"),
+                            Arg::with_name("min_balance")
+                                .long("min_balance")
+                                .takes_value(true)
+                                .allow_hyphen_values(true)
+                                .help("Minimum balance new accounts are allowed to have (can be negative)"),
+                            Arg::with_name("account_idle_timeout")
+                                .long("account_idle_timeout")
+                                .takes_value(true)
+                                .help("Delete accounts that have been disconnected with a zero balance for this many seconds"),
                         ])
                     ),
                 SubCommand::with_name("node")
@@ -146,6 +172,10 @@ pub fn main() {
                                 .long("btp_uri")
                                 .help("URI of a BTP server or moneyd that this account should use to connect")
                                 .takes_value(true),
+                            Arg::with_name("btp_outgoing_username")
+                                .long("btp_outgoing_username")
+                                .help("Username to send in the BTP auth_username field when connecting to the btp_uri (the password from the btp_uri is then used as the token by itself)")
+                                .takes_value(true),
                             Arg::with_name("http_url")
                                 .help("URL of the ILP-Over-HTTP endpoint that should be used when sending outgoing requests to this account")
                                 .long("http_url")
@@ -227,6 +257,7 @@ pub fn main() {
             ("pay", Some(matches)) => {
                 let receiver = value_t!(matches, "receiver", String).expect("Receiver is required");
                 let amount = value_t!(matches, "amount", u64).expect("Invalid amount");
+                let checkpoint = value_t!(matches, "checkpoint", String).ok();
                 let quiet = matches.is_present("quiet");
 
                 // Check for http_server first because btp_server has the default value of connecting to moneyd
@@ -235,10 +266,17 @@ pub fn main() {
                         &http_server,
                         &receiver,
                         amount,
+                        checkpoint,
                         quiet,
                     ));
                 } else if let Ok(btp_server) = value_t!(matches, "btp_server", String) {
-                    tokio::run(send_spsp_payment_btp(&btp_server, &receiver, amount, quiet));
+                    tokio::run(send_spsp_payment_btp(
+                        &btp_server,
+                        &receiver,
+                        amount,
+                        checkpoint,
+                        quiet,
+                    ));
                 } else {
                     panic!("Must specify either btp_server or http_server");
                 }
@@ -262,9 +300,20 @@ pub fn main() {
                     asset_scale,
                 }
                 .build();
+                let limits = OpenSignupLimits {
+                    max_accounts: value_t!(matches, "max_accounts", usize).ok(),
+                    max_accounts_per_ip: value_t!(matches, "max_accounts_per_ip", usize).ok(),
+                    max_packet_amount: value_t!(matches, "max_packet_amount", u64).ok(),
+                    min_balance: value_t!(matches, "min_balance", i64).ok(),
+                    idle_account_timeout: value_t!(matches, "account_idle_timeout", u64)
+                        .ok()
+                        .map(Duration::from_secs),
+                    ..Default::default()
+                };
                 tokio::run(run_moneyd_local(
                     ([127, 0, 0, 1], btp_port).into(),
                     ildcp_info,
+                    limits,
                 ));
             }
             _ => app.print_help().unwrap(),
@@ -320,10 +369,20 @@ pub fn main() {
                             .value_of("btp_incoming_token")
                             .map(|s| s.to_string()),
                         btp_uri: matches.value_of("btp_uri").map(|s| s.to_string()),
+                        btp_outgoing_username: matches
+                            .value_of("btp_outgoing_username")
+                            .map(|s| Username::from_str(s).unwrap()),
                         http_incoming_token: matches
                             .value_of("http_incoming_token")
                             .map(|s| format!("Bearer {}", s)),
                         http_outgoing_token,
+                        http_incoming_certificate: None,
+                        http_client_identity: None,
+                        http_client_identity_password: None,
+                        http_client_max_idle_per_host: None,
+                        http_client_http2_prior_knowledge: None,
+                        http_client_max_timeout: None,
+                        http_client_max_retries: None,
                         http_endpoint,
                         max_packet_amount: u64::max_value(),
                         min_balance: value_t!(matches, "min_balance", i64).ok(),
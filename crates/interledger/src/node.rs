@@ -1,10 +1,21 @@
 use bytes::Bytes;
-use futures::{future::result, Future};
+use futures::{
+    future::{empty, lazy, result, Either},
+    sync::oneshot,
+    Future, Stream,
+};
 use hex::FromHex;
 use interledger_api::{NodeApi, NodeStore};
-use interledger_btp::{connect_client, create_server, BtpStore};
-use interledger_ccp::CcpRouteManagerBuilder;
-use interledger_http::HttpClientService;
+use interledger_btp::{
+    connect_client, connect_client_with_tls, create_server, create_tls_server,
+    tls_acceptor_from_pem_files, tls_connector_with_root_certificates, BtpStore, TlsAcceptor,
+    TlsConnector,
+};
+use interledger_ccp::{trusted_route_auth, CcpRouteManagerBuilder, RoutingRelation};
+use interledger_http::{
+    mtls_acceptor_from_pem_files, serve_with_client_certificates, HttpClientConfig,
+    HttpClientService, HttpServerService, SslAcceptor,
+};
 use interledger_ildcp::IldcpService;
 use interledger_packet::Address;
 use interledger_packet::{ErrorCode, RejectBuilder};
@@ -17,6 +28,7 @@ use interledger_service_util::{
     MaxPacketAmountService, RateLimitService, ValidatorService,
 };
 use interledger_settlement::{SettlementApi, SettlementMessageService};
+use interledger_spsp::invoice_receive_max;
 use interledger_store_redis::{
     Account, AccountId, ConnectionInfo, IntoConnectionInfo, RedisStoreBuilder,
 };
@@ -24,12 +36,15 @@ use interledger_stream::StreamReceiverService;
 use log::{debug, error, info, trace};
 use ring::{digest, hmac};
 use serde::{de::Error as DeserializeError, Deserialize, Deserializer};
-use std::{net::SocketAddr, str};
-use tokio::{self, net::TcpListener};
+use std::{collections::HashMap, net::SocketAddr, str, time::Duration};
+use tokio::{self, net::TcpListener, runtime::Runtime};
 use url::Url;
 
 static REDIS_SECRET_GENERATION_STRING: &str = "ilp_redis_secret";
+static ROUTING_SECRET_GENERATION_STRING: &str = "ilp_routing_secret";
 static DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+/// How long `run` waits for the packets in flight to be answered when the node is shut down
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn default_settlement_address() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 7771))
@@ -40,6 +55,9 @@ fn default_http_address() -> SocketAddr {
 fn default_btp_address() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 7768))
 }
+fn default_http_mtls_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 7769))
+}
 fn default_redis_uri() -> ConnectionInfo {
     DEFAULT_REDIS_URL.into_connection_info().unwrap()
 }
@@ -65,6 +83,26 @@ where
     })
 }
 
+fn deserialize_trusted_route_auths<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, [u8; 32]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(prefix, auth)| {
+            let auth = <[u8; 32]>::from_hex(auth).map_err(|err| {
+                DeserializeError::custom(format!(
+                    "Invalid trusted route auth for {} (must be 32 hex-encoded bytes): {:?}",
+                    prefix, err
+                ))
+            })?;
+            Ok((prefix, auth))
+        })
+        .collect()
+}
+
 fn deserialize_redis_connection<'de, D>(deserializer: D) -> Result<ConnectionInfo, D::Error>
 where
     D: Deserializer<'de>,
@@ -117,6 +155,54 @@ pub struct InterledgerNode {
     /// Interval, defined in milliseconds, on which the node will broadcast routing
     /// information to other nodes using CCP. Defaults to 30000ms (30 seconds).
     pub route_broadcast_interval: Option<u64>,
+    /// Hex-encoded route auth values for address prefixes, obtained out of band from the
+    /// nodes the prefixes' routes originate from (see `InterledgerNode::trusted_route_auth`).
+    /// Routes for these prefixes are ignored if their auth does not match
+    #[serde(default, deserialize_with = "deserialize_trusted_route_auths")]
+    pub trusted_route_auths: HashMap<String, [u8; 32]>,
+    /// Accept routes that cannot be verified with the `trusted_route_auths` when they are
+    /// broadcast by accounts with these routing relations (Parent, Peer or Child).
+    /// Defaults to Parent and Child
+    pub accept_unverified_routes_from: Option<Vec<RoutingRelation>>,
+    /// PEM file with the certificate (chain) the BTP server should present. If this and
+    /// `btp_tls_key` are set, the BTP server accepts secure WebSocket (wss://) connections
+    pub btp_tls_cert: Option<String>,
+    /// PEM file with the PKCS#8 private key for `btp_tls_cert`
+    pub btp_tls_key: Option<String>,
+    /// PEM file with extra root certificates to trust when connecting to BTP servers over
+    /// wss://, for example peers on a private network using self-signed certificates
+    pub btp_root_certificates: Option<String>,
+    /// PEM file with the certificate (chain) to present to ILP-over-HTTP peers that
+    /// authenticate with TLS client certificates. If this, `http_tls_key` and
+    /// `http_tls_client_ca` are set, the node accepts ILP-over-HTTP requests from those
+    /// peers on `http_mtls_address`
+    pub http_tls_cert: Option<String>,
+    /// PEM file with the private key for `http_tls_cert`
+    pub http_tls_key: Option<String>,
+    /// PEM file with the root certificates that peers' client certificates must be issued by
+    pub http_tls_client_ca: Option<String>,
+    /// IP address and port to listen for ILP-over-HTTP requests authenticated with
+    /// client certificates. Defaults to 127.0.0.1:7769
+    pub http_mtls_address: Option<SocketAddr>,
+    /// The maximum number of idle ILP-over-HTTP connections to keep open to each peer
+    pub http_client_max_idle_per_host: Option<usize>,
+    /// Send ILP-over-HTTP requests using HTTP/2 without negotiating it first.
+    /// Only enable this if all of the node's ILP-over-HTTP peers support HTTP/2
+    #[serde(default)]
+    pub http_client_http2_prior_knowledge: bool,
+    /// How many times to resend an ILP-over-HTTP request if the connection to the
+    /// peer could not be established
+    pub http_client_max_retries: Option<usize>,
+    /// The longest time, in seconds, that invoices created through the API can be
+    /// paid for. Defaults to 30 days
+    pub max_invoice_expiry: Option<u64>,
+}
+
+/// Tells the node when to shut down and lets it report when it is done
+struct Shutdown {
+    signal: Box<dyn Future<Item = (), Error = ()> + Send>,
+    drain_timeout: Duration,
+    drained: oneshot::Sender<()>,
 }
 
 impl InterledgerNode {
@@ -124,6 +210,34 @@ impl InterledgerNode {
     // TODO when a BTP connection is made, insert a outgoing HTTP entry into the Store to tell other
     // connector instances to forward packets for that account to us
     pub fn serve(&self) -> impl Future<Item = (), Error = ()> {
+        self.start(None)
+    }
+
+    /// Same as `serve` but once the `shutdown` future resolves, the node stops accepting
+    /// new packets over BTP, waits up to `drain_timeout` for the packets in flight to be
+    /// answered, and closes its BTP connections. The returned future resolves when that is done,
+    /// so that another instance can take over without dropping packets (for example, during
+    /// a rolling restart).
+    pub fn serve_until<F>(
+        &self,
+        shutdown: F,
+        drain_timeout: Duration,
+    ) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let (drained, finished) = oneshot::channel();
+        self.start(Some(Shutdown {
+            signal: Box::new(shutdown),
+            drain_timeout,
+            drained,
+        }))
+        .and_then(|_| {
+            finished.map_err(|_| error!("Node stopped before its BTP connections were drained"))
+        })
+    }
+
+    fn start(&self, shutdown: Option<Shutdown>) -> impl Future<Item = (), Error = ()> {
         debug!(
             "Starting Interledger node with ILP address: {}",
             str::from_utf8(self.ilp_address.as_ref()).unwrap_or("<not utf8>")
@@ -138,13 +252,27 @@ impl InterledgerNode {
         let ilp_address_clone2 = ilp_address.clone();
         let admin_auth_token = self.admin_auth_token.clone();
         let default_spsp_account = self.default_spsp_account.clone();
+        let max_invoice_expiry = self.max_invoice_expiry;
         let redis_addr = self.redis_connection.addr.clone();
         let route_broadcast_interval = self.route_broadcast_interval;
+        let trusted_route_auths = self.trusted_route_auths.clone();
+        let accept_unverified_routes_from = self.accept_unverified_routes_from.clone();
+        let routing_secret = generate_routing_secret(&self.secret_seed);
+        let redis_connection = self.redis_connection.clone();
+        let http_client_config = self.http_client_config();
+        let http_mtls_address = self
+            .http_mtls_address
+            .unwrap_or_else(default_http_mtls_address);
 
-        RedisStoreBuilder::new(self.redis_connection.clone(), redis_secret)
-        .connect()
-        .map_err(move |err| error!("Error connecting to Redis: {:?} {:?}", redis_addr, err))
-        .and_then(move |store| {
+        result(self.load_btp_tls_config())
+        .join(result(self.load_http_mtls_acceptor()))
+        .and_then(move |((btp_tls_acceptor, btp_tls_connector), http_mtls_acceptor)| {
+            RedisStoreBuilder::new(redis_connection, redis_secret)
+            .connect()
+            .map_err(move |err| error!("Error connecting to Redis: {:?} {:?}", redis_addr, err))
+            .map(move |store| (store, btp_tls_acceptor, btp_tls_connector, http_mtls_acceptor))
+        })
+        .and_then(move |(store, btp_tls_acceptor, btp_tls_connector, http_mtls_acceptor)| {
                 store.clone().get_btp_outgoing_accounts()
                 .map_err(|_| error!("Error getting accounts"))
                 .and_then(move |btp_accounts| {
@@ -167,11 +295,21 @@ impl InterledgerNode {
                         });
 
                     // Connect to all of the accounts that have outgoing btp_uris configured
-                    // but don't fail if we are unable to connect
-                    // TODO try reconnecting to those accounts later
-                    connect_client(ilp_address_clone2.clone(), btp_accounts, false, outgoing_service).and_then(
+                    // but don't fail if we are unable to connect (the client will keep
+                    // trying to reconnect to those accounts in the background)
+                    let btp_client = if let Some(tls_connector) = btp_tls_connector {
+                        Either::A(connect_client_with_tls(ilp_address_clone2.clone(), btp_accounts, false, outgoing_service, tls_connector))
+                    } else {
+                        Either::B(connect_client(ilp_address_clone2.clone(), btp_accounts, false, outgoing_service))
+                    };
+                    btp_client.and_then(
                         move |btp_client_service| {
-                            create_server(ilp_address_clone2, btp_address, store.clone(), btp_client_service.clone()).and_then(
+                            let btp_server = if let Some(tls_acceptor) = btp_tls_acceptor {
+                                Either::A(create_tls_server(ilp_address_clone2, btp_address, store.clone(), btp_client_service.clone(), tls_acceptor))
+                            } else {
+                                Either::B(create_server(ilp_address_clone2, btp_address, store.clone(), btp_client_service.clone()))
+                            };
+                            btp_server.and_then(
                                 move |btp_server_service| {
                                     // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
                                     // service to others like the router and then call handle_incoming on it to set up the incoming handler
@@ -180,20 +318,24 @@ impl InterledgerNode {
                                         ilp_address.clone(),
                                         outgoing_service
                                     );
-                                    let outgoing_service = HttpClientService::new(
+                                    let outgoing_service = HttpClientService::with_config(
                                         ilp_address.clone(),
                                         store.clone(),
                                         outgoing_service,
+                                        http_client_config,
                                     );
 
                                     // Note: the expiry shortener must come after the Validator so that the expiry duration
                                     // is shortened before we check whether there is enough time left
                                     let outgoing_service =
                                         ExpiryShortenerService::new(outgoing_service);
-                                    let outgoing_service = StreamReceiverService::new(
+                                    let mut outgoing_service = StreamReceiverService::new(
                                         secret_seed.clone(),
+                                        store.clone(),
                                         outgoing_service,
                                     );
+                                    // Stop accepting money for invoices once they are paid or expire
+                                    outgoing_service.receive_max_for_tag(invoice_receive_max);
                                     let outgoing_service = BalanceService::new(
                                         ilp_address.clone(),
                                         store.clone(),
@@ -221,6 +363,15 @@ impl InterledgerNode {
                                     if let Some(ms) = route_broadcast_interval {
                                         ccp_builder.broadcast_interval(ms);
                                     }
+                                    ccp_builder.routing_secret(routing_secret);
+                                    for (prefix, auth) in trusted_route_auths {
+                                        ccp_builder.trusted_route_auth(Bytes::from(prefix), auth);
+                                    }
+                                    if let Some(relations) = accept_unverified_routes_from {
+                                        for relation in &[RoutingRelation::Parent, RoutingRelation::Peer, RoutingRelation::Child] {
+                                            ccp_builder.accept_unverified_routes(*relation, relations.contains(relation));
+                                        }
+                                    }
                                     let incoming_service = ccp_builder.to_service();
                                     let incoming_service = EchoService::new(ilp_address.clone(), incoming_service);
                                     let incoming_service = SettlementMessageService::new(ilp_address.clone(), incoming_service);
@@ -239,8 +390,20 @@ impl InterledgerNode {
                                     );
 
                                     // Handle incoming packets sent via BTP
-                                    btp_server_service.handle_incoming(incoming_service.clone());
-                                    btp_client_service.handle_incoming(incoming_service.clone());
+                                    let btp_server_service = btp_server_service.handle_incoming(incoming_service.clone());
+                                    let btp_client_service = btp_client_service.handle_incoming(incoming_service.clone());
+                                    let btp_server_metrics = btp_server_service.metrics();
+                                    let btp_client_metrics = btp_client_service.metrics();
+
+                                    if let Some(Shutdown { signal, drain_timeout, drained }) = shutdown {
+                                        tokio::spawn(signal.then(move |_| {
+                                            info!("Shutting down, draining BTP connections");
+                                            btp_server_service.drain(drain_timeout).join(btp_client_service.drain(drain_timeout))
+                                        }).then(move |_| {
+                                            let _ = drained.send(());
+                                            Ok(())
+                                        }));
+                                    }
 
                                     // TODO should this run the node api on a different port so it's easier to separate public/private?
                                     // Note the API also includes receiving ILP packets sent via HTTP
@@ -253,11 +416,24 @@ impl InterledgerNode {
                                     if let Some(username) = default_spsp_account {
                                         api.default_spsp_account(username);
                                     }
+                                    if let Some(max_invoice_expiry) = max_invoice_expiry {
+                                        api.max_invoice_expiry(max_invoice_expiry);
+                                    }
+                                    api.btp_metrics(btp_server_metrics);
+                                    api.btp_metrics(btp_client_metrics);
                                     let listener = TcpListener::bind(&http_address)
                                         .expect("Unable to bind to HTTP address");
                                     info!("Interledger node listening on: {}", http_address);
                                     tokio::spawn(api.serve(listener.incoming()));
 
+                                    if let Some(acceptor) = http_mtls_acceptor {
+                                        let http_server = HttpServerService::new(incoming_service.clone(), store.clone());
+                                        let listener = TcpListener::bind(&http_mtls_address)
+                                            .expect("Unable to bind to ILP-over-HTTP client certificate address");
+                                        info!("ILP-over-HTTP with client certificates listening on: {}", http_mtls_address);
+                                        tokio::spawn(serve_with_client_certificates(http_server, acceptor, listener.incoming()));
+                                    }
+
                                     let settlement_api = SettlementApi::new(
                                         store.clone(),
                                         outgoing_service.clone(),
@@ -276,9 +452,72 @@ impl InterledgerNode {
         })
     }
 
-    /// Run the node on the default Tokio runtime
+    /// Load the TLS certificate and key for the BTP server and the extra root
+    /// certificates for the BTP client, if they are configured
+    fn load_btp_tls_config(&self) -> Result<(Option<TlsAcceptor>, Option<TlsConnector>), ()> {
+        let tls_acceptor = match (&self.btp_tls_cert, &self.btp_tls_key) {
+            (Some(cert), Some(key)) => Some(tls_acceptor_from_pem_files(cert, key)?),
+            (None, None) => None,
+            _ => {
+                error!("btp_tls_cert and btp_tls_key must be configured together");
+                return Err(());
+            }
+        };
+        let tls_connector = if let Some(ref root_certificates) = self.btp_root_certificates {
+            Some(tls_connector_with_root_certificates(root_certificates)?)
+        } else {
+            None
+        };
+        Ok((tls_acceptor, tls_connector))
+    }
+
+    /// Load the TLS acceptor for ILP-over-HTTP requests authenticated with client certificates,
+    /// if it is configured
+    fn load_http_mtls_acceptor(&self) -> Result<Option<SslAcceptor>, ()> {
+        match (
+            &self.http_tls_cert,
+            &self.http_tls_key,
+            &self.http_tls_client_ca,
+        ) {
+            (Some(cert), Some(key), Some(client_ca)) => {
+                Ok(Some(mtls_acceptor_from_pem_files(cert, key, client_ca)?))
+            }
+            (None, None, None) => Ok(None),
+            _ => {
+                error!("http_tls_cert, http_tls_key and http_tls_client_ca must be configured together");
+                Err(())
+            }
+        }
+    }
+
+    /// The settings for ILP-over-HTTP clients, with defaults for the ones that are not configured
+    fn http_client_config(&self) -> HttpClientConfig {
+        let mut config = HttpClientConfig::default();
+        if let Some(max_idle_per_host) = self.http_client_max_idle_per_host {
+            config.max_idle_per_host = max_idle_per_host;
+        }
+        config.http2_prior_knowledge = self.http_client_http2_prior_knowledge;
+        if let Some(max_retries) = self.http_client_max_retries {
+            config.max_retries = max_retries;
+        }
+        config
+    }
+
+    /// The route auth other nodes should be configured to trust (in their `trusted_route_auths`)
+    /// to verify the routes this node originates for the given prefix.
+    /// Anyone who knows it can create routes for the prefix that look like they come from
+    /// this node, so it should only be shared with nodes that are trusted with it
+    pub fn trusted_route_auth(&self, prefix: &[u8]) -> [u8; 32] {
+        trusted_route_auth(&generate_routing_secret(&self.secret_seed), prefix)
+    }
+
+    /// Run the node on the default Tokio runtime until the process receives SIGINT (Ctrl-C)
+    /// or SIGTERM, then drain the BTP connections and stop
     pub fn run(&self) {
-        tokio::run(self.serve());
+        let mut runtime = Runtime::new().expect("Unable to start Tokio runtime");
+        let _ = runtime.block_on(self.serve_until(shutdown_signal(), DEFAULT_DRAIN_TIMEOUT));
+        // The HTTP and Settlement API servers would otherwise keep the runtime going forever
+        let _ = runtime.shutdown_now().wait();
     }
 
     pub fn insert_account(
@@ -289,6 +528,35 @@ impl InterledgerNode {
     }
 }
 
+/// Resolves when the process receives SIGINT (Ctrl-C) or, on Unix, SIGTERM
+fn shutdown_signal() -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let ctrl_c = lazy(tokio_signal::ctrl_c)
+        .flatten_stream()
+        .into_future()
+        .map(|_| info!("Received SIGINT"))
+        .or_else(|(err, _)| {
+            error!("Unable to listen for SIGINT: {:?}", err);
+            empty::<(), ()>()
+        });
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        let sigterm = lazy(|| Signal::new(SIGTERM))
+            .flatten_stream()
+            .into_future()
+            .map(|_| info!("Received SIGTERM"))
+            .or_else(|(err, _)| {
+                error!("Unable to listen for SIGTERM: {:?}", err);
+                empty::<(), ()>()
+            });
+        Box::new(ctrl_c.select(sigterm).map(|_| ()).map_err(|_| ()))
+    }
+    #[cfg(not(unix))]
+    {
+        Box::new(ctrl_c)
+    }
+}
+
 #[doc(hidden)]
 pub use interledger_api::AccountDetails;
 #[doc(hidden)]
@@ -325,3 +593,15 @@ pub fn generate_redis_secret(secret_seed: &[u8; 32]) -> [u8; 32] {
     redis_secret.copy_from_slice(sig.as_ref());
     redis_secret
 }
+
+/// The secret the node's CCP route auth values are generated from. It is derived from the
+/// secret seed so that peers see the same auth values after the node restarts
+fn generate_routing_secret(secret_seed: &[u8; 32]) -> [u8; 32] {
+    let mut routing_secret: [u8; 32] = [0; 32];
+    let sig = hmac::sign(
+        &hmac::SigningKey::new(&digest::SHA256, secret_seed),
+        ROUTING_SECRET_GENERATION_STRING.as_bytes(),
+    );
+    routing_secret.copy_from_slice(sig.as_ref());
+    routing_secret
+}
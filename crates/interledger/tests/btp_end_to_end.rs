@@ -10,6 +10,7 @@ use interledger::{
 use interledger_packet::Address;
 use interledger_service::Username;
 use log::debug;
+use std::collections::HashMap;
 use std::str::FromStr;
 use tokio::runtime::Runtime;
 
@@ -35,6 +36,19 @@ fn btp_end_to_end() {
         settlement_address: ([127, 0, 0, 1], settlement_port).into(),
         secret_seed: cli::random_secret(),
         route_broadcast_interval: Some(200),
+        btp_tls_cert: None,
+        btp_tls_key: None,
+        btp_root_certificates: None,
+        http_tls_cert: None,
+        http_tls_key: None,
+        http_tls_client_ca: None,
+        http_mtls_address: None,
+        http_client_max_idle_per_host: None,
+        http_client_http2_prior_knowledge: false,
+        http_client_max_retries: None,
+        trusted_route_auths: HashMap::new(),
+        accept_unverified_routes_from: None,
+        max_invoice_expiry: None,
     };
     let run = ok(()).and_then(move |_| {
         let spawn_connector = ok(tokio::spawn(node.serve())).and_then(move |_| {
@@ -45,10 +59,18 @@ fn btp_end_to_end() {
                     asset_code: "XYZ".to_string(),
                     asset_scale: 9,
                     btp_incoming_token: Some("token-one".to_string()),
+                    btp_outgoing_username: None,
                     btp_uri: None,
                     http_endpoint: None,
                     http_incoming_token: None,
                     http_outgoing_token: None,
+                    http_incoming_certificate: None,
+                    http_client_identity: None,
+                    http_client_identity_password: None,
+                    http_client_max_idle_per_host: None,
+                    http_client_http2_prior_knowledge: None,
+                    http_client_max_timeout: None,
+                    http_client_max_retries: None,
                     max_packet_amount: u64::max_value(),
                     min_balance: None,
                     settle_threshold: None,
@@ -67,10 +89,18 @@ fn btp_end_to_end() {
                     asset_code: "XYZ".to_string(),
                     asset_scale: 9,
                     btp_incoming_token: Some("token-two".to_string()),
+                    btp_outgoing_username: None,
                     btp_uri: None,
                     http_endpoint: None,
                     http_incoming_token: None,
                     http_outgoing_token: None,
+                    http_incoming_certificate: None,
+                    http_client_identity: None,
+                    http_client_identity_password: None,
+                    http_client_max_idle_per_host: None,
+                    http_client_http2_prior_knowledge: None,
+                    http_client_max_timeout: None,
+                    http_client_max_retries: None,
                     max_packet_amount: u64::max_value(),
                     min_balance: None,
                     settle_threshold: None,
@@ -109,6 +139,7 @@ fn btp_end_to_end() {
                     &format!("btp+ws://:{}@localhost:{}", auth, btp_port),
                     &format!("http://localhost:{}", spsp_server_port),
                     10000,
+                    None,
                     true,
                 )
                 .then(move |result| {